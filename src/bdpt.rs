@@ -0,0 +1,603 @@
+use crate::vec_math::*;
+use crate::hitable::*;
+use crate::material::*;
+use crate::color::*;
+use crate::integrator::*;
+use crate::camera::*;
+
+use std::cell::RefCell;
+use std::f32::consts::PI;
+
+// Bidirectional path tracer. Camera and light subpaths are traced independently,
+// every pair of their vertices is connected and the strategies are combined with
+// the balance heuristic. All densities stored in the vertices are per unit area.
+// The pinhole camera can't be hit, so a light subpath reaches it only by being
+// connected to it. Those strategies land anywhere on the image and are splatted
+// to a film, which is added to the pixels once the image is done.
+
+#[derive( Copy, Clone, PartialEq )]
+enum VertexKind {
+	Camera,
+	Light,
+	Surface,
+	Medium
+}
+
+struct Vertex<'a> {
+	kind : VertexKind,
+	pos : Vec3,
+	normal : Vec3,
+	tangent : Vec3,
+	bitangent : Vec3,
+	dir : Vec3,
+	u : f32,
+	v : f32,
+	material : Option<&'a Material>,
+	object : usize,
+	beta : Color,
+	delta : bool,
+	pdf_fwd : f32,
+	pdf_rev : f32,
+	time : f32
+}
+
+impl<'a> Vertex<'a> {
+
+	fn on_surface( &self ) -> bool {
+		self.kind == VertexKind::Surface || self.kind == VertexKind::Light
+	}
+
+	fn is_connectable( &self ) -> bool {
+		match self.kind {
+			VertexKind::Camera => false,
+			VertexKind::Light => true,
+			_ => !self.delta
+		}
+	}
+
+	// Media may glow, which only paths hitting them find
+	fn is_emitter( &self ) -> bool {
+		match self.material {
+			Some(m) => (self.kind == VertexKind::Surface && m.is_emitter()) || self.kind == VertexKind::Medium,
+			None => false
+		}
+	}
+
+	// Interaction record of the vertex for the material
+	fn hit( &self ) -> Option<Hit<'a>> {
+		Some( Hit {
+			distance : 0.0,
+			pos : self.pos.clone(),
+			normal : self.normal.clone(),
+			tangent : self.tangent.clone(),
+			bitangent : self.bitangent.clone(),
+			u : self.u,
+			v : self.v,
+			uv_scale : 0.0,
+			ray : Ray { time : self.time, ..Ray::new( &self.pos, &self.dir ) },
+			material : self.material?
+		} )
+	}
+
+	// Radiance leaving an emitter towards `out`
+	fn le( &self, out : &Vec3 ) -> Color {
+		if dot_product( &self.normal, out ) <= 0.0 {
+			return Color::new(0.0,0.0,0.0);
+		}
+		match self.hit() {
+			Some(h) => h.material.emit( &h, out ),
+			None => Color::new(0.0,0.0,0.0)
+		}
+	}
+
+	// BSDF for scattering towards `out`. Light flows along the subpath on the
+	// light side and against it on the camera side.
+	fn f( &self, out : &Vec3, light_side : bool ) -> Color {
+		let h = match self.hit() {
+			Some(h) => h,
+			None => return Color::new(0.0,0.0,0.0)
+		};
+		if light_side {
+			h.material.eval( &h, &-&self.dir, out )
+		} else {
+			h.material.eval( &h, out, &-&self.dir )
+		}
+	}
+
+	fn convert_density( &self, pdf : f32, next : &Vertex ) -> f32 {
+		let w = &next.pos - &self.pos;
+		let dist2 = w.squre_length();
+		if dist2 == 0.0 {
+			return 0.0;
+		}
+		let mut pdf = pdf / dist2;
+		if next.on_surface() {
+			pdf *= dot_product( &next.normal, &w ).abs() / dist2.sqrt();
+		}
+		pdf
+	}
+
+	// Density of sampling the point on a light which is represented by the vertex
+	fn pdf_light_origin( &self, objects : &Vec<Box<Hitable>>, lights : &Vec<usize> ) -> f32 {
+		if !lights.contains( &self.object ) {
+			return 0.0;
+		}
+		objects[self.object].surface_pdf( &self.pos, self.time ) / lights.len() as f32
+	}
+
+	// Density of an emitter sending light from the vertex to `next`
+	fn pdf_light( &self, next : &Vertex ) -> f32 {
+		let w = (&next.pos - &self.pos).normalized();
+		let pdf = dot_product( &self.normal, &w ).max(0.0) / PI;
+		self.convert_density( pdf, next )
+	}
+
+	// Density of sampling `next` at the vertex after arriving from `prev`
+	fn pdf( &self, prev : &Vertex, next : &Vertex ) -> f32 {
+		if self.kind == VertexKind::Light {
+			return self.pdf_light( next );
+		}
+		let h = match self.hit() {
+			Some(h) => h,
+			None => return 0.0
+		};
+		let dir = (&self.pos - &prev.pos).normalized();
+		let out = (&next.pos - &self.pos).normalized();
+		let pdf = h.material.pdf( &h, &out, &-&dir );
+		self.convert_density( pdf, next )
+	}
+}
+
+fn remap0( f : f32 ) -> f32 {
+	if f != 0.0 { f } else { 1.0 }
+}
+
+// Geometric term between two vertices, zero when they don't see each other
+fn g( a : &Vertex, b : &Vertex, objects : &Vec<Box<Hitable>> ) -> f32 {
+	let d = &b.pos - &a.pos;
+	let dist = d.length();
+	if dist < 0.0001 {
+		return 0.0;
+	}
+	let w = &d / dist;
+
+	let ray = Ray { time : a.time, ..Ray::new( &a.pos, &w ) };
+	let visible = transmittance( &ray, dist * 0.999, objects );
+	if visible <= 0.0 {
+		return 0.0;
+	}
+
+	let mut g = visible / (dist * dist);
+	if a.on_surface() {
+		g *= dot_product( &a.normal, &w ).abs();
+	}
+	if b.on_surface() {
+		g *= dot_product( &b.normal, &w ).abs();
+	}
+	g
+}
+
+pub struct Bdpt {
+	lights : Vec<usize>,
+	camera : Camera,
+	width : usize,
+	height : usize,
+	film : RefCell<Vec<Color>>
+}
+
+impl Bdpt {
+
+	pub fn new( objects : &Vec<Box<Hitable>>, camera : &Camera, width : usize, height : usize ) -> Bdpt {
+		Bdpt {
+			lights : find_lights( objects ),
+			camera : camera.clone(),
+			width,
+			height,
+			film : RefCell::new( vec![Color::new(0.0,0.0,0.0); width * height] )
+		}
+	}
+
+	// Light which reached the camera through the pixel, to be divided by the
+	// samples per pixel like the pixel's own estimate
+	pub fn splat( &self, x : usize, y : usize ) -> Color {
+		self.film.borrow()[y * self.width + x].clone()
+	}
+
+	// Extends `path` until the ray escapes, is absorbed or `max_depth` is reached.
+	// Returns the throughput and direction of an escaped ray.
+	fn random_walk<'a>( &self, ray : Ray, objects : &'a Vec<Box<Hitable>>, beta : Color, pdf : f32,
+	                    max_depth : usize, path : &mut Vec<Vertex<'a>> ) -> Option<(Color, Vec3)>
+	{
+		let mut ray = ray;
+		let mut beta = beta;
+		let mut pdf_fwd = pdf;
+
+		while path.len() < max_depth {
+			let (object, hit) = match closest_hit( &ray, objects ) {
+				Some(h) => h,
+				None => return Some((beta, ray.direction))
+			};
+
+			let kind = if hit.material.is_medium() { VertexKind::Medium } else { VertexKind::Surface };
+			let mut vertex = Vertex {
+				kind,
+				pos : hit.pos.clone(),
+				normal : hit.normal.clone(),
+				tangent : hit.tangent.clone(),
+				bitangent : hit.bitangent.clone(),
+				dir : ray.direction.clone(),
+				u : hit.u,
+				v : hit.v,
+				material : Some(hit.material),
+				object,
+				beta : beta.clone(),
+				delta : false,
+				pdf_fwd : 0.0,
+				pdf_rev : 0.0,
+				time : ray.time
+			};
+			vertex.pdf_fwd = path[path.len() - 1].convert_density( pdf_fwd, &vertex );
+			path.push( vertex );
+
+			// emitters which also scatter, like `Principled`, carry the path on
+			let sample = match hit.material.sample( &hit ) {
+				Some(s) => s,
+				None => break
+			};
+			let n = path.len();
+			path[n - 1].delta = sample.specular;
+
+			beta = &beta * &sample.weight;
+			if beta.is_black() {
+				break;
+			}
+
+			let pdf_rev = if sample.specular {
+				pdf_fwd = 0.0;
+				0.0
+			} else {
+				pdf_fwd = sample.pdf;
+				hit.material.pdf( &hit, &-&ray.direction, &sample.ray.direction )
+			};
+
+			let rev = path[n - 1].convert_density( pdf_rev, &path[n - 2] );
+			path[n - 2].pdf_rev = rev;
+
+			ray = sample.ray;
+		}
+		None
+	}
+
+	fn light_path<'a>( &self, objects : &'a Vec<Box<Hitable>>, max_depth : usize, time : f32 ) -> Vec<Vertex<'a>>
+	{
+		let mut path = Vec::new();
+		let (object, hit, out, pdf_pos, pdf_dir) = match sample_emission( objects, &self.lights, time ) {
+			Some(s) => s,
+			None => return path
+		};
+
+		let vertex = Vertex {
+			kind : VertexKind::Light,
+			pos : hit.pos.clone(),
+			normal : hit.normal.clone(),
+			tangent : hit.tangent.clone(),
+			bitangent : hit.bitangent.clone(),
+			dir : -&out,
+			u : hit.u,
+			v : hit.v,
+			material : Some(hit.material),
+			object,
+			beta : Color::new(1.0,1.0,1.0) * (1.0 / pdf_pos),
+			delta : false,
+			pdf_fwd : pdf_pos,
+			pdf_rev : 0.0,
+			time
+		};
+		let beta = &vertex.beta * &vertex.le( &out ) * PI;
+		path.push( vertex );
+
+		if !beta.is_black() {
+			self.random_walk( Ray { time, ..Ray::new( &hit.pos, &out ) }, objects, beta, pdf_dir, max_depth, &mut path );
+		}
+		path
+	}
+
+	fn mis_weight( &self, objects : &Vec<Box<Hitable>>, camera : &Vec<Vertex>, light : &Vec<Vertex>,
+	               s : usize, t : usize ) -> f32
+	{
+		let mut cam : Vec<(f32, f32, bool)> = camera[..t].iter().map(|v| (v.pdf_fwd, v.pdf_rev, v.delta)).collect();
+		let mut lig : Vec<(f32, f32, bool)> = light[..s].iter().map(|v| (v.pdf_fwd, v.pdf_rev, v.delta)).collect();
+
+		let pt = &camera[t - 1];
+		if s == 0 {
+			cam[t - 1].1 = pt.pdf_light_origin( objects, &self.lights );
+			cam[t - 1].2 = false;
+			if t > 2 {
+				cam[t - 2].1 = pt.pdf_light( &camera[t - 2] );
+			}
+		} else {
+			let qs = &light[s - 1];
+			cam[t - 1].1 = if s == 1 { qs.pdf_light( pt ) } else { qs.pdf( &light[s - 2], pt ) };
+			cam[t - 1].2 = false;
+			if t > 2 {
+				cam[t - 2].1 = pt.pdf( qs, &camera[t - 2] );
+			}
+			lig[s - 1].1 = if t == 1 {
+				pt.convert_density( self.camera.pdf_dir( &(&qs.pos - &pt.pos) ), qs )
+			} else {
+				pt.pdf( &camera[t - 2], qs )
+			};
+			lig[s - 1].2 = false;
+			if s > 1 {
+				lig[s - 2].1 = qs.pdf( pt, &light[s - 2] );
+			}
+		}
+
+		let mut sum = 0.0;
+
+		let mut ri = 1.0;
+		for i in (1..t).rev() {
+			ri *= remap0( cam[i].1 ) / remap0( cam[i].0 );
+			if !cam[i].2 && !cam[i - 1].2 {
+				sum += ri;
+			}
+		}
+
+		let mut ri = 1.0;
+		for i in (0..s).rev() {
+			ri *= remap0( lig[i].1 ) / remap0( lig[i].0 );
+			let delta_prev = if i > 0 { lig[i - 1].2 } else { false };
+			if !lig[i].2 && !delta_prev {
+				sum += ri;
+			}
+		}
+
+		1.0 / (1.0 + sum)
+	}
+
+	fn connect( &self, objects : &Vec<Box<Hitable>>, camera : &Vec<Vertex>, light : &Vec<Vertex>,
+	            s : usize, t : usize ) -> Color
+	{
+		let pt = &camera[t - 1];
+
+		let l = if s == 0 {
+			if !pt.is_emitter() {
+				return Color::new(0.0,0.0,0.0);
+			}
+			&pt.beta * &pt.le( &-&pt.dir )
+		} else {
+			let qs = &light[s - 1];
+			if !pt.is_connectable() || !qs.is_connectable() {
+				return Color::new(0.0,0.0,0.0);
+			}
+
+			let w = (&qs.pos - &pt.pos).normalized();
+			let fq = if s == 1 { qs.le( &-&w ) } else { qs.f( &-&w, true ) };
+			let l = &(&qs.beta * &fq) * &(&pt.beta * &pt.f( &w, false ));
+			if l.is_black() {
+				return l;
+			}
+			l * g( pt, qs, objects )
+		};
+
+		if l.is_black() {
+			return l;
+		}
+
+		if s == 0 && !self.lights.contains( &pt.object ) {
+			return l;
+		}
+		l * self.mis_weight( objects, camera, light, s, t )
+	}
+
+	// Connects the light subpath to the camera and splats the light to the pixel
+	// it is seen in
+	fn connect_camera( &self, objects : &Vec<Box<Hitable>>, camera : &Vec<Vertex>, light : &Vec<Vertex>, s : usize )
+	{
+		let pt = &camera[0];
+		let qs = &light[s - 1];
+		if !qs.is_connectable() {
+			return;
+		}
+		let (u, v) = match self.camera.project( &qs.pos ) {
+			Some(p) => p,
+			None => return
+		};
+
+		let w = (&pt.pos - &qs.pos).normalized();
+		let fq = if s == 1 { qs.le( &w ) } else { qs.f( &w, true ) };
+		let l = &qs.beta * &fq * (self.camera.pdf_dir( &-&w ) * g( pt, qs, objects ));
+		if l.is_black() {
+			return;
+		}
+		let l = l * self.mis_weight( objects, camera, light, s, 1 );
+
+		let x = ((u + 0.5) * self.width as f32) as usize;
+		let y = ((0.5 - v) * self.height as f32).ceil() as usize;
+		let pixel = y.min( self.height - 1 ) * self.width + x.min( self.width - 1 );
+		let mut film = self.film.borrow_mut();
+		film[pixel] = &film[pixel] + l;
+	}
+
+	pub fn colour( &self, ray : &Ray, objects : &Vec<Box<Hitable>>, settings : &RenderSettings ) -> Color
+	{
+		let max_depth = settings.max_depth.max(1) as usize;
+
+		// jittered rays may leave the image, where the light can't be connected
+		// to the camera, which a delta vertex leaves out of the weights
+		let pdf = self.camera.pdf_dir( &ray.direction );
+		let mut camera = vec![ Vertex {
+			kind : VertexKind::Camera,
+			pos : ray.origin.clone(),
+			normal : ray.direction.clone(),
+			tangent : Vec3::zero(),
+			bitangent : Vec3::zero(),
+			dir : ray.direction.clone(),
+			u : 0.0,
+			v : 0.0,
+			material : None,
+			object : 0,
+			beta : Color::new(1.0,1.0,1.0),
+			delta : pdf == 0.0,
+			pdf_fwd : 1.0,
+			pdf_rev : 0.0,
+			time : ray.time
+		} ];
+
+		let escaped = self.random_walk( ray.clone(), objects, Color::new(1.0,1.0,1.0), pdf,
+		                                max_depth + 1, &mut camera );
+		let light = self.light_path( objects, max_depth, ray.time );
+
+		let mut l = match escaped {
+			Some((beta, dir)) => &beta * &background( &dir ),
+			None => Color::new(0.0,0.0,0.0)
+		};
+
+		for t in 2..camera.len() + 1 {
+			for s in 0..light.len() + 1 {
+				if s + t - 2 > max_depth {
+					break;
+				}
+				l = l + self.connect( objects, &camera, &light, s, t );
+			}
+		}
+		for s in 1..light.len() + 1 {
+			self.connect_camera( objects, &camera, &light, s );
+		}
+		l
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::texture::*;
+	use crate::random::*;
+	use crate::shapes::*;
+
+	fn vertex<'a>( kind : VertexKind, pos : &Vec3, normal : &Vec3, material : &'a Material, object : usize ) -> Vertex<'a> {
+		Vertex {
+			kind,
+			pos : pos.clone(),
+			normal : normal.clone(),
+			tangent : Vec3::zero(),
+			bitangent : Vec3::zero(),
+			dir : Vec3::new( 0.0, 0.0, 1.0 ),
+			u : 0.0,
+			v : 0.0,
+			material : Some(material),
+			object,
+			beta : Color::new(1.0,1.0,1.0),
+			delta : false,
+			pdf_fwd : 0.0,
+			pdf_rev : 0.0,
+			time : 0.0
+		}
+	}
+
+	fn room() -> Vec<Box<Hitable>> {
+		let grey = || Box::new( Lambertian { albedo : uniform( 0.5 ) } );
+		let light = Box::new( BlackBody { radiation : uniform( 10.0 ), spectrum : None } );
+		vec![
+			Box::new( Quad { corner : Vec3::new( -5.0, 0.0, -5.0 ), edge_u : Vec3::new( 0.0, 0.0, 10.0 ), edge_v : Vec3::new( 10.0, 0.0, 0.0 ), material : grey() } ),
+			Box::new( Quad { corner : Vec3::new( 1.0, 0.0, -1.0 ), edge_u : Vec3::new( 0.0, 0.0, 2.0 ), edge_v : Vec3::new( 0.0, 2.0, 0.0 ), material : grey() } ),
+			Box::new( Quad { corner : Vec3::new( -0.5, 2.0, -0.5 ), edge_u : Vec3::new( 1.0, 0.0, 0.0 ), edge_v : Vec3::new( 0.0, 0.0, 1.0 ), material : light } )
+		]
+	}
+
+	// looks at the floor and the wall from in front of them
+	fn camera() -> Camera {
+		Camera {
+			origin : Vec3::new( 0.0, 1.0, -3.0 ),
+			low_left_corner : Vec3::new( 0.0, -0.25, 3.5 ),
+			horizontal : Vec3::new( 2.0, 0.0, 0.0 ),
+			vertical : Vec3::new( 0.0, 1.5, 0.0 ),
+			shutter_open : 0.0,
+			shutter_close : 0.0,
+			spread : 0.0
+		}
+	}
+
+	#[test]
+	fn test_mis_weights_sum_to_one() {
+		let objects = room();
+		let bdpt = Bdpt::new( &objects, &camera(), 8, 6 );
+		let grey = Lambertian { albedo : uniform( 0.5 ) };
+		let light = BlackBody { radiation : uniform( 10.0 ), spectrum : None };
+
+		// camera, floor, wall, floor and the light, every point sees the next one
+		let up = Vec3::new( 0.0, 1.0, 0.0 );
+		let points = [
+			(Vec3::new( 0.0, 1.0, -3.0 ), Vec3::new( 0.0, -1.0, 3.0 ).normalized(), &grey as &Material, 0),
+			(Vec3::new( 0.2, 0.0, 0.0 ), up.clone(), &grey, 0),
+			(Vec3::new( 1.0, 1.0, 0.3 ), Vec3::new( -1.0, 0.0, 0.0 ), &grey, 1),
+			(Vec3::new( -0.3, 0.0, 0.4 ), up.clone(), &grey, 0),
+			(Vec3::new( 0.1, 2.0, 0.1 ), -&up, &light, 2)
+		];
+
+		for n in 3..points.len() + 1 {
+			// the path through the first n - 1 points and the light
+			let path : Vec<&(Vec3, Vec3, &Material, usize)> = points[..n - 1].iter().chain( points[4..].iter() ).collect();
+			let kind = |i : usize| if i == 0 { VertexKind::Camera } else if i == n - 1 { VertexKind::Light } else { VertexKind::Surface };
+			let full : Vec<Vertex> = path.iter().enumerate().map(|(i, p)| vertex( kind( i ), &p.0, &p.1, p.2, p.3 )).collect();
+
+			// densities of each vertex when sampled from the camera and from the light
+			let fwd : Vec<f32> = (0..n).map(|i| match i {
+				0 => 1.0,
+				1 => full[0].convert_density( bdpt.camera.pdf_dir( &(&full[1].pos - &full[0].pos) ), &full[1] ),
+				_ => full[i - 1].pdf( &full[i - 2], &full[i] )
+			}).collect();
+			let rev : Vec<f32> = (0..n).map(|i| if i == n - 1 {
+				full[i].pdf_light_origin( &objects, &bdpt.lights )
+			} else if i == n - 2 {
+				full[n - 1].pdf_light( &full[i] )
+			} else {
+				full[i + 1].pdf( &full[i + 2], &full[i] )
+			}).collect();
+
+			let mut sum = 0.0;
+			for t in 1..n + 1 {
+				let camera : Vec<Vertex> = (0..t).map(|i| {
+					let k = if i == 0 { VertexKind::Camera } else { VertexKind::Surface };
+					Vertex { pdf_fwd : fwd[i], pdf_rev : rev[i], ..vertex( k, &path[i].0, &path[i].1, path[i].2, path[i].3 ) }
+				}).collect();
+				let light : Vec<Vertex> = (t..n).rev().map(|i| {
+					Vertex { pdf_fwd : rev[i], pdf_rev : fwd[i], ..vertex( kind( i ), &path[i].0, &path[i].1, path[i].2, path[i].3 ) }
+				}).collect();
+				let w = bdpt.mis_weight( &objects, &camera, &light, n - t, t );
+				assert!( w > 0.0 && w <= 1.0 );
+				sum += w;
+			}
+			assert!( (sum - 1.0).abs() < 1e-4, "{} vertices: {}", n, sum );
+		}
+	}
+
+	#[test]
+	fn test_agrees_with_path_tracing() {
+		let mut objects = room();
+		objects.push( Box::new( Sphere { center : Vec3::new( 0.0, 0.5, 0.5 ), radius : 0.5, material : Box::new( Lambertian { albedo : uniform( 0.5 ) } ) } ) );
+		let camera = camera();
+		let bdpt = Bdpt::new( &objects, &camera, 8, 6 );
+		let settings = RenderSettings { max_depth : 8, ..RenderSettings::new() };
+
+		// average over the image, where the light splatted to the film counts too
+		let n = 20000;
+		let lights = find_lights( &objects );
+		let ray = || camera.get_ray( random() - 0.5, random() - 0.5 );
+		let mut path = 0.0;
+		let mut bidirectional = 0.0;
+		for _ in 0..n {
+			path += find_colour( &ray(), &objects, &lights, 0, &settings ).luminance();
+			bidirectional += bdpt.colour( &ray(), &objects, &settings ).luminance();
+		}
+		let mut splats = 0.0;
+		for x in 0..8 {
+			for y in 0..6 {
+				splats += bdpt.splat( x, y ).luminance();
+			}
+		}
+		assert!( splats > 0.0 );
+		let (path, bidirectional) = (path / n as f32, (bidirectional + splats) / n as f32);
+		assert!( (path - bidirectional).abs() < 0.03 * path, "{} {}", path, bidirectional );
+	}
+}
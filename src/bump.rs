@@ -0,0 +1,209 @@
+use crate::vec_math::*;
+use crate::color::*;
+use crate::texture::*;
+use crate::material::*;
+use crate::hitable::*;
+
+use std::rc::Rc;
+
+// Materials which only tilt the shading normal and hand the interaction over
+// to another material. Both work in the tangent frame of the hit, so the
+// detail follows the u, v parametrisation of the surface.
+
+// Tangent space normal map, the texture holds the x, y, z of the normal
+// remapped from [-1, 1] to [0, 1], flat is (0.5, 0.5, 1.0)
+#[allow(dead_code)]
+pub struct NormalMap {
+	pub normals : Rc<Texture>,
+	// 0 keeps the surface normal, 1 uses the map as it is
+	pub strength : f32,
+	pub material : Box<Material>
+}
+
+// Bump map from the luminance of any texture
+#[allow(dead_code)]
+pub struct BumpMap {
+	pub height : Rc<Texture>,
+	// height of a luminance of one, in the units of u and v
+	pub scale : f32,
+	pub material : Box<Material>
+}
+
+// step of the finite differences, in the units of u and v
+#[allow(dead_code)]
+const BUMP_DELTA : f32 = 0.001;
+
+#[allow(dead_code)]
+impl NormalMap {
+	fn shade<'a>( &self, hit : &Hit<'a> ) -> Hit<'a> {
+		let c = self.normals.value( &hit.tex_coord() );
+		let x = (2.0 * c.r - 1.0) * self.strength;
+		let y = (2.0 * c.g - 1.0) * self.strength;
+		let z = 1.0 + (2.0 * c.b - 2.0) * self.strength;
+
+		let n = x * &hit.tangent + y * &hit.bitangent + z * &hit.normal;
+		if n.squre_length() < 1e-12 {
+			return hit.with_normal( &hit.normal );
+		}
+		hit.with_normal( &n.normalized() )
+	}
+}
+
+#[allow(dead_code)]
+impl BumpMap {
+	fn shade<'a>( &self, hit : &Hit<'a> ) -> Hit<'a> {
+		// the point in space follows the tangents for solid textures
+		let height = |du : f32, dv : f32| {
+			let at = TexCoord {
+				u : hit.u + du,
+				v : hit.v + dv,
+				pos : &hit.pos + du * &hit.tangent + dv * &hit.bitangent,
+				..hit.tex_coord()
+			};
+			self.height.value( &at ).luminance() * self.scale
+		};
+		let h = height( 0.0, 0.0 );
+		let dhdu = (height( BUMP_DELTA, 0.0 ) - h) / BUMP_DELTA;
+		let dhdv = (height( 0.0, BUMP_DELTA ) - h) / BUMP_DELTA;
+
+		let n = &hit.normal - dhdu * &hit.tangent - dhdv * &hit.bitangent;
+		hit.with_normal( &n.normalized() )
+	}
+}
+
+impl Material for NormalMap {
+	fn sample( &self, hit : &Hit ) -> Option<BsdfSample> {
+		self.material.sample( &self.shade( hit ) )
+	}
+
+	fn emit ( &self, hit : &Hit, wo : &Vec3 ) -> Color {
+		self.material.emit( &self.shade( hit ), wo )
+	}
+
+	fn emit_spectrum ( &self, hit : &Hit, wo : &Vec3, lambda : f32 ) -> f32 {
+		self.material.emit_spectrum( &self.shade( hit ), wo, lambda )
+	}
+
+	fn eval ( &self, hit : &Hit, wi : &Vec3, wo : &Vec3 ) -> Color {
+		self.material.eval( &self.shade( hit ), wi, wo )
+	}
+
+	fn pdf ( &self, hit : &Hit, wi : &Vec3, wo : &Vec3 ) -> f32 {
+		self.material.pdf( &self.shade( hit ), wi, wo )
+	}
+
+	fn interior ( &self, hit : &Hit ) -> Option<Interior> {
+		self.material.interior( hit )
+	}
+
+	fn sample_nested ( &self, hit : &Hit, outside : f32 ) -> Option<BsdfSample> {
+		self.material.sample_nested( &self.shade( hit ), outside )
+	}
+
+	fn is_emitter ( &self ) -> bool { self.material.is_emitter() }
+	fn is_medium ( &self ) -> bool { self.material.is_medium() }
+	fn is_dispersive ( &self ) -> bool { self.material.is_dispersive() }
+	fn is_diffuse ( &self, hit : &Hit ) -> bool { self.material.is_diffuse( &self.shade( hit ) ) }
+}
+
+impl Material for BumpMap {
+	fn sample( &self, hit : &Hit ) -> Option<BsdfSample> {
+		self.material.sample( &self.shade( hit ) )
+	}
+
+	fn emit ( &self, hit : &Hit, wo : &Vec3 ) -> Color {
+		self.material.emit( &self.shade( hit ), wo )
+	}
+
+	fn emit_spectrum ( &self, hit : &Hit, wo : &Vec3, lambda : f32 ) -> f32 {
+		self.material.emit_spectrum( &self.shade( hit ), wo, lambda )
+	}
+
+	fn eval ( &self, hit : &Hit, wi : &Vec3, wo : &Vec3 ) -> Color {
+		self.material.eval( &self.shade( hit ), wi, wo )
+	}
+
+	fn pdf ( &self, hit : &Hit, wi : &Vec3, wo : &Vec3 ) -> f32 {
+		self.material.pdf( &self.shade( hit ), wi, wo )
+	}
+
+	fn interior ( &self, hit : &Hit ) -> Option<Interior> {
+		self.material.interior( hit )
+	}
+
+	fn sample_nested ( &self, hit : &Hit, outside : f32 ) -> Option<BsdfSample> {
+		self.material.sample_nested( &self.shade( hit ), outside )
+	}
+
+	fn is_emitter ( &self ) -> bool { self.material.is_emitter() }
+	fn is_medium ( &self ) -> bool { self.material.is_medium() }
+	fn is_dispersive ( &self ) -> bool { self.material.is_dispersive() }
+	fn is_diffuse ( &self, hit : &Hit ) -> bool { self.material.is_diffuse( &self.shade( hit ) ) }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn lambertian() -> Box<Material> {
+		Box::new( Lambertian { albedo : uniform( 0.5 ) } )
+	}
+
+	// u along x and v along z on a surface facing up
+	fn hit_at<'a>( material : &'a Material, u : f32, v : f32 ) -> Hit<'a> {
+		Hit {
+			distance : 1.0,
+			pos : Vec3::new( u, 0.0, v ),
+			normal : Vec3::new( 0.0, 1.0, 0.0 ),
+			tangent : Vec3::new( 1.0, 0.0, 0.0 ),
+			bitangent : Vec3::new( 0.0, 0.0, 1.0 ),
+			u,
+			v,
+			uv_scale : 1.0,
+			ray : Ray::new( &Vec3::new( u, 1.0, v ), &Vec3::new( 0.0, -1.0, 0.0 ) ),
+			material
+		}
+	}
+
+	// Texture whose luminance grows along u
+	struct Ramp;
+
+	impl Texture for Ramp {
+		fn value( &self, at : &TexCoord ) -> Color {
+			Color::new( at.u, at.u, at.u )
+		}
+	}
+
+	fn normal_map( r : f32, g : f32, b : f32 ) -> NormalMap {
+		NormalMap {
+			normals : Rc::new( ConstantTexture { color : Color::new( r, g, b ) } ),
+			strength : 1.0,
+			material : lambertian()
+		}
+	}
+
+	#[test]
+	fn test_normal_map_works_in_tangent_space() {
+		let flat = normal_map( 0.5, 0.5, 1.0 );
+		let n = flat.shade( &hit_at( &flat, 0.3, 0.7 ) ).normal;
+		assert!( (n.y - 1.0).abs() < 0.001, "{:?}", n );
+
+		// tilted half way towards +v
+		let tilted = normal_map( 0.5, 1.0, 1.0 );
+		let shaded = tilted.shade( &hit_at( &tilted, 0.3, 0.7 ) );
+		let s = 0.5f32.sqrt();
+		assert!( (shaded.normal.z - s).abs() < 0.001 && (shaded.normal.y - s).abs() < 0.001, "{:?}", shaded.normal );
+		assert!( dot_product( &shaded.normal, &shaded.tangent ).abs() < 0.001 );
+		assert!( dot_product( &shaded.normal, &shaded.bitangent ).abs() < 0.001 );
+	}
+
+	#[test]
+	fn test_bump_tilts_against_the_slope() {
+		let m = BumpMap { height : Rc::new( Ramp ), scale : 1.0, material : lambertian() };
+		let n = m.shade( &hit_at( &m, 0.5, 0.5 ) ).normal;
+
+		// height rises by one per unit of u, the normal leans back at 45 degrees
+		let s = 0.5f32.sqrt();
+		assert!( (n.x + s).abs() < 0.01 && (n.y - s).abs() < 0.01 && n.z.abs() < 0.01, "{:?}", n );
+	}
+}
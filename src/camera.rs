@@ -0,0 +1,63 @@
+use crate::vec_math::*;
+use crate::random::*;
+
+#[derive( Clone )]
+pub struct Camera {
+	pub origin : Vec3,
+	pub low_left_corner : Vec3,
+	pub horizontal : Vec3,
+	pub vertical : Vec3,
+	// rays are spread evenly over the time the shutter is open, equal times
+	// freeze the motion
+	pub shutter_open : f32,
+	pub shutter_close : f32,
+	// angle one pixel covers, sizes the footprints textures are filtered over
+	pub spread : f32
+}
+
+impl Camera {
+
+	// `u` and `v` run from -0.5 to 0.5 across the image
+	pub fn get_ray( &self, u : f32, v : f32 ) -> Ray {
+		let dir = &self.low_left_corner + (&self.vertical * v) + (&self.horizontal * u);
+		let time = if self.shutter_close > self.shutter_open {
+			self.shutter_open + random() * (self.shutter_close - self.shutter_open)
+		} else {
+			self.shutter_open
+		};
+		Ray { time, spread : self.spread, ..Ray::new( &self.origin, &dir ) }
+	}
+
+	// `u` and `v` of the ray through `p`, None when it is outside of the image
+	pub fn project( &self, p : &Vec3 ) -> Option<(f32, f32)> {
+		let n = cross_product( &self.horizontal, &self.vertical );
+		let dir = p - &self.origin;
+		let along = dot_product( &dir, &n );
+		let plane = dot_product( &self.low_left_corner, &n );
+		if along * plane <= 0.0 {
+			return None;
+		}
+		let q = &(&dir * (plane / along)) - &self.low_left_corner;
+		let n2 = n.squre_length();
+		let u = dot_product( &cross_product( &q, &self.vertical ), &n ) / n2;
+		let v = dot_product( &cross_product( &self.horizontal, &q ), &n ) / n2;
+		if u.abs() > 0.5 || v.abs() > 0.5 {
+			return None;
+		}
+		Some((u, v))
+	}
+
+	// Density per unit solid angle of `get_ray` sending a ray along `dir` when
+	// `u` and `v` are uniform over the image. It is also the importance of the
+	// direction for the whole image.
+	pub fn pdf_dir( &self, dir : &Vec3 ) -> f32 {
+		if self.project( &(&self.origin + dir) ).is_none() {
+			return 0.0;
+		}
+		let n = cross_product( &self.horizontal, &self.vertical );
+		let area = n.length();
+		let d = dot_product( &self.low_left_corner, &n ) / area;
+		let cos = dot_product( &dir.normalized(), &n ) / area;
+		d * d / (area * (cos * cos * cos).abs())
+	}
+}
@@ -28,6 +28,14 @@ impl Color {
 
 		0xff000000 | (r << 16) | (g <<8) | (b << 0)
 	} 
+
+	pub fn luminance(&self) -> f32 {
+		0.2126 * self.r + 0.7152 * self.g + 0.0722 * self.b
+	}
+
+	pub fn is_black(&self) -> bool {
+		self.r <= 0.0 && self.g <= 0.0 && self.b <= 0.0
+	}
 }
 
 impl Add for Color {
@@ -130,6 +138,26 @@ impl Mul<f32> for &Color {
 	}
 }
 
+impl Mul<Color> for Color {
+	type Output = Color;
+
+	fn mul(self, other: Color) -> Color {
+		Color { r: self.r * other.r, 
+		        g: self.g * other.g, 
+		        b: self.b * other.b }
+	}
+}
+
+impl<'a> Mul for &'a Color {
+	type Output = Color;
+
+	fn mul(self, other: &'a Color) -> Color {
+		Color { r: self.r * other.r, 
+		        g: self.g * other.g, 
+		        b: self.b * other.b }
+	}
+}
+
 impl Mul<Color> for f32 {
 	type Output = Color;
 
@@ -0,0 +1,172 @@
+use crate::vec_math::*;
+use crate::hitable::*;
+
+// How the insides of the two operands of a `Csg` node combine
+#[allow(dead_code)]
+#[derive( Clone, Copy, Debug, PartialEq )]
+pub enum CsgOp {
+	Union,
+	Intersection,
+	// inside `a` but not `b`, the cut keeps the material of `b`
+	Difference
+}
+
+impl CsgOp {
+	fn inside( self, a : bool, b : bool ) -> bool {
+		match self {
+			CsgOp::Union => a || b,
+			CsgOp::Intersection => a && b,
+			CsgOp::Difference => a && !b
+		}
+	}
+}
+
+// Constructive solid geometry on two closed objects, which can be `Csg` nodes
+// themselves. Objects without an inside count as empty. The result isn't
+// sampled as a light.
+#[allow(dead_code)]
+pub struct Csg {
+	pub op : CsgOp,
+	pub a : Box<Hitable>,
+	pub b : Box<Hitable>
+}
+
+#[allow(dead_code)]
+impl Csg {
+	pub fn union( a : Box<Hitable>, b : Box<Hitable> ) -> Csg {
+		Csg { op : CsgOp::Union, a, b }
+	}
+
+	pub fn intersection( a : Box<Hitable>, b : Box<Hitable> ) -> Csg {
+		Csg { op : CsgOp::Intersection, a, b }
+	}
+
+	pub fn difference( a : Box<Hitable>, b : Box<Hitable> ) -> Csg {
+		Csg { op : CsgOp::Difference, a, b }
+	}
+}
+
+// Surface of one operand met along the line
+struct Crossing<'a> {
+	hit : Hit<'a>,
+	of_a : bool,
+	entering : bool
+}
+
+fn crossings<'a>( object : &'a Hitable, ray : &Ray, of_a : bool, out : &mut Vec<Crossing<'a>> ) {
+	for i in object.intervals( ray ).unwrap_or_default() {
+		out.push( Crossing { hit : i.enter, of_a, entering : true } );
+		out.push( Crossing { hit : i.exit, of_a, entering : false } );
+	}
+}
+
+impl Hitable for Csg {
+	fn hit( &self, ray : &Ray ) -> Option<Hit> {
+		self.intervals( ray )?.into_iter()
+			.flat_map(|i| vec![ i.enter, i.exit ])
+			.find(|h| h.distance >= 0.0001)
+	}
+
+	fn intervals( &self, ray : &Ray ) -> Option<Vec<Interval>> {
+		let mut all = Vec::new();
+		crossings( self.a.as_ref(), ray, true, &mut all );
+		crossings( self.b.as_ref(), ray, false, &mut all );
+		all.sort_by(|x, y| x.hit.distance.partial_cmp( &y.hit.distance ).unwrap_or( std::cmp::Ordering::Equal ));
+
+		// walk the line and keep the surfaces where the combined inside changes
+		let (mut in_a, mut in_b) = (false, false);
+		let mut enter = None;
+		let mut inside = Vec::new();
+		for c in all {
+			let was = self.op.inside( in_a, in_b );
+			if c.of_a { in_a = c.entering } else { in_b = c.entering }
+			let is = self.op.inside( in_a, in_b );
+			if was == is {
+				continue;
+			}
+			// normals face out of the result, so a cut turns the surface of `b` around
+			let hit = if is == c.entering { c.hit } else { c.hit.flipped() };
+			if is {
+				enter = Some( hit );
+			} else if let Some(enter) = enter.take() {
+				inside.push( Interval { enter, exit : hit } );
+			}
+		}
+		Some( inside )
+	}
+
+	fn bounds( &self ) -> Option<Aabb> {
+		match self.op {
+			CsgOp::Union => Some( self.a.bounds()?.union( &self.b.bounds()? ) ),
+			CsgOp::Intersection => self.a.bounds().or_else(|| self.b.bounds()),
+			CsgOp::Difference => self.a.bounds()
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::material::*;
+	use crate::texture::*;
+	use crate::mesh::*;
+	use std::rc::Rc;
+
+	fn grey() -> Box<Material> {
+		Box::new( Lambertian { albedo : uniform( 0.5 ) } )
+	}
+
+	fn ball( x : f32, radius : f32 ) -> Box<Hitable> {
+		Box::new( Sphere { center : Vec3::new( x, 0.0, 0.0 ), radius, material : grey() } )
+	}
+
+	fn spans( object : &Hitable, ray : &Ray ) -> Vec<(f32, f32)> {
+		object.intervals( ray ).unwrap().iter().map(|i| (i.enter.distance, i.exit.distance)).collect()
+	}
+
+	fn close( a : &Vec<(f32, f32)>, b : &[(f32, f32)] ) -> bool {
+		a.len() == b.len() && a.iter().zip( b.iter() ).all(|(x, y)| (x.0 - y.0).abs() < 1e-3 && (x.1 - y.1).abs() < 1e-3)
+	}
+
+	#[test]
+	fn test_boolean_operations() {
+		let ray = Ray::new( &Vec3::new( -5.0, 0.0, 0.0 ), &Vec3::new( 1.0, 0.0, 0.0 ) );
+
+		assert!( close( &spans( &Csg::union( ball( 0.0, 1.0 ), ball( 1.0, 1.0 ) ), &ray ), &[ (4.0, 7.0) ] ) );
+		assert!( close( &spans( &Csg::intersection( ball( 0.0, 1.0 ), ball( 1.0, 1.0 ) ), &ray ), &[ (5.0, 6.0) ] ) );
+		assert!( close( &spans( &Csg::difference( ball( 0.0, 1.0 ), ball( 1.0, 1.0 ) ), &ray ), &[ (4.0, 5.0) ] ) );
+		// a hole through the middle leaves two pieces
+		let drilled = Csg::difference( ball( 0.0, 2.0 ), ball( 0.0, 1.0 ) );
+		assert!( close( &spans( &drilled, &ray ), &[ (3.0, 4.0), (6.0, 7.0) ] ) );
+
+		// the cut faces out of what is left, and is found from inside the hole
+		let inside = Ray::new( &Vec3::new( 0.5, 0.0, 0.0 ), &Vec3::new( -1.0, 0.0, 0.0 ) );
+		let hit = drilled.hit( &inside ).unwrap();
+		assert!( (hit.distance - 1.5).abs() < 1e-3 );
+		assert!( (&hit.normal - Vec3::new( 1.0, 0.0, 0.0 )).length() < 1e-3 );
+		assert!( (cross_product( &hit.tangent, &hit.bitangent ) - &hit.normal).length() < 1e-3 );
+
+		// nested nodes, and nothing where the pieces miss the ray
+		let nested = Csg::union( Box::new( drilled ), ball( 4.0, 0.5 ) );
+		assert!( close( &spans( &nested, &ray ), &[ (3.0, 4.0), (6.0, 7.0), (8.5, 9.5) ] ) );
+		let up = Ray::new( &Vec3::new( 0.0, -5.0, 3.0 ), &Vec3::new( 0.0, 1.0, 0.0 ) );
+		assert!( nested.hit( &up ).is_none() );
+	}
+
+	#[test]
+	fn test_meshes_and_instances() {
+		let mesh = Mesh::uv_sphere( &Vec3::zero(), 1.0, 32, 64, grey() );
+		let flat : Rc<Hitable> = Rc::new( mesh );
+		let squashed = Transformed::new( flat, Mat4x4::scale( 2.0, 0.5, 2.0 ) );
+
+		// from inside the mesh the interval starts behind the origin
+		let ray = Ray::new( &Vec3::new( 0.0, 0.0, 0.0 ), &Vec3::new( 1.0, 0.0, 0.0 ) );
+		let s = spans( &squashed, &ray );
+		assert!( s.len() == 1 && (s[0].0 + 2.0).abs() < 0.01 && (s[0].1 - 2.0).abs() < 0.01 );
+
+		let disc = Csg::difference( Box::new( squashed ), ball( 0.0, 1.0 ) );
+		let hit = disc.hit( &ray ).unwrap();
+		assert!( (hit.distance - 1.0).abs() < 1e-3 );
+		assert!( hit.normal.x < -0.99 );
+	}
+}
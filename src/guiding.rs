@@ -0,0 +1,546 @@
+use crate::vec_math::*;
+use crate::hitable::*;
+use crate::color::*;
+use crate::random::*;
+use crate::camera::*;
+use crate::integrator::*;
+
+use std::f32::consts::PI;
+
+// Path guiding with a spatial-directional tree (Müller et al. 2017). A binary
+// tree over space holds in each leaf a quadtree over directions which learns
+// the incident radiance. The tree is trained over progressive passes with a
+// doubling number of paths, and bounces then sample directions from a mix of
+// the learned distribution and the BSDF. Glossy lobes are guided as well as
+// diffuse ones, the mix is weighted by its combined density so it stays
+// unbiased for any BSDF which `eval` and `pdf` describe. Specular lobes and
+// media are left alone.
+
+const MAX_DTREE_DEPTH : usize = 20;
+const DTREE_THRESHOLD : f32 = 0.01;
+const STREE_THRESHOLD : f32 = 12000.0;
+
+// Directions are mapped to the unit square through cylindrical coordinates,
+// which preserves area, so a uniform density on the square is 1 / 4pi on the sphere
+fn dir_to_square( dir : &Vec3 ) -> (f32, f32) {
+	let cos_theta = dir.z.max(-1.0).min(1.0);
+	let mut phi = dir.y.atan2( dir.x );
+	if phi < 0.0 {
+		phi += 2.0 * PI;
+	}
+	( ((cos_theta + 1.0) * 0.5).min(0.999999), (phi / (2.0 * PI)).min(0.999999) )
+}
+
+fn square_to_dir( p : (f32, f32) ) -> Vec3 {
+	let cos_theta = 2.0 * p.0 - 1.0;
+	let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+	let phi = 2.0 * PI * p.1;
+	Vec3::new( sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta )
+}
+
+#[derive( Clone )]
+struct QuadNode {
+	sum : [f32; 4],
+	children : [usize; 4]
+}
+
+impl QuadNode {
+	fn new() -> QuadNode {
+		QuadNode { sum : [0.0; 4], children : [0; 4] }
+	}
+
+	fn total( &self ) -> f32 {
+		self.sum[0] + self.sum[1] + self.sum[2] + self.sum[3]
+	}
+}
+
+fn quadrant( p : (f32, f32) ) -> (usize, (f32, f32)) {
+	let cx = if p.0 >= 0.5 { 1 } else { 0 };
+	let cy = if p.1 >= 0.5 { 1 } else { 0 };
+	(cx + 2 * cy, (p.0 * 2.0 - cx as f32, p.1 * 2.0 - cy as f32))
+}
+
+// Quadtree over the square of directions. A child index of 0 marks a leaf,
+// the root is never anybody's child.
+#[derive( Clone )]
+struct DTree {
+	nodes : Vec<QuadNode>,
+	count : f32
+}
+
+impl DTree {
+
+	fn new() -> DTree {
+		DTree { nodes : vec![QuadNode::new()], count : 0.0 }
+	}
+
+	fn total( &self ) -> f32 {
+		self.nodes[0].total()
+	}
+
+	fn record( &mut self, dir : &Vec3, value : f32 ) {
+		let mut p = dir_to_square( dir );
+		let mut node = 0;
+		loop {
+			let (c, q) = quadrant( p );
+			self.nodes[node].sum[c] += value;
+			if self.nodes[node].children[c] == 0 {
+				break;
+			}
+			node = self.nodes[node].children[c];
+			p = q;
+		}
+		self.count += 1.0;
+	}
+
+	fn sample( &self ) -> Vec3 {
+		let mut node = 0;
+		let mut origin = (0.0, 0.0);
+		let mut size = 1.0;
+		loop {
+			let n = &self.nodes[node];
+			let total = n.total();
+			if total <= 0.0 {
+				break;
+			}
+
+			let mut r = random() * total;
+			let mut c = 3;
+			for i in 0..4 {
+				if r < n.sum[i] {
+					c = i;
+					break;
+				}
+				r -= n.sum[i];
+			}
+			size *= 0.5;
+			origin = (origin.0 + (c % 2) as f32 * size, origin.1 + (c / 2) as f32 * size);
+
+			if n.children[c] == 0 {
+				break;
+			}
+			node = n.children[c];
+		}
+		square_to_dir( (origin.0 + random() * size, origin.1 + random() * size) )
+	}
+
+	// Density per unit solid angle
+	fn pdf( &self, dir : &Vec3 ) -> f32 {
+		let mut p = dir_to_square( dir );
+		let mut node = 0;
+		let mut pdf = 1.0;
+		loop {
+			let n = &self.nodes[node];
+			let total = n.total();
+			if total <= 0.0 {
+				break;
+			}
+			let (c, q) = quadrant( p );
+			pdf *= 4.0 * n.sum[c] / total;
+			if n.children[c] == 0 {
+				break;
+			}
+			node = n.children[c];
+			p = q;
+		}
+		pdf / (4.0 * PI)
+	}
+
+	// Empty tree whose cells are refined where this one collected much energy
+	fn refined( &self ) -> DTree {
+		let mut tree = DTree::new();
+		let total = self.total();
+		if total > 0.0 {
+			self.refine_node( 0, 0, total, 0, &mut tree );
+		}
+		tree
+	}
+
+	fn refine_node( &self, node : usize, into : usize, total : f32, depth : usize, tree : &mut DTree ) {
+		for c in 0..4 {
+			let fraction = self.nodes[node].sum[c] / total;
+			if fraction <= DTREE_THRESHOLD || depth + 1 >= MAX_DTREE_DEPTH {
+				continue;
+			}
+			let child = tree.nodes.len();
+			tree.nodes.push( QuadNode::new() );
+			tree.nodes[into].children[c] = child;
+
+			let old = self.nodes[node].children[c];
+			if old != 0 {
+				self.refine_node( old, child, total, depth + 1, tree );
+			} else {
+				// the old cell was a leaf, assume its energy spreads evenly
+				DTree::split_leaf( fraction / 4.0, child, depth + 1, tree );
+			}
+		}
+	}
+
+	fn split_leaf( fraction : f32, into : usize, depth : usize, tree : &mut DTree ) {
+		if fraction <= DTREE_THRESHOLD || depth + 1 >= MAX_DTREE_DEPTH {
+			return;
+		}
+		for c in 0..4 {
+			let child = tree.nodes.len();
+			tree.nodes.push( QuadNode::new() );
+			tree.nodes[into].children[c] = child;
+			DTree::split_leaf( fraction / 4.0, child, depth + 1, tree );
+		}
+	}
+}
+
+// Leaf of the spatial tree when `children` is None
+struct SNode {
+	axis : usize,
+	children : Option<[usize; 2]>,
+	sampling : DTree,
+	building : DTree
+}
+
+struct SdTree {
+	nodes : Vec<SNode>,
+	min : Vec3,
+	size : f32
+}
+
+impl SdTree {
+
+	fn new( min : Vec3, size : f32 ) -> SdTree {
+		SdTree {
+			nodes : vec![ SNode { axis : 0, children : None, sampling : DTree::new(), building : DTree::new() } ],
+			min,
+			size
+		}
+	}
+
+	fn leaf( &self, pos : &Vec3 ) -> usize {
+		let p = (pos - &self.min) / self.size;
+		let mut p = [ p.x.max(0.0).min(1.0), p.y.max(0.0).min(1.0), p.z.max(0.0).min(1.0) ];
+		let mut node = 0;
+		while let Some(children) = self.nodes[node].children {
+			let axis = self.nodes[node].axis;
+			let c = if p[axis] >= 0.5 { 1 } else { 0 };
+			p[axis] = p[axis] * 2.0 - c as f32;
+			node = children[c];
+		}
+		node
+	}
+
+	fn record( &mut self, pos : &Vec3, dir : &Vec3, value : f32 ) {
+		let leaf = self.leaf( pos );
+		self.nodes[leaf].building.record( dir, value );
+	}
+
+	fn subdivide( &mut self, node : usize, threshold : f32 ) {
+		if self.nodes[node].children.is_none() {
+			if self.nodes[node].building.count <= threshold {
+				return;
+			}
+			let axis = self.nodes[node].axis;
+			let mut half = self.nodes[node].building.clone();
+			half.count *= 0.5;
+			for v in half.nodes.iter_mut() {
+				for s in v.sum.iter_mut() {
+					*s *= 0.5;
+				}
+			}
+
+			let first = self.nodes.len();
+			for _ in 0..2 {
+				self.nodes.push( SNode { axis : (axis + 1) % 3, children : None, sampling : DTree::new(), building : half.clone() } );
+			}
+			self.nodes[node].children = Some([first, first + 1]);
+			self.nodes[node].building = DTree::new();
+		}
+
+		if let Some(children) = self.nodes[node].children {
+			self.subdivide( children[0], threshold );
+			self.subdivide( children[1], threshold );
+		}
+	}
+
+	// Ends a training pass: splits crowded regions, starts sampling from what was
+	// collected and prepares refined empty trees for the next pass
+	fn refine( &mut self, iteration : usize ) {
+		let threshold = STREE_THRESHOLD * (2.0_f32).powi( iteration as i32 ).sqrt();
+		self.subdivide( 0, threshold );
+
+		for node in self.nodes.iter_mut() {
+			if node.children.is_none() {
+				let refined = node.building.refined();
+				node.sampling = std::mem::replace( &mut node.building, refined );
+			}
+		}
+	}
+}
+
+struct GuideRecord {
+	pos : Vec3,
+	dir : Vec3,
+	throughput : Color,
+	radiance : Color,
+	pdf : f32
+}
+
+impl GuideRecord {
+	fn add( &mut self, contribution : &Color ) {
+		let div = |c : f32, t : f32| if t > 0.0 { c / t } else { 0.0 };
+		self.radiance = &self.radiance + Color::new(
+			div( contribution.r, self.throughput.r ),
+			div( contribution.g, self.throughput.g ),
+			div( contribution.b, self.throughput.b ) );
+	}
+}
+
+pub struct PathGuide {
+	tree : SdTree,
+	trained : bool
+}
+
+impl PathGuide {
+
+	pub fn new() -> PathGuide {
+		PathGuide { tree : SdTree::new( Vec3::zero(), 1.0 ), trained : false }
+	}
+
+	// Cube around the first two bounces of a coarse grid of camera rays
+	fn bounds( camera : &Camera, objects : &Vec<Box<Hitable>> ) -> (Vec3, f32) {
+		let mut min = Vec3::new( std::f32::MAX, std::f32::MAX, std::f32::MAX );
+		let mut max = -&min;
+		for i in 0..64 {
+			for j in 0..48 {
+				let mut ray = camera.get_ray( i as f32 / 64.0 - 0.5, j as f32 / 48.0 - 0.5 );
+				for _ in 0..2 {
+					let hit = match closest_hit( &ray, objects ) {
+						Some((_, hit)) => hit,
+						None => break
+					};
+					let p = &hit.pos;
+					min = Vec3::new( min.x.min(p.x), min.y.min(p.y), min.z.min(p.z) );
+					max = Vec3::new( max.x.max(p.x), max.y.max(p.y), max.z.max(p.z) );
+					ray = match hit.material.sample( &hit ) {
+						Some(s) => s.ray,
+						None => break
+					};
+				}
+			}
+		}
+		if min.x > max.x {
+			return (Vec3::new( -1.0, -1.0, -1.0 ), 2.0);
+		}
+		let extent = &max - &min;
+		let size = extent.x.max(extent.y).max(extent.z) * 1.05 + 0.001;
+		let center = (&min + &max) * 0.5;
+		(&center - Vec3::ones() * (size * 0.5), size)
+	}
+
+	// Training pass number `pass`, with twice the paths of the one before. The
+	// first one also places the tree around what the camera sees.
+	pub fn train_pass( &mut self, camera : &Camera, objects : &Vec<Box<Hitable>>, settings : &RenderSettings,
+	                   width : usize, height : usize, pass : usize )
+	{
+		if pass == 0 {
+			let (min, size) = PathGuide::bounds( camera, objects );
+			self.tree = SdTree::new( min, size );
+		}
+
+		let mut records = Vec::new();
+		let paths = (width * height / 4) << pass;
+		for _ in 0..paths {
+			let u = random() - 0.5;
+			let v = random() - 0.5;
+			records.clear();
+			self.trace( &camera.get_ray( u, v ), objects, settings, Some(&mut records) );
+			for r in &records {
+				let value = r.radiance.luminance() / r.pdf;
+				if value.is_finite() && value > 0.0 {
+					self.tree.record( &r.pos, &r.dir, value );
+				}
+			}
+		}
+		self.tree.refine( pass );
+		self.trained = true;
+	}
+
+	fn trace( &self, ray : &Ray, objects : &Vec<Box<Hitable>>, settings : &RenderSettings,
+	          mut records : Option<&mut Vec<GuideRecord>> ) -> Color
+	{
+		let mut l = Color::new(0.0,0.0,0.0);
+		let mut beta = Color::new(1.0,1.0,1.0);
+		let mut ray = ray.clone();
+		let first = records.as_ref().map_or( 0, |r| r.len() );
+
+		for _ in 0..settings.max_depth + 1 {
+			let hit = match closest_hit( &ray, objects ) {
+				Some((_, hit)) => hit,
+				None => {
+					let c = &beta * &background( &ray.direction );
+					l = l + &c;
+					if let Some(r) = records.as_mut() {
+						for v in r[first..].iter_mut() { v.add( &c ); }
+					}
+					break;
+				}
+			};
+
+			let c = &beta * &hit.emitted();
+			if !c.is_black() {
+				l = l + &c;
+				if let Some(r) = records.as_mut() {
+					for v in r[first..].iter_mut() { v.add( &c ); }
+				}
+			}
+
+			let material = hit.material;
+			let sample = match material.sample( &hit ) {
+				Some(s) => s,
+				None => break
+			};
+			let (new_ray, weight) = if sample.specular || material.is_medium() {
+				(sample.ray, sample.weight)
+			} else {
+				let dtree = &self.tree.nodes[self.tree.leaf( &hit.pos )].sampling;
+				let alpha = if self.trained && dtree.total() > 0.0 { settings.guide_fraction } else { 0.0 };
+
+				let wo = -&ray.direction;
+				let out = if random() < alpha { dtree.sample() } else { sample.ray.direction.clone() };
+				let pdf_guide = if alpha > 0.0 { dtree.pdf( &out ) } else { 0.0 };
+				let pdf = alpha * pdf_guide + (1.0 - alpha) * material.pdf( &hit, &out, &wo );
+				if pdf <= 0.0 {
+					break;
+				}
+
+				let f = material.eval( &hit, &out, &wo );
+				let weight = f * (dot_product( &out, &hit.normal ).abs() / pdf);
+				if let Some(r) = records.as_mut() {
+					r.push( GuideRecord {
+						pos : hit.pos.clone(),
+						dir : out.clone(),
+						throughput : &beta * &weight,
+						radiance : Color::new(0.0,0.0,0.0),
+						pdf
+					});
+				}
+				(ray.spawn( &hit.pos, &out ), weight)
+			};
+
+			beta = &beta * &weight;
+			if beta.is_black() {
+				break;
+			}
+			ray = new_ray;
+		}
+		l
+	}
+
+	pub fn colour( &self, ray : &Ray, objects : &Vec<Box<Hitable>>, settings : &RenderSettings ) -> Color {
+		self.trace( ray, objects, settings, None )
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::material::*;
+	use crate::texture::*;
+	use crate::shapes::*;
+
+	fn depth( tree : &DTree, dir : &Vec3 ) -> usize {
+		let mut p = dir_to_square( dir );
+		let mut node = 0;
+		let mut depth = 0;
+		loop {
+			let (c, q) = quadrant( p );
+			depth += 1;
+			if tree.nodes[node].children[c] == 0 {
+				return depth;
+			}
+			node = tree.nodes[node].children[c];
+			p = q;
+		}
+	}
+
+	#[test]
+	fn test_dtree_refines_and_samples_its_pdf() {
+		// most of the light comes from a small cone around +z
+		let light = |d : &Vec3| if d.z > 0.9 { 10.0 } else { 0.1 };
+		let mut tree = DTree::new();
+		for _ in 0..20000 {
+			let d = random_unit_vector();
+			tree.record( &d, light( &d ) );
+		}
+		let mut tree = tree.refined();
+		assert!( depth( &tree, &Vec3::new( 0.0, 0.0, 1.0 ) ) > depth( &tree, &Vec3::new( 0.0, 0.0, -1.0 ) ) );
+		for _ in 0..20000 {
+			let d = random_unit_vector();
+			tree.record( &d, light( &d ) );
+		}
+
+		// the pdf integrates to one and samples land where it says
+		let n = 200000;
+		let (mut total, mut cone, mut z) = (0.0, 0.0, 0.0);
+		for _ in 0..n {
+			let d = random_unit_vector();
+			let pdf = tree.pdf( &d ) * 4.0 * PI;
+			total += pdf;
+			z += d.z * pdf;
+			if d.z > 0.9 { cone += pdf; }
+		}
+		let (mut sampled_cone, mut sampled_z) = (0.0, 0.0);
+		for _ in 0..n {
+			let d = tree.sample();
+			sampled_z += d.z;
+			if d.z > 0.9 { sampled_cone += 1.0; }
+		}
+		let n = n as f32;
+		assert!( (total / n - 1.0).abs() < 0.02, "{}", total / n );
+		assert!( cone / n > 0.5 && (cone - sampled_cone).abs() / n < 0.02 );
+		assert!( (z - sampled_z).abs() / n < 0.02 );
+	}
+
+	#[test]
+	fn test_guided_glossy_and_diffuse_bounces_stay_unbiased() {
+		let floor = Quad {
+			corner : Vec3::new( -5.0, -1.0, -5.0 ),
+			edge_u : Vec3::new( 0.0, 0.0, 10.0 ),
+			edge_v : Vec3::new( 10.0, 0.0, 0.0 ),
+			material : Box::new( Lambertian { albedo : uniform( 0.5 ) } )
+		};
+		let light = Quad {
+			corner : Vec3::new( -1.0, 1.5, 2.0 ),
+			edge_u : Vec3::new( 2.0, 0.0, 0.0 ),
+			edge_v : Vec3::new( 0.0, 0.0, 2.0 ),
+			material : Box::new( BlackBody { radiation : uniform( 4.0 ), spectrum : None } )
+		};
+		let ball = Sphere { center : Vec3::new( 0.0, -0.5, 3.0 ), radius : 0.5, material : Box::new( Metal::new( Color::new( 0.9, 0.9, 0.9 ), 0.3 ) ) };
+		let objects : Vec<Box<Hitable>> = vec![ Box::new( floor ), Box::new( light ), Box::new( ball ) ];
+		let camera = Camera {
+			origin : Vec3::zero(),
+			low_left_corner : Vec3::new( 0.0, 0.0, 1.0 ),
+			vertical : Vec3::new( 0.0, 1.0, 0.0 ),
+			horizontal : Vec3::new( 1.0, 0.0, 0.0 ),
+			shutter_open : 0.0,
+			shutter_close : 0.0,
+			spread : 0.0
+		};
+		let settings = RenderSettings { max_depth : 8, ..RenderSettings::new() };
+
+		let mut guide = PathGuide::new();
+		for pass in 0..3 {
+			guide.train_pass( &camera, &objects, &settings, 32, 24, pass );
+		}
+		let leaf = &guide.tree.nodes[guide.tree.leaf( &Vec3::new( 0.0, -0.5, 2.5 ) )];
+		assert!( leaf.sampling.total() > 0.0 );
+
+		let n = 40000;
+		let lights = find_lights( &objects );
+		let (mut guided, mut path) = (0.0, 0.0);
+		for _ in 0..n {
+			let ray = camera.get_ray( random() - 0.5, random() - 0.5 );
+			guided += guide.colour( &ray, &objects, &settings ).luminance();
+			let ray = camera.get_ray( random() - 0.5, random() - 0.5 );
+			path += find_colour( &ray, &objects, &lights, 0, &settings ).luminance();
+		}
+		assert!( (guided - path).abs() < 0.03 * path, "{} {}", guided / n as f32, path / n as f32 );
+	}
+}
@@ -1,20 +1,192 @@
 
 use crate::vec_math::*;
 use crate::material::*;
+use crate::texture::*;
 use crate::color::*;
 use crate::random::*;
 
-pub struct Hit {
+use std::f32::consts::PI;
+use std::rc::Rc;
+
+// Surface interaction, everything a material needs to scatter the ray which found it
+pub struct Hit<'a> {
 	pub distance :f32,
-	pub pos : Vec3, //Todo is already  in scattered
+	pub pos : Vec3,
 	pub normal : Vec3,
-	pub scattered : Ray,
-	pub albedo : Color,
-	pub emited : Color
+	// unit vectors in the surface along increasing u and v, orthogonal to the normal
+	pub tangent : Vec3,
+	pub bitangent : Vec3,
+	pub u : f32,
+	pub v : f32,
+	// u, v units per unit of length on the surface, 0 where there are no u, v
+	pub uv_scale : f32,
+	pub ray : Ray,
+	pub material : &'a Material
+}
+
+impl<'a> Hit<'a> {
+	pub fn emitted( &self ) -> Color {
+		self.material.emit( self, &-&self.ray.direction )
+	}
+
+	// The footprint is the narrow side of the cone of the ray where it lands,
+	// so grazing views are filtered no more than head on ones
+	pub fn tex_coord( &self ) -> TexCoord {
+		TexCoord {
+			u : self.u,
+			v : self.v,
+			pos : self.pos.clone(),
+			normal : self.normal.clone(),
+			width : self.ray.spread * self.distance,
+			uv_scale : self.uv_scale
+		}
+	}
+
+	// Same interaction seen with another shading normal, the tangents are kept
+	// as close as possible to the original ones
+	#[allow(dead_code)]
+	pub fn with_normal( &self, normal : &Vec3 ) -> Hit<'a> {
+		let (tangent, bitangent) = tangent_frame( normal, &self.tangent, &self.bitangent );
+		Hit {
+			distance : self.distance,
+			pos : self.pos.clone(),
+			normal : normal.clone(),
+			tangent,
+			bitangent,
+			u : self.u,
+			v : self.v,
+			uv_scale : self.uv_scale,
+			ray : self.ray.clone(),
+			material : self.material
+		}
+	}
+
+	// Same interaction with the normal turned around, for surfaces which bound
+	// the other side of a `Csg` node
+	pub fn flipped( self ) -> Hit<'a> {
+		Hit { normal : -&self.normal, bitangent : -&self.bitangent, ..self }
+	}
+}
+
+// Stretch of a ray inside a closed object, from the surface where it enters
+// to the one where it leaves
+pub struct Interval<'a> {
+	pub enter : Hit<'a>,
+	pub exit : Hit<'a>
+}
+
+// Orthonormal tangents around the unit normal `n` from the derivatives of the
+// position along u and v. The first follows `dpdu`, or is arbitrary where it
+// vanishes like at the poles of a sphere, the second only takes its side from `dpdv`.
+pub fn tangent_frame( n : &Vec3, dpdu : &Vec3, dpdv : &Vec3 ) -> (Vec3, Vec3) {
+	let t = dpdu - dot_product( n, dpdu ) * n;
+	let t = if t.squre_length() < 1e-12 { Onb::from_w( n ).u } else { t.normalized() };
+	let b = cross_product( n, &t );
+	if dot_product( &b, dpdv ) < 0.0 { (t, -b) } else { (t, b) }
+}
+
+// u, v units per unit of length for the derivatives of the position along u
+// and v, from the area one unit square of u, v covers
+pub fn uv_scale( dpdu : &Vec3, dpdv : &Vec3 ) -> f32 {
+	let area = cross_product( dpdu, dpdv ).length();
+	if area > 0.0 { 1.0 / area.sqrt() } else { 0.0 }
 }
 
 pub trait Hitable { 
 	fn hit( &self, ray: &Ray ) -> Option<Hit>;
+
+	// Objects with an emitting material which can be sampled by `sample_surface`
+	fn is_light( &self ) -> bool { false }
+
+	// Random point on the surface at `time` and its density with respect to
+	// surface area
+	fn sample_surface( &self, _time : f32 ) -> Option<(Hit, f32)> { None }
+
+	// Density with which `sample_surface` returns `pos` at `time`
+	fn surface_pdf( &self, _pos : &Vec3, _time : f32 ) -> f32 { 0.0 }
+
+	// Media: fraction of the light which gets through along `ray` up to
+	// `distance`, possibly a random estimate. Opaque objects return None and
+	// are tested with `hit`.
+	fn transmittance( &self, _ray : &Ray, _distance : f32 ) -> Option<f32> { None }
+
+	// Closed objects: every interval of the whole line through `ray` which
+	// lies inside, in order and including those behind the origin, so they
+	// can be combined by `Csg`. None for objects without an inside.
+	fn intervals( &self, _ray : &Ray ) -> Option<Vec<Interval>> { None }
+
+	// Box around the object, None for unbounded ones like `Plane`
+	#[allow(dead_code)]
+	fn bounds( &self ) -> Option<Aabb> { None }
+}
+
+pub fn closest_hit<'a>( ray: &Ray, objects: &'a Vec<Box<Hitable>> ) -> Option<(usize, Hit<'a>)>
+{
+	let mut closest : Option<(usize, Hit)> = None;
+	for (i, h) in objects.iter().enumerate() {
+		let h = match h.hit( &ray ) {
+			Some(h) => h,
+			None => continue
+		};
+
+		let closer = match closest {
+			Some((_, ref c)) => h.distance < c.distance,
+			None => true
+		};
+		if closer {
+			closest = Some((i, h));
+		}
+	}
+	closest
+}
+
+// Fraction of the light which travels along `ray` for `distance` without
+// being blocked or scattered away
+pub fn transmittance( ray : &Ray, distance : f32, objects : &Vec<Box<Hitable>> ) -> f32
+{
+	let mut t = 1.0;
+	for object in objects.iter() {
+		match object.transmittance( ray, distance ) {
+			Some(f) => t *= f,
+			None => if let Some(h) = object.hit( ray ) {
+				if h.distance < distance {
+					return 0.0;
+				}
+			}
+		}
+		if t <= 0.0 {
+			return 0.0;
+		}
+	}
+	t
+}
+
+pub fn find_lights( objects: &Vec<Box<Hitable>> ) -> Vec<usize>
+{
+	(0..objects.len()).filter(|i| objects[*i].is_light()).collect()
+}
+
+// Picks one of `lights`, a point on it at `time` and a cosine distributed direction of emission.
+// Returns the light, the point, the direction and the densities of the point and the direction.
+pub fn sample_emission<'a>( objects: &'a Vec<Box<Hitable>>, lights: &Vec<usize>, time : f32 ) -> Option<(usize, Hit<'a>, Vec3, f32, f32)>
+{
+	if lights.is_empty() {
+		return None;
+	}
+
+	let n = lights.len();
+	let object = lights[ ((random() * n as f32) as usize).min(n - 1) ];
+	let (hit, pdf_pos) = objects[object].sample_surface( time )?;
+	if pdf_pos <= 0.0 {
+		return None;
+	}
+
+	let out = (&hit.normal + random_unit_vector()).normalized();
+	let pdf_dir = dot_product( &hit.normal, &out ).max(0.0) / PI;
+	if pdf_dir <= 0.0 {
+		return None;
+	}
+	Some( (object, hit, out, pdf_pos / n as f32, pdf_dir) )
 }
 
 
@@ -24,46 +196,192 @@ pub struct Sphere {
 	pub material : Box<Material>
 }
 
+impl Sphere {
+	// u goes around the y axis and v from the bottom to the top
+	fn tangents( n : &Vec3 ) -> (Vec3, Vec3) {
+		tangent_frame( n, &Vec3::new( n.z, 0.0, -n.x ), &Vec3::new( 0.0, 1.0, 0.0 ) )
+	}
+
+	// spherical coordinates of the unit normal, u = 0 faces -x and v = 0 is the bottom pole
+	fn uv( n : &Vec3 ) -> (f32, f32) {
+		let u = ((-n.z).atan2( n.x ) + PI) / (2.0 * PI);
+		let v = (-n.y).max( -1.0 ).min( 1.0 ).acos() / PI;
+		(u, v)
+	}
+
+	// along v, u is squeezed towards the poles
+	fn uv_scale( radius : f32 ) -> f32 {
+		1.0 / (PI * radius)
+	}
+}
+
+// Nearest intersection of `ray` with a sphere, shared by the still and the
+// moving one
+fn hit_sphere<'a>( center : &Vec3, radius : f32, material : &'a Material, ray : &Ray ) -> Option<Hit<'a>>
+{
+	let oc = &(ray.get_origin()) - center;
+	let a = dot_product( &(ray.get_direction()), &(ray.get_direction()));
+	let b = dot_product( &oc, &(ray.get_direction()) );
+	let c = dot_product( &oc, &oc ) - radius * radius;
+	let discriminant = b * b - a *c;
+
+
+	if discriminant < 0.0001 {
+		return None
+	} 
+
+	let temp_1 = ( -b - discriminant.sqrt() ) /  a ;
+	let temp_2 = ( -b + discriminant.sqrt() ) /  a ;
+	if temp_1 < 0.0001 && temp_2 < 0.0001 {
+		return None;
+	}
+
+	let distance = if temp_1 >= 0.0001 { temp_1 } else { temp_2 };
+	Some( sphere_hit( center, radius, material, ray, distance ) )
+}
+
+// Both crossings of the line through `ray` with a sphere
+fn sphere_intervals<'a>( center : &Vec3, radius : f32, material : &'a Material, ray : &Ray ) -> Vec<Interval<'a>>
+{
+	let oc = &ray.origin - center;
+	let a = dot_product( &ray.direction, &ray.direction );
+	let b = dot_product( &oc, &ray.direction );
+	let discriminant = b * b - a * (dot_product( &oc, &oc ) - radius * radius);
+	if discriminant < 0.0001 {
+		return Vec::new();
+	}
+	let (near, far) = ((-b - discriminant.sqrt()) / a, (-b + discriminant.sqrt()) / a);
+	vec![ Interval { enter : sphere_hit( center, radius, material, ray, near ), exit : sphere_hit( center, radius, material, ray, far ) } ]
+}
+
+fn sphere_hit<'a>( center : &Vec3, radius : f32, material : &'a Material, ray : &Ray, distance : f32 ) -> Hit<'a>
+{
+	let hit_point = ray.get_point(distance);
+
+	let n = ( &hit_point - center ) / radius;
+
+	let (tangent, bitangent) = Sphere::tangents( &n );
+	let (u, v) = Sphere::uv( &n );
+
+	Hit{
+		distance,
+		pos : hit_point,
+		normal: n,
+		tangent,
+		bitangent,
+		u,
+		v,
+		uv_scale : Sphere::uv_scale( radius ),
+		ray : ray.clone(),
+		material
+	}
+}
+
 impl Hitable for Sphere
 {
 	fn hit( &self, ray: &Ray ) -> Option<Hit>
 	{
-		let oc = &(ray.get_origin()) - &self.center;
-		let a = dot_product( &(ray.get_direction()), &(ray.get_direction()));
-		let b = dot_product( &oc, &(ray.get_direction()) );
-		let c = dot_product( &oc, &oc ) - self.radius * self.radius;
-		let discriminant = b * b - a *c;
+		hit_sphere( &self.center, self.radius, self.material.as_ref(), ray )
+	}
 
+	fn is_light( &self ) -> bool { self.material.is_emitter() }
 
-		if discriminant < 0.0001 {
-			return None
-		} 
+	fn sample_surface( &self, time : f32 ) -> Option<(Hit, f32)>
+	{
+		let hit = sample_sphere( &self.center, self.radius, self.material.as_ref(), time );
+		let pdf = self.surface_pdf( &hit.pos, time );
+		Some( (hit, pdf) )
+	}
 
-		let temp_1 = ( -b - discriminant.sqrt() ) /  a ;
-		let temp_2 = ( -b + discriminant.sqrt() ) /  a ;
-		if temp_1 < 0.0001 && temp_2 < 0.0001 {
-			return None;
-		}
+	fn surface_pdf( &self, _pos : &Vec3, _time : f32 ) -> f32
+	{
+		1.0 / (4.0 * PI * self.radius * self.radius)
+	}
 
-		let distance = if temp_1 >= 0.0001 { temp_1 } else { temp_2 };
+	fn intervals( &self, ray : &Ray ) -> Option<Vec<Interval>>
+	{
+		Some( sphere_intervals( &self.center, self.radius, self.material.as_ref(), ray ) )
+	}
 
+	fn bounds( &self ) -> Option<Aabb>
+	{
+		Some( sphere_bounds( &self.center, self.radius ) )
+	}
+}
 
-		let hit_point = ray.get_point(distance);
+#[allow(dead_code)]
+fn sphere_bounds( center : &Vec3, radius : f32 ) -> Aabb
+{
+	let r = Vec3::new( radius, radius, radius );
+	Aabb { min : center - &r, max : center + &r }
+}
 
-		let n = ( &hit_point - &self.center ) / self.radius;
+// Uniformly distributed point on a sphere
+fn sample_sphere<'a>( center : &Vec3, radius : f32, material : &'a Material, time : f32 ) -> Hit<'a>
+{
+	let n = random_unit_vector();
+	let pos = center + radius * &n;
+	let (tangent, bitangent) = Sphere::tangents( &n );
+	let (u, v) = Sphere::uv( &n );
+
+	Hit{
+		distance : 0.0,
+		pos : pos.clone(),
+		normal : n.clone(),
+		tangent,
+		bitangent,
+		u,
+		v,
+		uv_scale : Sphere::uv_scale( radius ),
+		ray : Ray { time, ..Ray::new( &pos, &-&n ) },
+		material
+	}
+}
 
-		let (scattered, albedo) = self.material.scatter( &ray.direction, &n, &hit_point,0.0,0.0 );
-		let emited = self.material.emit( &ray.direction, &n, &hit_point,0.0,0.0);
+// Sphere moving in a straight line, from `center_0` at `time_0` to `center_1`
+// at `time_1`, and standing still before and after
+#[allow(dead_code)]
+pub struct MovingSphere {
+	pub center_0 : Vec3,
+	pub center_1 : Vec3,
+	pub time_0 : f32,
+	pub time_1 : f32,
+	pub radius : f32,
+	pub material : Box<Material>
+}
 
-		return Some( Hit{
-			distance,
-			pos : hit_point,
-			normal: n,
-			scattered,
-			albedo,
-			emited
-		} );
-		
+#[allow(dead_code)]
+impl MovingSphere {
+	pub fn center( &self, time : f32 ) -> Vec3 {
+		let span = self.time_1 - self.time_0;
+		let t = if span > 0.0 { ((time - self.time_0) / span).max( 0.0 ).min( 1.0 ) } else { 0.0 };
+		&self.center_0 + t * (&self.center_1 - &self.center_0)
+	}
+}
+
+impl Hitable for MovingSphere {
+	fn hit( &self, ray : &Ray ) -> Option<Hit> {
+		hit_sphere( &self.center( ray.time ), self.radius, self.material.as_ref(), ray )
+	}
+
+	fn is_light( &self ) -> bool { self.material.is_emitter() }
+
+	fn sample_surface( &self, time : f32 ) -> Option<(Hit, f32)> {
+		let hit = sample_sphere( &self.center( time ), self.radius, self.material.as_ref(), time );
+		Some( (hit, self.surface_pdf( &Vec3::zero(), time )) )
+	}
+
+	fn surface_pdf( &self, _pos : &Vec3, _time : f32 ) -> f32 {
+		1.0 / (4.0 * PI * self.radius * self.radius)
+	}
+
+	fn intervals( &self, ray : &Ray ) -> Option<Vec<Interval>> {
+		Some( sphere_intervals( &self.center( ray.time ), self.radius, self.material.as_ref(), ray ) )
+	}
+
+	// around the whole way
+	fn bounds( &self ) -> Option<Aabb> {
+		Some( sphere_bounds( &self.center_0, self.radius ).union( &sphere_bounds( &self.center_1, self.radius ) ) )
 	}
 }
 
@@ -92,63 +410,281 @@ impl Hitable for Plane {
 		}
 
 		let hit_point = ray.get_point(distance);
+		let (tangent, bitangent) = tangent_frame( &n, &Vec3::new( 1.0, 0.0, 0.0 ), &Vec3::new( 0.0, 0.0, 1.0 ) );
 
-		let (scattered, albedo) = self.material.scatter( &ray.direction ,&n, &hit_point,hit_point.x,hit_point.z);
-		let emited = self.material.emit( &ray.direction, &n, &hit_point,0.0,0.0);
 		return Some( Hit{
 			distance,
-			pos : hit_point,
+			pos : hit_point.clone(),
+			tangent,
+			bitangent,
 			normal: n,
-			scattered,
-			albedo,
-			emited
+			u : hit_point.x,
+			v : hit_point.z,
+			uv_scale : 1.0,
+			ray : ray.clone(),
+			material : self.material.as_ref()
 		} );
 		
 	}
 }
 
-pub struct GlobalMedium {
-	pub density : f32
+// Affine transform of an object into the scene with its inverse
+#[derive( Clone )]
+struct Placement {
+	to_world : Mat4x4,
+	to_object : Mat4x4
 }
 
-impl Hitable for GlobalMedium{
-	fn hit( &self, ray: &Ray ) -> Option<Hit>{
+impl Placement {
+	fn new( transform : Mat4x4 ) -> Placement {
+		Placement { to_object : transform.inv(), to_world : transform }
+	}
 
-		let distance = -(1.0/self.density) / random().ln();
+	// The ray in object space and how much longer its direction got there,
+	// which divides object space distances into world ones
+	fn local( &self, ray : &Ray ) -> (Ray, f32) {
+		let dir = self.to_object.apply_rotation( &ray.direction );
+		let stretch = dir.length();
+		(ray.spawn( &self.to_object.apply( &ray.origin ), &dir ), stretch)
+	}
 
-		if distance > 6.0 { //It's dirty trick
-			return None;
+	fn world_hit<'a>( &self, hit : Hit<'a>, ray : &Ray, stretch : f32 ) -> Hit<'a> {
+		// scattering in a medium faces back along the ray whatever the shape
+		let normal = if hit.material.is_medium() {
+			-&ray.direction
+		} else {
+			self.to_object.apply_transposed_rotation( &hit.normal ).normalized()
+		};
+		let (tangent, bitangent) = tangent_frame( &normal,
+			&self.to_world.apply_rotation( &hit.tangent ), &self.to_world.apply_rotation( &hit.bitangent ) );
+		Hit {
+			distance : hit.distance / stretch,
+			pos : self.to_world.apply( &hit.pos ),
+			normal,
+			tangent,
+			bitangent,
+			u : hit.u,
+			v : hit.v,
+			uv_scale : hit.uv_scale / self.area_scale().sqrt(),
+			ray : ray.clone(),
+			material : hit.material
 		}
+	}
 
-		let hit_point = ray.get_point(distance);
-		let n = random_in_unit_sphere();
+	// Growth of surface areas, exact for similarity transforms
+	fn area_scale( &self ) -> f32 {
+		self.to_world.linear_det().abs().powf( 2.0 / 3.0 )
+	}
 
-		return Some( Hit{
-			distance,
-			pos : hit_point.clone(),
-			normal: n.clone(),
-			scattered : Ray::new(&hit_point, &n ),
-			albedo : Color::new(0.5,0.9,0.9),
-			emited : Color::new(0.0,0.0,0.0)
-		} );
+	fn hit<'a>( &self, object : &'a Hitable, ray : &Ray ) -> Option<Hit<'a>> {
+		let (local, stretch) = self.local( ray );
+		let hit = object.hit( &local )?;
+		Some( self.world_hit( hit, ray, stretch ) )
+	}
+
+	fn sample_surface<'a>( &self, object : &'a Hitable, time : f32 ) -> Option<(Hit<'a>, f32)> {
+		let (hit, pdf) = object.sample_surface( time )?;
+		let dir = self.to_world.apply_rotation( &hit.ray.direction );
+		let ray = hit.ray.spawn( &self.to_world.apply( &hit.ray.origin ), &dir );
+		Some( (self.world_hit( hit, &ray, 1.0 ), pdf / self.area_scale()) )
+	}
+
+	fn surface_pdf( &self, object : &Hitable, pos : &Vec3, time : f32 ) -> f32 {
+		object.surface_pdf( &self.to_object.apply( pos ), time ) / self.area_scale()
+	}
+
+	fn transmittance( &self, object : &Hitable, ray : &Ray, distance : f32 ) -> Option<f32> {
+		let (local, stretch) = self.local( ray );
+		object.transmittance( &local, distance * stretch )
+	}
+
+	fn intervals<'a>( &self, object : &'a Hitable, ray : &Ray ) -> Option<Vec<Interval<'a>>> {
+		let (local, stretch) = self.local( ray );
+		let inside = object.intervals( &local )?;
+		Some( inside.into_iter().map(|i| Interval {
+			enter : self.world_hit( i.enter, ray, stretch ),
+			exit : self.world_hit( i.exit, ray, stretch )
+		}).collect() )
+	}
+}
+
+// Instance of a shared object placed by an affine transform, so the geometry
+// of a mesh is stored once however many copies are in the scene. Lights are
+// sampled exactly under rotations, translations and uniform scales only.
+#[allow(dead_code)]
+pub struct Transformed {
+	pub object : Rc<Hitable>,
+	placement : Placement
+}
+
+#[allow(dead_code)]
+impl Transformed {
+	pub fn new( object : Rc<Hitable>, transform : Mat4x4 ) -> Transformed {
+		Transformed { object, placement : Placement::new( transform ) }
+	}
+
+	pub fn transform( &self ) -> &Mat4x4 {
+		&self.placement.to_world
+	}
+}
+
+impl Hitable for Transformed {
+	fn hit( &self, ray : &Ray ) -> Option<Hit> {
+		self.placement.hit( self.object.as_ref(), ray )
+	}
+
+	fn is_light( &self ) -> bool { self.object.is_light() }
+
+	fn sample_surface( &self, time : f32 ) -> Option<(Hit, f32)> {
+		self.placement.sample_surface( self.object.as_ref(), time )
+	}
+
+	fn surface_pdf( &self, pos : &Vec3, time : f32 ) -> f32 {
+		self.placement.surface_pdf( self.object.as_ref(), pos, time )
+	}
+
+	fn transmittance( &self, ray : &Ray, distance : f32 ) -> Option<f32> {
+		self.placement.transmittance( self.object.as_ref(), ray, distance )
+	}
+
+	fn intervals( &self, ray : &Ray ) -> Option<Vec<Interval>> {
+		self.placement.intervals( self.object.as_ref(), ray )
+	}
+
+	fn bounds( &self ) -> Option<Aabb> {
+		Some( self.object.bounds()?.transformed( &self.placement.to_world ) )
+	}
+}
+
+// Pose of an animated instance at `time`: scaled, then turned by `angle`
+// radians around `axis`, then moved by `translation`. Angles are interpolated
+// as they are, so a part spinning several turns between two keys needs no
+// keys in between.
+#[allow(dead_code)]
+#[derive( Clone, Debug )]
+pub struct Keyframe {
+	pub time : f32,
+	pub translation : Vec3,
+	pub axis : Vec3,
+	pub angle : f32,
+	pub scale : Vec3
+}
+
+#[allow(dead_code)]
+impl Keyframe {
+	// Moved to `translation` and neither turned nor scaled
+	pub fn new( time : f32, translation : Vec3 ) -> Keyframe {
+		Keyframe { time, translation, axis : Vec3::new( 0.0, 1.0, 0.0 ), angle : 0.0, scale : Vec3::ones() }
+	}
+
+	pub fn matrix( &self ) -> Mat4x4 {
+		let axis = if self.axis.squre_length() > 0.0 { self.axis.normalized() } else { Vec3::new( 0.0, 1.0, 0.0 ) };
+		let t = &self.translation;
+		let scaled_and_turned = &Mat4x4::scale( self.scale.x, self.scale.y, self.scale.z ) * &Mat4x4::axis_rotation( &axis, self.angle );
+		&scaled_and_turned * &Mat4x4::translation( t.x, t.y, t.z )
+	}
+
+	fn lerp( &self, other : &Keyframe, t : f32 ) -> Keyframe {
+		let mix = |a : &Vec3, b : &Vec3| a + t * (b - a);
+		Keyframe {
+			time : self.time + t * (other.time - self.time),
+			translation : mix( &self.translation, &other.translation ),
+			axis : mix( &self.axis, &other.axis ),
+			angle : self.angle + t * (other.angle - self.angle),
+			scale : mix( &self.scale, &other.scale )
+		}
+	}
+}
+
+// Instance of a shared object following keyframes, posed at the time of each
+// ray. Before the first key and after the last the object stands still.
+#[allow(dead_code)]
+pub struct Animated {
+	pub object : Rc<Hitable>,
+	keys : Vec<Keyframe>
+}
+
+#[allow(dead_code)]
+impl Animated {
+	pub fn new( object : Rc<Hitable>, keys : Vec<Keyframe> ) -> Animated {
+		let mut keys = keys;
+		keys.sort_by(|a, b| a.time.partial_cmp( &b.time ).unwrap_or( std::cmp::Ordering::Equal ));
+		Animated { object, keys }
+	}
+
+	// Pose at `time`, interpolated between the neighbouring keys
+	pub fn keyframe( &self, time : f32 ) -> Keyframe {
+		let count = self.keys.len();
+		match self.keys.iter().position(|k| k.time > time) {
+			_ if count == 0 => Keyframe::new( time, Vec3::zero() ),
+			Some(0) => self.keys[0].clone(),
+			Some(next) => {
+				let (a, b) = (&self.keys[next - 1], &self.keys[next]);
+				a.lerp( b, (time - a.time) / (b.time - a.time) )
+			},
+			None => self.keys[count - 1].clone()
+		}
+	}
+
+	fn placement( &self, time : f32 ) -> Placement {
+		Placement::new( self.keyframe( time ).matrix() )
+	}
+}
+
+impl Hitable for Animated {
+	fn hit( &self, ray : &Ray ) -> Option<Hit> {
+		self.placement( ray.time ).hit( self.object.as_ref(), ray )
+	}
+
+	fn is_light( &self ) -> bool { self.object.is_light() }
+
+	fn sample_surface( &self, time : f32 ) -> Option<(Hit, f32)> {
+		self.placement( time ).sample_surface( self.object.as_ref(), time )
+	}
+
+	fn surface_pdf( &self, pos : &Vec3, time : f32 ) -> f32 {
+		self.placement( time ).surface_pdf( self.object.as_ref(), pos, time )
+	}
+
+	fn transmittance( &self, ray : &Ray, distance : f32 ) -> Option<f32> {
+		self.placement( ray.time ).transmittance( self.object.as_ref(), ray, distance )
+	}
+
+	fn intervals( &self, ray : &Ray ) -> Option<Vec<Interval>> {
+		self.placement( ray.time ).intervals( self.object.as_ref(), ray )
+	}
+
+	// Keys only move the origin of the object in straight lines, so the ball
+	// around it spun any way stays within the boxes at the keys
+	fn bounds( &self ) -> Option<Aabb> {
+		let corners = self.object.bounds()?.corners();
+		let reach = corners.iter().map(|c| c.length()).fold( 0.0, f32::max );
+		let mut b = Aabb::empty();
+		for k in self.keys.iter() {
+			let s = reach * k.scale.x.abs().max( k.scale.y.abs() ).max( k.scale.z.abs() );
+			b = b.union( &sphere_bounds( &k.translation, s ) );
+		}
+		if self.keys.is_empty() { Some( sphere_bounds( &Vec3::zero(), reach ) ) } else { Some( b ) }
 	}
 }
 
 #[cfg(test)]
 mod tests {
 	use super::*;
-	use std::f32::consts::PI;
+	use crate::texture::*;
+	use std::rc::Rc;
 
 	#[test]
 	fn test_() {
 
-		let material = Lambertian { albedo : Color::new (0.0, 0.0, 0.0) };
+		let material = || Box::new( Lambertian { albedo : Rc::new( ConstantTexture { color : Color::new (0.0, 0.0, 0.0) } ) } );
 
 		{
-			let s = Sphere{center : Vec3::new(0.0,0.0,0.0), radius : 1.0, material };
+			let s = Sphere{center : Vec3::new(0.0,0.0,0.0), radius : 1.0, material : material() };
 			let ray = Ray::new( &Vec3::new(-2.0, 0.0, 0.0), &Vec3::new(1.0, 0.0, 0.0) );
 
-			let (d, p, n,_,_) = s.hit( &ray );
+			let hit = s.hit( &ray ).unwrap();
+			let (d, p, n) = (hit.distance, hit.pos, hit.normal);
 
 			assert!((d - 1.0).abs()< 0.001);
 			assert!((p.x - -1.0).abs()< 0.001);
@@ -160,10 +696,11 @@ mod tests {
 			assert!((n.z       ).abs()< 0.001);
 		}
 		{
-			let s = Sphere{center : Vec3::new(0.0,0.0,0.0), radius : 0.5, material};
+			let s = Sphere{center : Vec3::new(0.0,0.0,0.0), radius : 0.5, material : material() };
 			let ray = Ray::new( &Vec3::new(-1.0, 0.0, 0.0), &Vec3::new(1.0, 0.0, 0.0) );
 
-			let (d, p, n) = s.hit( &ray );
+			let hit = s.hit( &ray ).unwrap();
+			let (d, p, n) = (hit.distance, hit.pos, hit.normal);
 
 			assert!((d - 0.5).abs()< 0.001);
 			assert!((p.x - -0.5).abs()< 0.001);
@@ -175,10 +712,11 @@ mod tests {
 			assert!((n.z       ).abs()< 0.001);
 		}
 		{
-			let s = Sphere{center : Vec3::new(0.0,0.0,0.0), radius : 1.0, material};
+			let s = Sphere{center : Vec3::new(0.0,0.0,0.0), radius : 1.0, material : material() };
 			let ray = Ray::new( &Vec3::new(0.0, 2.0, 0.0), &Vec3::new(0.0, -1.0, 0.0) );
 
-			let (d, p, n) = s.hit( &ray );
+			let hit = s.hit( &ray ).unwrap();
+			let (d, p, n) = (hit.distance, hit.pos, hit.normal);
 
 			assert!((d - 1.0).abs()< 0.001);
 			assert!((p.x       ).abs()< 0.001);
@@ -190,10 +728,11 @@ mod tests {
 			assert!((n.z       ).abs()< 0.001);
 		}
 		{
-			let s = Sphere{center : Vec3::new(0.0, 0.0, 0.0), radius : 1.0, material };
+			let s = Sphere{center : Vec3::new(0.0, 0.0, 0.0), radius : 1.0, material : material() };
 			let ray = Ray::new( &Vec3::new(0.0, 0.0, 2.0), &Vec3::new(0.0, 0.0, -1.0) );
 
-			let (d, p, n) = s.hit( &ray );
+			let hit = s.hit( &ray ).unwrap();
+			let (d, p, n) = (hit.distance, hit.pos, hit.normal);
 
 			assert!((d - 1.0).abs()< 0.001);
 			assert!((p.x       ).abs()< 0.001);
@@ -206,10 +745,11 @@ mod tests {
 		}
 
 		{
-			let s = Sphere{center : Vec3::new(0.0, -0.31, 0.0), radius : 0.3, material};
+			let s = Sphere{center : Vec3::new(0.0, -0.31, 0.0), radius : 0.3, material : material() };
 			let ray = Ray::new( &Vec3::new(0.0, 0.0, 0.0), &Vec3::new(0.0, -1.0, 0.0) );
 
-			let (d, p, n) = s.hit( &ray );
+			let hit = s.hit( &ray ).unwrap();
+			let (d, p, n) = (hit.distance, hit.pos, hit.normal);
 
 			assert!((d - 0.01).abs()< 0.001);
 			assert!((p.x       ).abs()< 0.001);
@@ -223,4 +763,68 @@ mod tests {
 
 
 	}
-}
\ No newline at end of file
+
+	#[test]
+	fn test_transformed() {
+		let material = || Box::new( Lambertian { albedo : Rc::new( ConstantTexture { color : Color::new (0.0, 0.0, 0.0) } ) } );
+		let sphere : Rc<Hitable> = Rc::new( Sphere{ center : Vec3::zero(), radius : 1.0, material : material() } );
+
+		// twice the size, 5 along z
+		let t = Transformed::new( sphere.clone(), &Mat4x4::scale( 2.0, 2.0, 2.0 ) * &Mat4x4::translation( 0.0, 0.0, 5.0 ) );
+		let hit = t.hit( &Ray::new( &Vec3::zero(), &Vec3::new( 0.0, 0.0, 1.0 ) ) ).unwrap();
+		assert!( (hit.distance - 3.0).abs() < 1e-4 && (hit.pos.z - 3.0).abs() < 1e-4 );
+		assert!( (hit.normal.z + 1.0).abs() < 1e-4 );
+		assert!( (t.sample_surface( 0.0 ).unwrap().1 - 1.0 / (16.0 * PI)).abs() < 1e-6 );
+
+		// an ellipsoid x^2 / 4 + y^2 + z^2 = 1, whose normals go along the gradient
+		let e = Transformed::new( sphere.clone(), Mat4x4::scale( 2.0, 1.0, 1.0 ) );
+		let ray = Ray::new( &Vec3::new( -5.0, 0.3, 0.4 ), &Vec3::new( 1.0, 0.0, 0.0 ) );
+		let hit = e.hit( &ray ).unwrap();
+		let p = &hit.pos;
+		assert!( (p.x * p.x / 4.0 + p.y * p.y + p.z * p.z - 1.0).abs() < 1e-4 );
+		assert!( (hit.distance - (p.x + 5.0)).abs() < 1e-4 );
+		let gradient = Vec3::new( p.x / 4.0, p.y, p.z ).normalized();
+		assert!( (&hit.normal - &gradient).length() < 1e-4 );
+		assert!( dot_product( &hit.tangent, &hit.normal ).abs() < 1e-4 );
+
+		// copies share the geometry
+		let copies : Vec<Transformed> = (0..10000).map(|i| {
+			Transformed::new( sphere.clone(), Mat4x4::translation( i as f32 * 3.0, 0.0, 0.0 ) )
+		}).collect();
+		assert_eq!( Rc::strong_count( &sphere ), 10003 );
+		let ray = Ray::new( &Vec3::new( 29997.0, 0.0, -5.0 ), &Vec3::new( 0.0, 0.0, 1.0 ) );
+		assert!( copies[9999].hit( &ray ).is_some() && copies[9998].hit( &ray ).is_none() );
+	}
+
+	#[test]
+	fn test_motion() {
+		let material = || Box::new( Lambertian { albedo : Rc::new( ConstantTexture { color : Color::new (0.0, 0.0, 0.0) } ) } );
+		let at = |time : f32| Ray { time, ..Ray::new( &Vec3::new( 0.0, 0.0, -5.0 ), &Vec3::new( 0.0, 0.0, 1.0 ) ) };
+
+		// passes through the ray halfway through the shutter
+		let s = MovingSphere { center_0 : Vec3::new( -2.0, 0.0, 0.0 ), center_1 : Vec3::new( 2.0, 0.0, 0.0 ),
+		                       time_0 : 0.0, time_1 : 1.0, radius : 0.5, material : material() };
+		assert!( s.hit( &at( 0.0 ) ).is_none() && s.hit( &at( 1.0 ) ).is_none() );
+		assert!( (s.hit( &at( 0.5 ) ).unwrap().distance - 4.5).abs() < 1e-4 );
+		assert!( (s.sample_surface( 0.25 ).unwrap().0.pos - Vec3::new( -1.0, 0.0, 0.0 )).length() < 0.5001 );
+
+		// two and a half turns around y in a unit of time, carrying a ball at x = 1
+		let ball : Rc<Hitable> = Rc::new( Sphere{ center : Vec3::new( 1.0, 0.0, 0.0 ), radius : 0.2, material : material() } );
+		let mut last = Keyframe::new( 1.0, Vec3::new( 0.0, 0.0, 0.0 ) );
+		last.angle = 5.0 * PI;
+		let spinning = Animated::new( ball, vec![ last, Keyframe::new( 0.0, Vec3::zero() ) ] );
+		assert!( (spinning.keyframe( 0.5 ).angle - 2.5 * PI).abs() < 1e-5 );
+
+		// the ball crosses z = 0 between x = -1 and 1 at every half turn, and is
+		// at -z by the right hand rule a quarter turn in
+		let down = |x : f32, time : f32| Ray { time, ..Ray::new( &Vec3::new( x, 5.0, 0.0 ), &Vec3::new( 0.0, -1.0, 0.0 ) ) };
+		assert!( spinning.hit( &down( 1.0, 0.0 ) ).is_some() );
+		assert!( spinning.hit( &down( -1.0, 0.2 ) ).is_some() && spinning.hit( &down( 1.0, 0.2 ) ).is_none() );
+		assert!( spinning.hit( &down( -1.0, 1.0 ) ).is_some() );
+		let quarter = Ray { time : 0.1, ..Ray::new( &Vec3::new( 0.0, 5.0, -1.0 ), &Vec3::new( 0.0, -1.0, 0.0 ) ) };
+		assert!( (spinning.hit( &quarter ).unwrap().distance - 4.8).abs() < 1e-3 );
+
+		// standing still outside the keys
+		assert!( spinning.hit( &down( -1.0, 3.0 ) ).is_some() );
+	}
+}
@@ -0,0 +1,366 @@
+use crate::color::*;
+use crate::png;
+use crate::jpeg;
+
+use std::fs;
+use std::path::Path;
+
+// Decoded image in linear RGB, rows from the top
+#[derive(Debug, Clone)]
+pub struct Image {
+	pub width : usize,
+	pub height : usize,
+	pub pixels : Vec<Color>
+}
+
+// Larger images are refused by the decoders, a few bytes of header shouldn't be
+// able to ask for all the memory there is
+pub const MAX_PIXELS : usize = 1 << 26;
+
+// How 8 and 16 bit pixels are turned into linear values. Float formats are
+// always linear.
+#[allow(dead_code)]
+#[derive( Copy, Clone, PartialEq, Debug )]
+pub enum ColourSpace {
+	Srgb,
+	Linear
+}
+
+pub fn srgb_to_linear( c : f32 ) -> f32 {
+	if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf( 2.4 ) }
+}
+
+impl Image {
+
+	pub fn new( width : usize, height : usize ) -> Image {
+		Image { width, height, pixels : vec![ Color::new( 0.0, 0.0, 0.0 ); width * height ] }
+	}
+
+	pub fn get( &self, x : usize, y : usize ) -> Color {
+		self.pixels[y * self.width + x]
+	}
+
+	pub fn set( &mut self, x : usize, y : usize, c : Color ) {
+		self.pixels[y * self.width + x] = c;
+	}
+
+	// PNG, JPEG, Radiance HDR or PFM, recognised from the content of the file
+	#[allow(dead_code)]
+	pub fn load<P : AsRef<Path>>( path : P, space : ColourSpace ) -> Result<Image, String> {
+		let bytes = fs::read( path.as_ref() ).map_err(|e| format!( "{}: {}", path.as_ref().display(), e ))?;
+		Image::decode( &bytes, space )
+	}
+
+	pub fn decode( bytes : &[u8], space : ColourSpace ) -> Result<Image, String> {
+		let image = Image::decode_any( bytes, space )?;
+		if image.width == 0 || image.height == 0 {
+			return Err( "empty image".to_string() );
+		}
+		Ok( image )
+	}
+
+	fn decode_any( bytes : &[u8], space : ColourSpace ) -> Result<Image, String> {
+		if bytes.starts_with( b"\x89PNG" ) {
+			png::decode( bytes, space )
+		} else if bytes.starts_with( &[0xff, 0xd8] ) {
+			jpeg::decode( bytes, space )
+		} else if bytes.starts_with( b"#?" ) {
+			decode_hdr( bytes )
+		} else if bytes.starts_with( b"PF" ) || bytes.starts_with( b"Pf" ) {
+			decode_pfm( bytes )
+		} else {
+			Err( "unknown image format".to_string() )
+		}
+	}
+
+	// Pixel from 8 bit channels
+	pub fn from_bytes( r : u8, g : u8, b : u8, space : ColourSpace ) -> Color {
+		Image::from_unit( r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0, space )
+	}
+
+	// Pixel from channels in [0, 1]
+	pub fn from_unit( r : f32, g : f32, b : f32, space : ColourSpace ) -> Color {
+		match space {
+			ColourSpace::Srgb => Color::new( srgb_to_linear( r ), srgb_to_linear( g ), srgb_to_linear( b ) ),
+			ColourSpace::Linear => Color::new( r, g, b )
+		}
+	}
+
+	// Half the size with a box filter, odd rows and columns are folded into the last pixel
+	pub fn downsample( &self ) -> Image {
+		let w = (self.width / 2).max(1);
+		let h = (self.height / 2).max(1);
+		let mut out = Image::new( w, h );
+		for y in 0..h {
+			for x in 0..w {
+				let x1 = if x == w - 1 { self.width } else { 2 * x + 2 };
+				let y1 = if y == h - 1 { self.height } else { 2 * y + 2 };
+				let mut sum = Color::new( 0.0, 0.0, 0.0 );
+				for sy in 2 * y..y1 {
+					for sx in 2 * x..x1 {
+						sum = sum + self.get( sx, sy );
+					}
+				}
+				let n = ((x1 - 2 * x) * (y1 - 2 * y)) as f32;
+				out.set( x, y, sum * (1.0 / n) );
+			}
+		}
+		out
+	}
+}
+
+// Next whitespace separated word of a text header, and the position after it
+fn header_token( bytes : &[u8], mut pos : usize ) -> Option<(&str, usize)> {
+	while pos < bytes.len() && bytes[pos].is_ascii_whitespace() {
+		pos += 1;
+	}
+	let start = pos;
+	while pos < bytes.len() && !bytes[pos].is_ascii_whitespace() {
+		pos += 1;
+	}
+	if start == pos {
+		return None;
+	}
+	std::str::from_utf8( &bytes[start..pos] ).ok().map(|s| (s, pos))
+}
+
+// Portable float map, colour or grey, rows from the bottom. The sign of the
+// scale gives the byte order.
+fn decode_pfm( bytes : &[u8] ) -> Result<Image, String> {
+	let bad = || "malformed PFM header".to_string();
+	let (magic, pos) = header_token( bytes, 0 ).ok_or_else( bad )?;
+	let (w, pos) = header_token( bytes, pos ).ok_or_else( bad )?;
+	let (h, pos) = header_token( bytes, pos ).ok_or_else( bad )?;
+	let (scale, pos) = header_token( bytes, pos ).ok_or_else( bad )?;
+	let channels = if magic == "PF" { 3 } else { 1 };
+	let width : usize = w.parse().map_err(|_| bad())?;
+	let height : usize = h.parse().map_err(|_| bad())?;
+	let little = scale.parse::<f32>().map_err(|_| bad())? < 0.0;
+
+	// exactly one whitespace character ends the header
+	let data = bytes.get( pos + 1.. ).unwrap_or( &[] );
+	let size = width.checked_mul( height ).and_then(|n| n.checked_mul( channels * 4 )).ok_or_else( bad )?;
+	if data.len() < size {
+		return Err( "truncated PFM data".to_string() );
+	}
+	let float = |i : usize| {
+		let b = [ data[4 * i], data[4 * i + 1], data[4 * i + 2], data[4 * i + 3] ];
+		if little { f32::from_le_bytes( b ) } else { f32::from_be_bytes( b ) }
+	};
+
+	let mut image = Image::new( width, height );
+	for y in 0..height {
+		for x in 0..width {
+			let i = ((height - 1 - y) * width + x) * channels;
+			let c = if channels == 3 { Color::new( float( i ), float( i + 1 ), float( i + 2 ) ) } else { let v = float( i ); Color::new( v, v, v ) };
+			image.set( x, y, c );
+		}
+	}
+	Ok( image )
+}
+
+fn rgbe( r : u8, g : u8, b : u8, e : u8 ) -> Color {
+	if e == 0 {
+		return Color::new( 0.0, 0.0, 0.0 );
+	}
+	let f = 2.0f32.powi( e as i32 - 136 );
+	Color::new( (r as f32 + 0.5) * f, (g as f32 + 0.5) * f, (b as f32 + 0.5) * f )
+}
+
+// Radiance RGBE with flat or run length encoded scanlines
+fn decode_hdr( bytes : &[u8] ) -> Result<Image, String> {
+	// header lines up to an empty one, then the resolution line
+	let mut pos = 0;
+	loop {
+		let end = bytes[pos..].iter().position(|b| *b == b'\n').ok_or( "malformed HDR header" )? + pos;
+		let line = &bytes[pos..end];
+		pos = end + 1;
+		if line.starts_with( b"FORMAT=" ) && line != b"FORMAT=32-bit_rle_rgbe" {
+			return Err( "only RGBE Radiance files are supported".to_string() );
+		}
+		if line.is_empty() {
+			break;
+		}
+	}
+	let end = bytes[pos..].iter().position(|b| *b == b'\n').ok_or( "missing HDR resolution" )? + pos;
+	let resolution = std::str::from_utf8( &bytes[pos..end] ).map_err(|_| "bad HDR resolution")?;
+	pos = end + 1;
+	let words : Vec<&str> = resolution.split_whitespace().collect();
+	if words.len() != 4 || words[0] != "-Y" || words[2] != "+X" {
+		return Err( format!( "unsupported HDR orientation '{}'", resolution ) );
+	}
+	let height : usize = words[1].parse().map_err(|_| "bad HDR height")?;
+	let width : usize = words[3].parse().map_err(|_| "bad HDR width")?;
+	// every scanline takes at least four bytes
+	if width.checked_mul( height ).map_or( true, |n| n > MAX_PIXELS ) || height > bytes.len() / 4 {
+		return Err( "bad HDR resolution".to_string() );
+	}
+
+	let truncated = || "truncated HDR data".to_string();
+	let mut image = Image::new( width, height );
+	let mut line = vec![ [0u8; 4]; width ];
+	for y in 0..height {
+		let new_rle = width >= 8 && width < 32768 && pos + 4 <= bytes.len()
+			&& bytes[pos] == 2 && bytes[pos + 1] == 2 && ((bytes[pos + 2] as usize) << 8 | bytes[pos + 3] as usize) == width;
+
+		if new_rle {
+			// every channel on its own, runs have a count above 128
+			pos += 4;
+			for c in 0..4 {
+				let mut x = 0;
+				while x < width {
+					let count = *bytes.get( pos ).ok_or_else( truncated )? as usize;
+					pos += 1;
+					if count > 128 {
+						let value = *bytes.get( pos ).ok_or_else( truncated )?;
+						pos += 1;
+						for _ in 0..count - 128 {
+							line.get_mut( x ).ok_or( "bad HDR run" )?[c] = value;
+							x += 1;
+						}
+					} else {
+						if count == 0 || pos + count > bytes.len() {
+							return Err( truncated() );
+						}
+						for k in 0..count {
+							line.get_mut( x ).ok_or( "bad HDR run" )?[c] = bytes[pos + k];
+							x += 1;
+						}
+						pos += count;
+					}
+				}
+			}
+		} else {
+			// flat pixels, the old encoding repeats the previous one for (1, 1, 1, n)
+			let mut x = 0;
+			let mut shift = 0;
+			while x < width {
+				if pos + 4 > bytes.len() {
+					return Err( truncated() );
+				}
+				let p = [ bytes[pos], bytes[pos + 1], bytes[pos + 2], bytes[pos + 3] ];
+				pos += 4;
+				if p[0] == 1 && p[1] == 1 && p[2] == 1 && x > 0 {
+					// a fourth run in a row would count past any scanline
+					if shift > 16 {
+						return Err( "bad HDR run".to_string() );
+					}
+					let previous = line[x - 1];
+					for _ in 0..(p[3] as usize) << shift {
+						*line.get_mut( x ).ok_or( "bad HDR run" )? = previous;
+						x += 1;
+					}
+					shift += 8;
+				} else {
+					line[x] = p;
+					x += 1;
+					shift = 0;
+				}
+			}
+		}
+
+		for x in 0..width {
+			let p = line[x];
+			image.set( x, y, rgbe( p[0], p[1], p[2], p[3] ) );
+		}
+	}
+	Ok( image )
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_float_formats_decode() {
+		// 2x1 little endian colour PFM, stored bottom row first
+		let mut pfm = b"PF\n2 1\n-1.0\n".to_vec();
+		for v in [ 0.5f32, 1.0, 2.0, 4.0, 8.0, 16.0 ].iter() {
+			pfm.extend_from_slice( &v.to_le_bytes() );
+		}
+		let image = Image::decode( &pfm, ColourSpace::Srgb ).unwrap();
+		assert_eq!( (image.width, image.height), (2, 1) );
+		assert_eq!( image.get( 1, 0 ).b, 16.0 );
+
+		// 8 pixel wide run length encoded HDR, one run and one literal per channel
+		let mut hdr = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 1 +X 8\n".to_vec();
+		hdr.extend_from_slice( &[2, 2, 0, 8] );
+		for value in [ 128u8, 64, 32, 129 ].iter() {
+			hdr.extend_from_slice( &[128 + 7, *value, 1, *value] );
+		}
+		let image = Image::decode( &hdr, ColourSpace::Srgb ).unwrap();
+		let c = image.get( 7, 0 );
+		assert!( (c.r - 128.5 / 128.0).abs() < 1e-5 && (c.g - 64.5 / 128.0).abs() < 1e-5, "{:?}", c );
+		assert_eq!( image.get( 3, 0 ).b, image.get( 7, 0 ).b );
+	}
+
+	#[test]
+	fn test_malformed_files_are_rejected() {
+		// sizes which overflow or hold no pixels
+		assert!( Image::decode( b"PF\n4294967296 4294967296\n-1.0\n", ColourSpace::Linear ).is_err() );
+		assert!( Image::decode( b"PF\n0 0\n-1.0\n", ColourSpace::Linear ).is_err() );
+		assert!( Image::decode( b"#?RADIANCE\n\n-Y 0 +X 4\n", ColourSpace::Linear ).is_err() );
+
+		// old style runs one after the other multiply their counts
+		let mut hdr = b"#?RADIANCE\n\n-Y 1 +X 4\n".to_vec();
+		hdr.extend_from_slice( &[10, 10, 10, 130] );
+		for _ in 0..8 {
+			hdr.extend_from_slice( &[1, 1, 1, 0] );
+		}
+		for _ in 0..3 {
+			hdr.extend_from_slice( &[10, 10, 10, 130] );
+		}
+		assert!( Image::decode( &hdr, ColourSpace::Linear ).is_err() );
+
+		// a scanline wider than memory
+		assert!( Image::decode( b"#?RADIANCE\n\n-Y 1 +X 4000000000\n\x02\x02", ColourSpace::Linear ).is_err() );
+
+		// PNG headers promising more rows than the data holds
+		let png = |width : u32, height : u32| {
+			let mut bytes = b"\x89PNG\r\n\x1a\n".to_vec();
+			let mut chunk = |kind : &[u8], data : &[u8]| {
+				bytes.extend_from_slice( &(data.len() as u32).to_be_bytes() );
+				bytes.extend_from_slice( kind );
+				bytes.extend_from_slice( data );
+				bytes.extend_from_slice( &[0, 0, 0, 0] );
+			};
+			let mut header = width.to_be_bytes().to_vec();
+			header.extend_from_slice( &height.to_be_bytes() );
+			header.extend_from_slice( &[8, 2, 0, 0, 0] );
+			chunk( b"IHDR", &header );
+			// an empty stored block
+			chunk( b"IDAT", &[0x78, 0x01, 0x01, 0x00, 0x00, 0xff, 0xff] );
+			chunk( b"IEND", &[] );
+			bytes
+		};
+		assert!( Image::decode( &png( 100000, 100000 ), ColourSpace::Linear ).is_err() );
+		assert!( Image::decode( &png( 4000, 4000 ), ColourSpace::Linear ).is_err() );
+
+		// 8x8 greyscale JPEG whose only DC code has category 32
+		let mut jpeg = vec![ 0xff, 0xd8, 0xff, 0xc0, 0, 11, 8, 0, 8, 0, 8, 1, 1, 0x11, 0 ];
+		jpeg.extend_from_slice( &[0xff, 0xc4, 0, 38, 0x00, 1] );
+		jpeg.extend_from_slice( &[0; 15] );
+		jpeg.extend_from_slice( &[32, 0x10, 1] );
+		jpeg.extend_from_slice( &[0; 15] );
+		jpeg.push( 0 );
+		jpeg.extend_from_slice( &[0xff, 0xdb, 0, 67, 0] );
+		jpeg.extend_from_slice( &[1; 64] );
+		jpeg.extend_from_slice( &[0xff, 0xda, 0, 8, 1, 1, 0x00, 0, 63, 0] );
+		jpeg.extend_from_slice( &[0; 8] );
+		jpeg.extend_from_slice( &[0xff, 0xd9] );
+		let error = Image::decode( &jpeg, ColourSpace::Linear ).unwrap_err();
+		assert!( error.contains( "DC category" ), "{}", error );
+	}
+
+	#[test]
+	fn test_downsample_averages() {
+		let mut image = Image::new( 3, 2 );
+		for x in 0..3 {
+			image.set( x, 0, Color::new( x as f32, 0.0, 0.0 ) );
+			image.set( x, 1, Color::new( x as f32, 1.0, 0.0 ) );
+		}
+		let half = image.downsample();
+		assert_eq!( (half.width, half.height), (1, 1) );
+		assert!( (half.get( 0, 0 ).r - 1.0).abs() < 1e-6 && (half.get( 0, 0 ).g - 0.5).abs() < 1e-6 );
+	}
+}
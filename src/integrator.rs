@@ -0,0 +1,322 @@
+use crate::vec_math::*;
+use crate::hitable::*;
+use crate::color::*;
+use crate::random::*;
+use crate::material::*;
+
+#[derive( Copy, Clone, PartialEq, Debug )]
+pub enum Integrator {
+	PathTracing,
+	Bidirectional,
+	PhotonMapping,
+	Metropolis,
+	PathGuiding,
+	Spectral
+}
+
+#[derive( Copy, Clone )]
+pub struct RenderSettings {
+	pub samples : i32,
+	pub max_depth : i32,
+	pub jitter : f32,
+	pub integrator : Integrator,
+
+	// Caustic photon map, used by Integrator::PhotonMapping
+	pub photons : usize,
+	pub gather_radius : f32,
+	pub gather_count : usize,
+
+	// Primary sample space MLT, used by Integrator::Metropolis.
+	// `samples` is the number of mutations per pixel there.
+	pub bootstrap_samples : usize,
+	pub chains : usize,
+	pub large_step_probability : f32,
+	pub mutation_size : f32,
+
+	// SD-tree path guiding, used by Integrator::PathGuiding. `guide_fraction`
+	// of the non-specular bounces sample the learned distribution instead of the BSDF.
+	pub guide_passes : usize,
+	pub guide_fraction : f32
+}
+
+impl RenderSettings {
+	pub fn new () -> RenderSettings {
+		RenderSettings {
+			samples : 200,
+			max_depth : 50,
+			jitter : 1.1,
+			integrator : Integrator::PathTracing,
+			photons : 500000,
+			gather_radius : 0.05,
+			gather_count : 100,
+			bootstrap_samples : 100000,
+			chains : 1000,
+			large_step_probability : 0.3,
+			mutation_size : 0.01,
+			guide_passes : 5,
+			guide_fraction : 0.5
+		}
+	}
+}
+
+pub fn background ( dir : &Vec3 ) -> Color
+{
+	let t = 0.5 + dir.y;
+	Color::new (0.8,0.6,0.55) * (1.0 - t) + Color::new (0.7,0.8,1.0) * t
+}
+
+// Density, per unit solid angle as seen from `from`, with which `sample_light`
+// picks the point of `hit` on one of `lights`
+fn light_pdf( objects : &Vec<Box<Hitable>>, lights : &Vec<usize>, object : usize, from : &Vec3, hit : &Hit ) -> f32
+{
+	if !lights.contains( &object ) {
+		return 0.0;
+	}
+	let d = &hit.pos - from;
+	let cos = dot_product( &hit.normal, &d ).abs() / d.length();
+	if cos <= 0.0 {
+		return 0.0;
+	}
+	objects[object].surface_pdf( &hit.pos, hit.ray.time ) * d.squre_length() / cos / lights.len() as f32
+}
+
+fn power_heuristic( a : f32, b : f32 ) -> f32 {
+	a * a / (a * a + b * b)
+}
+
+// Light arriving at `hit` straight from a random point on one of `lights`,
+// weighted against BSDF sampling with the power heuristic
+fn sample_light( hit : &Hit, objects : &Vec<Box<Hitable>>, lights : &Vec<usize> ) -> Color
+{
+	let black = Color::new( 0.0, 0.0, 0.0 );
+	if lights.is_empty() {
+		return black;
+	}
+	let n = lights.len();
+	let object = lights[ ((random() * n as f32) as usize).min(n - 1) ];
+	let (light, pdf_area) = match objects[object].sample_surface( hit.ray.time ) {
+		Some(s) => s,
+		None => return black
+	};
+
+	let d = &light.pos - &hit.pos;
+	let dist = d.length();
+	let wi = &d / dist;
+	let cos_light = dot_product( &light.normal, &wi ).abs();
+	if dist < 0.0001 || cos_light <= 0.0 || pdf_area <= 0.0 {
+		return black;
+	}
+
+	let wo = -&hit.ray.direction;
+	let f = hit.material.eval( hit, &wi, &wo );
+	if f.is_black() {
+		return black;
+	}
+
+	let visible = transmittance( &hit.ray.spawn( &hit.pos, &wi ), dist * 0.999, objects );
+	if visible <= 0.0 {
+		return black;
+	}
+
+	let pdf = pdf_area * dist * dist / cos_light / n as f32;
+	let cos = if hit.material.is_medium() { 1.0 } else { dot_product( &hit.normal, &wi ).abs() };
+	let weight = power_heuristic( pdf, hit.material.pdf( hit, &wi, &wo ) );
+	f * light.material.emit( &light, &-&wi ) * (visible * cos * weight / pdf)
+}
+
+// Closed dielectrics a ray is inside of, with the objects they belong to.
+// The one with the highest priority fills the space, on a tie the one entered
+// last. Surfaces of the others are false intersections which rays go through.
+#[derive( Clone )]
+pub struct MediumStack {
+	entries : Vec<(usize, Interior)>
+}
+
+impl MediumStack {
+	pub fn new() -> MediumStack {
+		MediumStack { entries : Vec::new() }
+	}
+
+	fn top( &self ) -> Option<&(usize, Interior)> {
+		self.entries.iter().rev().max_by_key(|(_, i)| i.priority)
+	}
+
+	// Index of refraction around the ray, air outside everything
+	pub fn ior( &self ) -> f32 {
+		self.top().map_or( 1.0, |(_, i)| i.ior )
+	}
+
+	// Fraction of the light which gets through `distance` of the current medium
+	pub fn transmittance( &self, distance : f32 ) -> Color {
+		match self.top() {
+			Some((_, i)) => {
+				let a = i.absorption;
+				Color::new( (-a.r * distance).exp(), (-a.g * distance).exp(), (-a.b * distance).exp() )
+			},
+			None => Color::new( 1.0, 1.0, 1.0 )
+		}
+	}
+
+	// Index of refraction outside `object` where the ray crosses its surface,
+	// None when the surface is a false intersection
+	pub fn crossing( &self, object : usize, interior : &Interior, entering : bool ) -> Option<f32> {
+		if entering {
+			match self.top() {
+				Some((_, top)) if top.priority > interior.priority => None,
+				_ => Some( self.ior() )
+			}
+		} else {
+			match self.top() {
+				Some((o, _)) if *o == object => Some( self.without( object ).ior() ),
+				// leaving an object the ray was never seen entering, e.g. from a
+				// camera inside it
+				_ if !self.entries.iter().any(|(o, _)| *o == object) => Some( self.ior() ),
+				_ => None
+			}
+		}
+	}
+
+	// The stack after the ray went into or out of `object`
+	pub fn crossed( &self, object : usize, interior : &Interior, entering : bool ) -> MediumStack {
+		if entering {
+			let mut entries = self.entries.clone();
+			entries.push( (object, *interior) );
+			MediumStack { entries }
+		} else {
+			self.without( object )
+		}
+	}
+
+	fn without( &self, object : usize ) -> MediumStack {
+		let mut entries = self.entries.clone();
+		if let Some(i) = entries.iter().rposition(|(o, _)| *o == object) {
+			entries.remove( i );
+		}
+		MediumStack { entries }
+	}
+}
+
+// Path tracer with next event estimation. `bsdf_pdf` is the density of the
+// direction of `ray` when it was sampled from a non-specular BSDF, `media` the
+// dielectrics it travels in.
+fn radiance( ray : &Ray, objects : &Vec<Box<Hitable>>, lights : &Vec<usize>, depth : i32,
+             settings : &RenderSettings, bsdf_pdf : Option<f32>, media : &MediumStack ) -> Color
+{
+	if depth > settings.max_depth {
+		return Color::new ( 0.0, 0.0, 0.0 );
+	}
+
+	let (object, hit) = match closest_hit( &ray, &objects ) {
+		Some(h) => h,
+		None => return background( &ray.get_direction() )
+	};
+	let tint = media.transmittance( hit.distance );
+
+	let interior = hit.material.interior( &hit );
+	let entering = dot_product( &ray.direction, &hit.normal ) < 0.0;
+	let outside = match interior {
+		Some(ref i) => match media.crossing( object, i, entering ) {
+			Some(outside) => Some(outside),
+			None => {
+				// shadow rays stop at the surface, so no light was sampled beyond it
+				let through = ray.spawn( &hit.pos, &ray.direction );
+				let behind = media.crossed( object, i, entering );
+				return tint * radiance( &through, objects, lights, depth, settings, None, &behind );
+			}
+		},
+		None => None
+	};
+
+	// emitters reached through a BSDF sample could also have been sampled directly
+	let mut colour = hit.emitted();
+	if let Some(pdf) = bsdf_pdf {
+		let pdf_light = light_pdf( objects, lights, object, &ray.origin, &hit );
+		colour = colour * power_heuristic( pdf, pdf_light );
+	}
+
+	let sample = match outside {
+		Some(outside) => hit.material.sample_nested( &hit, outside ),
+		None => hit.material.sample( &hit )
+	};
+	let sample = match sample {
+		Some(s) => s,
+		None => return tint * colour
+	};
+
+	let mut pdf = None;
+	if !sample.specular {
+		colour = colour + sample_light( &hit, objects, lights );
+		pdf = Some( sample.pdf );
+	}
+
+	// refracted through the surface of a dielectric
+	let crossed;
+	let next = match interior {
+		Some(ref i) if (dot_product( &sample.ray.direction, &hit.normal ) < 0.0) == entering => {
+			crossed = media.crossed( object, i, entering );
+			&crossed
+		},
+		_ => media
+	};
+
+	let cn = radiance( &sample.ray, objects, lights, depth + 1, settings, pdf, next );
+	tint * (colour + sample.weight * cn)
+}
+
+// `lights` are the `find_lights` of `objects`, found once for the whole image
+pub fn find_colour (ray : &Ray, objects: & Vec<Box<Hitable>>, lights : &Vec<usize>,
+                depth : i32, settings : &RenderSettings) -> Color
+{
+	radiance( ray, objects, lights, depth, settings, None, &MediumStack::new() )
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_nested_dielectrics() {
+		let glass = Interior { ior : 1.5, priority : 1, absorption : Color::new( 0.0, 0.0, 0.0 ) };
+		let water = Interior { ior : 1.333, priority : 0, absorption : Color::new( 0.1, 0.0, 0.0 ) };
+		let (g, w) = (3, 7);
+
+		// into the wall of the glass, then into water overlapping the wall
+		let air = MediumStack::new();
+		assert_eq!( air.crossing( g, &glass, true ), Some( 1.0 ) );
+		let in_glass = air.crossed( g, &glass, true );
+		assert_eq!( in_glass.crossing( w, &water, true ), None );
+		let in_both = in_glass.crossed( w, &water, true );
+		assert_eq!( in_both.ior(), 1.5 );
+
+		// out of the wall into the water, and out of the water at the top
+		assert_eq!( in_both.crossing( g, &glass, false ), Some( 1.333 ) );
+		let in_water = in_both.crossed( g, &glass, false );
+		assert_eq!( in_water.ior(), 1.333 );
+		assert!( (in_water.transmittance( 2.0 ).r - (-0.2f32).exp()).abs() < 1e-6 );
+		assert_eq!( in_water.crossing( w, &water, false ), Some( 1.0 ) );
+		assert_eq!( in_water.crossed( w, &water, false ).ior(), 1.0 );
+
+		// the water's own surface inside the wall is ignored on the way out too
+		assert_eq!( in_both.crossing( w, &water, false ), None );
+
+		// between matching indices a ray goes straight on
+		let material = Glass::new( Color::new( 1.0, 1.0, 1.0 ), 1.5 );
+		let dir = Vec3::new( 1.0, -1.0, 0.0 ).normalized();
+		let hit = Hit {
+			distance : 1.0,
+			pos : Vec3::zero(),
+			normal : Vec3::new( 0.0, 1.0, 0.0 ),
+			tangent : Vec3::new( 1.0, 0.0, 0.0 ),
+			bitangent : Vec3::new( 0.0, 0.0, 1.0 ),
+			u : 0.0,
+			v : 0.0,
+			uv_scale : 1.0,
+			ray : Ray::new( &Vec3::new( -1.0, 1.0, 0.0 ), &dir ),
+			material : &material
+		};
+		let through = (0..100).filter_map(|_| material.sample_nested( &hit, 1.5 ))
+			.filter(|s| s.ray.direction.y < 0.0).collect::<Vec<BsdfSample>>();
+		assert!( through.len() > 50 );
+		assert!( through.iter().all(|s| (&s.ray.direction - &dir).length() < 1e-5) );
+	}
+}
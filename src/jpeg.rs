@@ -0,0 +1,429 @@
+use crate::image::*;
+
+use std::f32::consts::PI;
+
+// Baseline and extended sequential JPEG with Huffman coding, greyscale or
+// YCbCr with any chroma subsampling. Progressive files are refused.
+
+const ZIGZAG : [usize; 64] = [
+	 0,  1,  8, 16,  9,  2,  3, 10, 17, 24, 32, 25, 18, 11,  4,  5,
+	12, 19, 26, 33, 40, 48, 41, 34, 27, 20, 13,  6,  7, 14, 21, 28,
+	35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51,
+	58, 59, 52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63 ];
+
+#[derive(Clone, Default)]
+struct Huffman {
+	count : [u16; 17],
+	values : Vec<u8>
+}
+
+struct Component {
+	id : u8,
+	h : usize,
+	v : usize,
+	quant : usize,
+	dc : usize,
+	ac : usize,
+	prediction : i32,
+	// samples of whole MCUs, so wider and taller than the image
+	plane : Vec<u8>,
+	stride : usize
+}
+
+// Entropy coded data, most significant bit first. Stuffed zeros after 0xff are
+// skipped and a marker reads as zero bits.
+struct Reader<'a> {
+	data : &'a [u8],
+	pos : usize,
+	buffer : u32,
+	count : u32
+}
+
+impl<'a> Reader<'a> {
+	fn byte( &mut self ) -> u32 {
+		let b = match self.data.get( self.pos ) {
+			Some(b) => *b,
+			None => return 0
+		};
+		if b == 0xff {
+			if self.data.get( self.pos + 1 ) == Some( &0 ) {
+				self.pos += 2;
+				return 0xff;
+			}
+			return 0;
+		}
+		self.pos += 1;
+		b as u32
+	}
+
+	fn bits( &mut self, n : u32 ) -> u32 {
+		let mut v = 0;
+		for _ in 0..n {
+			if self.count == 0 {
+				self.buffer = self.byte();
+				self.count = 8;
+			}
+			self.count -= 1;
+			v = (v << 1) | ((self.buffer >> self.count) & 1);
+		}
+		v
+	}
+
+	// Signed value of a category `s` coefficient
+	fn extend( &mut self, s : u32 ) -> i32 {
+		if s == 0 {
+			return 0;
+		}
+		let v = self.bits( s ) as i32;
+		if v < 1 << (s - 1) { v - (1 << s) + 1 } else { v }
+	}
+
+	fn decode( &mut self, h : &Huffman ) -> Result<u8, String> {
+		let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+		for len in 1..17 {
+			code |= self.bits( 1 ) as i32;
+			let count = h.count[len] as i32;
+			if code - first < count {
+				return h.values.get( (index + code - first) as usize ).cloned().ok_or( "invalid Huffman code".to_string() );
+			}
+			index += count;
+			first = (first + count) << 1;
+			code <<= 1;
+		}
+		Err( "invalid Huffman code".to_string() )
+	}
+
+	fn restart( &mut self ) -> Result<(), String> {
+		self.count = 0;
+		while self.data.get( self.pos ) == Some( &0xff ) && self.data.get( self.pos + 1 ) == Some( &0xff ) {
+			self.pos += 1;
+		}
+		match (self.data.get( self.pos ), self.data.get( self.pos + 1 )) {
+			(Some( &0xff ), Some( m )) if *m >= 0xd0 && *m <= 0xd7 => {
+				self.pos += 2;
+				Ok( () )
+			},
+			_ => Err( "missing JPEG restart marker".to_string() )
+		}
+	}
+}
+
+fn be16( b : &[u8], pos : usize ) -> Result<usize, String> {
+	match (b.get( pos ), b.get( pos + 1 )) {
+		(Some( hi ), Some( lo )) => Ok( (*hi as usize) << 8 | *lo as usize ),
+		_ => Err( "truncated JPEG".to_string() )
+	}
+}
+
+// Cosines of the inverse DCT, indexed by sample and frequency
+fn idct_basis() -> [[f32; 8]; 8] {
+	let mut basis = [[0.0f32; 8]; 8];
+	for x in 0..8 {
+		for u in 0..8 {
+			let c = if u == 0 { 0.5f32.sqrt() } else { 1.0 };
+			basis[x][u] = 0.5 * c * (((2 * x + 1) * u) as f32 * PI / 16.0).cos();
+		}
+	}
+	basis
+}
+
+// Separable inverse DCT of dequantised coefficients in natural order
+fn idct( coefficients : &[f32; 64], basis : &[[f32; 8]; 8], out : &mut [u8], stride : usize ) {
+	let mut rows = [0.0f32; 64];
+	for v in 0..8 {
+		for x in 0..8 {
+			rows[v * 8 + x] = (0..8).map(|u| basis[x][u] * coefficients[v * 8 + u]).sum();
+		}
+	}
+	for y in 0..8 {
+		for x in 0..8 {
+			let s : f32 = (0..8).map(|v| basis[y][v] * rows[v * 8 + x]).sum();
+			out[y * stride + x] = (s + 128.0).round().max(0.0).min(255.0) as u8;
+		}
+	}
+}
+
+pub fn decode( bytes : &[u8], space : ColourSpace ) -> Result<Image, String> {
+	let mut quant = vec![ [0u16; 64]; 4 ];
+	let mut dc_tables = vec![ Huffman::default(); 4 ];
+	let mut ac_tables = vec![ Huffman::default(); 4 ];
+	let mut components : Vec<Component> = Vec::new();
+	let (mut width, mut height) = (0, 0);
+	let mut restart_interval = 0;
+	let mut pos = 2;
+
+	loop {
+		// markers may be padded with any number of 0xff
+		while bytes.get( pos ) == Some( &0xff ) && bytes.get( pos + 1 ) == Some( &0xff ) {
+			pos += 1;
+		}
+		if bytes.get( pos ) != Some( &0xff ) {
+			return Err( "expected a JPEG marker".to_string() );
+		}
+		let marker = *bytes.get( pos + 1 ).ok_or( "truncated JPEG" )?;
+		if marker == 0xd9 {
+			break;
+		}
+		let len = be16( bytes, pos + 2 )?;
+		let segment = bytes.get( pos + 4..pos + 2 + len ).ok_or( "truncated JPEG" )?;
+		pos += 2 + len;
+
+		match marker {
+			0xc0 | 0xc1 => {
+				if segment.len() < 6 {
+					return Err( "truncated JPEG frame".to_string() );
+				}
+				if segment[0] != 8 {
+					return Err( "only 8 bit JPEG is supported".to_string() );
+				}
+				height = be16( segment, 1 )?;
+				width = be16( segment, 3 )?;
+				for i in 0..segment[5] as usize {
+					let c = segment.get( 6 + 3 * i..9 + 3 * i ).ok_or( "truncated JPEG frame" )?;
+					components.push( Component {
+						id : c[0], h : (c[1] >> 4) as usize, v : (c[1] & 15) as usize, quant : (c[2] & 3) as usize,
+						dc : 0, ac : 0, prediction : 0, plane : Vec::new(), stride : 0
+					} );
+				}
+				if width == 0 || height == 0 || width * height > MAX_PIXELS || components.iter().any(|c| c.h == 0 || c.v == 0) {
+					return Err( "invalid JPEG frame".to_string() );
+				}
+			},
+			0xc2 | 0xc6 | 0xca | 0xce => return Err( "progressive JPEG is not supported".to_string() ),
+			0xc3 | 0xc5 | 0xc7 | 0xc9 | 0xcb | 0xcd | 0xcf => return Err( "unsupported JPEG coding".to_string() ),
+			0xc4 => {
+				let mut p = 0;
+				while p < segment.len() {
+					let mut h = Huffman::default();
+					let total : usize = (0..16).map(|i| *segment.get( p + 1 + i ).unwrap_or( &0 ) as usize).sum();
+					for i in 0..16 {
+						h.count[i + 1] = *segment.get( p + 1 + i ).ok_or( "truncated JPEG table" )? as u16;
+					}
+					h.values = segment.get( p + 17..p + 17 + total ).ok_or( "truncated JPEG table" )?.to_vec();
+					let id = (segment[p] & 3) as usize;
+					if segment[p] >> 4 == 0 { dc_tables[id] = h; } else { ac_tables[id] = h; }
+					p += 17 + total;
+				}
+			},
+			0xdb => {
+				let mut p = 0;
+				while p < segment.len() {
+					let wide = segment[p] >> 4 != 0;
+					let id = (segment[p] & 3) as usize;
+					for k in 0..64 {
+						quant[id][k] = if wide { be16( segment, p + 1 + 2 * k )? as u16 } else { *segment.get( p + 1 + k ).ok_or( "truncated JPEG table" )? as u16 };
+					}
+					p += if wide { 129 } else { 65 };
+				}
+			},
+			0xdd => restart_interval = be16( segment, 0 )?,
+			0xda => {
+				if components.is_empty() || segment.is_empty() {
+					return Err( "JPEG scan before the frame".to_string() );
+				}
+				let mut scan = Vec::new();
+				for i in 0..segment[0] as usize {
+					let s = segment.get( 1 + 2 * i..3 + 2 * i ).ok_or( "truncated JPEG scan" )?;
+					let c = components.iter().position(|c| c.id == s[0]).ok_or( "unknown JPEG component" )?;
+					components[c].dc = (s[1] >> 4) as usize & 3;
+					components[c].ac = (s[1] & 15) as usize & 3;
+					scan.push( c );
+				}
+				pos = decode_scan( bytes, pos, &mut components, &scan, &quant, &dc_tables, &ac_tables, width, height, restart_interval )?;
+			},
+			_ => {}
+		}
+	}
+
+	if components.is_empty() {
+		return Err( "JPEG without a frame".to_string() );
+	}
+	if components.len() != 1 && components.len() != 3 {
+		return Err( "only greyscale and YCbCr JPEG are supported".to_string() );
+	}
+
+	let hmax = components.iter().map(|c| c.h).max().unwrap();
+	let vmax = components.iter().map(|c| c.v).max().unwrap();
+	let sample = |c : &Component, x : usize, y : usize| {
+		let p = c.plane.get( (y * c.v / vmax) * c.stride + x * c.h / hmax ).cloned().unwrap_or( 0 );
+		p as f32
+	};
+
+	let mut image = Image::new( width, height );
+	for y in 0..height {
+		for x in 0..width {
+			let luma = sample( &components[0], x, y );
+			let (r, g, b) = if components.len() == 1 {
+				(luma, luma, luma)
+			} else {
+				let cb = sample( &components[1], x, y ) - 128.0;
+				let cr = sample( &components[2], x, y ) - 128.0;
+				(luma + 1.402 * cr, luma - 0.344136 * cb - 0.714136 * cr, luma + 1.772 * cb)
+			};
+			let unit = |v : f32| v.max(0.0).min(255.0) / 255.0;
+			image.set( x, y, Image::from_unit( unit( r ), unit( g ), unit( b ), space ) );
+		}
+	}
+	Ok( image )
+}
+
+// Decodes the entropy coded data starting at `pos`, returns where it ends
+fn decode_scan( bytes : &[u8], pos : usize, components : &mut Vec<Component>, scan : &Vec<usize>,
+                quant : &Vec<[u16; 64]>, dc_tables : &Vec<Huffman>, ac_tables : &Vec<Huffman>,
+                width : usize, height : usize, restart_interval : usize ) -> Result<usize, String>
+{
+	let hmax = components.iter().map(|c| c.h).max().unwrap();
+	let vmax = components.iter().map(|c| c.v).max().unwrap();
+	let mcux = (width + 8 * hmax - 1) / (8 * hmax);
+	let mcuy = (height + 8 * vmax - 1) / (8 * vmax);
+	for c in components.iter_mut() {
+		if c.plane.is_empty() {
+			c.stride = mcux * c.h * 8;
+			c.plane = vec![ 0; c.stride * mcuy * c.v * 8 ];
+		}
+		c.prediction = 0;
+	}
+
+	let basis = idct_basis();
+	let mut reader = Reader { data : bytes, pos, buffer : 0, count : 0 };
+	let block = |reader : &mut Reader, c : &mut Component, bx : usize, by : usize| -> Result<(), String> {
+		let q = &quant[c.quant];
+		let mut coefficients = [0.0f32; 64];
+
+		// categories beyond what 8 bit samples need would shift past the coefficient
+		let t = reader.decode( &dc_tables[c.dc] )?;
+		if t > 11 {
+			return Err( "invalid JPEG DC category".to_string() );
+		}
+		c.prediction = (c.prediction + reader.extend( t as u32 )).max( -2048 ).min( 2047 );
+		coefficients[0] = (c.prediction * q[0] as i32) as f32;
+
+		let mut k = 1;
+		while k < 64 {
+			let rs = reader.decode( &ac_tables[c.ac] )?;
+			let (run, size) = ((rs >> 4) as usize, (rs & 15) as u32);
+			if size == 0 {
+				if run != 15 {
+					break;
+				}
+				k += 16;
+				continue;
+			}
+			if size > 10 {
+				return Err( "invalid JPEG AC size".to_string() );
+			}
+			k += run;
+			if k > 63 {
+				return Err( "JPEG coefficient out of range".to_string() );
+			}
+			coefficients[ZIGZAG[k]] = (reader.extend( size ) * q[k] as i32) as f32;
+			k += 1;
+		}
+
+		let offset = by * 8 * c.stride + bx * 8;
+		let stride = c.stride;
+		idct( &coefficients, &basis, &mut c.plane[offset..], stride );
+		Ok( () )
+	};
+
+	// a scan with one component isn't interleaved and only covers the image
+	let units = if scan.len() == 1 {
+		let c = &components[scan[0]];
+		let w = (width * c.h + hmax - 1) / hmax;
+		let h = (height * c.v + vmax - 1) / vmax;
+		((w + 7) / 8) * ((h + 7) / 8)
+	} else {
+		mcux * mcuy
+	};
+
+	for unit in 0..units {
+		if restart_interval > 0 && unit > 0 && unit % restart_interval == 0 {
+			reader.restart()?;
+			for c in components.iter_mut() {
+				c.prediction = 0;
+			}
+		}
+
+		if scan.len() == 1 {
+			let c = &mut components[scan[0]];
+			let w = ((width * c.h + hmax - 1) / hmax + 7) / 8;
+			block( &mut reader, c, unit % w, unit / w )?;
+		} else {
+			let (mx, my) = (unit % mcux, unit / mcux);
+			for i in scan.iter() {
+				let c = &mut components[*i];
+				for by in 0..c.v {
+					for bx in 0..c.h {
+						let (h, v) = (c.h, c.v);
+						block( &mut reader, c, mx * h + bx, my * v + by )?;
+					}
+				}
+			}
+		}
+	}
+
+	// skip to the next marker which isn't a restart
+	let mut pos = reader.pos;
+	while pos + 1 < bytes.len() && !(bytes[pos] == 0xff && bytes[pos + 1] != 0 && !(bytes[pos + 1] >= 0xd0 && bytes[pos + 1] <= 0xd7)) {
+		pos += 1;
+	}
+	Ok( pos )
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::color::*;
+
+	fn unhex( lines : &[&str] ) -> Vec<u8> {
+		let hex : String = lines.concat();
+		(0..hex.len() / 2).map(|i| u8::from_str_radix( &hex[2 * i..2 * i + 2], 16 ).unwrap()).collect()
+	}
+
+	#[test]
+	fn test_greyscale_decodes_exactly() {
+		// 9x9, unit quantisation, pixels (x * x + 20 * y) % 256
+		let bytes = unhex( &[
+			"ffd8ffdb0043000101010101010101010101010101010101010101010101010101010101010101010101010101010101",
+			"0101010101010101010101010101010101010101010101ffc0000b080009000901011100ffc400140001000000000000",
+			"00000000000000000009ffc40023100000010c03000000000000000000000000010304082636446274a4b2e2091966ff",
+			"da0008010100003f002ed5ff00893691099988370939240ba7527e66d340ae2bfbc885506f3205d07fffd9",
+		] );
+		let image = decode( &bytes, ColourSpace::Linear ).unwrap();
+		assert_eq!( (image.width, image.height), (9, 9) );
+		for y in 0..9 {
+			for x in 0..9 {
+				let expected = ((x * x + 20 * y) % 256) as f32 / 255.0;
+				assert!( (image.get( x, y ).g - expected).abs() < 1.5 / 255.0, "{} {} {:?}", x, y, image.get( x, y ) );
+			}
+		}
+	}
+
+	#[test]
+	fn test_subsampled_colour_with_restarts() {
+		// 32x16 4:2:0, two MCUs with a restart marker between them, pixels (8x, 15y, 128)
+		let bytes = unhex( &[
+			"ffd8ffdb0043000404040404040404040404040404040404040404040404040404040404040404040404040404040404",
+			"0404040404040404040404040404040404040404040404ffdb0043010404040404040404040404040404040404040404",
+			"0404040404040404040404040404040404040404040404040404040404040404040404040404040404040404ffc00011",
+			"080010002003012200021101031101ffc400160001010100000000000000000000000000060708ffc400191000010500",
+			"0000000000000000000000000004062331a1ffc400160101010100000000000000000000000000060704ffc4001c1100",
+			"0103050000000000000000000000000600234204313351a1ffdd00040001ffda000c03010002110311003f00cd691af5",
+			"1e099235ea3c2cc91af51e09d235ea3c10d616dddea1036738ded497ffd09ba46bd4782648d7a8f0b3a46bd4782648d7",
+			"a8f0cb585b777aa9236738ded497ffd9",
+		] );
+		let image = decode( &bytes, ColourSpace::Linear ).unwrap();
+		assert_eq!( (image.width, image.height), (32, 16) );
+		for y in 0..16 {
+			for x in 0..32 {
+				let c = image.get( x, y );
+				let expected = Color::new( 8.0 * x as f32, 15.0 * y as f32, 128.0 ) * (1.0 / 255.0);
+				// half resolution chroma blurs the gradients a little
+				let error = (c.r - expected.r).abs().max( (c.g - expected.g).abs() ).max( (c.b - expected.b).abs() );
+				assert!( error < 14.0 / 255.0, "{} {} {:?}", x, y, c );
+			}
+		}
+
+		assert!( decode( &bytes[..200], ColourSpace::Linear ).is_err() );
+	}
+}
@@ -12,6 +12,27 @@ mod color;
 mod random;
 mod material;
 mod texture;
+mod integrator;
+mod bdpt;
+mod photon;
+mod camera;
+mod mlt;
+mod guiding;
+mod spectral;
+mod microfacet;
+mod principled;
+mod bump;
+mod mesh;
+mod image;
+mod png;
+mod jpeg;
+mod noise;
+mod nodes;
+mod medium;
+mod csg;
+mod shapes;
+mod sdf;
+mod terrain;
 
 use self::vec_math::*;
 use self::hitable::*;
@@ -19,59 +40,17 @@ use self::material::*;
 use self::color::*;
 use self::random::*;
 use self::texture::*;
+use self::integrator::*;
+use self::bdpt::*;
+use self::photon::*;
+use self::camera::*;
+use self::mlt::*;
+use self::guiding::*;
+use self::spectral::*;
+use self::medium::*;
 
 use std::rc::Rc;
 
-
-fn find_colour (ray : &Ray, objects: & Vec<Box<Hitable>>, 
-                depth : i32) -> Color
-{
-
-	if depth > 50 {
-		return Color::new ( 0.0, 0.0, 0.0 );
-	}
-
-	let mut hit : Option<Hit> = None;
-	for h in objects {
-		let h = h.hit( &ray );
-		if h.is_none(){
-			continue;
-		}
-
-		match hit {
-			Some(hit_) => {
-				let h = h.unwrap();
-				if hit_.distance > h.distance{ 
-					hit = Some(h) ;
-				} else {
-					hit = Some(hit_);
-				}},
-			None =>  hit = h
-		}
-
-	}
-
-	let colour = match hit {
-		Some(hit) => {
-
-			let new_ray = hit.scattered;
-			let albedo = hit.albedo;
-			let cn = find_colour(&new_ray, &objects, depth + 1 );
-			let cn = Color::new ( cn.r * albedo.r, cn.g * albedo.g, cn.b * albedo.b );
-
-			cn + hit.emited
-		},
-		None => {
-			let unit_direction = ray.get_direction();
-			let t = 0.5 + unit_direction.y;
-			let cn = Color::new (0.8,0.6,0.55) * (1.0 - t) + Color::new (0.7,0.8,1.0) * t;
-
-			cn
-		}
-	};
-	return colour;
-}
-
 //-----------------------------------------------------------------------------
 fn main() {
 
@@ -79,20 +58,26 @@ fn main() {
 
 	c.clear();
 
-	let origin = Vec3::new ( 0.0, 0.0, 0.0 );
-
-	let low_left_corner = Vec3::new( 0.0, 0.0, 1.0 );
-	let vertical        = Vec3::new( 0.0, 1.0, 0.0 );
-	let horizontal      = Vec3::new( 1.0, 0.0, 0.0 );
+	let camera = Camera {
+		origin          : Vec3::new( 0.0, 0.0, 0.0 ),
+		low_left_corner : Vec3::new( 0.0, 0.0, 1.0 ),
+		vertical        : Vec3::new( 0.0, 1.0, 0.0 ),
+		horizontal      : Vec3::new( 1.0, 0.0, 0.0 ),
+		shutter_open    : 0.0,
+		shutter_close   : 0.0,
+		spread          : 1.0 / 800.0
+	};
 
 	let material = Box::new( Lambertian { albedo : Rc::new(ConstantTexture{  color : Color::new (0.5, 0.5, 0.5) }) } );
-	let metal_1 = Box::new( Metal {albedo : Color::new (0.5,0.5,0.6), fuzz : 0.1});
-	let metal_2 = Box::new( Metal {albedo : Color::new (0.8,0.95,0.75), fuzz : 0.01});
+	let metal_1 = Box::new( Metal::new (Color::new (0.5,0.5,0.6), 0.3));
+	let metal_2 = Box::new( Metal::new (Color::new (0.8,0.95,0.75), 0.1));
 
-	let glass = Box::new( Glass {albedo : Color::new (0.95,0.95,1.0), ref_idx : 1.5});
+	let glass = Box::new( Glass::new (Color::new (0.95,0.95,1.0), 1.5));
 
 	let mut objects: Vec<Box<Hitable>> = Vec::new();
-	objects.push(Box::new( GlobalMedium { density : 0.15 } ) );
+	// thin haze around the camera, as far as the scene goes
+	let haze = Sphere{ center : Vec3::new( 0.0, 0.0, 0.0 ), radius : 6.0, material : material.clone() };
+	objects.push(Box::new( HomogeneousMedium::new( Box::new( haze ), 0.0, 0.15, Color::new( 0.5, 0.9, 0.9 ), Phase::Isotropic ) ) );
 	objects.push(Box::new( Sphere{ center : Vec3::new(  0.5, -0.6, 5.0 ) , radius :0.3, material : material.clone() }));
 	objects.push(Box::new( Sphere{ center : Vec3::new( -0.6, 0.0, 1.9 ) , radius :0.15, material : glass.clone() }));
 	objects.push(Box::new( Sphere{ center : Vec3::new( -0.8, 0.0, 3.0 ) , radius :0.9, material : material.clone() }));
@@ -100,43 +85,98 @@ fn main() {
 	objects.push(Box::new( Sphere{ center : Vec3::new(  0.4, -0.8, 2.0 ) , radius :0.1, material : metal_1.clone() }));
 	objects.push(Box::new( Sphere{ center : Vec3::new(  0.3, -0.7, 2.3 ) , radius :0.15, material : glass }));
 
-	let glowering_material = Box::new( BlackBody { radiation : Color::new (3.0, 2.0, 1.0) } );
+	let glowering_material = Box::new( BlackBody { radiation : constant( Color::new (3.0, 2.0, 1.0) ), spectrum : None } );
 	objects.push(Box::new( Sphere{ center : Vec3::new(  0.7, -0.6, 3.0 ) , radius :0.15, material : glowering_material }));
 
 
 	let plane_material = Box::new( Lambertian { albedo : Rc::new( ChessTexture{color_a : Color::new (0.1, 0.1, 0.1), color_b :Color::new (1.0, 1.0, 1.0), scale : 1.0}) } );
 	objects.push(Box::new( Plane{ normal : Vec3::new( 0.0, 1.0, 0.0 ) , d :0.9, material : plane_material }));
 
-	for x in 0..800 {
-		for y in 0..600{
+	let settings = RenderSettings::new();
+	let lights = find_lights( &objects );
+	let bdpt = Bdpt::new( &objects, &camera, 800, 600 );
+	let photon_map = if settings.integrator == Integrator::PhotonMapping {
+		let map = PhotonMap::caustics( &objects, settings.photons, settings.max_depth );
+		println!("{:?} caustic photons", map.len());
+		map
+	} else {
+		PhotonMap::new( Vec::new() )
+	};
+	let spectral = Spectral::new();
+	let mut guide = PathGuide::new();
+	if settings.integrator == Integrator::PathGuiding {
+		for pass in 0..settings.guide_passes {
+			guide.train_pass( &camera, &objects, &settings, 800, 600, pass );
+			println!("guiding pass {:?}", pass);
+		}
+	}
 
-			let mut color_accm = Color::new  (0.0, 0.0, 0.0);
+	if settings.integrator == Integrator::Metropolis {
+		let mut mlt = Metropolis::new( &camera, &objects, &settings, 800, 600 );
+		let rounds = 40;
+		for round in 0..rounds {
+			mlt.run( &camera, &objects, &settings, settings.samples as u64 * 800 * 600 / rounds );
+			for x in 0..800 {
+				for y in 0..600 {
+					let colour = 0.5 * &mlt.pixel( x, y );
+					c.point( x as i32, y as i32, colour.as_u32() );
+				}
+			}
+			c.present();
+			c.poll_events();
+			println!("{:?}", round);
+		}
+	} else {
+		let mut image = vec![Color::new (0.0, 0.0, 0.0); 800 * 600];
+		for x in 0..800 {
+			for y in 0..600{
 
-			let smpl = 200;
-			let rfactor = 1.1;
+				let mut color_accm = Color::new  (0.0, 0.0, 0.0);
 
-			for _ in 0..smpl {
+				let smpl = settings.samples;
+				let rfactor = settings.jitter;
 
-				let u = (x - 400) as f32 / 800.0 + rfactor * random()/800.0;
-				let v = (300 - y) as f32 / 600.0 + rfactor * random()/600.0;
+				for _ in 0..smpl {
 
-				let dir = &low_left_corner + (&vertical * v) + (&horizontal * u);
-				let ray = Ray::new(&origin, &dir );
+					let u = (x - 400) as f32 / 800.0 + rfactor * random()/800.0;
+					let v = (300 - y) as f32 / 600.0 + rfactor * random()/600.0;
 
-				let colour = find_colour (&ray, &objects, 0);
+					let ray = camera.get_ray( u, v );
 
-				color_accm = &color_accm + colour;
-			}
+					let colour = match settings.integrator {
+						Integrator::PathTracing => find_colour (&ray, &objects, &lights, 0, &settings),
+						Integrator::Bidirectional => bdpt.colour (&ray, &objects, &settings),
+						Integrator::PhotonMapping => photon_map.colour (&ray, &objects, &settings),
+						Integrator::PathGuiding => guide.colour (&ray, &objects, &settings),
+						Integrator::Spectral => spectral.colour (&ray, &objects, &settings),
+						Integrator::Metropolis => Color::new (0.0, 0.0, 0.0)
+					};
+
+					color_accm = &color_accm + colour;
+				}
 
-			let k = 1.0 / ( smpl as f32 );
-			color_accm = 0.5 * k * &color_accm ; // todo override oppertor
+				let k = 1.0 / ( smpl as f32 );
+				color_accm = 0.5 * k * &color_accm ; // todo override oppertor
 
-			c.point( x, y, color_accm.as_u32() );
+				c.point( x, y, color_accm.as_u32() );
+				image[(y * 800 + x) as usize] = color_accm;
+			}
+			if x % 20 == 0 {
+				c.present();
+				c.poll_events();
+				println!("{:?}", x);
+			}
 		}
-		if x % 20 == 0 {
-			c.present();
-			c.poll_events();
-			println!("{:?}", x);
+
+		// light traced to the camera lands on any pixel, so it is added at the end
+		if settings.integrator == Integrator::Bidirectional {
+			let k = 0.5 / settings.samples as f32;
+			for x in 0..800 {
+				for y in 0..600 {
+					let colour = &image[y * 800 + x] + k * &bdpt.splat( x, y );
+					c.point( x as i32, y as i32, colour.as_u32() );
+				}
+			}
 		}
 	}
 
@@ -3,15 +3,76 @@ use crate::vec_math::*;
 use crate::random::*;
 use crate::color::*;
 use crate::texture::*;
+use crate::microfacet::*;
+use crate::hitable::*;
+use crate::spectral::*;
+use crate::nodes::*;
 
 use std::rc::Rc;
+use std::f32::consts::PI;
 
+// Direction picked by `Material::sample`. `weight` is the BSDF times the cosine
+// over the pdf. Specular samples come from a delta or from a lobe which `eval`
+// and `pdf` don't know about, their pdf can't be used for MIS.
+pub struct BsdfSample {
+	pub ray : Ray,
+	pub weight : Color,
+	pub pdf : f32,
+	pub specular : bool
+}
+
+// Directions follow the usual convention: `wo` points back along the incoming
+// ray and `wi` towards where the light comes from, both away from the surface.
 pub trait Material  { 
-	fn scatter( &self, dir: &Vec3, normal: &Vec3, pos: &Vec3, u : f32, v : f32  ) -> (Ray, Color );
-	fn emit ( &self, dir: &Vec3, normal: &Vec3, pos: &Vec3, u : f32, v : f32  ) -> (Color)
+	// New direction for the ray of `hit`, None when the light is absorbed
+	fn sample( &self, hit : &Hit ) -> Option<BsdfSample>;
+
+	// Radiance leaving the surface towards `wo`
+	fn emit ( &self, _hit : &Hit, _wo : &Vec3 ) -> Color
+	{
+		Color::new(0.0,0.0,0.0)
+	}
+
+	// Radiance at `lambda` nanometers, for spectral rendering. Emitters with a
+	// real spectrum override this, the default upsamples `emit`.
+	fn emit_spectrum ( &self, hit : &Hit, wo : &Vec3, lambda : f32 ) -> f32
 	{
-		return Color::new(0.0,0.0,0.0);
+		rgb_to_spectrum( &self.emit( hit, wo ), lambda )
 	}
+
+	// BSDF value, without the cosine. Zero for the specular parts.
+	fn eval ( &self, _hit : &Hit, _wi : &Vec3, _wo : &Vec3 ) -> Color
+	{
+		Color::new(0.0,0.0,0.0)
+	}
+
+	// Solid angle density with which `sample` picks `wi` when looking from `wo`
+	fn pdf ( &self, _hit : &Hit, _wi : &Vec3, _wo : &Vec3 ) -> f32
+	{
+		0.0
+	}
+
+	// Closed dielectrics which can be nested in one another, see `Interior`
+	fn interior ( &self, _hit : &Hit ) -> Option<Interior> { None }
+
+	// `sample` for a dielectric whose surroundings at `hit` have the index of
+	// refraction `outside` rather than being air. Absorption inside is left
+	// to the caller, which knows what the ray travelled through.
+	fn sample_nested ( &self, hit : &Hit, _outside : f32 ) -> Option<BsdfSample>
+	{
+		self.sample( hit )
+	}
+
+	fn is_emitter ( &self ) -> bool { false }
+	fn is_medium ( &self ) -> bool { false }
+
+	// Part of the scattering at `hit` is spread out enough for `eval` to
+	// describe it, whichever lobe `sample` happens to pick. Caustic photons
+	// are stored and gathered there.
+	fn is_diffuse ( &self, _hit : &Hit ) -> bool { false }
+
+	// Scattering depends on the wavelength of the ray
+	fn is_dispersive ( &self ) -> bool { false }
 }
 
 #[derive(  Clone )]
@@ -20,34 +81,304 @@ pub struct Lambertian {
 }
 
 impl Material for Lambertian {
-	fn scatter( &self, dir: &Vec3, normal: &Vec3, pos: &Vec3, u : f32, v : f32) -> (Ray, Color )
+	fn sample( &self, hit : &Hit ) -> Option<BsdfSample>
 	{
-		let new_dir =  normal + 1.2*random_in_unit_sphere();
-		let new_ray = Ray::new( pos, &new_dir );
-		(new_ray, self.albedo.value(u,v))
+		let new_dir =  &hit.normal + random_unit_vector();
+		if new_dir.squre_length() < 1e-8 {
+			return None;
+		}
+		let ray = hit.ray.spawn( &hit.pos, &new_dir );
+		let pdf = dot_product( &ray.direction, &hit.normal ).max(0.0) / PI;
+		Some( BsdfSample { ray, weight : self.albedo.value( &hit.tex_coord() ), pdf, specular : false } )
 	}
+
+	fn eval ( &self, hit : &Hit, wi : &Vec3, _wo : &Vec3 ) -> Color
+	{
+		if dot_product(wi, &hit.normal) <= 0.0 {
+			return Color::new(0.0,0.0,0.0);
+		}
+		self.albedo.value( &hit.tex_coord() ) * (1.0 / PI)
+	}
+
+	fn pdf ( &self, hit : &Hit, wi : &Vec3, _wo : &Vec3 ) -> f32
+	{
+		dot_product(wi, &hit.normal).max(0.0) / PI
+	}
+
+	fn is_diffuse ( &self, _hit : &Hit ) -> bool { true }
 }
 
-#[derive( Copy, Clone )]
+// Reflectance at normal incidence with Schlick's approximation, or the
+// complex index of refraction of a real conductor
+#[derive( Clone )]
+pub enum MetalFresnel {
+	Schlick( Rc<Texture> ),
+	Conductor { eta : Rc<Texture>, k : Rc<Texture> }
+}
+
+// Microfacet conductor with a GGX distribution of normals. Roughness and
+// anisotropy are scalar textures.
+#[derive( Clone )]
 pub struct Metal {
-	pub albedo : Color,
-	pub fuzz : f32
+	pub fresnel : MetalFresnel,
+	pub roughness : Rc<Texture>,
+	pub anisotropy : Rc<Texture>
+}
+
+impl Metal {
+
+	pub fn new( albedo : Color, roughness : f32 ) -> Metal {
+		Metal::textured( constant( albedo ), uniform( roughness ) )
+	}
+
+	// e.g. scratches from a roughness map
+	pub fn textured( albedo : Rc<Texture>, roughness : Rc<Texture> ) -> Metal {
+		Metal { fresnel : MetalFresnel::Schlick( albedo ), roughness, anisotropy : uniform( 0.0 ) }
+	}
+
+	pub fn conductor( eta : Color, k : Color, roughness : f32 ) -> Metal {
+		Metal { fresnel : MetalFresnel::Conductor { eta : constant( eta ), k : constant( k ) }, roughness : uniform( roughness ), anisotropy : uniform( 0.0 ) }
+	}
+
+	#[allow(dead_code)]
+	pub fn gold( roughness : f32 ) -> Metal {
+		Metal::conductor( Color::new( 0.143, 0.374, 1.442 ), Color::new( 3.983, 2.385, 1.603 ), roughness )
+	}
+
+	#[allow(dead_code)]
+	pub fn copper( roughness : f32 ) -> Metal {
+		Metal::conductor( Color::new( 0.200, 0.924, 1.102 ), Color::new( 3.912, 2.452, 2.142 ), roughness )
+	}
+
+	#[allow(dead_code)]
+	pub fn aluminium( roughness : f32 ) -> Metal {
+		Metal::conductor( Color::new( 1.657, 0.880, 0.521 ), Color::new( 9.224, 6.270, 4.837 ), roughness )
+	}
+
+	#[allow(dead_code)]
+	pub fn silver( roughness : f32 ) -> Metal {
+		Metal::conductor( Color::new( 0.155, 0.117, 0.138 ), Color::new( 4.828, 3.122, 2.147 ), roughness )
+	}
+
+	fn ggx( &self, at : &TexCoord ) -> Ggx {
+		Ggx::new( self.roughness.scalar( at ), self.anisotropy.scalar( at ) )
+	}
+
+	fn reflectance( &self, at : &TexCoord, cos : f32 ) -> Color {
+		match &self.fresnel {
+			MetalFresnel::Schlick( albedo ) => {
+				let f0 = albedo.value( at );
+				let t = (1.0 - cos.max(0.0)).powf(5.0);
+				Color::new( f0.r + (1.0 - f0.r) * t, f0.g + (1.0 - f0.g) * t, f0.b + (1.0 - f0.b) * t )
+			},
+			MetalFresnel::Conductor { eta, k } => fresnel_conductor( cos, &eta.value( at ), &k.value( at ) )
+		}
+	}
+
+	// Shading frame on the side of the surface `wo` looks from. The first axis
+	// follows the tangent, so anisotropy stretches along the parametrisation.
+	fn frame( wo : &Vec3, hit : &Hit ) -> Onb {
+		let w = if dot_product( wo, &hit.normal ) < 0.0 { -&hit.normal } else { hit.normal.clone() };
+		let u = &hit.tangent - dot_product( &hit.tangent, &w ) * &w;
+		if u.squre_length() < 1e-12 {
+			return Onb::from_w( &w );
+		}
+		let u = u.normalized();
+		Onb { v : cross_product( &w, &u ), u, w }
+	}
+
+	// near mirrors are left to `sample`, evaluating their sharp lobe only adds noise
+	fn is_mirror( &self, at : &TexCoord ) -> bool {
+		self.roughness.scalar( at ) < 0.05
+	}
 }
 
 impl Material for Metal {
-	fn scatter( &self, dir: &Vec3, normal: &Vec3, pos: &Vec3, u : f32, v : f32  ) -> (Ray, Color )
+	fn sample( &self, hit : &Hit ) -> Option<BsdfSample>
+	{
+		let frame = Metal::frame( &-&hit.ray.direction, hit );
+		let wo = frame.to_local( &-&hit.ray.direction );
+		let at = hit.tex_coord();
+		let ggx = self.ggx( &at );
+
+		let h = ggx.sample_h( &wo );
+		let cos = dot_product( &wo, &h );
+		let wi = 2.0 * cos * &h - &wo;
+
+		// reflected below the surface, the path ends here
+		if wi.z <= 0.0 || wo.z <= 0.0 {
+			return None;
+		}
+
+		let weight = self.reflectance( &at, cos ) * (ggx.g( &wo, &wi ) / ggx.g1( &wo ));
+		let pdf = ggx.pdf_h( &wo, &h ) / (4.0 * cos);
+		let ray = hit.ray.spawn( &hit.pos, &frame.to_world( &wi ) );
+		Some( BsdfSample { ray, weight, pdf, specular : self.is_mirror( &at ) } )
+	}
+
+	fn eval ( &self, hit : &Hit, wi : &Vec3, wo : &Vec3 ) -> Color
+	{
+		let frame = Metal::frame( wo, hit );
+		let wo = frame.to_local( wo );
+		let wi = frame.to_local( wi );
+		let at = hit.tex_coord();
+		if self.is_mirror( &at ) || wo.z <= 0.0 || wi.z <= 0.0 {
+			return Color::new(0.0,0.0,0.0);
+		}
+
+		let ggx = self.ggx( &at );
+		let h = (&wo + &wi).normalized();
+		let f = self.reflectance( &at, dot_product( &wo, &h ) );
+		f * (ggx.d( &h ) * ggx.g( &wo, &wi ) / (4.0 * wo.z * wi.z))
+	}
+
+	fn pdf ( &self, hit : &Hit, wi : &Vec3, wo : &Vec3 ) -> f32
 	{
-		let reflected = reflect(dir, normal) +  self.fuzz * random_in_unit_sphere();;
-		let new_ray = Ray::new(pos, &reflected );
-		(new_ray, self.albedo.clone())
+		let frame = Metal::frame( wo, hit );
+		let wo = frame.to_local( wo );
+		let wi = frame.to_local( wi );
+		let at = hit.tex_coord();
+		if self.is_mirror( &at ) || wo.z <= 0.0 || wi.z <= 0.0 {
+			return 0.0;
+		}
+
+		let h = (&wo + &wi).normalized();
+		self.ggx( &at ).pdf_h( &wo, &h ) / (4.0 * dot_product( &wo, &h ))
 	}
+
+	fn is_diffuse ( &self, hit : &Hit ) -> bool { !self.is_mirror( &hit.tex_coord() ) }
+}
+
+
+// Wavelength dependence of the index of refraction, wavelengths in micrometers
+#[derive( Copy, Clone, PartialEq, Debug )]
+pub enum Dispersion {
+	None,
+	// n = a + b / l^2
+	#[allow(dead_code)]
+	Cauchy { a : f32, b : f32 },
+	// n^2 = 1 + sum of b_i l^2 / (l^2 - c_i)
+	Sellmeier { b : [f32; 3], c : [f32; 3] }
 }
 
+// What fills a closed dielectric. Where objects overlap, e.g. water poured
+// into a glass slightly overlapping its walls, the one with the highest
+// `priority` owns the space and the surfaces of the others are ignored there.
+#[derive( Copy, Clone, Debug )]
+pub struct Interior {
+	pub ior : f32,
+	pub priority : i32,
+	// Beer-Lambert coefficient per unit length
+	pub absorption : Color
+}
 
-#[derive( Copy, Clone )]
+// Index of refraction, roughness and absorption are read where the ray
+// crosses the surface, e.g. stained glass from an albedo texture
+#[derive( Clone )]
 pub struct Glass {
-	pub albedo : Color,
-	pub ref_idx : f32
+	pub albedo : Rc<Texture>,
+	// scalar texture
+	pub ref_idx : Rc<Texture>,
+	pub dispersion : Dispersion,
+	// GGX roughness of the interface, 0 for a perfectly smooth one
+	pub roughness : Rc<Texture>,
+	// Beer-Lambert absorption coefficient per unit length inside the object,
+	// taken where the ray leaves it
+	pub absorption : Rc<Texture>,
+	// wins over lower priorities where dielectrics overlap
+	pub priority : i32
+}
+
+impl Glass {
+
+	// Clear and smooth glass without dispersion
+	pub fn new( albedo : Color, ref_idx : f32 ) -> Glass {
+		Glass {
+			albedo : constant( albedo ),
+			ref_idx : uniform( ref_idx ),
+			dispersion : Dispersion::None,
+			roughness : uniform( 0.0 ),
+			absorption : constant( Color::new( 0.0, 0.0, 0.0 ) ),
+			priority : 0
+		}
+	}
+
+	// Clear water, whose walls should win over it where they overlap
+	#[allow(dead_code)]
+	pub fn water() -> Glass {
+		Glass {
+			absorption : constant( Glass::absorption_for( Color::new( 0.85, 0.95, 0.97 ), 1.0 ) ),
+			..Glass::new( Color::new( 1.0, 1.0, 1.0 ), 1.333 )
+		}
+	}
+
+	// Ice floating in water, above it in priority
+	#[allow(dead_code)]
+	pub fn ice() -> Glass {
+		Glass { priority : 1, ..Glass::new( Color::new( 0.98, 0.99, 1.0 ), 1.309 ) }
+	}
+
+	// Borosilicate crown glass
+	#[allow(dead_code)]
+	pub fn bk7( albedo : Color ) -> Glass {
+		Glass {
+			dispersion : Dispersion::Sellmeier {
+				b : [1.03961212, 0.231792344, 1.01046945],
+				c : [0.00600069867, 0.0200179144, 103.560653]
+			},
+			..Glass::new( albedo, 1.5168 )
+		}
+	}
+
+	// Dense flint glass, disperses much more than crown glass
+	#[allow(dead_code)]
+	pub fn flint( albedo : Color ) -> Glass {
+		Glass {
+			dispersion : Dispersion::Sellmeier {
+				b : [1.73759695, 0.313747346, 1.89878101],
+				c : [0.013188707, 0.0623068142, 155.23629]
+			},
+			..Glass::new( albedo, 1.7847 )
+		}
+	}
+
+	#[allow(dead_code)]
+	pub fn diamond( albedo : Color ) -> Glass {
+		Glass {
+			dispersion : Dispersion::Sellmeier {
+				b : [0.3306, 4.3356, 0.0],
+				c : [0.030625, 0.011236, 0.0]
+			},
+			..Glass::new( albedo, 2.417 )
+		}
+	}
+
+	// Absorption coefficient which tints white light to `colour` after `distance`
+	#[allow(dead_code)]
+	pub fn absorption_for( colour : Color, distance : f32 ) -> Color {
+		let sigma = |c : f32| -c.max( 1e-6 ).min( 1.0 ).ln() / distance;
+		Color::new( sigma( colour.r ), sigma( colour.g ), sigma( colour.b ) )
+	}
+
+	// Index of refraction at `wavelength` nanometers, `ref_idx` for RGB rays
+	pub fn ior( &self, at : &TexCoord, wavelength : f32 ) -> f32 {
+		if wavelength <= 0.0 {
+			return self.ref_idx.scalar( at );
+		}
+		let l = wavelength * 0.001;
+		let l2 = l * l;
+		match self.dispersion {
+			Dispersion::None => self.ref_idx.scalar( at ),
+			Dispersion::Cauchy { a, b } => a + b / l2,
+			Dispersion::Sellmeier { b, c } => {
+				let mut n2 = 1.0;
+				for i in 0..3 {
+					n2 += b[i] * l2 / (l2 - c[i]);
+				}
+				n2.sqrt()
+			}
+		}
+	}
 }
 
 fn schlick(cos :f32, ref_idx : f32) ->f32 {
@@ -56,40 +387,367 @@ fn schlick(cos :f32, ref_idx : f32) ->f32 {
 	r0 + (1.0 - r0) * (1.0 - cos).powf(5.0)
 }
 
-impl Material for Glass {
-	fn scatter( &self, dir: &Vec3, normal: &Vec3, pos: &Vec3, u : f32, v : f32  ) -> (Ray, Color )
+impl Glass {
+
+	// Walter et al. 2007: picks a visible microfacet normal and reflects or
+	// refracts through it with the Fresnel probability. `outward` is the normal
+	// on the side of the incoming ray and `ref_idx` the ratio of the indices.
+	// Returns the direction, the weight and its density.
+	fn sample_rough( dir : &Vec3, outward : &Vec3, ref_idx : f32, roughness : f32 ) -> Option<(Vec3, f32, f32)>
 	{
+		let frame = Onb::from_w( outward );
+		let wo = frame.to_local( &-dir );
+		let ggx = Ggx::new( roughness, 0.0 );
+
+		let h = ggx.sample_h( &wo );
+		let cos = dot_product( &wo, &h );
+		let f = fresnel_dielectric( cos, 1.0 / ref_idx );
 
-		let (outward, ref_idx, cos) = if dot_product(dir, normal) > 0.0 {
+		let (wi, reflected) = match refract( &-&wo, &h, ref_idx ) {
+			Some(t) if random() >= f => (t, false),
+			_ => (2.0 * cos * &h - &wo, true)
+		};
+
+		// the microfacet sent the ray to the wrong side of the surface
+		if (wi.z > 0.0) != reflected || wo.z <= 0.0 {
+			return None;
+		}
+		let pdf = ggx.dielectric( &wo, &wi, 1.0 / ref_idx ).1;
+		if pdf <= 0.0 {
+			return None;
+		}
+		Some( (frame.to_world( &wi ), ggx.g( &wo, &wi ) / ggx.g1( &wo ), pdf) )
+	}
+
+	// Local frame with z on the side `wo` looks from, and the index of
+	// refraction behind the surface over the one in front, with air outside
+	fn rough_frame( &self, hit : &Hit, wo : &Vec3 ) -> (Onb, f32)
+	{
+		let ior = self.ior( &hit.tex_coord(), hit.ray.wavelength );
+		if dot_product( wo, &hit.normal ) < 0.0 {
+			(Onb::from_w( &-&hit.normal ), 1.0 / ior)
+		} else {
+			(Onb::from_w( &hit.normal ), ior)
+		}
+	}
+
+	// Reflection or refraction into a medium of index `outside`. Without
+	// `absorb` the ray leaving the object isn't tinted by what it crossed.
+	fn scatter( &self, hit : &Hit, outside : f32, absorb : bool ) -> Option<BsdfSample>
+	{
+		let dir = &hit.ray.direction;
+		let normal = &hit.normal;
+		let at = hit.tex_coord();
+		let ior = self.ior( &at, hit.ray.wavelength );
+		let inside = dot_product(dir, normal) > 0.0;
+
+		let (outward, ref_idx, cos) = if inside {
 			let cos = dot_product(dir, normal);
-			(-normal, self.ref_idx, cos)
+			(-normal, ior / outside, cos)
 		} else {
 			let cos = -dot_product(dir, normal);
-			(normal.clone(), 1.0 / self.ref_idx, cos)
+			(normal.clone(), outside / ior, cos)
 		};
 
+		// a ray leaving the object has crossed it from its origin
+		let mut tint = self.albedo.value( &at );
+		if inside && absorb {
+			let d = hit.distance;
+			let a = self.absorption.value( &at );
+			tint = tint * Color::new( (-a.r * d).exp(), (-a.g * d).exp(), (-a.b * d).exp() );
+		}
+
+		// `eval` and `pdf` only know about glass surrounded by air
+		let roughness = self.roughness.scalar( &at );
+		if roughness > 0.0 {
+			let (wi, weight, pdf) = Glass::sample_rough( dir, &outward, ref_idx, roughness )?;
+			let ray = hit.ray.spawn( &hit.pos, &wi );
+			return Some( BsdfSample { ray, weight : tint * weight, pdf, specular : outside != 1.0 } );
+		}
+
 		let refracted = refract(dir, &outward, ref_idx);
 		let prob : f32 = if refracted.is_some() {  schlick (cos, ref_idx) } else { 1.0 };
 		
-		let new_ray = if random() > prob { Ray::new(pos, &refracted.unwrap() ) } else { Ray::new(pos, &reflect(dir, &outward)  ) };
+		let (ray, pdf) = if random() > prob {
+			(hit.ray.spawn(&hit.pos, &refracted.unwrap() ), 1.0 - prob)
+		} else {
+			(hit.ray.spawn(&hit.pos, &reflect(dir, &outward) ), prob)
+		};
+
+		Some( BsdfSample { ray, weight : tint, pdf, specular : true } )
+	}
+}
+
+// Smooth glass is only ever sampled. Rough glass in air is also evaluated,
+// without the absorption inside, which is left to `sample` or to the caller
+// like for `sample_nested`.
+impl Material for Glass {
+	fn sample( &self, hit : &Hit ) -> Option<BsdfSample>
+	{
+		self.scatter( hit, 1.0, true )
+	}
+
+	fn eval ( &self, hit : &Hit, wi : &Vec3, wo : &Vec3 ) -> Color
+	{
+		let at = hit.tex_coord();
+		let roughness = self.roughness.scalar( &at );
+		if roughness <= 0.0 {
+			return Color::new(0.0,0.0,0.0);
+		}
+		let (frame, eta) = self.rough_frame( hit, wo );
+		let (f, _) = Ggx::new( roughness, 0.0 ).dielectric( &frame.to_local( wo ), &frame.to_local( wi ), eta );
+		self.albedo.value( &at ) * f
+	}
+
+	fn pdf ( &self, hit : &Hit, wi : &Vec3, wo : &Vec3 ) -> f32
+	{
+		let roughness = self.roughness.scalar( &hit.tex_coord() );
+		if roughness <= 0.0 {
+			return 0.0;
+		}
+		let (frame, eta) = self.rough_frame( hit, wo );
+		Ggx::new( roughness, 0.0 ).dielectric( &frame.to_local( wo ), &frame.to_local( wi ), eta ).1
+	}
+
+	fn interior ( &self, hit : &Hit ) -> Option<Interior>
+	{
+		let at = hit.tex_coord();
+		Some( Interior {
+			ior : self.ior( &at, hit.ray.wavelength ),
+			priority : self.priority,
+			absorption : self.absorption.value( &at )
+		} )
+	}
 
-		(new_ray, self.albedo.clone())
+	fn sample_nested ( &self, hit : &Hit, outside : f32 ) -> Option<BsdfSample>
+	{
+		self.scatter( hit, outside, false )
 	}
+
+	fn is_dispersive ( &self ) -> bool { self.dispersion != Dispersion::None }
+
+	fn is_diffuse ( &self, hit : &Hit ) -> bool { self.roughness.scalar( &hit.tex_coord() ) > 0.0 }
 }
 
-#[derive( Copy, Clone )]
+// Diffuse emitter, shining from the side the normal faces. Without a spectrum `radiation` is the emitted radiance,
+// with one it tints and scales the black body colour, which has a luminance of
+// one, so a white texture gives the luminance of the light.
+#[derive( Clone )]
 pub struct BlackBody {
-	pub radiation : Color
+	pub radiation : Rc<Texture>,
+	pub spectrum : Option<Planck>
+}
+
+#[allow(dead_code)]
+impl BlackBody {
+
+	// `luminance` in candela per square meter when a scene unit is a meter
+	pub fn temperature( kelvin : f32, luminance : f32 ) -> BlackBody {
+		BlackBody { radiation : uniform( luminance ), spectrum : Some( Planck::new( kelvin ) ) }
+	}
+
+	// Light emitting `lumens` from a surface of `area`, evenly in all directions
+	pub fn from_flux( kelvin : f32, lumens : f32, area : f32 ) -> BlackBody {
+		BlackBody::temperature( kelvin, lumens / (PI * area) )
+	}
+
+	// Light radiating `watts`, as visible light and infrared alike
+	pub fn from_power( kelvin : f32, watts : f32, area : f32 ) -> BlackBody {
+		BlackBody::from_flux( kelvin, watts * Planck::new( kelvin ).efficacy(), area )
+	}
+
+	// Multiplies the emission by `texture`, e.g. for signs
+	pub fn textured( self, texture : Rc<Texture> ) -> BlackBody {
+		let radiation = self.radiation;
+		BlackBody {
+			radiation : Rc::new( MultiplyTexture { a : radiation, b : texture } ),
+			spectrum : self.spectrum
+		}
+	}
 }
 
 impl Material for BlackBody {
-	fn scatter( &self, dir: &Vec3, normal: &Vec3, pos: &Vec3, u : f32, v : f32  ) -> (Ray, Color )
+	fn sample( &self, _hit : &Hit ) -> Option<BsdfSample>
 	{
-		let new_ray = Ray::new(pos, &normal ) ;
-		(new_ray, Color::new( 0.0,0.0,0.0))
+		None
 	}
-	fn emit ( &self, dir: &Vec3, normal: &Vec3, pos: &Vec3, u : f32, v : f32  ) -> (Color)
+	fn emit ( &self, hit : &Hit, wo : &Vec3 ) -> Color
 	{
-		return self.radiation.clone();
+		if dot_product( &hit.normal, wo ) <= 0.0 {
+			return Color::new( 0.0, 0.0, 0.0 );
+		}
+		let radiation = self.radiation.value( &hit.tex_coord() );
+		match &self.spectrum {
+			Some( planck ) => radiation * planck.colour(),
+			None => radiation
+		}
+	}
+	fn emit_spectrum ( &self, hit : &Hit, wo : &Vec3, lambda : f32 ) -> f32
+	{
+		if dot_product( &hit.normal, wo ) <= 0.0 {
+			return 0.0;
+		}
+		let radiation = rgb_to_spectrum( &self.radiation.value( &hit.tex_coord() ), lambda );
+		match &self.spectrum {
+			Some( planck ) => radiation * planck.value( lambda ),
+			None => radiation
+		}
+	}
+	fn is_emitter ( &self ) -> bool { true }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn hit_at<'a>( normal : &Vec3, tangent : &Vec3, material : &'a Material ) -> Hit<'a> {
+		Hit {
+			distance : 1.0,
+			pos : Vec3::zero(),
+			normal : normal.clone(),
+			tangent : tangent.clone(),
+			bitangent : cross_product( normal, tangent ),
+			u : 0.0,
+			v : 0.0,
+			uv_scale : 1.0,
+			ray : Ray::new( normal, &-normal ),
+			material
+		}
+	}
+
+	#[test]
+	fn test_anisotropy_follows_the_tangent() {
+		let metal = Metal { anisotropy : uniform( 0.9 ), ..Metal::new( Color::new( 0.9, 0.9, 0.9 ), 0.5 ) };
+
+		// on either side of the equator of a sphere, and with the tangent turned
+		let s = 0.5f32.sqrt();
+		let frames = [
+			(Vec3::new( 0.0, 0.0, 1.0 ), Vec3::new( 1.0, 0.0, 0.0 )),
+			(Vec3::new( 0.0, 0.0, 1.0 ), Vec3::new( 0.0, 1.0, 0.0 )),
+			(Vec3::new( 0.6, 0.0, 0.8 ), Vec3::new( 0.0, 1.0, 0.0 )),
+			(Vec3::new( 0.6, 0.0, -0.8 ), Vec3::new( 0.0, 1.0, 0.0 )),
+			(Vec3::new( 0.0, s, -s ), Vec3::new( 1.0, 0.0, 0.0 ))
+		];
+		for (normal, tangent) in frames.iter() {
+			let hit = hit_at( normal, tangent, &metal );
+			let (mut along, mut across) = (0.0, 0.0);
+			for _ in 0..2000 {
+				if let Some(sample) = metal.sample( &hit ) {
+					along += dot_product( &sample.ray.direction, &hit.tangent ).powi( 2 );
+					across += dot_product( &sample.ray.direction, &hit.bitangent ).powi( 2 );
+				}
+			}
+			assert!( along > 4.0 * across, "{:?} {:?}: {} {}", normal, tangent, along, across );
+
+			// eval agrees, the lobe is wider along the tangent
+			let wo = normal.clone();
+			let tilt = |axis : &Vec3| (normal + 0.3 * axis).normalized();
+			let f_along = metal.eval( &hit, &tilt( &hit.tangent ), &wo ).r;
+			let f_across = metal.eval( &hit, &tilt( &hit.bitangent ), &wo ).r;
+			assert!( f_along > 2.0 * f_across, "{} {}", f_along, f_across );
+		}
+	}
+
+	// Sampled directions carry the weight and density of `eval` and `pdf`: the
+	// sampled weights add up to the integral of `eval` over the sphere
+	fn check_sampling( material : &Material, wo : &Vec3 ) {
+		let wo = wo.normalized();
+		let mut hit = hit_at( &Vec3::new( 0.0, 0.0, 1.0 ), &Vec3::new( 1.0, 0.0, 0.0 ), material );
+		hit.ray = Ray::new( &(&wo * 2.0), &-&wo );
+		let n = 400;
+
+		let mut sampled = Color::new( 0.0, 0.0, 0.0 );
+		let mut integrated = Color::new( 0.0, 0.0, 0.0 );
+		for i in 0..n {
+			for j in 0..n {
+				if let Some(sample) = material.sample( &hit ) {
+					assert!( !sample.specular );
+					let pdf = material.pdf( &hit, &sample.ray.direction, &wo );
+					assert!( (pdf - sample.pdf).abs() <= 1e-3 * pdf.max( 1.0 ), "{} {}", pdf, sample.pdf );
+					sampled = sampled + sample.weight;
+				}
+
+				let z = 2.0 * (i as f32 + random()) / n as f32 - 1.0;
+				let phi = 2.0 * PI * (j as f32 + random()) / n as f32;
+				let r = (1.0 - z * z).max(0.0).sqrt();
+				let wi = Vec3::new( r * phi.cos(), r * phi.sin(), z );
+				let f = material.eval( &hit, &wi, &wo );
+				integrated = integrated + f * (wi.z.abs() * 4.0 * PI);
+			}
+		}
+		let k = 1.0 / (n * n) as f32;
+		let (sampled, integrated) = (sampled * k, integrated * k);
+		for (a, b) in [(sampled.r, integrated.r), (sampled.g, integrated.g), (sampled.b, integrated.b)].iter() {
+			assert!( (a - b).abs() < 0.03 * b, "{:?}: {} {}", wo, a, b );
+		}
+	}
+
+	#[test]
+	fn test_rough_glass_sampling_matches_evaluation() {
+		let glass = Glass { roughness : uniform( 0.3 ), ..Glass::new( Color::new( 0.9, 0.8, 0.7 ), 1.5 ) };
+
+		// from the air and from inside the glass
+		check_sampling( &glass, &Vec3::new( 0.3, 0.1, 0.8 ) );
+		check_sampling( &glass, &Vec3::new( 0.5, 0.0, -0.6 ) );
+	}
+
+	#[test]
+	fn test_diffuse_and_metal_sampling_matches_evaluation() {
+		let lambertian = Lambertian { albedo : constant( Color::new( 0.9, 0.5, 0.2 ) ) };
+		let metal = Metal::new( Color::new( 0.9, 0.8, 0.7 ), 0.3 );
+		let brushed = Metal { anisotropy : uniform( 0.6 ), ..Metal::new( Color::new( 0.9, 0.8, 0.7 ), 0.3 ) };
+
+		// straight above and at a grazing angle
+		for wo in [Vec3::new( 0.0, 0.0, 1.0 ), Vec3::new( 0.8, 0.3, 0.3 )].iter() {
+			check_sampling( &lambertian, wo );
+			check_sampling( &metal, wo );
+			check_sampling( &brushed, wo );
+		}
+	}
+
+	// Scalar texture running from `low` at u = 0 to `high` at u = 1
+	struct Across {
+		low : f32,
+		high : f32
+	}
+
+	impl Texture for Across {
+		fn value( &self, at : &TexCoord ) -> Color {
+			let x = self.low + (self.high - self.low) * at.u;
+			Color::new( x, x, x )
+		}
+	}
+
+	#[test]
+	fn test_parameters_follow_their_textures() {
+		let wo = Vec3::new( 0.6, 0.0, 0.8 );
+		fn on<'a>( material : &'a Material, u : f32 ) -> Hit<'a> {
+			let mut hit = hit_at( &Vec3::new( 0.0, 0.0, 1.0 ), &Vec3::new( 1.0, 0.0, 0.0 ), material );
+			hit.ray = Ray::new( &Vec3::new( 1.2, 0.0, 1.6 ), &Vec3::new( -0.6, 0.0, -0.8 ) );
+			hit.u = u;
+			hit
+		}
+
+		// the mirror direction is brighter where the metal is smoother
+		let metal = Metal::textured( constant( Color::new( 0.9, 0.9, 0.9 ) ), Rc::new( Across { low : 0.05, high : 0.6 } ) );
+		let mirror = Vec3::new( -0.6, 0.0, 0.8 );
+		let (smooth, rough) = (on( &metal, 0.0 ), on( &metal, 1.0 ));
+		assert!( metal.eval( &smooth, &mirror, &wo ).r > 10.0 * metal.eval( &rough, &mirror, &wo ).r );
+		assert!( metal.pdf( &smooth, &mirror, &wo ) > 10.0 * metal.pdf( &rough, &mirror, &wo ) );
+
+		// light bends more into the denser glass
+		let glass = Glass { ref_idx : Rc::new( Across { low : 1.2, high : 2.0 } ), ..Glass::new( Color::new( 1.0, 1.0, 1.0 ), 1.5 ) };
+		let refracted = |u : f32| {
+			let hit = on( &glass, u );
+			(0..1000).filter_map(|_| glass.sample( &hit )).map(|s| s.ray.direction).find(|d| d.z < 0.0).unwrap()
+		};
+		assert!( (refracted( 0.0 ).x.abs() - 0.6 / 1.2).abs() < 1e-3 );
+		assert!( (refracted( 1.0 ).x.abs() - 0.6 / 2.0).abs() < 1e-3 );
+
+		// and glows brighter where the radiation is stronger
+		let light = BlackBody { radiation : Rc::new( Across { low : 1.0, high : 3.0 } ), spectrum : None };
+		let (dim, bright) = (on( &light, 0.0 ), on( &light, 1.0 ));
+		assert!( (light.emit( &dim, &wo ).r - 1.0).abs() < 1e-5 );
+		assert!( (light.emit( &bright, &wo ).r - 3.0).abs() < 1e-5 );
 	}
 }
@@ -0,0 +1,627 @@
+use crate::vec_math::*;
+use crate::color::*;
+use crate::random::*;
+use crate::material::*;
+use crate::hitable::*;
+use crate::noise::*;
+use crate::spectral::*;
+
+use std::fs;
+use std::path::Path;
+
+use std::f32::consts::PI;
+
+// Participating media. A medium is a `Hitable` whose hits are the points where
+// a ray scatters inside it, their material is the phase function. When a ray
+// gets through without scattering the medium isn't hit at all, so a surface
+// inside the medium wins whenever it's closer than the sampled collision.
+
+// Angular distribution of scattered light
+#[allow(dead_code)]
+#[derive( Copy, Clone, PartialEq, Debug )]
+pub enum Phase {
+	Isotropic,
+	// mean cosine of the scattering angle in (-1, 1), positive scatters forward
+	HenyeyGreenstein( f32 )
+}
+
+impl Phase {
+	// Density over the sphere of directions for `cos` between the direction the
+	// light travelled in and the one it leaves in
+	pub fn value( &self, cos : f32 ) -> f32 {
+		match *self {
+			Phase::Isotropic => 0.25 / PI,
+			Phase::HenyeyGreenstein( g ) => {
+				let denom = 1.0 + g * g - 2.0 * g * cos;
+				(1.0 - g * g) / (4.0 * PI * denom * denom.max( 1e-12 ).sqrt())
+			}
+		}
+	}
+
+	// Cosine of the scattering angle, distributed as `value`
+	pub fn sample_cos( &self ) -> f32 {
+		let xi = random();
+		match *self {
+			Phase::HenyeyGreenstein( g ) if g.abs() > 1e-3 => {
+				let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * xi);
+				((1.0 + g * g - s * s) / (2.0 * g)).max( -1.0 ).min( 1.0 )
+			},
+			_ => 1.0 - 2.0 * xi
+		}
+	}
+}
+
+// Material of a scattering event. `albedo` is the fraction of the extinction
+// which is scattering, there is no cosine term in a medium.
+#[allow(dead_code)]
+pub struct PhaseFunction {
+	pub albedo : Color,
+	pub phase : Phase
+}
+
+impl Material for PhaseFunction {
+	fn sample( &self, hit : &Hit ) -> Option<BsdfSample>
+	{
+		let cos = self.phase.sample_cos();
+		let sin = (1.0 - cos * cos).max( 0.0 ).sqrt();
+		let phi = 2.0 * PI * random();
+		let frame = Onb::from_w( &hit.ray.direction );
+		let dir = frame.to_world( &Vec3::new( sin * phi.cos(), sin * phi.sin(), cos ) );
+
+		let ray = hit.ray.spawn( &hit.pos, &dir );
+		Some( BsdfSample { ray, weight : self.albedo, pdf : self.phase.value( cos ), specular : false } )
+	}
+
+	fn eval ( &self, _hit : &Hit, wi : &Vec3, wo : &Vec3 ) -> Color
+	{
+		self.albedo * self.phase.value( -dot_product( wi, wo ) )
+	}
+
+	fn pdf ( &self, _hit : &Hit, wi : &Vec3, wo : &Vec3 ) -> f32
+	{
+		self.phase.value( -dot_product( wi, wo ) )
+	}
+
+	fn is_medium ( &self ) -> bool { true }
+}
+
+// Medium of constant density filling a closed `boundary`, whose material is
+// never used. Extinction is the same for all colours, the colour of the medium
+// comes from `tint` on the scattered light.
+#[allow(dead_code)]
+pub struct HomogeneousMedium {
+	pub boundary : Box<Hitable>,
+	// coefficients per unit length
+	pub absorption : f32,
+	pub scattering : f32,
+	pub phase : PhaseFunction
+}
+
+#[allow(dead_code)]
+impl HomogeneousMedium {
+	pub fn new( boundary : Box<Hitable>, absorption : f32, scattering : f32, tint : Color, phase : Phase ) -> HomogeneousMedium {
+		let extinction = absorption + scattering;
+		let albedo = if extinction > 0.0 { tint * (scattering / extinction) } else { tint };
+		HomogeneousMedium { boundary, absorption, scattering, phase : PhaseFunction { albedo, phase } }
+	}
+
+	pub fn extinction( &self ) -> f32 {
+		self.absorption + self.scattering
+	}
+}
+
+// Where `ray` is inside the closed `boundary`: from the origin or the entry
+// point to the exit point, in distances along the ray
+pub fn boundary_interval( boundary : &Hitable, ray : &Ray ) -> Option<(f32, f32)> {
+	let first = boundary.hit( ray )?;
+	if dot_product( &first.normal, &ray.direction ) > 0.0 {
+		return Some( (0.0, first.distance) );
+	}
+	let inside = ray.spawn( &first.pos, &ray.direction );
+	let second = boundary.hit( &inside )?;
+	Some( (first.distance, first.distance + second.distance) )
+}
+
+// Scattering event at `distance` along `ray`, facing back along the ray
+fn scatter_hit<'a>( ray : &Ray, distance : f32, phase : &'a Material ) -> Hit<'a> {
+	let n = -&ray.direction;
+	let (tangent, bitangent) = tangent_frame( &n, &Vec3::zero(), &Vec3::zero() );
+	Hit {
+		distance,
+		pos : ray.get_point( distance ),
+		normal : n,
+		tangent,
+		bitangent,
+		u : 0.0,
+		v : 0.0,
+		uv_scale : 0.0,
+		ray : ray.clone(),
+		material : phase
+	}
+}
+
+impl Hitable for HomogeneousMedium {
+	fn hit( &self, ray : &Ray ) -> Option<Hit> {
+		let (enter, exit) = boundary_interval( self.boundary.as_ref(), ray )?;
+		let extinction = self.extinction();
+		if extinction <= 0.0 {
+			return None;
+		}
+
+		// free flight, the ray leaves before the collision most of the time
+		let distance = enter - (1.0 - random()).ln() / extinction;
+		if distance >= exit {
+			return None;
+		}
+
+		Some( scatter_hit( ray, distance, &self.phase ) )
+	}
+
+	fn transmittance( &self, ray : &Ray, distance : f32 ) -> Option<f32> {
+		let (enter, exit) = match boundary_interval( self.boundary.as_ref(), ray ) {
+			Some(i) => i,
+			None => return Some( 1.0 )
+		};
+		let length = (exit.min( distance ) - enter).max( 0.0 );
+		Some( (-self.extinction() * length).exp() )
+	}
+
+	fn bounds( &self ) -> Option<Aabb> {
+		self.boundary.bounds()
+	}
+}
+
+// Densities on the nodes of a regular grid spanning `bounds`, read between the
+// nodes with trilinear interpolation and zero outside
+#[derive(Debug, Clone)]
+pub struct DensityGrid {
+	pub res : [usize; 3],
+	pub bounds : Aabb,
+	// x varies fastest, then y
+	pub data : Vec<f32>,
+	max : f32
+}
+
+#[allow(dead_code)]
+impl DensityGrid {
+	pub fn new( res : [usize; 3], bounds : Aabb, data : Vec<f32> ) -> Result<DensityGrid, String> {
+		if res.iter().any(|n| *n == 0) || data.len() != res[0] * res[1] * res[2] {
+			return Err( format!( "{} densities for a {}x{}x{} grid", data.len(), res[0], res[1], res[2] ) );
+		}
+		let max = data.iter().fold( 0.0f32, |m, d| m.max( *d ) );
+		Ok( DensityGrid { res, bounds, data, max } )
+	}
+
+	// Grid with `f` evaluated at every node
+	pub fn from_fn<F : Fn( &Vec3 ) -> f32>( res : [usize; 3], bounds : Aabb, f : F ) -> DensityGrid {
+		let t = |i : usize, n : usize| if n > 1 { i as f32 / (n - 1) as f32 } else { 0.5 };
+		let e = &bounds.max - &bounds.min;
+		let mut data = Vec::with_capacity( res[0] * res[1] * res[2] );
+		for z in 0..res[2] {
+			for y in 0..res[1] {
+				for x in 0..res[0] {
+					let p = &bounds.min + Vec3::new( t( x, res[0] ) * e.x, t( y, res[1] ) * e.y, t( z, res[2] ) * e.z );
+					data.push( f( &p ).max( 0.0 ) );
+				}
+			}
+		}
+		DensityGrid::new( res, bounds, data ).unwrap()
+	}
+
+	// Mitsuba `.vol` grid, 32 bit float or 8 bit voxels. Grids with more than
+	// one channel use the first.
+	pub fn load_vol<P : AsRef<Path>>( path : P ) -> Result<DensityGrid, String> {
+		let bytes = fs::read( path.as_ref() ).map_err(|e| format!( "{}: {}", path.as_ref().display(), e ))?;
+		DensityGrid::decode_vol( &bytes )
+	}
+
+	pub fn decode_vol( bytes : &[u8] ) -> Result<DensityGrid, String> {
+		if bytes.len() < 48 || !bytes.starts_with( b"VOL" ) || bytes[3] != 3 {
+			return Err( "not a version 3 volume".to_string() );
+		}
+		let int = |i : usize| u32::from_le_bytes( [ bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3] ] ) as usize;
+		let float = |i : usize| f32::from_le_bytes( [ bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3] ] );
+
+		let encoding = int( 4 );
+		let res = [ int( 8 ), int( 12 ), int( 16 ) ];
+		let channels = int( 20 );
+		let bounds = Aabb { min : Vec3::new( float( 24 ), float( 28 ), float( 32 ) ),
+		                    max : Vec3::new( float( 36 ), float( 40 ), float( 44 ) ) };
+		let size = match encoding {
+			1 => 4,
+			3 => 1,
+			_ => return Err( format!( "unsupported volume encoding {}", encoding ) )
+		};
+
+		let count = res[0].saturating_mul( res[1] ).saturating_mul( res[2] );
+		if channels == 0 || count.saturating_mul( channels ).saturating_mul( size ) > bytes.len() - 48 {
+			return Err( "truncated volume".to_string() );
+		}
+		let data = (0..count).map(|i| {
+			let at = 48 + i * channels * size;
+			if size == 4 { float( at ) } else { bytes[at] as f32 / 255.0 }
+		}).collect();
+		DensityGrid::new( res, bounds, data )
+	}
+
+	// Headerless little endian 32 bit floats, x varying fastest
+	pub fn load_raw<P : AsRef<Path>>( path : P, res : [usize; 3], bounds : Aabb ) -> Result<DensityGrid, String> {
+		let bytes = fs::read( path.as_ref() ).map_err(|e| format!( "{}: {}", path.as_ref().display(), e ))?;
+		let data = bytes.chunks_exact( 4 ).map(|b| f32::from_le_bytes( [ b[0], b[1], b[2], b[3] ] )).collect();
+		DensityGrid::new( res, bounds, data )
+	}
+
+	// Largest density anywhere in the grid
+	pub fn max_density( &self ) -> f32 {
+		self.max
+	}
+
+	fn node( &self, x : usize, y : usize, z : usize ) -> f32 {
+		self.data[(z * self.res[1] + y) * self.res[0] + x]
+	}
+
+	pub fn density( &self, p : &Vec3 ) -> f32 {
+		let lo = [ self.bounds.min.x, self.bounds.min.y, self.bounds.min.z ];
+		let hi = [ self.bounds.max.x, self.bounds.max.y, self.bounds.max.z ];
+		let q = [ p.x, p.y, p.z ];
+
+		let mut i = [0usize; 3];
+		let mut f = [0.0f32; 3];
+		for a in 0..3 {
+			let t = (q[a] - lo[a]) / (hi[a] - lo[a]);
+			if !(t >= 0.0 && t <= 1.0) {
+				return 0.0;
+			}
+			let x = t * (self.res[a] - 1) as f32;
+			i[a] = (x as usize).min( self.res[a].saturating_sub( 2 ) );
+			f[a] = if self.res[a] > 1 { x - i[a] as f32 } else { 0.0 };
+		}
+
+		let step = |a : usize| if self.res[a] > 1 { 1 } else { 0 };
+		let (x1, y1, z1) = (i[0] + step( 0 ), i[1] + step( 1 ), i[2] + step( 2 ));
+		let lerp = |a : f32, b : f32, t : f32| a + (b - a) * t;
+		let c00 = lerp( self.node( i[0], i[1], i[2] ), self.node( x1, i[1], i[2] ), f[0] );
+		let c10 = lerp( self.node( i[0], y1, i[2] ), self.node( x1, y1, i[2] ), f[0] );
+		let c01 = lerp( self.node( i[0], i[1], z1 ), self.node( x1, i[1], z1 ), f[0] );
+		let c11 = lerp( self.node( i[0], y1, z1 ), self.node( x1, y1, z1 ), f[0] );
+		lerp( lerp( c00, c10, f[1] ), lerp( c01, c11, f[1] ), f[2] )
+	}
+
+	// Billowing cumulus in a `res` grid over the unit cube
+	pub fn cloud( res : usize, seed : u32 ) -> DensityGrid {
+		let noise = Perlin::new( seed );
+		let fractal = Fractal::new();
+		DensityGrid::from_fn( [ res; 3 ], unit_cube(), |p| {
+			// flat base, rounded top
+			let c = Vec3::new( p.x - 0.5, (p.y - 0.3) * 1.6, p.z - 0.5 );
+			let shape = 0.42 - c.length() - if p.y < 0.3 { (0.3 - p.y) * 4.0 } else { 0.0 };
+			let detail = noise.turbulence( &(p * 6.0), &fractal );
+			((shape + 0.25 * detail) * 12.0).max( 0.0 ).min( 1.0 )
+		} )
+	}
+
+	// Rising plume, dense at the bottom of the unit cube and dispersing upwards
+	pub fn smoke( res : usize, seed : u32 ) -> DensityGrid {
+		let noise = Perlin::new( seed );
+		let fractal = Fractal::new();
+		DensityGrid::from_fn( [ res; 3 ], unit_cube(), |p| {
+			let swirl = Vec3::new( noise.fbm( &(p * 3.0), &fractal ), 0.0, noise.fbm( &(p * 3.0 + Vec3::new( 5.2, 1.3, 7.1 )), &fractal ) );
+			let spread = 0.05 + 0.3 * p.y;
+			let dx = p.x - 0.5 + swirl.x * p.y * 0.6;
+			let dz = p.z - 0.5 + swirl.z * p.y * 0.6;
+			let core = (-(dx * dx + dz * dz) / (spread * spread)).exp();
+			let wisps = 0.5 + noise.turbulence( &(p * 9.0), &fractal );
+			core * wisps * (1.0 - p.y).max( 0.0 )
+		} )
+	}
+}
+
+// Density of soot and temperature in Kelvin of a fireball in the unit cube,
+// hottest in its turbulent core
+#[allow(dead_code)]
+pub fn fireball( res : usize, seed : u32 ) -> (DensityGrid, DensityGrid) {
+	let noise = Perlin::new( seed );
+	let fractal = Fractal::new();
+	let heat = |p : &Vec3| {
+		let r = (p - Vec3::new( 0.5, 0.45, 0.5 )).length() + 0.15 * noise.fbm( &(p * 4.0), &fractal );
+		(1.0 - r / 0.4).max( 0.0 )
+	};
+	let density = DensityGrid::from_fn( [ res; 3 ], unit_cube(), |p| {
+		let h = heat( p );
+		if h > 0.0 { 0.3 + 0.7 * noise.turbulence( &(p * 8.0), &fractal ) } else { 0.0 }
+	} );
+	let temperature = DensityGrid::from_fn( [ res; 3 ], unit_cube(), |p| 800.0 + 1700.0 * heat( p ).sqrt() );
+	(density, temperature)
+}
+
+fn unit_cube() -> Aabb {
+	Aabb { min : Vec3::zero(), max : Vec3::ones() }
+}
+
+// Heterogeneous medium with `grid` placed in the scene by `transform`. The
+// extinction is the grid density times `scale`, per unit length in the scene.
+// Collisions are found with delta tracking and shadow rays use ratio tracking,
+// both against the largest density as majorant.
+#[allow(dead_code)]
+pub struct GridMedium {
+	pub grid : DensityGrid,
+	pub scale : f32,
+	pub phase : PhaseFunction,
+	to_grid : Mat4x4
+}
+
+#[allow(dead_code)]
+impl GridMedium {
+	// `albedo` is the fraction of the extinction which is scattering
+	pub fn new( grid : DensityGrid, scale : f32, transform : &Mat4x4, albedo : Color, phase : Phase ) -> GridMedium {
+		GridMedium { grid, scale, phase : PhaseFunction { albedo, phase }, to_grid : transform.inv() }
+	}
+
+	fn majorant( &self ) -> f32 {
+		self.grid.max_density() * self.scale
+	}
+
+	// The ray in grid space, with distances still measured in the scene, and
+	// the part of it inside the grid
+	fn local( &self, ray : &Ray ) -> Option<(Ray, f32, f32)> {
+		let local = Ray {
+			origin : self.to_grid.apply( &ray.origin ),
+			direction : self.to_grid.apply_rotation( &ray.direction ),
+			..ray.clone()
+		};
+		let (enter, exit) = self.grid.bounds.intersect( &local, std::f32::INFINITY )?;
+		Some( (local, enter, exit) )
+	}
+
+	fn extinction( &self, local : &Ray, t : f32 ) -> f32 {
+		self.grid.density( &local.get_point( t ) ) * self.scale
+	}
+
+	// Distance to a collision along `ray` by delta tracking, tentative
+	// collisions against the majorant are real with probability extinction /
+	// majorant
+	fn collision( &self, ray : &Ray ) -> Option<f32> {
+		let majorant = self.majorant();
+		if majorant <= 0.0 {
+			return None;
+		}
+		let (local, mut t, exit) = self.local( ray )?;
+		loop {
+			t -= (1.0 - random()).ln() / majorant;
+			if t >= exit {
+				return None;
+			}
+			if random() * majorant < self.extinction( &local, t ) {
+				return Some( t );
+			}
+		}
+	}
+}
+
+impl Hitable for GridMedium {
+	fn hit( &self, ray : &Ray ) -> Option<Hit> {
+		let distance = self.collision( ray )?;
+		Some( scatter_hit( ray, distance, &self.phase ) )
+	}
+
+	fn transmittance( &self, ray : &Ray, distance : f32 ) -> Option<f32> {
+		let majorant = self.majorant();
+		let (local, mut t, exit) = match self.local( ray ) {
+			Some(l) if majorant > 0.0 => l,
+			_ => return Some( 1.0 )
+		};
+
+		// ratio tracking
+		let end = exit.min( distance );
+		let mut transmittance = 1.0;
+		loop {
+			t -= (1.0 - random()).ln() / majorant;
+			if t >= end {
+				return Some( transmittance );
+			}
+			transmittance *= 1.0 - self.extinction( &local, t ) / majorant;
+		}
+	}
+
+	fn bounds( &self ) -> Option<Aabb> {
+		Some( self.grid.bounds.transformed( &self.to_grid.inv() ) )
+	}
+}
+
+// Glowing grid medium such as fire. The emission lives in the grid space of
+// `medium` and is radiated where the medium absorbs, so it is picked up at
+// the collisions which aren't scattering.
+#[allow(dead_code)]
+pub struct EmissiveMedium {
+	pub medium : GridMedium,
+	pub emission : DensityGrid,
+	radiance : VolumeRadiance
+}
+
+enum VolumeRadiance {
+	BlackBody( PlanckTable, f32 ),
+	Constant( Color )
+}
+
+#[allow(dead_code)]
+impl EmissiveMedium {
+	// `temperatures` in Kelvin glow with black body colours, and a voxel at
+	// `reference` Kelvin has `luminance`
+	pub fn temperature( medium : GridMedium, temperatures : DensityGrid, reference : f32, luminance : f32 ) -> EmissiveMedium {
+		let table = PlanckTable::new( temperatures.max_density().max( reference ), 50.0 );
+		let scale = luminance / table.value( reference ).luminance().max( 1e-20 );
+		EmissiveMedium { medium, emission : temperatures, radiance : VolumeRadiance::BlackBody( table, scale ) }
+	}
+
+	// Voxels scale the radiance `colour`
+	pub fn radiance( medium : GridMedium, emission : DensityGrid, colour : Color ) -> EmissiveMedium {
+		EmissiveMedium { medium, emission, radiance : VolumeRadiance::Constant( colour ) }
+	}
+
+	// Radiance emitted at `pos` in the scene
+	pub fn radiance_at( &self, pos : &Vec3 ) -> Color {
+		let e = self.emission.density( &self.medium.to_grid.apply( pos ) );
+		match self.radiance {
+			VolumeRadiance::BlackBody( ref table, scale ) => table.value( e ) * scale,
+			VolumeRadiance::Constant( colour ) => colour * e
+		}
+	}
+}
+
+impl Material for EmissiveMedium {
+	fn sample( &self, hit : &Hit ) -> Option<BsdfSample> { self.medium.phase.sample( hit ) }
+
+	fn eval ( &self, hit : &Hit, wi : &Vec3, wo : &Vec3 ) -> Color { self.medium.phase.eval( hit, wi, wo ) }
+
+	fn pdf ( &self, hit : &Hit, wi : &Vec3, wo : &Vec3 ) -> f32 { self.medium.phase.pdf( hit, wi, wo ) }
+
+	// collisions scatter with probability albedo, the rest absorb and emit
+	fn emit( &self, hit : &Hit, _wo : &Vec3 ) -> Color {
+		let a = self.medium.phase.albedo;
+		Color::new( 1.0 - a.r, 1.0 - a.g, 1.0 - a.b ) * self.radiance_at( &hit.pos )
+	}
+
+	fn is_medium ( &self ) -> bool { true }
+}
+
+impl Hitable for EmissiveMedium {
+	fn hit( &self, ray : &Ray ) -> Option<Hit> {
+		let distance = self.medium.collision( ray )?;
+		Some( scatter_hit( ray, distance, self ) )
+	}
+
+	fn transmittance( &self, ray : &Ray, distance : f32 ) -> Option<f32> {
+		self.medium.transmittance( ray, distance )
+	}
+
+	fn bounds( &self ) -> Option<Aabb> {
+		self.medium.bounds()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::texture::*;
+
+	#[test]
+	fn test_henyey_greenstein_sampling() {
+		for g in [ -0.5f32, 0.0, 0.3, 0.8 ].iter() {
+			let phase = Phase::HenyeyGreenstein( *g );
+			let n = 20000;
+			let mean = (0..n).map(|_| phase.sample_cos()).sum::<f32>() / n as f32;
+			assert!( (mean - g).abs() < 0.02, "{} {}", g, mean );
+
+			// normalised over the sphere
+			let steps = 2000;
+			let total : f32 = (0..steps).map(|i| {
+				let cos = -1.0 + 2.0 * (i as f32 + 0.5) / steps as f32;
+				phase.value( cos ) * 2.0 * PI * (2.0 / steps as f32)
+			}).sum();
+			assert!( (total - 1.0).abs() < 0.01, "{} {}", g, total );
+		}
+	}
+
+	#[test]
+	fn test_transmittance_through_sphere() {
+		let boundary = Box::new( Sphere {
+			center : Vec3::zero(),
+			radius : 1.0,
+			material : Box::new( Lambertian { albedo : constant( Color::new( 0.0, 0.0, 0.0 ) ) } )
+		} );
+		let medium = HomogeneousMedium::new( boundary, 0.2, 0.5, Color::new( 1.0, 1.0, 1.0 ), Phase::Isotropic );
+
+		// through the centre from outside, and from the centre out
+		let cases = [ (Ray::new( &Vec3::new( 0.0, 0.0, -3.0 ), &Vec3::new( 0.0, 0.0, 1.0 ) ), 2.0f32),
+		              (Ray::new( &Vec3::zero(), &Vec3::new( 0.0, 1.0, 0.0 ) ), 1.0) ];
+		for (ray, length) in cases.iter() {
+			let n = 20000;
+			let mut through = 0;
+			for _ in 0..n {
+				match medium.hit( ray ) {
+					Some(hit) => assert!( hit.pos.squre_length() <= 1.0001 && hit.material.is_medium() ),
+					None => through += 1
+				}
+			}
+			let expected = (-0.7 * length).exp();
+			assert!( (through as f32 / n as f32 - expected).abs() < 0.015, "{} {}", through, expected );
+		}
+
+		// scattering over extinction
+		assert!( (medium.phase.albedo.g - 0.5 / 0.7).abs() < 1e-6 );
+	}
+
+	#[test]
+	fn test_decode_vol() {
+		let mut bytes = b"VOL\x03".to_vec();
+		for v in [ 1u32, 2, 2, 2, 1 ].iter() {
+			bytes.extend_from_slice( &v.to_le_bytes() );
+		}
+		for v in [ -1.0f32, 0.0, 0.0, 1.0, 1.0, 1.0 ].iter() {
+			bytes.extend_from_slice( &v.to_le_bytes() );
+		}
+		for v in 0..8 {
+			bytes.extend_from_slice( &(v as f32).to_le_bytes() );
+		}
+
+		let grid = DensityGrid::decode_vol( &bytes ).unwrap();
+		assert_eq!( grid.res, [2, 2, 2] );
+		assert_eq!( grid.max_density(), 7.0 );
+		assert_eq!( grid.density( &Vec3::new( 1.0, 1.0, 1.0 ) ), 7.0 );
+		assert!( (grid.density( &Vec3::new( 0.0, 0.5, 0.5 ) ) - 3.5).abs() < 1e-5 );
+		assert!( (grid.density( &Vec3::new( -0.5, 0.0, 0.0 ) ) - 0.25).abs() < 1e-5 );
+		assert_eq!( grid.density( &Vec3::new( 0.0, 0.5, 1.5 ) ), 0.0 );
+
+		assert!( DensityGrid::decode_vol( &bytes[..bytes.len() - 1] ).is_err() );
+	}
+
+	#[test]
+	fn test_grid_tracking() {
+		// density rising from 0 to 1 along x, stretched and then moved to x in [1, 3]
+		let grid = DensityGrid::new( [2, 1, 1], unit_cube(), vec![ 0.0, 1.0 ] ).unwrap();
+		let transform = &Mat4x4::scale( 2.0, 1.0, 1.0 ) * &Mat4x4::translation( 1.0, 0.0, 0.0 );
+		let medium = GridMedium::new( grid, 0.6, &transform, Color::new( 1.0, 1.0, 1.0 ), Phase::Isotropic );
+
+		// optical depth is the mean density over 2 units of length
+		let ray = Ray::new( &Vec3::new( 0.0, 0.5, 0.5 ), &Vec3::new( 1.0, 0.0, 0.0 ) );
+		let expected = (-0.6f32).exp();
+		let n = 20000;
+		let through = (0..n).filter(|_| match medium.hit( &ray ) {
+			Some(hit) => { assert!( hit.distance >= 1.0 && hit.distance <= 3.0 ); false },
+			None => true
+		}).count();
+		assert!( (through as f32 / n as f32 - expected).abs() < 0.015, "{} {}", through, expected );
+
+		let ratio = (0..n).map(|_| medium.transmittance( &ray, 10.0 ).unwrap()).sum::<f32>() / n as f32;
+		assert!( (ratio - expected).abs() < 0.01, "{} {}", ratio, expected );
+
+		// stopping halfway leaves a quarter of the optical depth
+		let half = (0..n).map(|_| medium.transmittance( &ray, 2.0 ).unwrap()).sum::<f32>() / n as f32;
+		assert!( (half - (-0.15f32).exp()).abs() < 0.01, "{}", half );
+	}
+
+	#[test]
+	fn test_emission() {
+		// black smoke of constant density glowing with a constant radiance
+		let density = DensityGrid::new( [1, 1, 1], unit_cube(), vec![ 1.0 ] ).unwrap();
+		let medium = GridMedium::new( density.clone(), 0.8, &Mat4x4::ident(), Color::new( 0.0, 0.0, 0.0 ), Phase::Isotropic );
+		let glow = EmissiveMedium::radiance( medium, density, Color::new( 2.0, 1.0, 0.5 ) );
+
+		// every collision absorbs, so the expected emission is the radiance times
+		// the probability of a collision
+		let ray = Ray::new( &Vec3::new( 0.5, 0.5, -1.0 ), &Vec3::new( 0.0, 0.0, 1.0 ) );
+		let n = 20000;
+		let total = (0..n).filter_map(|_| glow.hit( &ray ).map(|h| h.emitted().r)).sum::<f32>();
+		let expected = 2.0 * (1.0 - (-0.8f32).exp());
+		assert!( (total / n as f32 - expected).abs() < 0.03, "{} {}", total / n as f32, expected );
+
+		// temperatures, with the luminance given at the reference
+		let temperatures = DensityGrid::new( [2, 1, 1], unit_cube(), vec![ 1000.0, 3000.0 ] ).unwrap();
+		let medium = GridMedium::new( temperatures.clone(), 1.0, &Mat4x4::ident(), Color::new( 0.0, 0.0, 0.0 ), Phase::Isotropic );
+		let fire = EmissiveMedium::temperature( medium, temperatures, 2000.0, 5.0 );
+		let middle = fire.radiance_at( &Vec3::new( 0.5, 0.5, 0.5 ) );
+		let hot = fire.radiance_at( &Vec3::new( 1.0, 0.5, 0.5 ) );
+		assert!( (middle.luminance() - 5.0).abs() < 1e-3, "{:?}", middle );
+		assert!( hot.luminance() > 20.0 * middle.luminance() && hot.b / hot.r > middle.b / middle.r );
+	}
+}
@@ -0,0 +1,417 @@
+use crate::vec_math::*;
+use crate::hitable::*;
+use crate::material::*;
+use crate::texture::*;
+
+use std::collections::HashMap;
+use std::f32::consts::PI;
+
+// Triangle mesh with smooth normals and uvs, intersected through a bounding
+// volume hierarchy. The normals and the hierarchy are rebuilt whenever the
+// geometry is changed by `subdivide` or `displace`.
+pub struct Mesh {
+	pub positions : Vec<Vec3>,
+	pub normals : Vec<Vec3>,
+	pub uvs : Vec<(f32, f32)>,
+	pub triangles : Vec<[usize; 3]>,
+	pub material : Box<Material>,
+	nodes : Vec<BvhNode>,
+	// triangle indices, every leaf owns a range of them
+	order : Vec<usize>
+}
+
+// Leaves have a count and start at `first` in `order`, inner nodes have their
+// two children at `first` and `first + 1`
+struct BvhNode {
+	bounds : Aabb,
+	first : usize,
+	count : usize
+}
+
+const LEAF_SIZE : usize = 4;
+
+// Identifies vertices at the same place, adding zero turns -0 into 0
+fn weld_key( p : &Vec3 ) -> (u32, u32, u32) {
+	((p.x + 0.0).to_bits(), (p.y + 0.0).to_bits(), (p.z + 0.0).to_bits())
+}
+
+impl Mesh {
+
+	pub fn new( positions : Vec<Vec3>, uvs : Vec<(f32, f32)>, triangles : Vec<[usize; 3]>, material : Box<Material> ) -> Mesh {
+		let mut mesh = Mesh { positions, normals : Vec::new(), uvs, triangles, material, nodes : Vec::new(), order : Vec::new() };
+		mesh.rebuild();
+		mesh
+	}
+
+	// Tessellated sphere with the same uvs as `Sphere`
+	#[allow(dead_code)]
+	pub fn uv_sphere( center : &Vec3, radius : f32, rings : usize, segments : usize, material : Box<Material> ) -> Mesh {
+		let mut positions = Vec::new();
+		let mut uvs = Vec::new();
+		for i in 0..rings + 1 {
+			for j in 0..segments + 1 {
+				let (u, v) = (j as f32 / segments as f32, i as f32 / rings as f32);
+				// the seam and the poles land exactly on their twins so their normals are shared
+				let phi = 2.0 * PI * (j % segments) as f32 / segments as f32;
+				let (sin, cos) = if i == 0 || i == rings { (0.0, 1.0 - 2.0 * v) } else { ((PI * v).sin(), (PI * v).cos()) };
+				let n = Vec3::new( -phi.cos() * sin, -cos, phi.sin() * sin );
+				positions.push( center + radius * &n );
+				uvs.push( (u, v) );
+			}
+		}
+
+		let index = |i : usize, j : usize| i * (segments + 1) + j;
+		let mut triangles = Vec::new();
+		for i in 0..rings {
+			for j in 0..segments {
+				let (a, b, c, d) = (index( i, j ), index( i, j + 1 ), index( i + 1, j ), index( i + 1, j + 1 ));
+				if i != 0 {
+					triangles.push( [a, b, c] );
+				}
+				if i != rings - 1 {
+					triangles.push( [b, d, c] );
+				}
+			}
+		}
+		Mesh::new( positions, uvs, triangles, material )
+	}
+
+	// Splits every triangle in four through the middle of its edges
+	#[allow(dead_code)]
+	pub fn subdivide( &mut self ) {
+		let mut middles : HashMap<(usize, usize), usize> = HashMap::new();
+		let mut triangles = Vec::with_capacity( self.triangles.len() * 4 );
+
+		for t in self.triangles.clone().iter() {
+			let mut m = [0; 3];
+			for k in 0..3 {
+				let (a, b) = (t[k], t[(k + 1) % 3]);
+				let key = (a.min(b), a.max(b));
+				m[k] = match middles.get( &key ) {
+					Some(i) => *i,
+					None => {
+						let i = self.positions.len();
+						self.positions.push( (&self.positions[a] + &self.positions[b]) * 0.5 );
+						self.uvs.push( ((self.uvs[a].0 + self.uvs[b].0) * 0.5, (self.uvs[a].1 + self.uvs[b].1) * 0.5) );
+						middles.insert( key, i );
+						i
+					}
+				};
+			}
+			triangles.push( [t[0], m[0], m[2]] );
+			triangles.push( [m[0], t[1], m[1]] );
+			triangles.push( [m[2], m[1], t[2]] );
+			triangles.push( [m[0], m[1], m[2]] );
+		}
+		self.triangles = triangles;
+		self.rebuild();
+	}
+
+	// Moves every vertex along its normal by the luminance of `height` at the vertex.
+	// Vertices at the same place move by their average height, so the surface
+	// doesn't tear along seams in the uvs.
+	#[allow(dead_code)]
+	pub fn displace( &mut self, height : &Texture, scale : f32 ) {
+		let mut sums : HashMap<(u32, u32, u32), (f32, usize)> = HashMap::new();
+		for i in 0..self.positions.len() {
+			let (u, v) = self.uvs[i];
+			let at = TexCoord { pos : self.positions[i].clone(), normal : self.normals[i].clone(), ..TexCoord::uv( u, v ) };
+			let sum = sums.entry( weld_key( &self.positions[i] ) ).or_insert( (0.0, 0) );
+			sum.0 += height.value( &at ).luminance();
+			sum.1 += 1;
+		}
+		for i in 0..self.positions.len() {
+			let (h, count) = sums[&weld_key( &self.positions[i] )];
+			self.positions[i] = &self.positions[i] + (h / count as f32 * scale) * &self.normals[i];
+		}
+		self.rebuild();
+	}
+
+	fn rebuild( &mut self ) {
+		self.smooth_normals();
+		self.order = (0..self.triangles.len()).collect();
+		self.nodes = vec![ BvhNode { bounds : Aabb::empty(), first : 0, count : self.triangles.len() } ];
+		if !self.triangles.is_empty() {
+			self.split( 0 );
+		}
+	}
+
+	// Area weighted vertex normals. Vertices at the same place share their normal,
+	// so seams in the uvs don't show up in the shading.
+	fn smooth_normals( &mut self ) {
+		let mut sums : HashMap<(u32, u32, u32), Vec3> = HashMap::new();
+		for t in self.triangles.iter() {
+			let (p0, p1, p2) = (&self.positions[t[0]], &self.positions[t[1]], &self.positions[t[2]]);
+			let n = cross_product( &(p1 - p0), &(p2 - p0) );
+			for i in t.iter() {
+				let sum = sums.entry( weld_key( &self.positions[*i] ) ).or_insert( Vec3::zero() );
+				*sum = &*sum + &n;
+			}
+		}
+		self.normals = self.positions.iter().map(|p| match sums.get( &weld_key( p ) ) {
+			Some(n) if n.squre_length() > 0.0 => n.normalized(),
+			_ => Vec3::new( 0.0, 1.0, 0.0 )
+		}).collect();
+	}
+
+	fn triangle_bounds( &self, t : usize ) -> Aabb {
+		let mut b = Aabb::empty();
+		for i in self.triangles[t].iter() {
+			b.grow( &self.positions[*i] );
+		}
+		b
+	}
+
+	// Median split along the longest side of the centroids
+	fn split( &mut self, node : usize ) {
+		let (first, count) = (self.nodes[node].first, self.nodes[node].count);
+		let mut bounds = Aabb::empty();
+		let mut centroids = Aabb::empty();
+		for t in self.order[first..first + count].iter() {
+			let b = self.triangle_bounds( *t );
+			centroids.grow( &b.centroid() );
+			bounds = bounds.union( &b );
+		}
+		self.nodes[node].bounds = bounds;
+		if count <= LEAF_SIZE {
+			return;
+		}
+
+		let axis = centroids.largest_axis();
+		let coord = |p : &Vec3| match axis { 0 => p.x, 1 => p.y, _ => p.z };
+		let mut keyed : Vec<(f32, usize)> = self.order[first..first + count].iter()
+			.map(|t| (coord( &self.triangle_bounds( *t ).centroid() ), *t)).collect();
+		keyed.sort_by(|a, b| a.0.partial_cmp( &b.0 ).unwrap_or( std::cmp::Ordering::Equal ));
+		for (i, k) in keyed.iter().enumerate() {
+			self.order[first + i] = k.1;
+		}
+
+		let half = count / 2;
+		let left = self.nodes.len();
+		self.nodes.push( BvhNode { bounds : Aabb::empty(), first, count : half } );
+		self.nodes.push( BvhNode { bounds : Aabb::empty(), first : first + half, count : count - half } );
+		self.nodes[node].first = left;
+		self.nodes[node].count = 0;
+		self.split( left );
+		self.split( left + 1 );
+	}
+
+	// Möller-Trumbore, returns the distance and the barycentrics of the second and third vertex
+	fn intersect( &self, ray : &Ray, t : usize, t_max : f32 ) -> Option<(f32, f32, f32)> {
+		let [i0, i1, i2] = self.triangles[t];
+		let p0 = &self.positions[i0];
+		let e1 = &self.positions[i1] - p0;
+		let e2 = &self.positions[i2] - p0;
+
+		let pvec = cross_product( &ray.direction, &e2 );
+		let det = dot_product( &e1, &pvec );
+		if det.abs() < 1e-12 {
+			return None;
+		}
+		let inv = 1.0 / det;
+
+		let tvec = &ray.origin - p0;
+		let b1 = dot_product( &tvec, &pvec ) * inv;
+		if b1 < 0.0 || b1 > 1.0 {
+			return None;
+		}
+		let qvec = cross_product( &tvec, &e1 );
+		let b2 = dot_product( &ray.direction, &qvec ) * inv;
+		if b2 < 0.0 || b1 + b2 > 1.0 {
+			return None;
+		}
+
+		let distance = dot_product( &e2, &qvec ) * inv;
+		if distance < 0.0001 || distance >= t_max {
+			return None;
+		}
+		Some( (distance, b1, b2) )
+	}
+
+	fn closest( &self, ray : &Ray ) -> Option<(usize, f32, f32, f32)> {
+		let mut best : Option<(usize, f32, f32, f32)> = None;
+		let mut t_max = std::f32::INFINITY;
+		let mut stack = vec![ 0 ];
+
+		while let Some(n) = stack.pop() {
+			let node = &self.nodes[n];
+			if node.bounds.intersect( ray, t_max ).is_none() {
+				continue;
+			}
+			if node.count == 0 {
+				stack.push( node.first );
+				stack.push( node.first + 1 );
+				continue;
+			}
+			for t in self.order[node.first..node.first + node.count].iter() {
+				if let Some((d, b1, b2)) = self.intersect( ray, *t, t_max ) {
+					t_max = d;
+					best = Some( (*t, d, b1, b2) );
+				}
+			}
+		}
+		best
+	}
+
+	fn surface<'a>( &'a self, ray : &Ray, t : usize, distance : f32, b1 : f32, b2 : f32 ) -> Hit<'a> {
+		let [i0, i1, i2] = self.triangles[t];
+		let b0 = 1.0 - b1 - b2;
+
+		let normal = (b0 * &self.normals[i0] + b1 * &self.normals[i1] + b2 * &self.normals[i2]).normalized();
+		let (uv0, uv1, uv2) = (self.uvs[i0], self.uvs[i1], self.uvs[i2]);
+		let u = b0 * uv0.0 + b1 * uv1.0 + b2 * uv2.0;
+		let v = b0 * uv0.1 + b1 * uv1.1 + b2 * uv2.1;
+
+		// derivatives of the position along u and v from the uvs of the corners
+		let e1 = &self.positions[i1] - &self.positions[i0];
+		let e2 = &self.positions[i2] - &self.positions[i0];
+		let (du1, dv1, du2, dv2) = (uv1.0 - uv0.0, uv1.1 - uv0.1, uv2.0 - uv0.0, uv2.1 - uv0.1);
+		let det = du1 * dv2 - dv1 * du2;
+		let (dpdu, dpdv, scale) = if det.abs() < 1e-12 {
+			(e1.clone(), e2.clone(), 0.0)
+		} else {
+			let (dpdu, dpdv) = ((dv2 * &e1 - dv1 * &e2) / det, (du1 * &e2 - du2 * &e1) / det);
+			let scale = uv_scale( &dpdu, &dpdv );
+			(dpdu, dpdv, scale)
+		};
+		let (tangent, bitangent) = tangent_frame( &normal, &dpdu, &dpdv );
+
+		Hit {
+			distance,
+			pos : ray.get_point( distance ),
+			normal,
+			tangent,
+			bitangent,
+			u,
+			v,
+			uv_scale : scale,
+			ray : ray.clone(),
+			material : self.material.as_ref()
+		}
+	}
+}
+
+impl Hitable for Mesh {
+	fn hit( &self, ray: &Ray ) -> Option<Hit>
+	{
+		if self.triangles.is_empty() {
+			return None;
+		}
+		let (t, distance, b1, b2) = self.closest( ray )?;
+		Some( self.surface( ray, t, distance, b1, b2 ) )
+	}
+
+	// Only meaningful for closed meshes with the triangles wound counterclockwise
+	// seen from outside, like the ones `uv_sphere` makes
+	fn intervals( &self, ray : &Ray ) -> Option<Vec<Interval>>
+	{
+		if self.triangles.is_empty() {
+			return Some( Vec::new() );
+		}
+		// march along the line from behind the bounds, counting how deep inside it is
+		let bounds = &self.nodes[0].bounds;
+		let back = (&ray.origin - &bounds.centroid()).length() + (&bounds.max - &bounds.min).length();
+		let mut travelled = -back;
+		let mut depth = 0;
+		let mut enter = None;
+		let mut inside = Vec::new();
+		while let Some((t, distance, b1, b2)) = self.closest( &ray.spawn( &ray.get_point( travelled ), &ray.direction ) ) {
+			travelled += distance;
+			let [i0, i1, i2] = self.triangles[t];
+			let face = cross_product( &(&self.positions[i1] - &self.positions[i0]), &(&self.positions[i2] - &self.positions[i0]) );
+			let hit = self.surface( ray, t, travelled, b1, b2 );
+			if dot_product( &face, &ray.direction ) < 0.0 {
+				depth += 1;
+				if depth == 1 {
+					enter = Some( hit );
+				}
+			} else if depth > 0 {
+				depth -= 1;
+				if depth == 0 {
+					inside.push( Interval { enter : enter.take()?, exit : hit } );
+				}
+			}
+		}
+		Some( inside )
+	}
+
+	fn bounds( &self ) -> Option<Aabb>
+	{
+		self.nodes.first().filter(|_| !self.triangles.is_empty()).map(|root| root.bounds.clone())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::color::*;
+	use crate::random::*;
+
+	fn material() -> Box<Material> {
+		Box::new( Lambertian { albedo : uniform( 0.5 ) } )
+	}
+
+	#[test]
+	fn test_hierarchy_finds_the_closest_triangle() {
+		let mut mesh = Mesh::uv_sphere( &Vec3::new( 0.0, 0.0, 3.0 ), 1.0, 12, 24, material() );
+		mesh.subdivide();
+
+		for _ in 0..500 {
+			let target = Vec3::new( 0.0, 0.0, 3.0 ) + random_unit_vector() * 1.2;
+			let ray = Ray::new( &Vec3::zero(), &target );
+
+			let mut brute : Option<f32> = None;
+			for t in 0..mesh.triangles.len() {
+				if let Some((d, _, _)) = mesh.intersect( &ray, t, std::f32::INFINITY ) {
+					brute = Some( brute.map_or( d, |b| b.min( d ) ) );
+				}
+			}
+			let hit = mesh.hit( &ray ).map(|h| h.distance);
+			assert_eq!( hit, brute );
+		}
+	}
+
+	#[test]
+	fn test_displacement_moves_the_surface() {
+		let mut mesh = Mesh::uv_sphere( &Vec3::zero(), 1.0, 32, 64, material() );
+		let ray = Ray::new( &Vec3::new( -3.0, 0.01, 0.02 ), &Vec3::new( 1.0, 0.0, 0.0 ) );
+		let before = mesh.hit( &ray ).unwrap();
+		assert!( (before.distance - 2.0).abs() < 0.01 );
+		assert!( (before.normal.x + 1.0).abs() < 0.01 );
+		assert!( dot_product( &before.normal, &before.tangent ).abs() < 0.001 );
+
+		mesh.displace( &ConstantTexture { color : Color::new( 1.0, 1.0, 1.0 ) }, 0.5 );
+		let after = mesh.hit( &ray ).unwrap();
+		assert!( (after.distance - 1.5).abs() < 0.01, "{}", after.distance );
+		assert!( (mesh.bounds().unwrap().max.y - 1.5).abs() < 0.001 );
+	}
+
+	// Height growing with u, which differs across the seam and at the poles
+	struct Ramp;
+
+	impl Texture for Ramp {
+		fn value( &self, at : &TexCoord ) -> Color {
+			Color::new( at.u, at.u, at.u )
+		}
+	}
+
+	#[test]
+	fn test_displacement_keeps_seams_closed() {
+		let mut mesh = Mesh::uv_sphere( &Vec3::zero(), 1.0, 8, 16, material() );
+		let mut twins : HashMap<(u32, u32, u32), Vec<usize>> = HashMap::new();
+		for (i, p) in mesh.positions.iter().enumerate() {
+			twins.entry( weld_key( p ) ).or_insert( Vec::new() ).push( i );
+		}
+		assert!( twins.len() < mesh.positions.len() );
+
+		mesh.displace( &Ramp, 0.5 );
+		for group in twins.values() {
+			for i in group.iter() {
+				assert!( (&mesh.positions[*i] - &mesh.positions[group[0]]).length() < 1e-6 );
+			}
+		}
+		// the seam on the equator moves by the average of its heights at u = 0 and u = 1
+		let seam = &mesh.positions[4 * 17];
+		assert!( (seam.length() - 1.25).abs() < 1e-4, "{}", seam.length() );
+	}
+}
@@ -0,0 +1,223 @@
+use crate::vec_math::*;
+use crate::color::*;
+use crate::random::*;
+
+use std::f32::consts::PI;
+
+// GGX (Trowbridge-Reitz) microfacet distribution. All directions are in the
+// local shading frame with the normal along z and point away from the surface.
+#[derive( Copy, Clone, Debug )]
+pub struct Ggx {
+	pub alpha_x : f32,
+	pub alpha_y : f32
+}
+
+impl Ggx {
+
+	// Roughness is perceptually linear, alpha = roughness^2. Anisotropy in [0, 1)
+	// stretches the highlight along the first tangent.
+	pub fn new( roughness : f32, anisotropy : f32 ) -> Ggx {
+		let alpha = (roughness * roughness).max( 0.0001 );
+		let aspect = (1.0 - 0.9 * anisotropy.max(0.0).min(1.0)).sqrt();
+		Ggx { alpha_x : (alpha / aspect).max( 0.0001 ), alpha_y : (alpha * aspect).max( 0.0001 ) }
+	}
+
+	// Density of microfacet normals, normalized over the projected hemisphere
+	pub fn d( &self, h : &Vec3 ) -> f32 {
+		if h.z <= 0.0 {
+			return 0.0;
+		}
+		let x = h.x / self.alpha_x;
+		let y = h.y / self.alpha_y;
+		let t = x * x + y * y + h.z * h.z;
+		1.0 / (PI * self.alpha_x * self.alpha_y * t * t)
+	}
+
+	fn lambda( &self, w : &Vec3 ) -> f32 {
+		if w.z == 0.0 {
+			return std::f32::INFINITY;
+		}
+		let a2 = (self.alpha_x * w.x).powi(2) + (self.alpha_y * w.y).powi(2);
+		(-1.0 + (1.0 + a2 / (w.z * w.z)).sqrt()) * 0.5
+	}
+
+	// Smith masking
+	pub fn g1( &self, w : &Vec3 ) -> f32 {
+		1.0 / (1.0 + self.lambda( w ))
+	}
+
+	// Height correlated Smith masking-shadowing
+	pub fn g( &self, wo : &Vec3, wi : &Vec3 ) -> f32 {
+		1.0 / (1.0 + self.lambda( wo ) + self.lambda( wi ))
+	}
+
+	// Microfacet normal visible from `wo` (Heitz 2018)
+	pub fn sample_h( &self, wo : &Vec3 ) -> Vec3 {
+		let vh = Vec3::new( self.alpha_x * wo.x, self.alpha_y * wo.y, wo.z ).normalized();
+
+		let len2 = vh.x * vh.x + vh.y * vh.y;
+		let t1 = if len2 > 0.0 { Vec3::new( -vh.y, vh.x, 0.0 ) / len2.sqrt() } else { Vec3::new( 1.0, 0.0, 0.0 ) };
+		let t2 = cross_product( &vh, &t1 );
+
+		let r = random().sqrt();
+		let phi = 2.0 * PI * random();
+		let p1 = r * phi.cos();
+		let s = 0.5 * (1.0 + vh.z);
+		let p2 = (1.0 - s) * (1.0 - p1 * p1).max(0.0).sqrt() + s * r * phi.sin();
+
+		let nh = p1 * &t1 + p2 * &t2 + (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt() * &vh;
+		Vec3::new( self.alpha_x * nh.x, self.alpha_y * nh.y, nh.z.max( 1e-6 ) ).normalized()
+	}
+
+	// Density with which `sample_h` returns `h`
+	pub fn pdf_h( &self, wo : &Vec3, h : &Vec3 ) -> f32 {
+		if wo.z <= 0.0 {
+			return 0.0;
+		}
+		self.g1( wo ) * dot_product( wo, h ).max(0.0) * self.d( h ) / wo.z
+	}
+
+	// Rough dielectric interface (Walter et al. 2007). BSDF without the cosine
+	// between `wo` in front of the surface and `wi` on either side, and the
+	// density of `wi` when a visible normal is sampled and the ray reflected or
+	// refracted through it with the Fresnel probability. `eta` is the index of
+	// refraction behind the surface over the one in front. Radiance isn't
+	// scaled by eta^2 on the way through, like for smooth glass.
+	pub fn dielectric( &self, wo : &Vec3, wi : &Vec3, eta : f32 ) -> (f32, f32) {
+		if wo.z <= 0.0 || wi.z == 0.0 {
+			return (0.0, 0.0);
+		}
+
+		if wi.z > 0.0 {
+			let h = (wo + wi).normalized();
+			let oh = dot_product( wo, &h );
+			if oh <= 0.0 {
+				return (0.0, 0.0);
+			}
+			let f = fresnel_dielectric( oh, eta );
+			return (f * self.d( &h ) * self.g( wo, wi ) / (4.0 * wo.z * wi.z), f * self.pdf_h( wo, &h ) / (4.0 * oh));
+		}
+
+		let h = match transmission_half_vector( wo, wi, eta ) {
+			Some(h) => h,
+			None => return (0.0, 0.0)
+		};
+		let (oh, ih) = (dot_product( wo, &h ), dot_product( wi, &h ));
+		let t = 1.0 - fresnel_dielectric( oh, eta );
+		let denom = oh + eta * ih;
+		let jacobian = eta * eta * ih.abs() / (denom * denom);
+		(t * self.d( &h ) * self.g( wo, wi ) * oh * jacobian / (wo.z * -wi.z), t * self.pdf_h( wo, &h ) * jacobian)
+	}
+}
+
+// Microfacet normal which refracts `wo` into `wi`, on the side of `wo`. `eta`
+// is the index of refraction on the side of `wi` over the one of `wo`.
+pub fn transmission_half_vector( wo : &Vec3, wi : &Vec3, eta : f32 ) -> Option<Vec3> {
+	let h = -(wo + eta * wi);
+	if h.squre_length() < 1e-12 {
+		return None;
+	}
+	let h = h.normalized();
+	let h = if h.z < 0.0 { -h } else { h };
+	if dot_product( wo, &h ) <= 0.0 || dot_product( wi, &h ) >= 0.0 {
+		return None;
+	}
+	Some( h )
+}
+
+fn conductor_channel( cos : f32, eta : f32, k : f32 ) -> f32 {
+	let cos2 = cos * cos;
+	let sin2 = 1.0 - cos2;
+	let t0 = eta * eta - k * k - sin2;
+	let a2b2 = (t0 * t0 + 4.0 * eta * eta * k * k).sqrt();
+	let a = (0.5 * (a2b2 + t0)).max(0.0).sqrt();
+
+	let t1 = a2b2 + cos2;
+	let t2 = 2.0 * cos * a;
+	let rs = (t1 - t2) / (t1 + t2);
+
+	let t3 = cos2 * a2b2 + sin2 * sin2;
+	let t4 = t2 * sin2;
+	let rp = rs * (t3 - t4) / (t3 + t4);
+
+	0.5 * (rs + rp)
+}
+
+// Fresnel reflectance of a conductor with complex index of refraction eta + ik
+pub fn fresnel_conductor( cos : f32, eta : &Color, k : &Color ) -> Color {
+	let cos = cos.max(0.0).min(1.0);
+	Color::new(
+		conductor_channel( cos, eta.r, k.r ),
+		conductor_channel( cos, eta.g, k.g ),
+		conductor_channel( cos, eta.b, k.b ) )
+}
+
+// Fresnel reflectance of a dielectric interface for light arriving at `cos`
+// from the side with index 1, `eta` is the index on the other side
+pub fn fresnel_dielectric( cos : f32, eta : f32 ) -> f32 {
+	let cos_i = cos.max(0.0).min(1.0);
+	let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+	if sin2_t >= 1.0 {
+		return 1.0;
+	}
+	let cos_t = (1.0 - sin2_t).sqrt();
+	let rs = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+	let rp = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+	0.5 * (rs * rs + rp * rp)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_ggx_projected_area_is_one() {
+		// integral of D(h) cos(h) over the hemisphere with uniform samples
+		for ggx in [ Ggx::new( 0.3, 0.0 ), Ggx::new( 0.6, 0.0 ), Ggx::new( 0.5, 0.8 ) ].iter() {
+			let n = 400000;
+			let mut sum = 0.0;
+			for _ in 0..n {
+				let mut h = random_unit_vector();
+				h.z = h.z.abs();
+				sum += ggx.d( &h ) * h.z;
+			}
+			let integral = sum / n as f32 * 2.0 * PI;
+			assert!( (integral - 1.0).abs() < 0.05, "{:?} {}", ggx, integral );
+		}
+	}
+
+	#[test]
+	fn test_visible_normals_match_their_pdf() {
+		// integral of D_wo(h) over the hemisphere is one, estimated with samples of D_wo
+		let ggx = Ggx::new( 0.5, 0.5 );
+		let wo = Vec3::new( 0.6, 0.2, 0.5 ).normalized();
+		let n = 400000;
+		let mut sum = 0.0;
+		for _ in 0..n {
+			let mut h = random_unit_vector();
+			h.z = h.z.abs();
+			sum += ggx.pdf_h( &wo, &h );
+		}
+		assert!( (sum / n as f32 * 2.0 * PI - 1.0).abs() < 0.05 );
+
+		// samples land where the pdf says, compare the mean of h.z with the integral
+		let mut mean = 0.0;
+		let mut expected = 0.0;
+		for _ in 0..n {
+			mean += ggx.sample_h( &wo ).z;
+			let mut h = random_unit_vector();
+			h.z = h.z.abs();
+			expected += h.z * ggx.pdf_h( &wo, &h ) * 2.0 * PI;
+		}
+		assert!( ((mean - expected) / n as f32).abs() < 0.02 );
+	}
+
+	#[test]
+	fn test_dielectric_fresnel() {
+		assert!( (fresnel_dielectric( 1.0, 1.5 ) - 0.04).abs() < 0.001 );
+		assert!( (fresnel_dielectric( 0.0001, 1.5 ) - 1.0).abs() < 0.01 );
+		// beyond the critical angle inside glass everything is reflected
+		assert_eq!( fresnel_dielectric( 0.5, 1.0 / 1.5 ), 1.0 );
+		assert!( fresnel_dielectric( 0.9, 1.0 / 1.5 ) < 0.1 );
+	}
+}
@@ -0,0 +1,402 @@
+use crate::hitable::*;
+use crate::color::*;
+use crate::random::*;
+use crate::camera::*;
+use crate::integrator::*;
+
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::f32::consts::PI;
+
+// Primary sample space Metropolis light transport (Kelemen et al.). The path
+// tracer is run with a stream of random numbers which is mutated from one
+// sample to the next; the first two numbers of the stream pick the pixel.
+
+#[derive( Copy, Clone )]
+struct PrimarySample {
+	value : f32,
+	last_modified : u64,
+	value_backup : f32,
+	modify_backup : u64
+}
+
+pub struct MltSampler {
+	rng : Lcg,
+	samples : Vec<PrimarySample>,
+	index : usize,
+	iteration : u64,
+	last_large_step : u64,
+	large_step : bool,
+	sigma : f32,
+	large_step_probability : f32
+}
+
+impl MltSampler {
+
+	pub fn new( seed : u32, sigma : f32, large_step_probability : f32 ) -> MltSampler {
+		MltSampler {
+			rng : Lcg::new( seed ),
+			samples : Vec::new(),
+			index : 0,
+			iteration : 0,
+			last_large_step : 0,
+			large_step : true,
+			sigma,
+			large_step_probability
+		}
+	}
+
+	fn normal( &mut self ) -> f32 {
+		let u1 = self.rng.next().max( 1e-7 );
+		let u2 = self.rng.next();
+		(-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos()
+	}
+
+	fn ensure_ready( &mut self, i : usize ) {
+		// dimensions used for the first time start from a uniform value,
+		// repeated rejection sampling in the path tracer never ends otherwise
+		while self.samples.len() <= i {
+			let value = self.rng.next();
+			let last_modified = self.last_large_step;
+			self.samples.push( PrimarySample { value, last_modified, value_backup : value, modify_backup : last_modified } );
+		}
+
+		// catch up with a large step the sample missed
+		if self.samples[i].last_modified < self.last_large_step {
+			self.samples[i].value = self.rng.next();
+			self.samples[i].last_modified = self.last_large_step;
+		}
+
+		let mut x = self.samples[i];
+		x.value_backup = x.value;
+		x.modify_backup = x.last_modified;
+		if self.large_step {
+			x.value = self.rng.next();
+		} else {
+			// all the small steps since the last use collapse into one
+			let steps = self.iteration.saturating_sub( x.last_modified ) as f32;
+			x.value += self.normal() * self.sigma * steps.sqrt();
+			x.value -= x.value.floor();
+		}
+		x.last_modified = self.iteration;
+		self.samples[i] = x;
+	}
+
+	pub fn start_iteration( &mut self ) {
+		self.iteration += 1;
+		self.large_step = self.rng.next() < self.large_step_probability;
+	}
+
+	pub fn start_stream( &mut self ) {
+		self.index = 0;
+	}
+
+	pub fn accept( &mut self ) {
+		if self.large_step {
+			self.last_large_step = self.iteration;
+		}
+	}
+
+	pub fn reject( &mut self ) {
+		for x in self.samples.iter_mut() {
+			if x.last_modified == self.iteration {
+				x.value = x.value_backup;
+				x.last_modified = x.modify_backup;
+			}
+		}
+		self.iteration -= 1;
+	}
+}
+
+impl RandomStream for MltSampler {
+	fn next( &mut self ) -> f32 {
+		let i = self.index;
+		self.index += 1;
+		self.ensure_ready( i );
+		self.samples[i].value
+	}
+}
+
+// Lets the sampler be installed as the stream of `random` while the chain keeps it
+struct SharedSampler( Rc<RefCell<MltSampler>> );
+
+impl RandomStream for SharedSampler {
+	fn next( &mut self ) -> f32 {
+		self.0.borrow_mut().next()
+	}
+}
+
+struct Chain {
+	sampler : Rc<RefCell<MltSampler>>,
+	pixel : usize,
+	colour : Color,
+	luminance : f32
+}
+
+pub struct Metropolis {
+	width : usize,
+	height : usize,
+	film : Vec<Color>,
+	lights : Vec<usize>,
+	chains : Vec<Chain>,
+	next_chain : usize,
+	mutations : u64,
+	b : f32
+}
+
+impl Metropolis {
+
+	// Traces one path with the numbers of `sampler`, returns its pixel and colour
+	fn evaluate( sampler : &Rc<RefCell<MltSampler>>, camera : &Camera, objects : &Vec<Box<Hitable>>, lights : &Vec<usize>,
+	             settings : &RenderSettings, width : usize, height : usize ) -> (usize, Color)
+	{
+		sampler.borrow_mut().start_stream();
+		let previous = set_stream( Box::new( SharedSampler( sampler.clone() ) ) );
+
+		let x = random() * width as f32;
+		let y = random() * height as f32;
+		let u = (x - (width / 2) as f32) / width as f32;
+		let v = ((height / 2) as f32 - y) / height as f32;
+		let colour = find_colour( &camera.get_ray( u, v ), objects, lights, 0, settings );
+
+		set_stream( previous );
+
+		let pixel = (y as usize).min(height - 1) * width + (x as usize).min(width - 1);
+		if colour.r.is_finite() && colour.g.is_finite() && colour.b.is_finite() {
+			(pixel, colour)
+		} else {
+			(pixel, Color::new(0.0,0.0,0.0))
+		}
+	}
+
+	// Estimates the normalization constant from `settings.bootstrap_samples` independent
+	// paths and starts `settings.chains` chains from paths picked in proportion to their brightness
+	pub fn new( camera : &Camera, objects : &Vec<Box<Hitable>>, settings : &RenderSettings,
+	            width : usize, height : usize ) -> Metropolis
+	{
+		let sigma = settings.mutation_size;
+		let p_large = settings.large_step_probability;
+		let lights = find_lights( objects );
+
+		let mut cdf = Vec::with_capacity( settings.bootstrap_samples );
+		let mut sum = 0.0_f64;
+		for i in 0..settings.bootstrap_samples {
+			let sampler = Rc::new( RefCell::new( MltSampler::new( i as u32, sigma, p_large ) ) );
+			let (_, colour) = Metropolis::evaluate( &sampler, camera, objects, &lights, settings, width, height );
+			sum += colour.luminance() as f64;
+			cdf.push( sum );
+		}
+
+		let b = if cdf.is_empty() { 0.0 } else { (sum / cdf.len() as f64) as f32 };
+
+		let mut chains = Vec::new();
+		if sum > 0.0 {
+			for i in 0..settings.chains {
+				// stratified pick of a bootstrap path
+				let target = (i as f64 + 0.5) / settings.chains as f64 * sum;
+				let seed = cdf.partition_point(|c| *c <= target).min( cdf.len() - 1 );
+
+				let sampler = Rc::new( RefCell::new( MltSampler::new( seed as u32, sigma, p_large ) ) );
+				let (pixel, colour) = Metropolis::evaluate( &sampler, camera, objects, &lights, settings, width, height );
+				let luminance = colour.luminance();
+				chains.push( Chain { sampler, pixel, colour, luminance } );
+			}
+		}
+
+		Metropolis {
+			width,
+			height,
+			film : vec![Color::new(0.0,0.0,0.0); width * height],
+			lights,
+			chains,
+			next_chain : 0,
+			mutations : 0,
+			b
+		}
+	}
+
+	// Mutates the chains in turn, `count` mutations in total
+	pub fn run( &mut self, camera : &Camera, objects : &Vec<Box<Hitable>>, settings : &RenderSettings, count : u64 )
+	{
+		if self.chains.is_empty() {
+			self.mutations += count;
+			return;
+		}
+
+		for _ in 0..count {
+			let n = self.chains.len();
+			let chain = &mut self.chains[self.next_chain];
+			self.next_chain = (self.next_chain + 1) % n;
+
+			chain.sampler.borrow_mut().start_iteration();
+			let (pixel, colour) = Metropolis::evaluate( &chain.sampler, camera, objects, &self.lights, settings, self.width, self.height );
+			let luminance = colour.luminance();
+
+			let accept = if chain.luminance > 0.0 { (luminance / chain.luminance).min(1.0) } else { 1.0 };
+
+			if luminance > 0.0 {
+				self.film[pixel] = &self.film[pixel] + colour * (accept / luminance);
+			}
+			if chain.luminance > 0.0 {
+				self.film[chain.pixel] = &self.film[chain.pixel] + chain.colour * ((1.0 - accept) / chain.luminance);
+			}
+
+			if random() < accept {
+				chain.pixel = pixel;
+				chain.colour = colour;
+				chain.luminance = luminance;
+				chain.sampler.borrow_mut().accept();
+			} else {
+				chain.sampler.borrow_mut().reject();
+			}
+		}
+		self.mutations += count;
+	}
+
+	pub fn pixel( &self, x : usize, y : usize ) -> Color {
+		if self.mutations == 0 {
+			return Color::new(0.0,0.0,0.0);
+		}
+		let per_pixel = self.mutations as f32 / (self.width * self.height) as f32;
+		&self.film[y * self.width + x] * (self.b / per_pixel)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::vec_math::*;
+	use crate::material::*;
+	use crate::texture::*;
+	use crate::shapes::*;
+
+	fn values( sampler : &MltSampler ) -> Vec<f32> {
+		sampler.samples.iter().map(|x| x.value).collect()
+	}
+
+	// distance between two points of the unit circle the sample values live on
+	fn wrapped( a : f32, b : f32 ) -> f32 {
+		let d = (a - b).abs();
+		d.min( 1.0 - d )
+	}
+
+	#[test]
+	fn test_mutations_and_rejection() {
+		let mut sampler = MltSampler::new( 7, 0.01, 0.0 );
+		sampler.start_stream();
+		for _ in 0..8 {
+			sampler.next();
+		}
+		let first = values( &sampler );
+
+		// small steps move every value a little and rejecting them undoes that
+		sampler.start_iteration();
+		sampler.start_stream();
+		for _ in 0..8 {
+			sampler.next();
+		}
+		let moved = values( &sampler );
+		assert!( first.iter().zip( moved.iter() ).all(|(a, b)| a != b && wrapped( *a, *b ) < 0.1) );
+		sampler.reject();
+		assert_eq!( values( &sampler ), first );
+
+		// accepted steps stay, unused dimensions catch up later
+		sampler.start_iteration();
+		sampler.start_stream();
+		for _ in 0..4 {
+			sampler.next();
+		}
+		sampler.accept();
+		let kept = values( &sampler );
+		assert!( kept[..4] != first[..4] && kept[4..] == first[4..] );
+
+		// large steps pick new values anywhere, the last used dimensions too
+		sampler.large_step_probability = 1.0;
+		let mut jumps = 0.0;
+		let mut mean = 0.0;
+		let n = 2000;
+		for _ in 0..n {
+			let before = values( &sampler );
+			sampler.start_iteration();
+			sampler.start_stream();
+			for _ in 0..8 {
+				sampler.next();
+			}
+			sampler.accept();
+			jumps += wrapped( before[7], sampler.samples[7].value );
+			mean += sampler.samples[7].value;
+		}
+		assert!( (jumps / n as f32 - 0.25).abs() < 0.03 && (mean / n as f32 - 0.5).abs() < 0.03 );
+	}
+
+	#[test]
+	fn test_stream_is_swapped() {
+		let sampler = Rc::new( RefCell::new( MltSampler::new( 3, 0.01, 0.3 ) ) );
+		let previous = set_stream( Box::new( SharedSampler( sampler.clone() ) ) );
+		let drawn = vec![ random(), random(), random() ];
+		let mut installed = set_stream( Box::new( Lcg::new( 5 ) ) );
+		assert_eq!( drawn, values( &sampler.borrow() ) );
+
+		// the stream handed back is the one which was installed
+		let mut reference = Lcg::new( 5 );
+		assert_eq!( random(), reference.next() );
+		assert_eq!( installed.next(), sampler.borrow().samples[3].value );
+		set_stream( previous );
+	}
+
+	#[test]
+	fn test_bootstrap_normalisation() {
+		// a wall of light filling the view, bright and dark squares of 0.2 and 1
+		let squares = Rc::new( ChessTexture { color_a : Color::new( 0.2, 0.2, 0.2 ), color_b : Color::new( 1.0, 1.0, 1.0 ), scale : 1.0 } );
+		let wall = Quad {
+			corner : Vec3::new( -5.0, -5.0, 2.25 ),
+			edge_u : Vec3::new( 0.0, 10.0, 0.0 ),
+			edge_v : Vec3::new( 10.0, 0.0, 0.0 ),
+			material : Box::new( BlackBody { radiation : squares, spectrum : None } )
+		};
+		let objects : Vec<Box<Hitable>> = vec![ Box::new( wall ) ];
+		let camera = Camera {
+			origin : Vec3::zero(),
+			low_left_corner : Vec3::new( 0.0, 0.0, 1.0 ),
+			vertical : Vec3::new( 0.0, 1.0, 0.0 ),
+			horizontal : Vec3::new( 1.0, 0.0, 0.0 ),
+			shutter_open : 0.0,
+			shutter_close : 0.0,
+			spread : 0.0
+		};
+		let settings = RenderSettings { bootstrap_samples : 4000, chains : 64, ..RenderSettings::new() };
+		let (width, height) = (16, 12);
+
+		let n = 20000;
+		let lights = find_lights( &objects );
+		let mean = (0..n).map(|_| {
+			let ray = camera.get_ray( random() - 0.5, random() - 0.5 );
+			find_colour( &ray, &objects, &lights, 0, &settings ).luminance()
+		}).sum::<f32>() / n as f32;
+
+		let mut mlt = Metropolis::new( &camera, &objects, &settings, width, height );
+		assert!( (mlt.b - mean).abs() < 0.03, "{} {}", mlt.b, mean );
+		mlt.run( &camera, &objects, &settings, 100000 );
+
+		// pixels away from the edges of the squares converge to their brightness
+		let mut bright = (0.0, 0);
+		let mut dark = (0.0, 0);
+		for y in 0..height {
+			for x in 0..width {
+				let u = (x as f32 + 0.5 - (width / 2) as f32) / width as f32;
+				let v = ((height / 2) as f32 - y as f32 - 0.5) / height as f32;
+				let at = |du : f32, dv : f32| find_colour( &camera.get_ray( u + du, v + dv ), &objects, &lights, 0, &settings ).luminance();
+				let (du, dv) = (0.5 / width as f32, 0.5 / height as f32);
+				let corners = [ at( -du, -dv ), at( du, -dv ), at( -du, dv ), at( du, dv ) ];
+				if corners.iter().any(|c| (c - corners[0]).abs() > 1e-3) {
+					continue;
+				}
+				let group = if corners[0] > 0.5 { &mut bright } else { &mut dark };
+				group.0 += mlt.pixel( x, y ).luminance();
+				group.1 += 1;
+			}
+		}
+		let (bright, dark) = (bright.0 / bright.1 as f32, dark.0 / dark.1 as f32);
+		assert!( (bright - 1.0).abs() < 0.1 && (dark - 0.2).abs() < 0.03, "{} {}", bright, dark );
+	}
+}
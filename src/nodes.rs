@@ -0,0 +1,496 @@
+use crate::color::*;
+use crate::texture::*;
+use crate::image::*;
+use crate::noise::*;
+
+use std::rc::Rc;
+
+// Texture nodes which combine other textures into a graph. Every node holds
+// its inputs as `Rc<Texture>`, so a graph can share a subtree between several
+// nodes and materials. Graphs are either put together in code or described
+// with a `TextureDesc`, which can also be parsed from text.
+
+// `a` where the mask is black, `b` where it's white, by the luminance of the mask
+#[allow(dead_code)]
+pub struct MixTexture {
+	pub a : Rc<Texture>,
+	pub b : Rc<Texture>,
+	pub mask : Rc<Texture>
+}
+
+impl Texture for MixTexture {
+	fn value( &self, at : &TexCoord ) -> Color {
+		let t = self.mask.value( at ).luminance().max(0.0).min(1.0);
+		self.a.value( at ) * (1.0 - t) + self.b.value( at ) * t
+	}
+}
+
+#[allow(dead_code)]
+pub struct AddTexture {
+	pub a : Rc<Texture>,
+	pub b : Rc<Texture>
+}
+
+impl Texture for AddTexture {
+	fn value( &self, at : &TexCoord ) -> Color {
+		self.a.value( at ) + self.b.value( at )
+	}
+}
+
+#[allow(dead_code)]
+pub struct MultiplyTexture {
+	pub a : Rc<Texture>,
+	pub b : Rc<Texture>
+}
+
+impl Texture for MultiplyTexture {
+	fn value( &self, at : &TexCoord ) -> Color {
+		self.a.value( at ) * self.b.value( at )
+	}
+}
+
+// Colour ramp over the luminance of `input`. The stops are sorted by
+// position, the ramp is flat before the first and after the last.
+#[allow(dead_code)]
+pub struct RampTexture {
+	pub input : Rc<Texture>,
+	pub stops : Vec<(f32, Color)>
+}
+
+#[allow(dead_code)]
+impl RampTexture {
+	pub fn new( input : Rc<Texture>, mut stops : Vec<(f32, Color)> ) -> RampTexture {
+		stops.sort_by(|a, b| a.0.partial_cmp( &b.0 ).unwrap_or( std::cmp::Ordering::Equal ));
+		RampTexture { input, stops }
+	}
+}
+
+impl Texture for RampTexture {
+	fn value( &self, at : &TexCoord ) -> Color {
+		let t = self.input.value( at ).luminance();
+		let stops = &self.stops;
+		if stops.is_empty() {
+			return Color::new( t, t, t );
+		}
+		if t <= stops[0].0 {
+			return stops[0].1;
+		}
+		for pair in stops.windows(2) {
+			let ((t0, c0), (t1, c1)) = (pair[0], pair[1]);
+			if t <= t1 {
+				let s = if t1 > t0 { (t - t0) / (t1 - t0) } else { 1.0 };
+				return c0 * (1.0 - s) + c1 * s;
+			}
+		}
+		stops[stops.len() - 1].1
+	}
+}
+
+// Scales, then rotates around the origin, then offsets u, v before the lookup
+#[allow(dead_code)]
+pub struct TransformTexture {
+	pub texture : Rc<Texture>,
+	pub scale : (f32, f32),
+	// degrees, counterclockwise
+	pub rotation : f32,
+	pub offset : (f32, f32)
+}
+
+impl Texture for TransformTexture {
+	fn value( &self, at : &TexCoord ) -> Color {
+		let (s, c) = self.rotation.to_radians().sin_cos();
+		let u = at.u * self.scale.0;
+		let v = at.v * self.scale.1;
+		let moved = TexCoord {
+			u : c * u - s * v + self.offset.0,
+			v : s * u + c * v + self.offset.1,
+			pos : at.pos.clone(),
+			normal : at.normal.clone(),
+			width : at.width,
+			uv_scale : at.uv_scale * self.scale.0.abs().max( self.scale.1.abs() )
+		};
+		self.texture.value( &moved )
+	}
+}
+
+// Projects a mapped texture along the three axes and blends the projections by
+// the normal, for surfaces without usable u, v
+#[allow(dead_code)]
+pub struct TriplanarTexture {
+	pub texture : Rc<Texture>,
+	// repeats per unit of world space
+	pub scale : f32,
+	// higher values give narrower transitions between the projections
+	pub sharpness : f32
+}
+
+impl Texture for TriplanarTexture {
+	fn value( &self, at : &TexCoord ) -> Color {
+		let n = &at.normal;
+		let w = [ n.x.abs().powf( self.sharpness ), n.y.abs().powf( self.sharpness ), n.z.abs().powf( self.sharpness ) ];
+		let total = w[0] + w[1] + w[2];
+		if total <= 0.0 {
+			return Color::new( 0.0, 0.0, 0.0 );
+		}
+
+		let p = self.scale * &at.pos;
+		let planes = [ (p.z, p.y), (p.x, p.z), (p.x, p.y) ];
+		let mut sum = Color::new( 0.0, 0.0, 0.0 );
+		for axis in 0..3 {
+			if w[axis] > 0.0 {
+				let (u, v) = planes[axis];
+				let projected = TexCoord { u, v, pos : at.pos.clone(), normal : at.normal.clone(), width : at.width, uv_scale : self.scale };
+				sum = sum + self.texture.value( &projected ) * (w[axis] / total);
+			}
+		}
+		sum
+	}
+}
+
+// Declarative description of a texture graph. Procedural leaves are grey from
+// black to white, colour them with a `Ramp`.
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub enum TextureDesc {
+	Constant( Color ),
+	Chess { a : Color, b : Color, scale : f32 },
+	Image { path : String, space : ColourSpace },
+	Noise { seed : u32, scale : f32 },
+	Turbulence { seed : u32, scale : f32 },
+	Marble { seed : u32, scale : f32 },
+	Wood { seed : u32, scale : f32 },
+	Cells { seed : u32, scale : f32 },
+	Mix( Box<TextureDesc>, Box<TextureDesc>, Box<TextureDesc> ),
+	Add( Box<TextureDesc>, Box<TextureDesc> ),
+	Multiply( Box<TextureDesc>, Box<TextureDesc> ),
+	Ramp( Box<TextureDesc>, Vec<(f32, Color)> ),
+	Transform { texture : Box<TextureDesc>, scale : (f32, f32), rotation : f32, offset : (f32, f32) },
+	Triplanar { texture : Box<TextureDesc>, scale : f32, sharpness : f32 }
+}
+
+fn grey( v : f32 ) -> Color {
+	Color::new( v, v, v )
+}
+
+#[allow(dead_code)]
+impl TextureDesc {
+	// Builds the graph, image files are loaded here
+	pub fn build( &self ) -> Result<Rc<Texture>, String> {
+		let black = grey( 0.0 );
+		let white = grey( 1.0 );
+		let texture : Rc<Texture> = match self {
+			TextureDesc::Constant( color ) => Rc::new( ConstantTexture { color : *color } ),
+			TextureDesc::Chess { a, b, scale } => Rc::new( ChessTexture { color_a : *a, color_b : *b, scale : *scale } ),
+			TextureDesc::Image { path, space } => Rc::new( ImageTexture::load( path, *space, Wrap::Repeat, Filter::Trilinear )? ),
+			TextureDesc::Noise { seed, scale } | TextureDesc::Turbulence { seed, scale } => Rc::new( NoiseTexture {
+				noise : Perlin::new( *seed ), fractal : Fractal::new(), scale : *scale,
+				turbulence : match self { TextureDesc::Turbulence { .. } => true, _ => false },
+				color_a : black, color_b : white
+			} ),
+			TextureDesc::Marble { seed, scale } => Rc::new( MarbleTexture {
+				noise : Perlin::new( *seed ), fractal : Fractal::new(), scale : *scale, distortion : 4.0, vein : black, base : white
+			} ),
+			TextureDesc::Wood { seed, scale } => Rc::new( WoodTexture {
+				noise : Perlin::new( *seed ), fractal : Fractal::new(), scale : *scale, distortion : 0.5, early : white, late : black
+			} ),
+			TextureDesc::Cells { seed, scale } => Rc::new( CellularTexture {
+				worley : Worley::new( *seed ), scale : *scale, pattern : CellPattern::F2MinusF1, color_a : black, color_b : white
+			} ),
+			TextureDesc::Mix( a, b, mask ) => Rc::new( MixTexture { a : a.build()?, b : b.build()?, mask : mask.build()? } ),
+			TextureDesc::Add( a, b ) => Rc::new( AddTexture { a : a.build()?, b : b.build()? } ),
+			TextureDesc::Multiply( a, b ) => Rc::new( MultiplyTexture { a : a.build()?, b : b.build()? } ),
+			TextureDesc::Ramp( input, stops ) => Rc::new( RampTexture::new( input.build()?, stops.clone() ) ),
+			TextureDesc::Transform { texture, scale, rotation, offset } => Rc::new( TransformTexture {
+				texture : texture.build()?, scale : *scale, rotation : *rotation, offset : *offset
+			} ),
+			TextureDesc::Triplanar { texture, scale, sharpness } => Rc::new( TriplanarTexture {
+				texture : texture.build()?, scale : *scale, sharpness : *sharpness
+			} )
+		};
+		Ok( texture )
+	}
+
+	// Reads a description from s-expressions:
+	//
+	//   0.5 or (rgb r g b)                      constant, also wherever a colour goes
+	//   (chess a b scale)
+	//   (image "path") or (image "path" linear) 8 bit images are sRGB by default
+	//   (noise seed scale), also turbulence, marble, wood and cells
+	//   (mix a b mask) (add a b) (multiply a b)
+	//   (ramp input (position colour) ...)
+	//   (transform texture (scale su sv) (rotate degrees) (offset du dv))
+	//   (triplanar texture scale sharpness)
+	pub fn parse( text : &str ) -> Result<TextureDesc, String> {
+		let mut tokens = tokenize( text )?.into_iter().peekable();
+		let sexp = read( &mut tokens )?;
+		if let Some(extra) = tokens.next() {
+			return Err( format!( "unexpected '{}' after the texture", extra.text() ) );
+		}
+		TextureDesc::from_sexp( &sexp )
+	}
+
+	fn from_sexp( sexp : &Sexp ) -> Result<TextureDesc, String> {
+		let list = match sexp {
+			Sexp::List( list ) => list,
+			_ => return Ok( TextureDesc::Constant( colour( sexp )? ) )
+		};
+		let (head, args) = match list.split_first() {
+			Some( (Sexp::Atom( head ), args) ) => (head.as_str(), args),
+			_ => return Err( "expected a node name".to_string() )
+		};
+		let count = |n : usize| if args.len() == n { Ok(()) } else { Err( format!( "'{}' takes {} arguments", head, n ) ) };
+		let node = |i : usize| TextureDesc::from_sexp( &args[i] ).map( Box::new );
+
+		match head {
+			"rgb" => Ok( TextureDesc::Constant( colour( sexp )? ) ),
+			"chess" => {
+				count(3)?;
+				Ok( TextureDesc::Chess { a : colour( &args[0] )?, b : colour( &args[1] )?, scale : number( &args[2] )? } )
+			},
+			"image" => {
+				let space = match args.get(1) {
+					None => ColourSpace::Srgb,
+					Some( Sexp::Atom( s ) ) if s == "srgb" => ColourSpace::Srgb,
+					Some( Sexp::Atom( s ) ) if s == "linear" => ColourSpace::Linear,
+					_ => return Err( "image space is srgb or linear".to_string() )
+				};
+				match args.get(0) {
+					Some( Sexp::Str( path ) ) if args.len() <= 2 => Ok( TextureDesc::Image { path : path.clone(), space } ),
+					_ => Err( "image takes a quoted path".to_string() )
+				}
+			},
+			"noise" | "turbulence" | "marble" | "wood" | "cells" => {
+				count(2)?;
+				let seed = number( &args[0] )? as u32;
+				let scale = number( &args[1] )?;
+				Ok( match head {
+					"noise" => TextureDesc::Noise { seed, scale },
+					"turbulence" => TextureDesc::Turbulence { seed, scale },
+					"marble" => TextureDesc::Marble { seed, scale },
+					"wood" => TextureDesc::Wood { seed, scale },
+					_ => TextureDesc::Cells { seed, scale }
+				} )
+			},
+			"mix" => { count(3)?; Ok( TextureDesc::Mix( node(0)?, node(1)?, node(2)? ) ) },
+			"add" => { count(2)?; Ok( TextureDesc::Add( node(0)?, node(1)? ) ) },
+			"multiply" => { count(2)?; Ok( TextureDesc::Multiply( node(0)?, node(1)? ) ) },
+			"ramp" => {
+				if args.is_empty() {
+					return Err( "ramp needs an input".to_string() );
+				}
+				let mut stops = Vec::new();
+				for stop in &args[1..] {
+					match stop {
+						Sexp::List( pair ) if pair.len() == 2 => stops.push( (number( &pair[0] )?, colour( &pair[1] )?) ),
+						_ => return Err( "ramp stops are (position colour)".to_string() )
+					}
+				}
+				Ok( TextureDesc::Ramp( node(0)?, stops ) )
+			},
+			"transform" => {
+				if args.is_empty() {
+					return Err( "transform needs a texture".to_string() );
+				}
+				let (mut scale, mut rotation, mut offset) = ((1.0, 1.0), 0.0, (0.0, 0.0));
+				for clause in &args[1..] {
+					let usage = "transform clauses are (scale su sv), (rotate degrees) and (offset du dv)";
+					let (name, rest) = match clause {
+						Sexp::List( parts ) => match parts.split_first() {
+							Some( (Sexp::Atom( name ), rest) ) => (name.as_str(), rest),
+							_ => return Err( usage.to_string() )
+						},
+						_ => return Err( "transform clauses are lists".to_string() )
+					};
+					let values = rest.iter().map( number ).collect::<Result<Vec<f32>, String>>()?;
+					match (name, values.len()) {
+						("scale", 1) => scale = (values[0], values[0]),
+						("scale", 2) => scale = (values[0], values[1]),
+						("rotate", 1) => rotation = values[0],
+						("offset", 2) => offset = (values[0], values[1]),
+						_ => return Err( usage.to_string() )
+					}
+				}
+				Ok( TextureDesc::Transform { texture : node(0)?, scale, rotation, offset } )
+			},
+			"triplanar" => {
+				count(3)?;
+				Ok( TextureDesc::Triplanar { texture : node(0)?, scale : number( &args[1] )?, sharpness : number( &args[2] )? } )
+			},
+			_ => Err( format!( "unknown texture node '{}'", head ) )
+		}
+	}
+}
+
+enum Token {
+	Open,
+	Close,
+	Atom( String ),
+	Str( String )
+}
+
+impl Token {
+	fn text( &self ) -> String {
+		match self {
+			Token::Open => "(".to_string(),
+			Token::Close => ")".to_string(),
+			Token::Atom( s ) => s.clone(),
+			Token::Str( s ) => format!( "\"{}\"", s )
+		}
+	}
+}
+
+enum Sexp {
+	Atom( String ),
+	Str( String ),
+	List( Vec<Sexp> )
+}
+
+fn tokenize( text : &str ) -> Result<Vec<Token>, String> {
+	let mut tokens = Vec::new();
+	let mut chars = text.chars().peekable();
+	while let Some(c) = chars.next() {
+		match c {
+			'(' => tokens.push( Token::Open ),
+			')' => tokens.push( Token::Close ),
+			'"' => {
+				let mut s = String::new();
+				loop {
+					match chars.next() {
+						Some('"') => break,
+						Some(c) => s.push( c ),
+						None => return Err( "unterminated string".to_string() )
+					}
+				}
+				tokens.push( Token::Str( s ) );
+			},
+			// comments run to the end of the line
+			';' => while chars.peek().map_or( false, |c| *c != '\n' ) { chars.next(); },
+			c if c.is_whitespace() => {},
+			c => {
+				let mut s = c.to_string();
+				while let Some(&c) = chars.peek() {
+					if c.is_whitespace() || c == '(' || c == ')' || c == '"' {
+						break;
+					}
+					s.push( c );
+					chars.next();
+				}
+				tokens.push( Token::Atom( s ) );
+			}
+		}
+	}
+	Ok( tokens )
+}
+
+fn read<I : Iterator<Item = Token>>( tokens : &mut std::iter::Peekable<I> ) -> Result<Sexp, String> {
+	match tokens.next() {
+		Some( Token::Open ) => {
+			let mut list = Vec::new();
+			loop {
+				match tokens.peek() {
+					Some( Token::Close ) => { tokens.next(); return Ok( Sexp::List( list ) ); },
+					Some( _ ) => list.push( read( tokens )? ),
+					None => return Err( "missing ')'".to_string() )
+				}
+			}
+		},
+		Some( Token::Close ) => Err( "unexpected ')'".to_string() ),
+		Some( Token::Atom( s ) ) => Ok( Sexp::Atom( s ) ),
+		Some( Token::Str( s ) ) => Ok( Sexp::Str( s ) ),
+		None => Err( "empty texture description".to_string() )
+	}
+}
+
+fn number( sexp : &Sexp ) -> Result<f32, String> {
+	match sexp {
+		Sexp::Atom( s ) => s.parse::<f32>().map_err(|_| format!( "expected a number, found '{}'", s )),
+		_ => Err( "expected a number".to_string() )
+	}
+}
+
+// A grey level or (rgb r g b)
+fn colour( sexp : &Sexp ) -> Result<Color, String> {
+	match sexp {
+		Sexp::List( list ) if list.len() == 4 => match &list[0] {
+			Sexp::Atom( s ) if s == "rgb" => Ok( Color::new( number( &list[1] )?, number( &list[2] )?, number( &list[3] )? ) ),
+			_ => Err( "expected (rgb r g b)".to_string() )
+		},
+		Sexp::List( _ ) => Err( "expected (rgb r g b)".to_string() ),
+		_ => Ok( grey( number( sexp )? ) )
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::vec_math::*;
+
+	// Texture which shows its u, v as red and green
+	struct Uv;
+
+	impl Texture for Uv {
+		fn value( &self, at : &TexCoord ) -> Color {
+			Color::new( at.u, at.v, 0.0 )
+		}
+	}
+
+	fn close( c : Color, r : f32, g : f32, b : f32 ) -> bool {
+		(c.r - r).abs() < 1e-5 && (c.g - g).abs() < 1e-5 && (c.b - b).abs() < 1e-5
+	}
+
+	#[test]
+	fn test_nodes_combine_their_inputs() {
+		let at = TexCoord::uv( 0.25, 0.5 );
+		let red = constant( Color::new( 1.0, 0.0, 0.0 ) );
+		let blue = constant( Color::new( 0.0, 0.0, 1.0 ) );
+
+		let mix = MixTexture { a : red.clone(), b : blue.clone(), mask : constant( Color::new( 0.25, 0.25, 0.25 ) ) };
+		assert!( close( mix.value( &at ), 0.75, 0.0, 0.25 ) );
+		let sum = AddTexture { a : red.clone(), b : blue.clone() };
+		assert!( close( sum.value( &at ), 1.0, 0.0, 1.0 ) );
+		let product = MultiplyTexture { a : red.clone(), b : constant( Color::new( 0.5, 1.0, 1.0 ) ) };
+		assert!( close( product.value( &at ), 0.5, 0.0, 0.0 ) );
+
+		let ramp = RampTexture::new( constant( Color::new( 0.75, 0.75, 0.75 ) ), vec![ (1.0, Color::new( 0.0, 1.0, 0.0 )), (0.5, Color::new( 1.0, 0.0, 0.0 )) ] );
+		assert!( close( ramp.value( &at ), 0.5, 0.5, 0.0 ) );
+
+		// scale, then a quarter turn, then offset
+		let transform = TransformTexture { texture : Rc::new( Uv ), scale : (2.0, 2.0), rotation : 90.0, offset : (1.0, 0.0) };
+		assert!( close( transform.value( &at ), 0.0, 0.5, 0.0 ), "{:?}", transform.value( &at ) );
+
+		// a surface facing +y sees the x, z projection only
+		let triplanar = TriplanarTexture { texture : Rc::new( Uv ), scale : 2.0, sharpness : 4.0 };
+		let top = TexCoord { pos : Vec3::new( 0.1, 5.0, 0.2 ), normal : Vec3::new( 0.0, 1.0, 0.0 ), ..TexCoord::uv( 0.0, 0.0 ) };
+		assert!( close( triplanar.value( &top ), 0.2, 0.4, 0.0 ) );
+	}
+
+	#[test]
+	fn test_parse_and_build_description() {
+		let desc = TextureDesc::parse( "
+			; red and blue tiles, darkened by noise
+			(multiply
+				(mix (rgb 1 0 0) (rgb 0 0 1) (chess 0 1 4))
+				(ramp (noise 3 4) (0 0.5) (1 1)))" ).unwrap();
+		let texture = desc.build().unwrap();
+		let c = texture.value( &TexCoord::uv( 0.1, 0.3 ) );
+		assert!( c.g == 0.0 && c.r + c.b >= 0.5 && c.r + c.b <= 1.0, "{:?}", c );
+
+		match TextureDesc::parse( "(transform (image \"a.png\" linear) (scale 2) (rotate 45))" ).unwrap() {
+			TextureDesc::Transform { texture, scale, rotation, offset } => {
+				assert_eq!( (scale, rotation, offset), ((2.0, 2.0), 45.0, (0.0, 0.0)) );
+				match *texture {
+					TextureDesc::Image { path, space } => assert!( path == "a.png" && space == ColourSpace::Linear ),
+					other => panic!( "{:?}", other )
+				}
+			},
+			other => panic!( "{:?}", other )
+		}
+
+		assert!( TextureDesc::parse( "(mix 1 0)" ).is_err() );
+		assert!( TextureDesc::parse( "(ramp (wood 1 2) (0 (rgb 1 0)))" ).is_err() );
+		assert!( TextureDesc::parse( "(add 1 2))" ).is_err() );
+		assert!( TextureDesc::parse( "(transform 0.5 ())" ).is_err() );
+		assert!( TextureDesc::parse( "(transform 0.5 (2 2))" ).is_err() );
+		assert!( TextureDesc::parse( "(image \"/nonexistent.png\")" ).unwrap().build().is_err() );
+	}
+}
@@ -0,0 +1,335 @@
+use crate::vec_math::*;
+use crate::color::*;
+use crate::random::*;
+use crate::texture::*;
+
+// Solid procedural textures. Everything is driven by the position of the
+// lookup, so the patterns don't depend on how a surface is parametrised, and
+// every generator takes a seed so a scene can hold several unrelated copies.
+
+// Gradient noise (Perlin's improved noise) in about [-1, 1], zero on the
+// integer lattice
+pub struct Perlin {
+	perm : Vec<usize>
+}
+
+// Directions from the centre of a cube to its edges
+const GRADIENTS : [(f32, f32, f32); 16] = [
+	(1.0, 1.0, 0.0), (-1.0, 1.0, 0.0), (1.0, -1.0, 0.0), (-1.0, -1.0, 0.0),
+	(1.0, 0.0, 1.0), (-1.0, 0.0, 1.0), (1.0, 0.0, -1.0), (-1.0, 0.0, -1.0),
+	(0.0, 1.0, 1.0), (0.0, -1.0, 1.0), (0.0, 1.0, -1.0), (0.0, -1.0, -1.0),
+	(1.0, 1.0, 0.0), (-1.0, 1.0, 0.0), (0.0, -1.0, 1.0), (0.0, -1.0, -1.0)
+];
+
+fn fade( t : f32 ) -> f32 {
+	t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp( a : f32, b : f32, t : f32 ) -> f32 {
+	a + (b - a) * t
+}
+
+#[allow(dead_code)]
+impl Perlin {
+	pub fn new( seed : u32 ) -> Perlin {
+		// Fisher-Yates shuffle of 0..256, repeated so lookups never wrap
+		let mut rng = Lcg::new( seed );
+		let mut perm : Vec<usize> = (0..256).collect();
+		for i in (1..256).rev() {
+			let j = ((rng.next() * (i + 1) as f32) as usize).min( i );
+			perm.swap( i, j );
+		}
+		let copy = perm.clone();
+		perm.extend( copy );
+		Perlin { perm }
+	}
+
+	fn gradient( &self, hash : usize, x : f32, y : f32, z : f32 ) -> f32 {
+		let g = GRADIENTS[hash & 15];
+		g.0 * x + g.1 * y + g.2 * z
+	}
+
+	pub fn noise( &self, p : &Vec3 ) -> f32 {
+		let (fx, fy, fz) = (p.x.floor(), p.y.floor(), p.z.floor());
+		let (x, y, z) = (p.x - fx, p.y - fy, p.z - fz);
+		let xi = (fx as i64).rem_euclid( 256 ) as usize;
+		let yi = (fy as i64).rem_euclid( 256 ) as usize;
+		let zi = (fz as i64).rem_euclid( 256 ) as usize;
+		let (u, v, w) = (fade( x ), fade( y ), fade( z ));
+
+		let perm = &self.perm;
+		let a = perm[xi] + yi;
+		let b = perm[xi + 1] + yi;
+		let (aa, ab) = (perm[a] + zi, perm[a + 1] + zi);
+		let (ba, bb) = (perm[b] + zi, perm[b + 1] + zi);
+
+		lerp(
+			lerp(
+				lerp( self.gradient( perm[aa], x, y, z ), self.gradient( perm[ba], x - 1.0, y, z ), u ),
+				lerp( self.gradient( perm[ab], x, y - 1.0, z ), self.gradient( perm[bb], x - 1.0, y - 1.0, z ), u ),
+				v ),
+			lerp(
+				lerp( self.gradient( perm[aa + 1], x, y, z - 1.0 ), self.gradient( perm[ba + 1], x - 1.0, y, z - 1.0 ), u ),
+				lerp( self.gradient( perm[ab + 1], x, y - 1.0, z - 1.0 ), self.gradient( perm[bb + 1], x - 1.0, y - 1.0, z - 1.0 ), u ),
+				v ),
+			w )
+	}
+
+	// Sum of octaves, each `lacunarity` times finer and `gain` times weaker
+	pub fn fbm( &self, p : &Vec3, fractal : &Fractal ) -> f32 {
+		fractal.sum( p, |q| self.noise( q ) )
+	}
+
+	// Like `fbm` with the absolute value of every octave, in about [0, 1]
+	pub fn turbulence( &self, p : &Vec3, fractal : &Fractal ) -> f32 {
+		fractal.sum( p, |q| self.noise( q ).abs() )
+	}
+}
+
+// How octaves of a noise are stacked
+#[allow(dead_code)]
+#[derive( Copy, Clone, Debug )]
+pub struct Fractal {
+	pub octaves : u32,
+	pub lacunarity : f32,
+	pub gain : f32
+}
+
+#[allow(dead_code)]
+impl Fractal {
+	pub fn new() -> Fractal {
+		Fractal { octaves : 5, lacunarity : 2.0, gain : 0.5 }
+	}
+
+	// Weighted sum of the octaves, divided by the sum of the weights
+	fn sum<F : Fn( &Vec3 ) -> f32>( &self, p : &Vec3, octave : F ) -> f32 {
+		let mut total = 0.0;
+		let mut norm = 0.0;
+		let mut frequency = 1.0;
+		let mut amplitude = 1.0;
+		for _ in 0..self.octaves.max(1) {
+			total += amplitude * octave( &(frequency * p) );
+			norm += amplitude;
+			frequency *= self.lacunarity;
+			amplitude *= self.gain;
+		}
+		total / norm
+	}
+}
+
+// Cellular noise: distances to the nearest and second nearest of a set of
+// points scattered one per unit cell
+#[allow(dead_code)]
+pub struct Worley {
+	seed : u32
+}
+
+#[allow(dead_code)]
+impl Worley {
+	pub fn new( seed : u32 ) -> Worley {
+		Worley { seed }
+	}
+
+	fn hash( &self, x : i64, y : i64, z : i64, k : u32 ) -> f32 {
+		let mut h = self.seed ^ k.wrapping_mul( 0x27d4eb2d );
+		for c in [ x, y, z ].iter() {
+			h = (h ^ (*c as u32)).wrapping_mul( 0x9e3779b1 );
+			h ^= h >> 15;
+		}
+		h = h.wrapping_mul( 0x85ebca6b );
+		h ^= h >> 13;
+		(h >> 8) as f32 / 16777216.0
+	}
+
+	fn feature( &self, x : i64, y : i64, z : i64 ) -> Vec3 {
+		Vec3::new(
+			x as f32 + self.hash( x, y, z, 0 ),
+			y as f32 + self.hash( x, y, z, 1 ),
+			z as f32 + self.hash( x, y, z, 2 ) )
+	}
+
+	// (F1, F2), the two smallest distances from `p` to a feature point
+	pub fn distances( &self, p : &Vec3 ) -> (f32, f32) {
+		let (cx, cy, cz) = (p.x.floor() as i64, p.y.floor() as i64, p.z.floor() as i64);
+		let mut f1 = std::f32::MAX;
+		let mut f2 = std::f32::MAX;
+		for dz in -1..2 {
+			for dy in -1..2 {
+				for dx in -1..2 {
+					let d = (&self.feature( cx + dx, cy + dy, cz + dz ) - p).length();
+					if d < f1 {
+						f2 = f1;
+						f1 = d;
+					} else if d < f2 {
+						f2 = d;
+					}
+				}
+			}
+		}
+		(f1, f2)
+	}
+}
+
+#[allow(dead_code)]
+fn mix( a : &Color, b : &Color, t : f32 ) -> Color {
+	let t = t.max(0.0).min(1.0);
+	a * (1.0 - t) + b * t
+}
+
+// Fractal noise or turbulence blended between two colours
+#[allow(dead_code)]
+pub struct NoiseTexture {
+	pub noise : Perlin,
+	pub fractal : Fractal,
+	// frequency of the first octave, features are about 1 / scale wide
+	pub scale : f32,
+	pub turbulence : bool,
+	pub color_a : Color,
+	pub color_b : Color
+}
+
+impl Texture for NoiseTexture {
+	fn value( &self, at : &TexCoord ) -> Color {
+		let p = self.scale * &at.pos;
+		let t = if self.turbulence {
+			self.noise.turbulence( &p, &self.fractal )
+		} else {
+			0.5 + 0.5 * self.noise.fbm( &p, &self.fractal )
+		};
+		mix( &self.color_a, &self.color_b, t )
+	}
+}
+
+// Veins along x, bent by turbulence
+#[allow(dead_code)]
+pub struct MarbleTexture {
+	pub noise : Perlin,
+	pub fractal : Fractal,
+	// veins per unit, over 2 pi
+	pub scale : f32,
+	pub distortion : f32,
+	pub vein : Color,
+	pub base : Color
+}
+
+impl Texture for MarbleTexture {
+	fn value( &self, at : &TexCoord ) -> Color {
+		let p = self.scale * &at.pos;
+		let phase = p.x + self.distortion * self.noise.turbulence( &p, &self.fractal );
+		let t = 0.5 + 0.5 * phase.sin();
+		// sharpen the veins a little
+		mix( &self.vein, &self.base, t.powf( 0.5 ) )
+	}
+}
+
+// Growth rings around the y axis, wobbled by noise
+#[allow(dead_code)]
+pub struct WoodTexture {
+	pub noise : Perlin,
+	pub fractal : Fractal,
+	// rings per unit
+	pub scale : f32,
+	pub distortion : f32,
+	pub early : Color,
+	pub late : Color
+}
+
+impl Texture for WoodTexture {
+	fn value( &self, at : &TexCoord ) -> Color {
+		let p = &at.pos;
+		let r = (p.x * p.x + p.z * p.z).sqrt() * self.scale;
+		let r = r + self.distortion * self.noise.fbm( &(self.scale * p), &self.fractal );
+		// light early wood grows slowly into the dark band at the end of a ring
+		let t = r - r.floor();
+		mix( &self.early, &self.late, t * t * t )
+	}
+}
+
+// Cells from Worley noise, `F2 - F1` gives bright borders and `F1` round blobs
+#[allow(dead_code)]
+#[derive( Copy, Clone, PartialEq, Debug )]
+pub enum CellPattern {
+	F1,
+	F2MinusF1
+}
+
+#[allow(dead_code)]
+pub struct CellularTexture {
+	pub worley : Worley,
+	// cells per unit
+	pub scale : f32,
+	pub pattern : CellPattern,
+	pub color_a : Color,
+	pub color_b : Color
+}
+
+impl Texture for CellularTexture {
+	fn value( &self, at : &TexCoord ) -> Color {
+		let (f1, f2) = self.worley.distances( &(self.scale * &at.pos) );
+		let t = match self.pattern {
+			CellPattern::F1 => f1,
+			CellPattern::F2MinusF1 => f2 - f1
+		};
+		mix( &self.color_a, &self.color_b, t )
+	}
+}
+
+// Speckled crystals: fractal Worley cells over a base colour
+#[allow(dead_code)]
+pub struct GraniteTexture {
+	pub worley : Worley,
+	pub fractal : Fractal,
+	pub scale : f32,
+	pub base : Color,
+	pub speckle : Color
+}
+
+impl Texture for GraniteTexture {
+	fn value( &self, at : &TexCoord ) -> Color {
+		let p = self.scale * &at.pos;
+		let t = self.fractal.sum( &p, |q| { let (f1, f2) = self.worley.distances( q ); f2 - f1 } );
+		// thin borders between the crystals are the dark grains
+		mix( &self.speckle, &self.base, (4.0 * t).min( 1.0 ) )
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_perlin_is_seeded_and_bounded() {
+		let a = Perlin::new( 1 );
+		let b = Perlin::new( 2 );
+		let p = Vec3::new( 0.3, 1.7, -2.2 );
+		assert_eq!( a.noise( &p ), Perlin::new( 1 ).noise( &p ) );
+		assert!( a.noise( &p ) != b.noise( &p ) );
+		assert_eq!( a.noise( &Vec3::new( 3.0, -1.0, 7.0 ) ), 0.0 );
+
+		let mut sum = 0.0;
+		for i in 0..1000 {
+			let q = Vec3::new( i as f32 * 0.137, i as f32 * 0.071, i as f32 * -0.053 );
+			let n = a.noise( &q );
+			assert!( n.abs() <= 1.1, "{}", n );
+			let t = a.turbulence( &q, &Fractal::new() );
+			assert!( t >= 0.0 && t <= 1.1, "{}", t );
+			sum += n;
+		}
+		assert!( (sum / 1000.0).abs() < 0.1, "{}", sum );
+	}
+
+	#[test]
+	fn test_worley_distances() {
+		let w = Worley::new( 7 );
+		for i in 0..200 {
+			let p = Vec3::new( i as f32 * 0.31, i as f32 * -0.17, i as f32 * 0.05 );
+			let (f1, f2) = w.distances( &p );
+			assert!( f1 <= f2 && f1 < 3.0f32.sqrt(), "{} {}", f1, f2 );
+		}
+
+		// a feature point is at distance zero from itself
+		let f = w.feature( 2, -3, 5 );
+		assert!( w.distances( &f ).0 < 1e-6 );
+		assert!( Worley::new( 8 ).distances( &f ).0 > 1e-6 );
+	}
+}
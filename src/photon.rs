@@ -0,0 +1,347 @@
+use crate::vec_math::*;
+use crate::hitable::*;
+use crate::color::*;
+use crate::integrator::*;
+
+use std::f32::consts::PI;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+// Caustic photon map. Photons leave the emitters, bounce off specular surfaces
+// and are stored where they land on a diffuse one. The path tracer gathers them
+// at every hit whose material has a diffuse part, and ignores emitters reached
+// through specular chains after sampling such a part, so caustics are only
+// counted once.
+
+pub struct Photon {
+	pub pos : Vec3,
+	pub dir : Vec3,
+	pub power : Color
+}
+
+// Photons are kept as an implicit balanced kd-tree: the median of every range
+// sits in its middle and splits the rest of the range along `axis`.
+pub struct PhotonMap {
+	photons : Vec<Photon>,
+	axis : Vec<u8>
+}
+
+struct Neighbour {
+	dist2 : f32,
+	index : usize
+}
+
+impl PartialEq for Neighbour {
+	fn eq( &self, other: &Neighbour ) -> bool { self.dist2 == other.dist2 }
+}
+
+impl Eq for Neighbour {}
+
+impl PartialOrd for Neighbour {
+	fn partial_cmp( &self, other: &Neighbour ) -> Option<Ordering> { Some( self.cmp(other) ) }
+}
+
+impl Ord for Neighbour {
+	fn cmp( &self, other: &Neighbour ) -> Ordering {
+		self.dist2.partial_cmp( &other.dist2 ).unwrap_or( Ordering::Equal )
+	}
+}
+
+fn coord( p : &Vec3, axis : u8 ) -> f32 {
+	match axis {
+		0 => p.x,
+		1 => p.y,
+		_ => p.z
+	}
+}
+
+#[derive( Copy, Clone, PartialEq )]
+enum PathState {
+	Camera,
+	Diffuse,
+	Caustic
+}
+
+impl PhotonMap {
+
+	pub fn new( photons : Vec<Photon> ) -> PhotonMap {
+		let n = photons.len();
+		let mut map = PhotonMap { photons, axis : vec![0; n] };
+		map.balance( 0, n );
+		map
+	}
+
+	// Emits `count` photons from the emitters of the scene and keeps the ones
+	// which reached a diffuse surface through at least one specular bounce
+	pub fn caustics( objects : &Vec<Box<Hitable>>, count : usize, max_depth : i32 ) -> PhotonMap {
+		let lights = find_lights( objects );
+		let mut photons = Vec::new();
+
+		for _ in 0..count {
+			// caustics are traced at time 0, they don't blur with motion
+			let (_, hit, out, pdf_pos, _) = match sample_emission( objects, &lights, 0.0 ) {
+				Some(s) => s,
+				None => continue
+			};
+
+			let le = hit.material.emit( &hit, &out );
+			let mut power = le * (PI / (pdf_pos * count as f32));
+			let mut ray = Ray::new( &hit.pos, &out );
+			let mut specular = false;
+
+			for _ in 0..max_depth {
+				let hit = match closest_hit( &ray, objects ) {
+					Some((_, h)) => h,
+					None => break
+				};
+				if hit.material.is_medium() {
+					break;
+				}
+				if specular && hit.material.is_diffuse( &hit ) {
+					photons.push( Photon { pos : hit.pos.clone(), dir : ray.direction.clone(), power : power.clone() } );
+				}
+				// specular lobes of mixed materials carry the photon on
+				let sample = match hit.material.sample( &hit ) {
+					Some(s) => s,
+					None => break
+				};
+				if !sample.specular {
+					break;
+				}
+
+				specular = true;
+				power = &power * &sample.weight;
+				if power.is_black() {
+					break;
+				}
+				ray = sample.ray;
+			}
+		}
+
+		PhotonMap::new( photons )
+	}
+
+	pub fn len( &self ) -> usize {
+		self.photons.len()
+	}
+
+	fn balance( &mut self, begin : usize, end : usize ) {
+		if end <= begin + 1 {
+			return;
+		}
+
+		let mut min = self.photons[begin].pos.clone();
+		let mut max = self.photons[begin].pos.clone();
+		for p in &self.photons[begin..end] {
+			min = Vec3::new( min.x.min(p.pos.x), min.y.min(p.pos.y), min.z.min(p.pos.z) );
+			max = Vec3::new( max.x.max(p.pos.x), max.y.max(p.pos.y), max.z.max(p.pos.z) );
+		}
+		let extent = &max - &min;
+		let axis = if extent.x > extent.y && extent.x > extent.z { 0 } else if extent.y > extent.z { 1 } else { 2 };
+
+		let mid = (begin + end) / 2;
+		self.photons[begin..end].select_nth_unstable_by( mid - begin, |a, b| {
+			coord( &a.pos, axis ).partial_cmp( &coord( &b.pos, axis ) ).unwrap_or( Ordering::Equal )
+		});
+		self.axis[mid] = axis;
+
+		self.balance( begin, mid );
+		self.balance( mid + 1, end );
+	}
+
+	fn search( &self, begin : usize, end : usize, pos : &Vec3, k : usize,
+	           found : &mut BinaryHeap<Neighbour>, max_dist2 : &mut f32 ) {
+		if end <= begin {
+			return;
+		}
+
+		let mid = (begin + end) / 2;
+		let photon = &self.photons[mid];
+		let axis = self.axis[mid];
+		let delta = coord( pos, axis ) - coord( &photon.pos, axis );
+
+		if delta < 0.0 {
+			self.search( begin, mid, pos, k, found, max_dist2 );
+		} else {
+			self.search( mid + 1, end, pos, k, found, max_dist2 );
+		}
+
+		let dist2 = (&photon.pos - pos).squre_length();
+		if dist2 < *max_dist2 {
+			found.push( Neighbour { dist2, index : mid } );
+			if found.len() > k {
+				found.pop();
+			}
+			if found.len() == k {
+				*max_dist2 = found.peek().unwrap().dist2;
+			}
+		}
+
+		if delta * delta < *max_dist2 {
+			if delta < 0.0 {
+				self.search( mid + 1, end, pos, k, found, max_dist2 );
+			} else {
+				self.search( begin, mid, pos, k, found, max_dist2 );
+			}
+		}
+	}
+
+	// Indices of the `k` photons closest to `pos` but not further than `max_dist`,
+	// together with the squared radius of the sphere which encloses them
+	pub fn nearest( &self, pos : &Vec3, k : usize, max_dist : f32 ) -> (Vec<usize>, f32) {
+		let mut found = BinaryHeap::with_capacity( k + 1 );
+		let mut max_dist2 = max_dist * max_dist;
+		self.search( 0, self.photons.len(), pos, k, &mut found, &mut max_dist2 );
+		let indices = found.into_iter().map(|n| n.index).collect();
+		(indices, max_dist2)
+	}
+
+	// Caustic radiance leaving the hit towards the origin of a ray travelling along `dir`
+	fn radiance( &self, hit : &Hit, dir : &Vec3, settings : &RenderSettings ) -> Color {
+		let (found, r2) = self.nearest( &hit.pos, settings.gather_count, settings.gather_radius );
+
+		let mut l = Color::new(0.0,0.0,0.0);
+		for i in found {
+			let photon = &self.photons[i];
+			if dot_product( &photon.dir, &hit.normal ) >= 0.0 {
+				continue;
+			}
+			let f = hit.material.eval( hit, &-&photon.dir, &-dir );
+			l = l + &f * &photon.power;
+		}
+		l * (1.0 / (PI * r2))
+	}
+
+	fn trace( &self, ray : &Ray, objects : &Vec<Box<Hitable>>, depth : i32,
+	          state : PathState, settings : &RenderSettings ) -> Color {
+		if depth > settings.max_depth {
+			return Color::new(0.0,0.0,0.0);
+		}
+
+		let hit = match closest_hit( ray, objects ) {
+			Some((_, hit)) => hit,
+			None => return background( &ray.get_direction() )
+		};
+
+		let emited = if state == PathState::Caustic && hit.material.is_emitter() {
+			Color::new(0.0,0.0,0.0)
+		} else {
+			hit.emitted()
+		};
+
+		let sample = match hit.material.sample( &hit ) {
+			Some(s) => s,
+			None => return emited
+		};
+
+		// the photons hold all the light reaching the diffuse lobes through
+		// specular chains, whichever lobe the path goes on with
+		let caustic = if !hit.material.is_medium() && hit.material.is_diffuse( &hit ) {
+			self.radiance( &hit, &ray.direction, settings )
+		} else {
+			Color::new(0.0,0.0,0.0)
+		};
+
+		let next = if hit.material.is_medium() {
+			PathState::Camera
+		} else if !sample.specular {
+			PathState::Diffuse
+		} else if state == PathState::Camera {
+			PathState::Camera
+		} else {
+			PathState::Caustic
+		};
+
+		let cn = self.trace( &sample.ray, objects, depth + 1, next, settings );
+		&cn * &sample.weight + emited + caustic
+	}
+
+	pub fn colour( &self, ray : &Ray, objects : &Vec<Box<Hitable>>, settings : &RenderSettings ) -> Color {
+		self.trace( ray, objects, 0, PathState::Camera, settings )
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::random::*;
+	use crate::material::*;
+	use crate::shapes::*;
+
+	// Lambertian under a clear coat which mirrors half the light
+	struct Coated {
+		albedo : Color
+	}
+
+	impl Material for Coated {
+		fn sample( &self, hit : &Hit ) -> Option<BsdfSample> {
+			if random() < 0.5 {
+				let dir = reflect( &hit.ray.direction, &hit.normal );
+				return Some( BsdfSample { ray : hit.ray.spawn( &hit.pos, &dir ), weight : Color::new( 1.0, 1.0, 1.0 ), pdf : 0.5, specular : true } );
+			}
+			let ray = hit.ray.spawn( &hit.pos, &(&hit.normal + random_unit_vector()) );
+			let pdf = 0.5 * dot_product( &ray.direction, &hit.normal ).max(0.0) / PI;
+			Some( BsdfSample { ray, weight : self.albedo.clone(), pdf, specular : false } )
+		}
+
+		fn eval( &self, _hit : &Hit, _wi : &Vec3, _wo : &Vec3 ) -> Color {
+			self.albedo * (0.5 / PI)
+		}
+
+		fn is_diffuse( &self, _hit : &Hit ) -> bool { true }
+	}
+
+	#[test]
+	fn test_nearest_photons_match_brute_force() {
+		let photons : Vec<Photon> = (0..1000).map(|_| Photon {
+			pos : Vec3::new( random(), random(), random() ),
+			dir : Vec3::new( 0.0, -1.0, 0.0 ),
+			power : Color::new( 1.0, 1.0, 1.0 )
+		}).collect();
+		let map = PhotonMap::new( photons );
+
+		let pos = Vec3::new( 0.5, 0.5, 0.5 );
+		let (found, r2) = map.nearest( &pos, 10, 1.0 );
+		assert_eq!( found.len(), 10 );
+
+		let mut dists : Vec<f32> = map.photons.iter().map(|p| (&p.pos - &pos).squre_length()).collect();
+		dists.sort_by(|a, b| a.partial_cmp(b).unwrap());
+		assert!( (r2 - dists[9]).abs() < 0.00001 );
+		for i in found {
+			assert!( (&map.photons[i].pos - &pos).squre_length() <= dists[9] );
+		}
+	}
+
+	#[test]
+	fn test_mixed_materials_always_gather() {
+		let floor = Quad {
+			corner : Vec3::new( -1.0, 0.0, -1.0 ),
+			edge_u : Vec3::new( 0.0, 0.0, 2.0 ),
+			edge_v : Vec3::new( 2.0, 0.0, 0.0 ),
+			material : Box::new( Coated { albedo : Color::new( 0.8, 0.8, 0.8 ) } )
+		};
+		let objects : Vec<Box<Hitable>> = vec![ Box::new( floor ) ];
+		let photons = (0..100).map(|_| Photon {
+			pos : Vec3::new( 0.1 * random() - 0.05, 0.0, 0.1 * random() - 0.05 ),
+			dir : Vec3::new( 0.0, -1.0, 0.0 ),
+			power : Color::new( 0.01, 0.01, 0.01 )
+		}).collect();
+		let map = PhotonMap::new( photons );
+		let empty = PhotonMap::new( Vec::new() );
+		let settings = RenderSettings { gather_count : 50, ..RenderSettings::new() };
+
+		// the same random numbers with and without photons, so the difference is
+		// what was gathered, the same whichever lobe the coat picked
+		let ray = Ray::new( &Vec3::new( 0.0, 1.0, 0.0 ), &Vec3::new( 0.0, -1.0, 0.0 ) );
+		let mut gathered = Vec::new();
+		for seed in 0..20 {
+			set_stream( Box::new( Lcg::new( seed ) ) );
+			let with = map.colour( &ray, &objects, &settings );
+			set_stream( Box::new( Lcg::new( seed ) ) );
+			let without = empty.colour( &ray, &objects, &settings );
+			gathered.push( (with - without).luminance() );
+		}
+		assert!( gathered[0] > 0.0 );
+		assert!( gathered.iter().all(|g| (g - gathered[0]).abs() < 1e-4 * gathered[0]), "{:?}", gathered );
+	}
+}
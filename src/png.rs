@@ -0,0 +1,377 @@
+use crate::color::*;
+use crate::image::*;
+
+// PNG decoding, with its own inflate. All colour types and bit depths are
+// read, interlaced or not. Alpha is dropped.
+
+// Bits of a deflate stream, least significant first
+struct Bits<'a> {
+	data : &'a [u8],
+	pos : usize,
+	buffer : u32,
+	count : u32
+}
+
+impl<'a> Bits<'a> {
+	fn bits( &mut self, n : u32 ) -> Result<u32, String> {
+		let mut v = self.buffer;
+		while self.count < n {
+			let b = *self.data.get( self.pos ).ok_or( "truncated deflate stream" )? as u32;
+			self.pos += 1;
+			v |= b << self.count;
+			self.count += 8;
+		}
+		self.buffer = if n < 32 { v >> n } else { 0 };
+		self.count -= n;
+		Ok( v & ((1u64 << n) - 1) as u32 )
+	}
+}
+
+// Canonical Huffman code from the code length of every symbol
+struct Huffman {
+	count : [u16; 16],
+	symbol : Vec<u16>
+}
+
+impl Huffman {
+	fn new( lengths : &[u8] ) -> Huffman {
+		let mut count = [0u16; 16];
+		for l in lengths.iter() {
+			count[*l as usize] += 1;
+		}
+		count[0] = 0;
+
+		let mut offset = [0u16; 16];
+		for len in 1..15 {
+			offset[len + 1] = offset[len] + count[len];
+		}
+		let mut symbol = vec![ 0; lengths.len() ];
+		for (s, l) in lengths.iter().enumerate() {
+			if *l != 0 {
+				symbol[offset[*l as usize] as usize] = s as u16;
+				offset[*l as usize] += 1;
+			}
+		}
+		Huffman { count, symbol }
+	}
+
+	// One bit at a time, codes of every length are consecutive numbers
+	fn decode( &self, bits : &mut Bits ) -> Result<u16, String> {
+		let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+		for len in 1..16 {
+			code |= bits.bits( 1 )? as i32;
+			let count = self.count[len] as i32;
+			if code - first < count {
+				return Ok( self.symbol[(index + code - first) as usize] );
+			}
+			index += count;
+			first = (first + count) << 1;
+			code <<= 1;
+		}
+		Err( "invalid Huffman code".to_string() )
+	}
+}
+
+const LENGTH_BASE : [u16; 29] = [ 3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258 ];
+const LENGTH_EXTRA : [u32; 29] = [ 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0 ];
+const DIST_BASE : [u16; 30] = [ 1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577 ];
+const DIST_EXTRA : [u32; 30] = [ 0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13 ];
+// order in which the lengths of the code length code are stored
+const CODE_ORDER : [usize; 19] = [ 16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15 ];
+
+fn inflate_block( bits : &mut Bits, out : &mut Vec<u8>, lengths : &Huffman, distances : &Huffman ) -> Result<(), String> {
+	loop {
+		let symbol = lengths.decode( bits )? as usize;
+		if symbol < 256 {
+			out.push( symbol as u8 );
+		} else if symbol == 256 {
+			return Ok( () );
+		} else {
+			let s = symbol - 257;
+			if s >= 29 {
+				return Err( "invalid deflate length".to_string() );
+			}
+			let len = LENGTH_BASE[s] as usize + bits.bits( LENGTH_EXTRA[s] )? as usize;
+			let d = distances.decode( bits )? as usize;
+			if d >= 30 {
+				return Err( "invalid deflate distance".to_string() );
+			}
+			let dist = DIST_BASE[d] as usize + bits.bits( DIST_EXTRA[d] )? as usize;
+			if dist > out.len() {
+				return Err( "deflate distance too far back".to_string() );
+			}
+			let start = out.len() - dist;
+			for i in 0..len {
+				let b = out[start + i];
+				out.push( b );
+			}
+		}
+	}
+}
+
+fn dynamic_tables( bits : &mut Bits ) -> Result<(Huffman, Huffman), String> {
+	let nlen = bits.bits( 5 )? as usize + 257;
+	let ndist = bits.bits( 5 )? as usize + 1;
+	let ncode = bits.bits( 4 )? as usize + 4;
+
+	let mut code_lengths = [0u8; 19];
+	for i in 0..ncode {
+		code_lengths[CODE_ORDER[i]] = bits.bits( 3 )? as u8;
+	}
+	let code = Huffman::new( &code_lengths );
+
+	let mut lengths = Vec::with_capacity( nlen + ndist );
+	while lengths.len() < nlen + ndist {
+		let symbol = code.decode( bits )?;
+		let (value, repeat) = match symbol {
+			0..=15 => (symbol as u8, 1),
+			16 => (*lengths.last().ok_or( "repeat without a length" )?, 3 + bits.bits( 2 )?),
+			17 => (0, 3 + bits.bits( 3 )?),
+			_ => (0, 11 + bits.bits( 7 )?)
+		};
+		for _ in 0..repeat {
+			lengths.push( value );
+		}
+	}
+	if lengths.len() > nlen + ndist {
+		return Err( "too many code lengths".to_string() );
+	}
+	Ok( (Huffman::new( &lengths[..nlen] ), Huffman::new( &lengths[nlen..] )) )
+}
+
+// Decompresses a zlib stream, the checksum isn't verified
+pub fn inflate( zlib : &[u8] ) -> Result<Vec<u8>, String> {
+	if zlib.len() < 2 || zlib[0] & 0x0f != 8 || ((zlib[0] as u16) << 8 | zlib[1] as u16) % 31 != 0 {
+		return Err( "invalid zlib header".to_string() );
+	}
+	if zlib[1] & 0x20 != 0 {
+		return Err( "zlib preset dictionaries are not supported".to_string() );
+	}
+
+	let mut bits = Bits { data : &zlib[2..], pos : 0, buffer : 0, count : 0 };
+	let mut out = Vec::new();
+	loop {
+		let last = bits.bits( 1 )?;
+		match bits.bits( 2 )? {
+			0 => {
+				// stored, starts on the next byte
+				bits.buffer = 0;
+				bits.count = 0;
+				let p = bits.pos;
+				let header = bits.data.get( p..p + 4 ).ok_or( "truncated stored block" )?;
+				let len = header[0] as usize | (header[1] as usize) << 8;
+				let block = bits.data.get( p + 4..p + 4 + len ).ok_or( "truncated stored block" )?;
+				out.extend_from_slice( block );
+				bits.pos = p + 4 + len;
+			},
+			1 => {
+				let mut lengths = [0u8; 288];
+				for (i, l) in lengths.iter_mut().enumerate() {
+					*l = if i < 144 { 8 } else if i < 256 { 9 } else if i < 280 { 7 } else { 8 };
+				}
+				inflate_block( &mut bits, &mut out, &Huffman::new( &lengths ), &Huffman::new( &[5u8; 30] ) )?;
+			},
+			2 => {
+				let (lengths, distances) = dynamic_tables( &mut bits )?;
+				inflate_block( &mut bits, &mut out, &lengths, &distances )?;
+			},
+			_ => return Err( "invalid deflate block type".to_string() )
+		}
+		if last == 1 {
+			return Ok( out );
+		}
+	}
+}
+
+fn paeth( a : u8, b : u8, c : u8 ) -> u8 {
+	let p = a as i16 + b as i16 - c as i16;
+	let (pa, pb, pc) = ((p - a as i16).abs(), (p - b as i16).abs(), (p - c as i16).abs());
+	if pa <= pb && pa <= pc { a } else if pb <= pc { b } else { c }
+}
+
+// Undoes the filter of `row` in place, `previous` is the row above after unfiltering
+fn unfilter( kind : u8, row : &mut [u8], previous : &[u8], bpp : usize ) -> Result<(), String> {
+	for i in 0..row.len() {
+		let a = if i >= bpp { row[i - bpp] } else { 0 };
+		let b = previous[i];
+		let c = if i >= bpp { previous[i - bpp] } else { 0 };
+		row[i] = row[i].wrapping_add( match kind {
+			0 => 0,
+			1 => a,
+			2 => b,
+			3 => ((a as u16 + b as u16) / 2) as u8,
+			4 => paeth( a, b, c ),
+			_ => return Err( format!( "invalid PNG filter {}", kind ) )
+		} );
+	}
+	Ok( () )
+}
+
+fn be32( b : &[u8] ) -> usize {
+	(b[0] as usize) << 24 | (b[1] as usize) << 16 | (b[2] as usize) << 8 | b[3] as usize
+}
+
+pub fn decode( bytes : &[u8], space : ColourSpace ) -> Result<Image, String> {
+	let truncated = || "truncated PNG".to_string();
+	let (mut width, mut height, mut depth, mut colour, mut interlace) = (0, 0, 0, 0, 0);
+	let mut palette : Vec<Color> = Vec::new();
+	let mut data = Vec::new();
+
+	let mut pos = 8;
+	loop {
+		let header = bytes.get( pos..pos + 8 ).ok_or_else( truncated )?;
+		let len = be32( header );
+		let chunk = bytes.get( pos + 8..pos + 8 + len ).ok_or_else( truncated )?;
+		match &header[4..8] {
+			b"IHDR" => {
+				if len < 13 {
+					return Err( truncated() );
+				}
+				width = be32( &chunk[0..4] );
+				height = be32( &chunk[4..8] );
+				depth = chunk[8] as usize;
+				colour = chunk[9];
+				interlace = chunk[12];
+			},
+			b"PLTE" => {
+				palette = chunk.chunks( 3 ).filter(|c| c.len() == 3).map(|c| Image::from_bytes( c[0], c[1], c[2], space )).collect();
+			},
+			b"IDAT" => data.extend_from_slice( chunk ),
+			b"IEND" => break,
+			_ => {}
+		}
+		// data and CRC
+		pos += 12 + len;
+	}
+
+	let channels = match colour {
+		0 => 1,
+		2 => 3,
+		3 => 1,
+		4 => 2,
+		6 => 4,
+		_ => return Err( format!( "invalid PNG colour type {}", colour ) )
+	};
+	if ![1, 2, 4, 8, 16].contains( &depth ) || width == 0 || height == 0 || width.saturating_mul( height ) > MAX_PIXELS {
+		return Err( "invalid PNG header".to_string() );
+	}
+	if colour == 3 && palette.is_empty() {
+		return Err( "PNG palette missing".to_string() );
+	}
+
+	let raw = inflate( &data )?;
+	let bits_per_pixel = channels * depth;
+	let bpp = (bits_per_pixel / 8).max(1);
+	let max = ((1u32 << depth) - 1) as f32;
+
+	// value of channel `c` of pixel `x` in an unfiltered row
+	let sample = |row : &[u8], x : usize, c : usize| -> u32 {
+		let bit = (x * channels + c) * depth;
+		match depth {
+			16 => (row[bit / 8] as u32) << 8 | row[bit / 8 + 1] as u32,
+			8 => row[bit / 8] as u32,
+			_ => (row[bit / 8] as u32 >> (8 - depth - bit % 8)) & ((1 << depth) - 1)
+		}
+	};
+
+	// Adam7 passes as origin and step, or the whole image at once
+	let passes : &[(usize, usize, usize, usize)] = if interlace == 1 {
+		&[ (0, 0, 8, 8), (4, 0, 8, 8), (0, 4, 4, 8), (2, 0, 4, 4), (0, 2, 2, 4), (1, 0, 2, 2), (0, 1, 1, 2) ]
+	} else {
+		&[ (0, 0, 1, 1) ]
+	};
+
+	// filtered rows of every pass, with the filter byte in front
+	let pass_size = |&(x0, y0, dx, dy) : &(usize, usize, usize, usize)| {
+		if x0 >= width || y0 >= height {
+			return (0, 0, 0);
+		}
+		let pw = (width - x0 + dx - 1) / dx;
+		let ph = (height - y0 + dy - 1) / dy;
+		(pw, ph, (pw * bits_per_pixel + 7) / 8)
+	};
+	let expected : usize = passes.iter().map(|p| { let (_, ph, stride) = pass_size( p ); (1 + stride) * ph }).sum();
+	if raw.len() < expected {
+		return Err( truncated() );
+	}
+
+	let mut image = Image::new( width, height );
+	let mut offset = 0;
+	for &(x0, y0, dx, dy) in passes.iter() {
+		let (pw, ph, stride) = pass_size( &(x0, y0, dx, dy) );
+		if pw == 0 {
+			continue;
+		}
+
+		let mut previous = vec![ 0u8; stride ];
+		for j in 0..ph {
+			let kind = *raw.get( offset ).ok_or_else( truncated )?;
+			let mut row = raw.get( offset + 1..offset + 1 + stride ).ok_or_else( truncated )?.to_vec();
+			offset += 1 + stride;
+			unfilter( kind, &mut row, &previous, bpp )?;
+
+			for i in 0..pw {
+				let c = match colour {
+					3 => *palette.get( sample( &row, i, 0 ) as usize ).ok_or( "PNG palette index out of range" )?,
+					0 | 4 => {
+						let v = sample( &row, i, 0 ) as f32 / max;
+						Image::from_unit( v, v, v, space )
+					},
+					_ => Image::from_unit( sample( &row, i, 0 ) as f32 / max, sample( &row, i, 1 ) as f32 / max, sample( &row, i, 2 ) as f32 / max, space )
+				};
+				image.set( x0 + i * dx, y0 + j * dy, c );
+			}
+			previous = row;
+		}
+	}
+	Ok( image )
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn hex( s : &str ) -> Vec<u8> {
+		(0..s.len() / 2).map(|i| u8::from_str_radix( &s[2 * i..2 * i + 2], 16 ).unwrap()).collect()
+	}
+
+	#[test]
+	fn test_inflate_stored_fixed_and_dynamic_blocks() {
+		// stored
+		let stored = [ 0x78, 0x01, 0x01, 0x03, 0x00, 0xfc, 0xff, b'a', b'b', b'c' ];
+		assert_eq!( inflate( &stored ).unwrap(), b"abc" );
+
+		// fixed Huffman codes, zlib.compress( b"hello hello hello" )
+		let fixed = hex( "789ccb48cdc9c957c84090003a2e067d" );
+		assert_eq!( inflate( &fixed ).unwrap(), b"hello hello hello".to_vec() );
+
+		// dynamic Huffman codes
+		let dynamic = hex( "78da2d8e8b0d003108426705fcb0ff0487cd25465b5e40b11e8e84050ae5450390a60963b9100d341bd63cb44304462e46002357ecf17b15049d7a15fbcbbbe620fe280f2775ce3ff917a8b497c617ba4cf806753666397f9469bedbc8a12a375d2a3f5d704d25" );
+		let expected : Vec<u8> = (0..200u32).map(|i| b"aaaaaaabbbbccdefgh"[((i * i * 7 + i / 3 + (i * 31) % 11) % 18) as usize]).collect();
+		assert_eq!( inflate( &dynamic ).unwrap(), expected );
+	}
+
+	#[test]
+	fn test_png_filters_and_interlacing() {
+		// 3x2 RGB, rows filtered with Sub and Paeth
+		let rgb = hex( "89504e470d0a1a0a0000000d49484452000000030000000208020000001216f14d0000001b49444154789c63e41291038205464758bebe49627ad73c49ce0d00383f06deffbb97230000000049454e44ae426082" );
+		let image = decode( &rgb, ColourSpace::Linear ).unwrap();
+		assert_eq!( (image.width, image.height), (3, 2) );
+		let c = image.get( 2, 0 );
+		assert!( (c.r - 200.0 / 255.0).abs() < 1e-6 && (c.g - 100.0 / 255.0).abs() < 1e-6 && c.b == 0.0 );
+		let c = image.get( 2, 1 );
+		assert!( (c.r - 90.0 / 255.0).abs() < 1e-6 && (c.b - 70.0 / 255.0).abs() < 1e-6 );
+
+		let srgb = decode( &rgb, ColourSpace::Srgb ).unwrap();
+		assert!( (srgb.get( 0, 1 ).r - 1.0).abs() < 1e-6 && srgb.get( 0, 1 ).b < 128.0 / 255.0 );
+
+		// 5x5 grey with Adam7, every pixel holds 10 y + x
+		let grey = hex( "89504e470d0a1a0a0000000d4948445200000005000000050800000001df0349af0000002649444154789c6362606261d2d0616262d26012119360626466121101212e6e1e5e3e200308001f1301cf7007d2a90000000049454e44ae426082" );
+		let image = decode( &grey, ColourSpace::Linear ).unwrap();
+		for y in 0..5 {
+			for x in 0..5 {
+				assert!( (image.get( x, y ).g * 255.0 - (10 * y + x) as f32).abs() < 1e-3 );
+			}
+		}
+	}
+}
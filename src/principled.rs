@@ -0,0 +1,357 @@
+use crate::vec_math::*;
+use crate::color::*;
+use crate::random::*;
+use crate::texture::*;
+use crate::material::*;
+use crate::hitable::*;
+use crate::microfacet::*;
+
+use std::rc::Rc;
+use std::f32::consts::PI;
+
+// Disney style principled BSDF (Burley 2012, 2015). One material covering
+// plastics, metals and glass: a retro-reflective diffuse base with sheen, a GGX
+// specular layer, a clearcoat and rough transmission, all blended by textured
+// parameters. Scalar parameters are read from the luminance of their texture.
+pub struct Principled {
+	pub base_colour : Rc<Texture>,
+	pub metallic : Rc<Texture>,
+	pub roughness : Rc<Texture>,
+	// reflectance at normal incidence of the dielectric part, 0.5 is 4% and an IOR of 1.5
+	pub specular : Rc<Texture>,
+	pub specular_tint : Rc<Texture>,
+	pub sheen : Rc<Texture>,
+	pub clearcoat : Rc<Texture>,
+	pub transmission : Rc<Texture>,
+	pub emission : Rc<Texture>
+}
+
+const CLEARCOAT_ROUGHNESS : f32 = 0.25;
+
+fn scalar( t : &Rc<Texture>, at : &TexCoord ) -> f32 {
+	t.scalar( at ).max(0.0).min(1.0)
+}
+
+fn mix( a : &Color, b : &Color, t : f32 ) -> Color {
+	Color::new( a.r + (b.r - a.r) * t, a.g + (b.g - a.g) * t, a.b + (b.b - a.b) * t )
+}
+
+fn schlick_weight( cos : f32 ) -> f32 {
+	(1.0 - cos.max(0.0).min(1.0)).powf(5.0)
+}
+
+// Parameters at one point of the surface, with the lobe selection probabilities
+struct Lobes {
+	base : Color,
+	metallic : f32,
+	roughness : f32,
+	specular_colour : Color,
+	sheen_colour : Color,
+	transmission : f32,
+	clearcoat : f32,
+	ior : f32,
+	ggx : Ggx,
+	coat : Ggx,
+	p_diffuse : f32,
+	p_specular : f32,
+	p_clearcoat : f32,
+	p_transmission : f32
+}
+
+impl Principled {
+
+	// Plain dielectric with the given base colour and default parameters
+	#[allow(dead_code)]
+	pub fn new( base_colour : Color ) -> Principled {
+		let zero = Color::new( 0.0, 0.0, 0.0 );
+		Principled {
+			base_colour : constant( base_colour ),
+			metallic : constant( zero ),
+			roughness : constant( Color::new( 0.5, 0.5, 0.5 ) ),
+			specular : constant( Color::new( 0.5, 0.5, 0.5 ) ),
+			specular_tint : constant( zero ),
+			sheen : constant( zero ),
+			clearcoat : constant( zero ),
+			transmission : constant( zero ),
+			emission : constant( zero )
+		}
+	}
+
+	fn lobes( &self, at : &TexCoord ) -> Lobes {
+		let base = self.base_colour.value( at );
+		let metallic = scalar( &self.metallic, at );
+		let roughness = scalar( &self.roughness, at );
+		let specular = scalar( &self.specular, at );
+		let clearcoat = scalar( &self.clearcoat, at );
+		let transmission = (1.0 - metallic) * scalar( &self.transmission, at );
+
+		// hue of the base colour, used to tint the specular and sheen
+		let lum = base.luminance();
+		let white = Color::new( 1.0, 1.0, 1.0 );
+		let tint = if lum > 0.0 { base * (1.0 / lum) } else { white };
+
+		let f0 = 0.08 * specular;
+		let dielectric = mix( &white, &tint, scalar( &self.specular_tint, at ) ) * f0;
+		let s = f0.sqrt().min(0.99);
+
+		let w_diffuse = (1.0 - transmission) * (1.0 - metallic);
+		let w_specular = 1.0 - transmission;
+		let w_clearcoat = 0.25 * clearcoat;
+		let total = w_diffuse + w_specular + w_clearcoat + transmission;
+
+		Lobes {
+			base,
+			metallic,
+			roughness,
+			specular_colour : mix( &dielectric, &base, metallic ),
+			sheen_colour : mix( &white, &tint, 0.5 ) * scalar( &self.sheen, at ),
+			transmission,
+			clearcoat,
+			ior : ((1.0 + s) / (1.0 - s)).max( 1.001 ),
+			ggx : Ggx::new( roughness, 0.0 ),
+			coat : Ggx::new( CLEARCOAT_ROUGHNESS, 0.0 ),
+			p_diffuse : w_diffuse / total,
+			p_specular : w_specular / total,
+			p_clearcoat : w_clearcoat / total,
+			p_transmission : transmission / total
+		}
+	}
+
+	// Local frame with z on the side of the incoming ray, and the ratio of the
+	// index of refraction behind the surface to the one in front of it
+	fn frame( dir : &Vec3, normal : &Vec3, ior : f32 ) -> (Onb, f32) {
+		if dot_product( dir, normal ) > 0.0 {
+			(Onb::from_w( &-normal ), 1.0 / ior)
+		} else {
+			(Onb::from_w( normal ), ior)
+		}
+	}
+
+	fn eval_local( l : &Lobes, wo : &Vec3, wi : &Vec3, eta : f32 ) -> Color {
+		if wo.z <= 0.0 || wi.z == 0.0 {
+			return Color::new(0.0,0.0,0.0);
+		}
+
+		if wi.z < 0.0 {
+			if l.transmission <= 0.0 {
+				return Color::new(0.0,0.0,0.0);
+			}
+			return l.base * (l.transmission * l.ggx.dielectric( wo, wi, eta ).0);
+		}
+
+		let h = (wo + wi).normalized();
+		let ih = dot_product( wi, &h );
+		let oh = dot_product( wo, &h );
+		let white = Color::new( 1.0, 1.0, 1.0 );
+
+		// retro-reflective diffuse and sheen
+		let fl = schlick_weight( wi.z );
+		let fv = schlick_weight( wo.z );
+		let fd90 = 0.5 + 2.0 * l.roughness * ih * ih;
+		let fd = (1.0 + (fd90 - 1.0) * fl) * (1.0 + (fd90 - 1.0) * fv) / PI;
+		let diffuse = l.base * fd + l.sheen_colour * schlick_weight( ih );
+		let mut f = diffuse * ((1.0 - l.transmission) * (1.0 - l.metallic));
+
+		// specular layer, the transmissive part reflects with the exact dielectric Fresnel
+		let microfacet = l.ggx.d( &h ) * l.ggx.g( wo, wi ) / (4.0 * wo.z * wi.z);
+		let fs = mix( &l.specular_colour, &white, schlick_weight( oh ) );
+		f = f + fs * ((1.0 - l.transmission) * microfacet);
+		if l.transmission > 0.0 {
+			f = f + white * (l.transmission * l.ggx.dielectric( wo, wi, eta ).0);
+		}
+
+		if l.clearcoat > 0.0 {
+			let fc = 0.04 + 0.96 * schlick_weight( oh );
+			let coat = l.coat.d( &h ) * l.coat.g( wo, wi ) / (4.0 * wo.z * wi.z);
+			f = f + white * (0.25 * l.clearcoat * fc * coat);
+		}
+		f
+	}
+
+	fn pdf_local( l : &Lobes, wo : &Vec3, wi : &Vec3, eta : f32 ) -> f32 {
+		if wo.z <= 0.0 || wi.z == 0.0 {
+			return 0.0;
+		}
+
+		if wi.z < 0.0 {
+			if l.p_transmission <= 0.0 {
+				return 0.0;
+			}
+			return l.p_transmission * l.ggx.dielectric( wo, wi, eta ).1;
+		}
+
+		let h = (wo + wi).normalized();
+		let oh = dot_product( wo, &h );
+		if oh <= 0.0 {
+			return 0.0;
+		}
+		let specular = l.ggx.pdf_h( wo, &h ) / (4.0 * oh);
+		let mut pdf = l.p_diffuse * wi.z / PI + l.p_specular * specular;
+		if l.p_clearcoat > 0.0 {
+			pdf += l.p_clearcoat * l.coat.pdf_h( wo, &h ) / (4.0 * oh);
+		}
+		if l.p_transmission > 0.0 {
+			pdf += l.p_transmission * l.ggx.dielectric( wo, wi, eta ).1;
+		}
+		pdf
+	}
+
+	fn sample_local( l : &Lobes, wo : &Vec3, eta : f32 ) -> Vec3 {
+		let r = random();
+		let reflect_about = |h : &Vec3| 2.0 * dot_product( wo, h ) * h - wo;
+
+		if r < l.p_diffuse {
+			let d = &Vec3::new( 0.0, 0.0, 1.0 ) + random_unit_vector();
+			if d.squre_length() < 1e-8 { Vec3::new( 0.0, 0.0, 1.0 ) } else { d.normalized() }
+		} else if r < l.p_diffuse + l.p_specular {
+			reflect_about( &l.ggx.sample_h( wo ) )
+		} else if r < l.p_diffuse + l.p_specular + l.p_clearcoat {
+			reflect_about( &l.coat.sample_h( wo ) )
+		} else {
+			let h = l.ggx.sample_h( wo );
+			let f = fresnel_dielectric( dot_product( wo, &h ), eta );
+			match refract( &-wo, &h, 1.0 / eta ) {
+				Some(t) if random() >= f => t,
+				_ => reflect_about( &h )
+			}
+		}
+	}
+}
+
+impl Material for Principled {
+	fn sample( &self, hit : &Hit ) -> Option<BsdfSample>
+	{
+		let l = self.lobes( &hit.tex_coord() );
+		let (frame, eta) = Principled::frame( &hit.ray.direction, &hit.normal, l.ior );
+		let wo = frame.to_local( &-&hit.ray.direction );
+		let wi = Principled::sample_local( &l, &wo, eta );
+
+		let pdf = Principled::pdf_local( &l, &wo, &wi, eta );
+		if pdf <= 0.0 || !pdf.is_finite() {
+			return None;
+		}
+		let f = Principled::eval_local( &l, &wo, &wi, eta );
+		Some( BsdfSample {
+			ray : hit.ray.spawn( &hit.pos, &frame.to_world( &wi ) ),
+			weight : f * (wi.z.abs() / pdf),
+			pdf,
+			specular : false
+		} )
+	}
+
+	// like `BlackBody`, only the front of the surface glows
+	fn emit( &self, hit : &Hit, wo : &Vec3 ) -> Color
+	{
+		if dot_product( &hit.normal, wo ) <= 0.0 {
+			return Color::new( 0.0, 0.0, 0.0 );
+		}
+		self.emission.value( &hit.tex_coord() )
+	}
+
+	fn eval( &self, hit : &Hit, wi : &Vec3, wo : &Vec3 ) -> Color
+	{
+		let l = self.lobes( &hit.tex_coord() );
+		let (frame, eta) = Principled::frame( &-wo, &hit.normal, l.ior );
+		Principled::eval_local( &l, &frame.to_local( wo ), &frame.to_local( wi ), eta )
+	}
+
+	fn pdf( &self, hit : &Hit, wi : &Vec3, wo : &Vec3 ) -> f32
+	{
+		let l = self.lobes( &hit.tex_coord() );
+		let (frame, eta) = Principled::frame( &-wo, &hit.normal, l.ior );
+		Principled::pdf_local( &l, &frame.to_local( wo ), &frame.to_local( wi ), eta )
+	}
+
+	// every lobe is rough enough to be evaluated
+	fn is_diffuse( &self, _hit : &Hit ) -> bool { true }
+	fn is_emitter( &self ) -> bool { !self.emission.is_black() }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::shapes::*;
+
+	// Directional albedo estimated by sampling and by integrating eval over the
+	// sphere with stratified directions
+	fn albedos( m : &Principled, wo : &Vec3 ) -> (Color, Color) {
+		let hit = Hit {
+			distance : 2.0,
+			pos : Vec3::new( 0.0, 0.0, 0.0 ),
+			normal : Vec3::new( 0.0, 0.0, 1.0 ),
+			tangent : Vec3::new( 1.0, 0.0, 0.0 ),
+			bitangent : Vec3::new( 0.0, 1.0, 0.0 ),
+			u : 0.0,
+			v : 0.0,
+			uv_scale : 1.0,
+			ray : Ray::new( &(wo * 2.0), &-wo ),
+			material : m
+		};
+		let n = 1000;
+
+		let mut sampled = Color::new( 0.0, 0.0, 0.0 );
+		let mut integrated = Color::new( 0.0, 0.0, 0.0 );
+		for i in 0..n {
+			for j in 0..n {
+				if let Some(sample) = m.sample( &hit ) {
+					sampled = sampled + sample.weight;
+				}
+
+				let z = 2.0 * (i as f32 + random()) / n as f32 - 1.0;
+				let phi = 2.0 * PI * (j as f32 + random()) / n as f32;
+				let r = (1.0 - z * z).max(0.0).sqrt();
+				let wi = Vec3::new( r * phi.cos(), r * phi.sin(), z );
+				let f = m.eval( &hit, &wi, wo );
+				integrated = integrated + f * (wi.z.abs() * 4.0 * PI);
+			}
+		}
+		let k = 1.0 / (n * n) as f32;
+		(sampled * k, integrated * k)
+	}
+
+	#[test]
+	fn test_sampling_matches_evaluation() {
+		let wo = Vec3::new( 0.3, 0.1, 0.8 ).normalized();
+
+		let mut plastic = Principled::new( Color::new( 0.8, 0.3, 0.2 ) );
+		plastic.clearcoat = constant( Color::new( 1.0, 1.0, 1.0 ) );
+		plastic.sheen = constant( Color::new( 0.5, 0.5, 0.5 ) );
+
+		let mut metal = Principled::new( Color::new( 0.9, 0.6, 0.3 ) );
+		metal.metallic = constant( Color::new( 1.0, 1.0, 1.0 ) );
+		metal.roughness = constant( Color::new( 0.4, 0.4, 0.4 ) );
+
+		let mut glass = Principled::new( Color::new( 1.0, 1.0, 1.0 ) );
+		glass.transmission = constant( Color::new( 1.0, 1.0, 1.0 ) );
+		glass.roughness = constant( Color::new( 0.6, 0.6, 0.6 ) );
+
+		for m in [ plastic, metal, glass ].iter() {
+			let (sampled, integrated) = albedos( m, &wo );
+			assert!( (sampled.r - integrated.r).abs() < 0.03, "{:?} {:?}", sampled, integrated );
+			assert!( (sampled.g - integrated.g).abs() < 0.03, "{:?} {:?}", sampled, integrated );
+			assert!( sampled.r <= 1.05 && sampled.g <= 1.05 && sampled.b <= 1.05 );
+		}
+	}
+
+	#[test]
+	fn test_emission_makes_a_light() {
+		let quad = |material : Principled| -> Box<Hitable> {
+			Box::new( Quad {
+				corner : Vec3::new( -1.0, 2.0, -1.0 ),
+				edge_u : Vec3::new( 2.0, 0.0, 0.0 ),
+				edge_v : Vec3::new( 0.0, 0.0, 2.0 ),
+				material : Box::new( material )
+			} )
+		};
+		let mut lamp = Principled::new( Color::new( 0.5, 0.5, 0.5 ) );
+		lamp.emission = constant( Color::new( 4.0, 4.0, 4.0 ) );
+		let objects = vec![ quad( Principled::new( Color::new( 0.5, 0.5, 0.5 ) ) ), quad( lamp ) ];
+		assert_eq!( find_lights( &objects ), vec![ 1 ] );
+
+		// the quad faces down and only glows that way
+		let below = Ray::new( &Vec3::zero(), &Vec3::new( 0.0, 1.0, 0.0 ) );
+		assert_eq!( objects[1].hit( &below ).unwrap().emitted().r, 4.0 );
+		let above = Ray::new( &Vec3::new( 0.0, 4.0, 0.0 ), &Vec3::new( 0.0, -1.0, 0.0 ) );
+		assert_eq!( objects[1].hit( &above ).unwrap().emitted().r, 0.0 );
+	}
+}
@@ -1,25 +1,69 @@
-
 use crate::vec_math::*;
-use std::u32;
+use std::cell::RefCell;
 
-static mut seed : u32 = 1;
+// Source of the numbers returned by `random`. Integrators which need control
+// over the random numbers of a path (e.g. Metropolis light transport) install
+// their own stream with `set_stream`.
+pub trait RandomStream {
+	fn next( &mut self ) -> f32;
+}
 
-pub fn random () -> f32 {
-	let a = 16807_u32;
-	let m = 2147483647;
-	let random = unsafe {
-		seed = a.wrapping_mul(seed).wrapping_rem( m);
-		seed
-		};
+// Park-Miller minimal standard generator
+pub struct Lcg {
+	seed : u32
+}
+
+impl Lcg {
+	pub fn new( seed : u32 ) -> Lcg {
+		// scramble the seed, nearby seeds give correlated first numbers otherwise
+		let mut h = seed.wrapping_add( 0x9e3779b9 );
+		h = (h ^ (h >> 16)).wrapping_mul( 0x85ebca6b );
+		h = (h ^ (h >> 13)).wrapping_mul( 0xc2b2ae35 );
+		h ^= h >> 16;
+		Lcg { seed : h % 2147483646 + 1 }
+	}
+}
+
+impl RandomStream for Lcg {
+	fn next( &mut self ) -> f32 {
+		let a = 16807_u64;
+		let m = 2147483647_u64;
+		self.seed = ((a * self.seed as u64) % m) as u32;
+
+		// top 24 bits fit into the mantissa exactly, so the result stays below 1
+		((self.seed >> 7) as f32) / 16777216.0
+	}
+}
 
-	(random as f32) / 4294967295.0
+thread_local! {
+	static STREAM : RefCell<Box<RandomStream>> = RefCell::new( Box::new( Lcg { seed : 1 } ) );
+}
+
+// Replaces the stream used by `random` and returns the previous one
+pub fn set_stream( stream : Box<RandomStream> ) -> Box<RandomStream> {
+	STREAM.with(|s| std::mem::replace( &mut *s.borrow_mut(), stream ))
+}
+
+pub fn random () -> f32 {
+	STREAM.with(|s| s.borrow_mut().next())
 }
 
 pub fn random_in_unit_sphere() -> Vec3 {
 	loop {
-		let v = Vec3::new (random(), random(), random());
+		let v = Vec3::new (2.0 * random() - 1.0, 2.0 * random() - 1.0, 2.0 * random() - 1.0);
 		if v.squre_length() < 1.0{
 			return v;
 		}
 	}
-}
\ No newline at end of file
+}
+
+// Uniformly distributed over the surface of the unit sphere
+pub fn random_unit_vector() -> Vec3 {
+	loop {
+		let v = random_in_unit_sphere();
+		let l = v.squre_length();
+		if l > 0.0001 {
+			return v / l.sqrt();
+		}
+	}
+}
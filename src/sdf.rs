@@ -0,0 +1,392 @@
+use crate::vec_math::*;
+use crate::hitable::*;
+use crate::material::*;
+
+// Signed distance field as an expression tree, negative inside. Primitives
+// sit at the origin, transforms apply to everything below them.
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub enum Sdf {
+	Sphere { radius : f32 },
+	// half the size along each axis
+	Cuboid { half : Vec3 },
+	// ring around the y axis
+	Torus { major : f32, minor : f32 },
+	// along the y axis, closed at +-`half_height`
+	Cylinder { radius : f32, half_height : f32 },
+	Capsule { a : Vec3, b : Vec3, radius : f32 },
+	Union( Box<Sdf>, Box<Sdf> ),
+	Intersection( Box<Sdf>, Box<Sdf> ),
+	// the first without the second
+	Difference( Box<Sdf>, Box<Sdf> ),
+	// blends the two over about `k`
+	SmoothUnion { a : Box<Sdf>, b : Box<Sdf>, k : f32 },
+	// grows the surface by `radius`, rounding its edges
+	Round { sdf : Box<Sdf>, radius : f32 },
+	Translate { sdf : Box<Sdf>, offset : Vec3 },
+	// only the rotation part of `rotation` is used
+	Rotate { sdf : Box<Sdf>, rotation : Mat4x4 },
+	Scale { sdf : Box<Sdf>, factor : f32 },
+	// turns by `rate` radians per unit up the y axis
+	Twist { sdf : Box<Sdf>, rate : f32 },
+	// curls the x axis towards y, `rate` radians per unit along x
+	Bend { sdf : Box<Sdf>, rate : f32 },
+	// copies `count` times either way along each axis, `period` apart. A
+	// zero period leaves that axis alone. The copies shouldn't overlap.
+	Repeat { sdf : Box<Sdf>, period : Vec3, count : Vec3 },
+	// power 8 gives the usual bulb, which fits in a ball of radius 1.2
+	Mandelbulb { power : f32, iterations : u32 }
+}
+
+#[allow(dead_code)]
+impl Sdf {
+	pub fn union( a : Sdf, b : Sdf ) -> Sdf { Sdf::Union( Box::new( a ), Box::new( b ) ) }
+
+	pub fn intersection( a : Sdf, b : Sdf ) -> Sdf { Sdf::Intersection( Box::new( a ), Box::new( b ) ) }
+
+	pub fn difference( a : Sdf, b : Sdf ) -> Sdf { Sdf::Difference( Box::new( a ), Box::new( b ) ) }
+
+	pub fn smooth_union( a : Sdf, b : Sdf, k : f32 ) -> Sdf { Sdf::SmoothUnion { a : Box::new( a ), b : Box::new( b ), k } }
+
+	pub fn translated( self, offset : Vec3 ) -> Sdf { Sdf::Translate { sdf : Box::new( self ), offset } }
+
+	pub fn rotated( self, rotation : Mat4x4 ) -> Sdf { Sdf::Rotate { sdf : Box::new( self ), rotation } }
+
+	pub fn scaled( self, factor : f32 ) -> Sdf { Sdf::Scale { sdf : Box::new( self ), factor } }
+
+	pub fn rounded( self, radius : f32 ) -> Sdf { Sdf::Round { sdf : Box::new( self ), radius } }
+
+	pub fn twisted( self, rate : f32 ) -> Sdf { Sdf::Twist { sdf : Box::new( self ), rate } }
+
+	pub fn bent( self, rate : f32 ) -> Sdf { Sdf::Bend { sdf : Box::new( self ), rate } }
+
+	pub fn repeated( self, period : Vec3, count : Vec3 ) -> Sdf { Sdf::Repeat { sdf : Box::new( self ), period, count } }
+
+	pub fn distance( &self, p : &Vec3 ) -> f32 {
+		match self {
+			Sdf::Sphere { radius } => p.length() - radius,
+			Sdf::Cuboid { half } => {
+				let q = Vec3::new( p.x.abs() - half.x, p.y.abs() - half.y, p.z.abs() - half.z );
+				let outside = Vec3::new( q.x.max( 0.0 ), q.y.max( 0.0 ), q.z.max( 0.0 ) ).length();
+				outside + q.x.max( q.y ).max( q.z ).min( 0.0 )
+			},
+			Sdf::Torus { major, minor } => {
+				let ring = (p.x * p.x + p.z * p.z).sqrt() - major;
+				(ring * ring + p.y * p.y).sqrt() - minor
+			},
+			Sdf::Cylinder { radius, half_height } => {
+				let (dr, dy) = ((p.x * p.x + p.z * p.z).sqrt() - radius, p.y.abs() - half_height);
+				dr.max( dy ).min( 0.0 ) + (dr.max( 0.0 ) * dr.max( 0.0 ) + dy.max( 0.0 ) * dy.max( 0.0 )).sqrt()
+			},
+			Sdf::Capsule { a, b, radius } => {
+				let (pa, ba) = (p - a, b - a);
+				let h = (dot_product( &pa, &ba ) / ba.squre_length().max( 1e-12 )).max( 0.0 ).min( 1.0 );
+				(pa - h * &ba).length() - radius
+			},
+			Sdf::Union( a, b ) => a.distance( p ).min( b.distance( p ) ),
+			Sdf::Intersection( a, b ) => a.distance( p ).max( b.distance( p ) ),
+			Sdf::Difference( a, b ) => a.distance( p ).max( -b.distance( p ) ),
+			Sdf::SmoothUnion { a, b, k } => {
+				let (da, db) = (a.distance( p ), b.distance( p ));
+				if *k <= 0.0 {
+					return da.min( db );
+				}
+				let h = (0.5 + 0.5 * (db - da) / k).max( 0.0 ).min( 1.0 );
+				db + (da - db) * h - k * h * (1.0 - h)
+			},
+			Sdf::Round { sdf, radius } => sdf.distance( p ) - radius,
+			Sdf::Translate { sdf, offset } => sdf.distance( &(p - offset) ),
+			Sdf::Rotate { sdf, rotation } => sdf.distance( &rotation.apply_transposed_rotation( p ) ),
+			Sdf::Scale { sdf, factor } => sdf.distance( &(p / *factor) ) * factor,
+			Sdf::Twist { sdf, rate } => {
+				let (s, c) = (rate * p.y).sin_cos();
+				sdf.distance( &Vec3::new( c * p.x - s * p.z, p.y, s * p.x + c * p.z ) )
+			},
+			Sdf::Bend { sdf, rate } => {
+				let (s, c) = (rate * p.x).sin_cos();
+				sdf.distance( &Vec3::new( c * p.x - s * p.y, s * p.x + c * p.y, p.z ) )
+			},
+			Sdf::Repeat { sdf, period, count } => {
+				let cell = |x : f32, period : f32, count : f32| if period > 0.0 {
+					x - period * (x / period).round().max( -count ).min( count )
+				} else {
+					x
+				};
+				sdf.distance( &Vec3::new( cell( p.x, period.x, count.x ), cell( p.y, period.y, count.y ), cell( p.z, period.z, count.z ) ) )
+			},
+			Sdf::Mandelbulb { power, iterations } => mandelbulb( p, *power, *iterations )
+		}
+	}
+
+	// Box around the surface
+	pub fn bounds( &self ) -> Aabb {
+		let ball = |r : f32| Aabb { min : Vec3::new( -r, -r, -r ), max : Vec3::new( r, r, r ) };
+		let grown = |b : Aabb, r : f32| Aabb { min : &b.min - &Vec3::new( r, r, r ), max : &b.max + &Vec3::new( r, r, r ) };
+		match self {
+			Sdf::Sphere { radius } => ball( *radius ),
+			Sdf::Cuboid { half } => Aabb { min : -half, max : half.clone() },
+			Sdf::Torus { major, minor } => {
+				let r = major + minor;
+				Aabb { min : Vec3::new( -r, -minor, -r ), max : Vec3::new( r, *minor, r ) }
+			},
+			Sdf::Cylinder { radius, half_height } => {
+				let r = Vec3::new( *radius, *half_height, *radius );
+				Aabb { min : -&r, max : r }
+			},
+			Sdf::Capsule { a, b, radius } => {
+				let mut ends = Aabb::empty();
+				ends.grow( a );
+				ends.grow( b );
+				grown( ends, *radius )
+			},
+			Sdf::Union( a, b ) => a.bounds().union( &b.bounds() ),
+			Sdf::Intersection( a, _ ) | Sdf::Difference( a, _ ) => a.bounds(),
+			// the blend stays within k / 4 of the sharp union
+			Sdf::SmoothUnion { a, b, k } => grown( a.bounds().union( &b.bounds() ), 0.25 * k.max( 0.0 ) ),
+			Sdf::Round { sdf, radius } => grown( sdf.bounds(), *radius ),
+			Sdf::Translate { sdf, offset } => sdf.bounds().transformed( &Mat4x4::translation( offset.x, offset.y, offset.z ) ),
+			Sdf::Rotate { sdf, rotation } => {
+				let mut b = Aabb::empty();
+				for c in sdf.bounds().corners().iter() {
+					b.grow( &rotation.apply_rotation( c ) );
+				}
+				b
+			},
+			Sdf::Scale { sdf, factor } => sdf.bounds().transformed( &Mat4x4::scale( *factor, *factor, *factor ) ),
+			Sdf::Twist { sdf, .. } => {
+				let b = sdf.bounds();
+				let r = reach_around_y( &b );
+				Aabb { min : Vec3::new( -r, b.min.y, -r ), max : Vec3::new( r, b.max.y, r ) }
+			},
+			// turning within the xy plane keeps the distance from the z axis
+			Sdf::Bend { sdf, .. } => {
+				let b = sdf.bounds();
+				let r = b.corners().iter().map(|c| (c.x * c.x + c.y * c.y).sqrt()).fold( 0.0, f32::max );
+				Aabb { min : Vec3::new( -r, -r, b.min.z ), max : Vec3::new( r, r, b.max.z ) }
+			},
+			Sdf::Repeat { sdf, period, count } => {
+				let b = sdf.bounds();
+				let spread = Vec3::new( period.x * count.x, period.y * count.y, period.z * count.z );
+				Aabb { min : &b.min - &spread, max : &b.max + &spread }
+			},
+			Sdf::Mandelbulb { .. } => ball( 1.2 )
+		}
+	}
+
+	// How much faster than the distance to the surface the field can change.
+	// Twists and bends stretch space by up to 1 + rate times the distance
+	// from their axis, so steps through them have to be shorter.
+	pub fn lipschitz( &self ) -> f32 {
+		match self {
+			Sdf::Union( a, b ) | Sdf::Intersection( a, b ) | Sdf::Difference( a, b ) => a.lipschitz().max( b.lipschitz() ),
+			Sdf::SmoothUnion { a, b, .. } => a.lipschitz().max( b.lipschitz() ),
+			Sdf::Round { sdf, .. } | Sdf::Translate { sdf, .. } | Sdf::Rotate { sdf, .. } | Sdf::Scale { sdf, .. } | Sdf::Repeat { sdf, .. } => sdf.lipschitz(),
+			Sdf::Twist { sdf, rate } => {
+				sdf.lipschitz() * (1.0 + rate.abs() * reach_around_y( &sdf.bounds() ))
+			},
+			Sdf::Bend { sdf, rate } => {
+				let r = sdf.bounds().corners().iter().map(|c| (c.x * c.x + c.y * c.y).sqrt()).fold( 0.0, f32::max );
+				sdf.lipschitz() * (1.0 + rate.abs() * r)
+			},
+			_ => 1.0
+		}
+	}
+}
+
+fn reach_around_y( b : &Aabb ) -> f32 {
+	b.corners().iter().map(|c| (c.x * c.x + c.z * c.z).sqrt()).fold( 0.0, f32::max )
+}
+
+// Distance estimate from the running derivative of z -> z^power + c
+fn mandelbulb( c : &Vec3, power : f32, iterations : u32 ) -> f32 {
+	let mut z = c.clone();
+	let mut dr = 1.0;
+	let mut r = z.length();
+	for _ in 0..iterations {
+		if r > 2.0 || r == 0.0 {
+			break;
+		}
+		let theta = (z.z / r).acos() * power;
+		let phi = z.y.atan2( z.x ) * power;
+		dr = power * r.powf( power - 1.0 ) * dr + 1.0;
+		let zr = r.powf( power );
+		z = zr * Vec3::new( theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos() ) + c;
+		r = z.length();
+	}
+	if r == 0.0 {
+		return 0.0;
+	}
+	0.5 * r.ln() * r / dr
+}
+
+const EPSILON : f32 = 1e-4;
+const MAX_STEPS : usize = 1000;
+
+// Implicit surface of an `Sdf`, found by sphere tracing within its bounds.
+// Normals come from central differences of the field. There are no uvs, so
+// give it textures which work from the position, like triplanar ones.
+#[allow(dead_code)]
+pub struct SdfSurface {
+	pub sdf : Sdf,
+	pub material : Box<Material>,
+	bounds : Aabb,
+	lipschitz : f32
+}
+
+#[allow(dead_code)]
+impl SdfSurface {
+	pub fn new( sdf : Sdf, material : Box<Material> ) -> SdfSurface {
+		let (b, lipschitz) = (sdf.bounds(), sdf.lipschitz());
+		let pad = Vec3::new( 1e-3, 1e-3, 1e-3 );
+		let bounds = Aabb { min : &b.min - &pad, max : &b.max + &pad };
+		SdfSurface { sdf, material, bounds, lipschitz }
+	}
+
+	// Stretch of the whole line through `ray` within the bounds, seen from
+	// behind them
+	fn span( &self, ray : &Ray ) -> Option<(f32, f32)> {
+		let b = &self.bounds;
+		let back = (&ray.origin - &b.centroid()).length() + (&b.max - &b.min).length();
+		let (enter, exit) = b.intersect( &ray.spawn( &ray.get_point( -back ), &ray.direction ), std::f32::INFINITY )?;
+		Some( (enter - back, exit - back) )
+	}
+
+	// Where the line first crosses the surface after `from`, first leaving
+	// the surface it may start on
+	fn next_crossing( &self, ray : &Ray, from : f32, to : f32 ) -> Option<f32> {
+		let side = self.sdf.distance( &ray.get_point( from + 2.0 * EPSILON ) ).signum();
+		let mut t = from;
+		let mut left = false;
+		for _ in 0..MAX_STEPS {
+			if t > to {
+				return None;
+			}
+			let d = side * self.sdf.distance( &ray.get_point( t ) );
+			if d < EPSILON {
+				if left {
+					return Some( t );
+				}
+				t += EPSILON;
+			} else {
+				left = true;
+				t += d / self.lipschitz;
+			}
+		}
+		None
+	}
+
+	fn normal( &self, p : &Vec3 ) -> Vec3 {
+		let h = 0.5 * EPSILON;
+		let d = |x : f32, y : f32, z : f32| self.sdf.distance( &(p + &Vec3::new( x, y, z )) );
+		let n = Vec3::new( d( h, 0.0, 0.0 ) - d( -h, 0.0, 0.0 ), d( 0.0, h, 0.0 ) - d( 0.0, -h, 0.0 ), d( 0.0, 0.0, h ) - d( 0.0, 0.0, -h ) );
+		if n.squre_length() > 0.0 { n.normalized() } else { Vec3::new( 0.0, 1.0, 0.0 ) }
+	}
+
+	fn hit_at( &self, ray : &Ray, distance : f32 ) -> Hit {
+		let pos = ray.get_point( distance );
+		let normal = self.normal( &pos );
+		let (tangent, bitangent) = tangent_frame( &normal, &Vec3::zero(), &Vec3::zero() );
+		Hit { distance, pos, normal, tangent, bitangent, u : 0.0, v : 0.0, uv_scale : 0.0, ray : ray.clone(), material : self.material.as_ref() }
+	}
+}
+
+impl Hitable for SdfSurface {
+	fn hit( &self, ray : &Ray ) -> Option<Hit> {
+		let (enter, exit) = self.span( ray )?;
+		if exit < EPSILON {
+			return None;
+		}
+		let t = self.next_crossing( ray, enter.max( 0.0 ), exit )?;
+		Some( self.hit_at( ray, t ) )
+	}
+
+	// Outside the bounds is outside, so the crossings from there go in and
+	// out in turn
+	fn intervals( &self, ray : &Ray ) -> Option<Vec<Interval>> {
+		let mut inside = Vec::new();
+		let (mut t, exit) = match self.span( ray ) {
+			Some(s) => s,
+			None => return Some( inside )
+		};
+		while let Some(enter) = self.next_crossing( ray, t, exit ) {
+			let leave = match self.next_crossing( ray, enter, exit ) {
+				Some(l) => l,
+				None => break
+			};
+			inside.push( Interval { enter : self.hit_at( ray, enter ), exit : self.hit_at( ray, leave ) } );
+			t = leave;
+		}
+		Some( inside )
+	}
+
+	fn bounds( &self ) -> Option<Aabb> {
+		Some( self.bounds.clone() )
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::texture::*;
+	use crate::random::*;
+
+	fn grey() -> Box<Material> {
+		Box::new( Lambertian { albedo : uniform( 0.5 ) } )
+	}
+
+	#[test]
+	fn test_sphere_tracing() {
+		let x = Vec3::new( 1.0, 0.0, 0.0 );
+		let from_left = Ray::new( &Vec3::new( -5.0, 0.0, 0.0 ), &x );
+
+		// a ball and a box apart, then melted together
+		let ball = Sdf::Sphere { radius : 1.0 };
+		let block = Sdf::Cuboid { half : Vec3::new( 0.5, 0.5, 0.5 ) }.translated( Vec3::new( 3.0, 0.0, 0.0 ) );
+		let apart = SdfSurface::new( Sdf::union( ball.clone(), block ), grey() );
+		let hit = apart.hit( &from_left ).unwrap();
+		assert!( (hit.distance - 4.0).abs() < 1e-3 );
+		assert!( (&hit.normal + &x).length() < 1e-2 );
+		let spans : Vec<(f32, f32)> = apart.intervals( &from_left ).unwrap().iter().map(|i| (i.enter.distance, i.exit.distance)).collect();
+		assert!( spans.len() == 2 && (spans[0].1 - 6.0).abs() < 1e-3 && (spans[1].0 - 7.5).abs() < 1e-3 && (spans[1].1 - 8.5).abs() < 1e-3 );
+
+		// out of the ball from inside, and on to the box
+		let inside = Ray::new( &Vec3::zero(), &x );
+		assert!( (apart.hit( &inside ).unwrap().distance - 1.0).abs() < 1e-3 );
+		let on_surface = inside.spawn( &apart.hit( &inside ).unwrap().pos, &x );
+		assert!( (apart.hit( &on_surface ).unwrap().distance - 1.5).abs() < 1e-3 );
+
+		let near = Sdf::Cuboid { half : Vec3::new( 0.5, 0.5, 0.5 ) }.translated( Vec3::new( 1.8, 0.0, 0.0 ) );
+		assert_eq!( SdfSurface::new( Sdf::union( ball.clone(), near.clone() ), grey() ).intervals( &from_left ).unwrap().len(), 2 );
+		let melted = SdfSurface::new( Sdf::smooth_union( ball, near, 1.0 ), grey() );
+		assert_eq!( melted.intervals( &from_left ).unwrap().len(), 1 );
+
+		// hits of twisted, bent and repeated shapes are on the surface and
+		// within the bounds, facing the ray. The estimate of the fractal jumps
+		// between iteration counts, so a few of its normals come out wrong.
+		let twisted = Sdf::Cuboid { half : Vec3::new( 0.8, 1.0, 0.2 ) }.twisted( 1.5 );
+		let bent = Sdf::Cylinder { radius : 0.2, half_height : 1.0 }.rotated( Mat4x4::z_rotation( std::f32::consts::PI / 2.0 ) ).bent( 0.8 );
+		let rows = Sdf::Sphere { radius : 0.2 }.repeated( Vec3::new( 0.5, 0.0, 0.5 ), Vec3::new( 2.0, 0.0, 2.0 ) );
+		let bulb = Sdf::Mandelbulb { power : 8.0, iterations : 8 };
+		for (sdf, wrong) in vec![ (twisted, 0), (bent, 0), (rows, 0), (bulb, 10) ] {
+			let surface = SdfSurface::new( sdf, grey() );
+			assert!( surface.lipschitz >= 1.0 );
+			let b = surface.bounds().unwrap();
+			let (mut hits, mut facing) = (0, 0);
+			for _ in 0..200 {
+				let from = random_unit_vector() * 4.0;
+				let ray = Ray::new( &from, &(random_unit_vector() * 0.5 - &from) );
+				if let Some(h) = surface.hit( &ray ) {
+					hits += 1;
+					assert!( surface.sdf.distance( &h.pos ).abs() < 2.0 * EPSILON );
+					assert!( h.pos.x >= b.min.x && h.pos.y >= b.min.y && h.pos.z >= b.min.z );
+					assert!( h.pos.x <= b.max.x && h.pos.y <= b.max.y && h.pos.z <= b.max.z );
+					if dot_product( &h.normal, &ray.direction ) < 0.0 {
+						facing += 1;
+					}
+				}
+			}
+			assert!( hits - facing <= wrong );
+			assert!( hits > 50 );
+		}
+	}
+}
@@ -0,0 +1,782 @@
+use crate::vec_math::*;
+use crate::hitable::*;
+use crate::material::*;
+use crate::random::*;
+
+use std::f32::consts::PI;
+
+// Where a ray meets one of the shapes below, in the frame of the shape
+struct Crossing {
+	distance : f32,
+	normal : Vec3,
+	dpdu : Vec3,
+	dpdv : Vec3,
+	u : f32,
+	v : f32
+}
+
+// Position and orientation of a shape, its local z runs along `axes.w`. Local
+// rays keep the length of their direction, so distances are the same in both.
+struct Frame {
+	origin : Vec3,
+	axes : Onb
+}
+
+#[allow(dead_code)]
+impl Frame {
+	fn along( origin : &Vec3, axis : &Vec3 ) -> Frame {
+		let w = if axis.squre_length() > 0.0 { axis.normalized() } else { Vec3::new( 0.0, 0.0, 1.0 ) };
+		Frame { origin : origin.clone(), axes : Onb::from_w( &w ) }
+	}
+
+	fn local( &self, ray : &Ray ) -> Ray {
+		Ray {
+			origin : self.axes.to_local( &(&ray.origin - &self.origin) ),
+			direction : self.axes.to_local( &ray.direction ),
+			..ray.clone()
+		}
+	}
+
+	fn world( &self, p : &Vec3 ) -> Vec3 {
+		&self.origin + self.axes.to_world( p )
+	}
+
+	fn bounds( &self, local : &Aabb ) -> Aabb {
+		let mut b = Aabb::empty();
+		for c in local.corners().iter() {
+			b.grow( &self.world( c ) );
+		}
+		b
+	}
+
+	fn hit<'a>( &self, ray : &Ray, c : &Crossing, material : &'a Material ) -> Hit<'a> {
+		let normal = self.axes.to_world( &c.normal ).normalized();
+		let (tangent, bitangent) = tangent_frame( &normal, &self.axes.to_world( &c.dpdu ), &self.axes.to_world( &c.dpdv ) );
+		Hit {
+			distance : c.distance,
+			pos : ray.get_point( c.distance ),
+			normal,
+			tangent,
+			bitangent,
+			u : c.u,
+			v : c.v,
+			uv_scale : uv_scale( &c.dpdu, &c.dpdv ),
+			ray : ray.clone(),
+			material
+		}
+	}
+}
+
+// Closed shapes worked out in their own frame
+trait Solid {
+	fn frame( &self ) -> Frame;
+
+	// Every crossing of the line through the local `ray` in order, entering
+	// and leaving in turn
+	fn crossings( &self, ray : &Ray ) -> Vec<Crossing>;
+
+	#[allow(dead_code)]
+	fn local_bounds( &self ) -> Aabb;
+
+	fn material( &self ) -> &Material;
+}
+
+fn solid_hit<'a, S : Solid>( solid : &'a S, ray : &Ray ) -> Option<Hit<'a>> {
+	let frame = solid.frame();
+	let c = solid.crossings( &frame.local( ray ) ).into_iter().find(|c| c.distance >= 0.0001)?;
+	Some( frame.hit( ray, &c, solid.material() ) )
+}
+
+fn solid_intervals<'a, S : Solid>( solid : &'a S, ray : &Ray ) -> Vec<Interval<'a>> {
+	let frame = solid.frame();
+	let all = solid.crossings( &frame.local( ray ) );
+	all.chunks( 2 ).filter(|pair| pair.len() == 2).map(|pair| Interval {
+		enter : frame.hit( ray, &pair[0], solid.material() ),
+		exit : frame.hit( ray, &pair[1], solid.material() )
+	}).collect()
+}
+
+#[allow(dead_code)]
+fn solid_bounds<S : Solid>( solid : &S ) -> Aabb {
+	solid.frame().bounds( &solid.local_bounds() )
+}
+
+// A line goes in and out of a convex shape once, which drops the doubles
+// where it passes through an edge
+fn convex( mut all : Vec<Crossing> ) -> Vec<Crossing> {
+	if all.len() < 2 {
+		return Vec::new();
+	}
+	all.sort_by(|a, b| a.distance.partial_cmp( &b.distance ).unwrap_or( std::cmp::Ordering::Equal ));
+	let last = all.pop().unwrap();
+	all.truncate( 1 );
+	all.push( last );
+	all
+}
+
+// Real roots of a t² + 2 b t + c in order
+fn quadratic( a : f32, b : f32, c : f32 ) -> Vec<f32> {
+	if a.abs() < 1e-12 {
+		return if b == 0.0 { Vec::new() } else { vec![ -c / (2.0 * b) ] };
+	}
+	let discriminant = b * b - a * c;
+	if discriminant < 0.0 {
+		return Vec::new();
+	}
+	let q = -(b + discriminant.sqrt().copysign( b ));
+	if q == 0.0 {
+		return vec![ 0.0, 0.0 ];
+	}
+	let (t0, t1) = (q / a, c / q);
+	if t0 < t1 { vec![ t0, t1 ] } else { vec![ t1, t0 ] }
+}
+
+// Real roots of the polynomial with coefficients `c`, lowest power first, in
+// [lo, hi] and in order. Between the roots of its derivative it is monotonic,
+// so every piece holds at most one root, found by bisection. Double roots
+// where a ray grazes a surface are missed.
+#[allow(dead_code)]
+fn poly_roots( c : &[f64], lo : f64, hi : f64 ) -> Vec<f64> {
+	if c.len() < 2 {
+		return Vec::new();
+	}
+	let eval = |x : f64| c.iter().rev().fold( 0.0, |acc, k| acc * x + k );
+	let derivative : Vec<f64> = c.iter().enumerate().skip( 1 ).map(|(i, k)| i as f64 * k).collect();
+
+	let mut ends = vec![ lo ];
+	ends.extend( poly_roots( &derivative, lo, hi ) );
+	ends.push( hi );
+
+	let mut roots : Vec<f64> = Vec::new();
+	for piece in ends.windows( 2 ) {
+		let (mut a, mut b) = (piece[0], piece[1]);
+		let fa = eval( a );
+		if fa * eval( b ) > 0.0 {
+			continue;
+		}
+		for _ in 0..64 {
+			let m = 0.5 * (a + b);
+			if fa * eval( m ) > 0.0 { a = m } else { b = m }
+		}
+		let root = 0.5 * (a + b);
+		if roots.last().map_or( true, |r| root - r > 1e-9 ) {
+			roots.push( root );
+		}
+	}
+	roots
+}
+
+// Turn around the local z axis in [0, 1), from x towards y
+fn turn( x : f32, y : f32 ) -> f32 {
+	let a = y.atan2( x ) / (2.0 * PI);
+	if a < 0.0 { a + 1.0 } else { a }
+}
+
+// Flat parallelogram spanned by two edges from a corner, with uvs along them.
+// The normal is `edge_u` × `edge_v`. Works as an area light.
+#[allow(dead_code)]
+pub struct Quad {
+	pub corner : Vec3,
+	pub edge_u : Vec3,
+	pub edge_v : Vec3,
+	pub material : Box<Material>
+}
+
+#[allow(dead_code)]
+impl Quad {
+	fn hit_at<'a>( &'a self, ray : &Ray, distance : f32, u : f32, v : f32 ) -> Hit<'a> {
+		let normal = cross_product( &self.edge_u, &self.edge_v ).normalized();
+		let (tangent, bitangent) = tangent_frame( &normal, &self.edge_u, &self.edge_v );
+		Hit {
+			distance,
+			pos : ray.get_point( distance ),
+			normal,
+			tangent,
+			bitangent,
+			u,
+			v,
+			uv_scale : uv_scale( &self.edge_u, &self.edge_v ),
+			ray : ray.clone(),
+			material : self.material.as_ref()
+		}
+	}
+}
+
+impl Hitable for Quad {
+	fn hit( &self, ray : &Ray ) -> Option<Hit> {
+		let n = cross_product( &self.edge_u, &self.edge_v );
+		let den = dot_product( &n, &ray.direction );
+		if den.abs() < 1e-12 {
+			return None;
+		}
+		let distance = dot_product( &n, &(&self.corner - &ray.origin) ) / den;
+		if distance < 0.0001 {
+			return None;
+		}
+
+		// coordinates along the edges
+		let p = &ray.get_point( distance ) - &self.corner;
+		let w = &n / n.squre_length();
+		let u = dot_product( &w, &cross_product( &p, &self.edge_v ) );
+		let v = dot_product( &w, &cross_product( &self.edge_u, &p ) );
+		if u < 0.0 || u > 1.0 || v < 0.0 || v > 1.0 {
+			return None;
+		}
+		Some( self.hit_at( ray, distance, u, v ) )
+	}
+
+	fn is_light( &self ) -> bool { self.material.is_emitter() }
+
+	fn sample_surface( &self, time : f32 ) -> Option<(Hit, f32)> {
+		let (u, v) = (random(), random());
+		let pos = &self.corner + u * &self.edge_u + v * &self.edge_v;
+		let normal = cross_product( &self.edge_u, &self.edge_v ).normalized();
+		let hit = self.hit_at( &Ray { time, ..Ray::new( &pos, &-&normal ) }, 0.0, u, v );
+		Some( (hit, self.surface_pdf( &pos, time )) )
+	}
+
+	fn surface_pdf( &self, _pos : &Vec3, _time : f32 ) -> f32 {
+		1.0 / cross_product( &self.edge_u, &self.edge_v ).length()
+	}
+
+	fn bounds( &self ) -> Option<Aabb> {
+		let mut b = Aabb::empty();
+		for p in [ Vec3::zero(), self.edge_u.clone(), self.edge_v.clone(), &self.edge_u + &self.edge_v ].iter() {
+			b.grow( &(&self.corner + p) );
+		}
+		Some( b )
+	}
+}
+
+// Round flat disk facing along `normal`, u goes around and v out from the
+// middle. Works as an area light.
+#[allow(dead_code)]
+pub struct Disk {
+	pub center : Vec3,
+	pub normal : Vec3,
+	pub radius : f32,
+	pub material : Box<Material>
+}
+
+fn disk_crossing( p : &Vec3, distance : f32, radius : f32 ) -> Crossing {
+	let rho = (p.x * p.x + p.y * p.y).sqrt().max( 1e-12 );
+	Crossing {
+		distance,
+		normal : Vec3::new( 0.0, 0.0, 1.0 ),
+		dpdu : 2.0 * PI * Vec3::new( -p.y, p.x, 0.0 ),
+		dpdv : (radius / rho) * Vec3::new( p.x, p.y, 0.0 ),
+		u : turn( p.x, p.y ),
+		v : (p.x * p.x + p.y * p.y).sqrt() / radius
+	}
+}
+
+impl Hitable for Disk {
+	fn hit( &self, ray : &Ray ) -> Option<Hit> {
+		let frame = Frame::along( &self.center, &self.normal );
+		let local = frame.local( ray );
+		if local.direction.z == 0.0 {
+			return None;
+		}
+		let distance = -local.origin.z / local.direction.z;
+		let p = local.get_point( distance );
+		if distance < 0.0001 || p.x * p.x + p.y * p.y > self.radius * self.radius {
+			return None;
+		}
+		Some( frame.hit( ray, &disk_crossing( &p, distance, self.radius ), self.material.as_ref() ) )
+	}
+
+	fn is_light( &self ) -> bool { self.material.is_emitter() }
+
+	fn sample_surface( &self, time : f32 ) -> Option<(Hit, f32)> {
+		let frame = Frame::along( &self.center, &self.normal );
+		let (r, phi) = (self.radius * random().sqrt(), 2.0 * PI * random());
+		let p = Vec3::new( r * phi.cos(), r * phi.sin(), 0.0 );
+		let pos = frame.world( &p );
+		let ray = Ray { time, ..Ray::new( &pos, &-&frame.axes.w ) };
+		let hit = frame.hit( &ray, &disk_crossing( &p, 0.0, self.radius ), self.material.as_ref() );
+		Some( (hit, self.surface_pdf( &pos, time )) )
+	}
+
+	fn surface_pdf( &self, _pos : &Vec3, _time : f32 ) -> f32 {
+		1.0 / (PI * self.radius * self.radius)
+	}
+
+	fn bounds( &self ) -> Option<Aabb> {
+		let n = self.normal.normalized();
+		let reach = |c : f32| self.radius * (1.0 - c * c).max( 0.0 ).sqrt();
+		let r = Vec3::new( reach( n.x ), reach( n.y ), reach( n.z ) );
+		Some( Aabb { min : &self.center - &r, max : &self.center + &r } )
+	}
+}
+
+// Box between two corners, or turned by a rotation. Every face has its own
+// uvs over the whole face.
+#[allow(dead_code)]
+pub struct Cuboid {
+	pub center : Vec3,
+	pub axes : Onb,
+	// distances from the center to the faces along the axes
+	pub half : Vec3,
+	pub material : Box<Material>
+}
+
+#[allow(dead_code)]
+impl Cuboid {
+	pub fn aligned( min : &Vec3, max : &Vec3, material : Box<Material> ) -> Cuboid {
+		let axes = Onb { u : Vec3::new( 1.0, 0.0, 0.0 ), v : Vec3::new( 0.0, 1.0, 0.0 ), w : Vec3::new( 0.0, 0.0, 1.0 ) };
+		Cuboid { center : (min + max) * 0.5, axes, half : (max - min) * 0.5, material }
+	}
+
+	// `rotation` turns the box around its center, translations in it are ignored
+	pub fn oriented( center : &Vec3, half : &Vec3, rotation : &Mat4x4, material : Box<Material> ) -> Cuboid {
+		let axis = |x, y, z| rotation.apply_rotation( &Vec3::new( x, y, z ) ).normalized();
+		let axes = Onb { u : axis( 1.0, 0.0, 0.0 ), v : axis( 0.0, 1.0, 0.0 ), w : axis( 0.0, 0.0, 1.0 ) };
+		Cuboid { center : center.clone(), axes, half : half.clone(), material }
+	}
+}
+
+fn components( v : &Vec3 ) -> [f32; 3] {
+	[ v.x, v.y, v.z ]
+}
+
+fn unit( axis : usize, length : f32 ) -> Vec3 {
+	match axis { 0 => Vec3::new( length, 0.0, 0.0 ), 1 => Vec3::new( 0.0, length, 0.0 ), _ => Vec3::new( 0.0, 0.0, length ) }
+}
+
+impl Solid for Cuboid {
+	fn frame( &self ) -> Frame {
+		Frame { origin : self.center.clone(), axes : self.axes.clone() }
+	}
+
+	fn crossings( &self, ray : &Ray ) -> Vec<Crossing> {
+		let (o, d, h) = (components( &ray.origin ), components( &ray.direction ), components( &self.half ));
+		// nearest exit and farthest entry, with the faces they go through
+		let (mut near, mut far) = ((std::f32::NEG_INFINITY, 0, 0.0), (std::f32::INFINITY, 0, 0.0));
+		for i in 0..3 {
+			if d[i] == 0.0 {
+				if o[i].abs() > h[i] {
+					return Vec::new();
+				}
+				continue;
+			}
+			let side = d[i].signum();
+			let (enter, exit) = ((-side * h[i] - o[i]) / d[i], (side * h[i] - o[i]) / d[i]);
+			if enter > near.0 { near = (enter, i, -side) }
+			if exit < far.0 { far = (exit, i, side) }
+		}
+		if near.0 > far.0 || near.0 == std::f32::NEG_INFINITY {
+			return Vec::new();
+		}
+
+		// uvs of a face run along the next two axes
+		let face = |(distance, i, side) : (f32, usize, f32)| {
+			let p = components( &ray.get_point( distance ) );
+			let (j, k) = ((i + 1) % 3, (i + 2) % 3);
+			Crossing {
+				distance,
+				normal : unit( i, side ),
+				dpdu : unit( j, 2.0 * h[j] ),
+				dpdv : unit( k, 2.0 * h[k] ),
+				u : 0.5 + 0.5 * p[j] / h[j],
+				v : 0.5 + 0.5 * p[k] / h[k]
+			}
+		};
+		vec![ face( near ), face( far ) ]
+	}
+
+	fn local_bounds( &self ) -> Aabb {
+		Aabb { min : -&self.half, max : self.half.clone() }
+	}
+
+	fn material( &self ) -> &Material { self.material.as_ref() }
+}
+
+// Side and flat caps of a cone cut off at the heights 0 and `h` of its local
+// frame, with radius `r0` at the bottom and `r1` at the top
+fn frustum( ray : &Ray, h : f32, r0 : f32, r1 : f32 ) -> Vec<Crossing> {
+	let (o, d) = (&ray.origin, &ray.direction);
+	let k = (r1 - r0) / h;
+	let ro = r0 + k * o.z;
+
+	let mut all = Vec::new();
+	let a = d.x * d.x + d.y * d.y - k * k * d.z * d.z;
+	let b = o.x * d.x + o.y * d.y - k * ro * d.z;
+	for t in quadratic( a, b, o.x * o.x + o.y * o.y - ro * ro ) {
+		let p = ray.get_point( t );
+		if p.z < 0.0 || p.z > h {
+			continue;
+		}
+		let r = r0 + k * p.z;
+		let slope = k / r.max( 1e-12 );
+		all.push( Crossing {
+			distance : t,
+			normal : Vec3::new( p.x, p.y, -k * r ),
+			dpdu : 2.0 * PI * Vec3::new( -p.y, p.x, 0.0 ),
+			dpdv : h * Vec3::new( slope * p.x, slope * p.y, 1.0 ),
+			u : turn( p.x, p.y ),
+			v : p.z / h
+		} );
+	}
+
+	for &(z, r, side) in [ (0.0, r0, -1.0), (h, r1, 1.0) ].iter() {
+		if r <= 0.0 || d.z == 0.0 {
+			continue;
+		}
+		let t = (z - o.z) / d.z;
+		let p = ray.get_point( t );
+		if p.x * p.x + p.y * p.y > r * r {
+			continue;
+		}
+		all.push( Crossing { normal : Vec3::new( 0.0, 0.0, side ), ..disk_crossing( &p, t, r ) } );
+	}
+	convex( all )
+}
+
+// Cylinder closed by flat caps, u goes around and v up the side from `base`
+// to `top`, the caps have the uvs of `Disk`
+#[allow(dead_code)]
+pub struct Cylinder {
+	pub base : Vec3,
+	pub top : Vec3,
+	pub radius : f32,
+	pub material : Box<Material>
+}
+
+impl Solid for Cylinder {
+	fn frame( &self ) -> Frame {
+		Frame::along( &self.base, &(&self.top - &self.base) )
+	}
+
+	fn crossings( &self, ray : &Ray ) -> Vec<Crossing> {
+		frustum( ray, (&self.top - &self.base).length(), self.radius, self.radius )
+	}
+
+	fn local_bounds( &self ) -> Aabb {
+		let r = self.radius;
+		Aabb { min : Vec3::new( -r, -r, 0.0 ), max : Vec3::new( r, r, (&self.top - &self.base).length() ) }
+	}
+
+	fn material( &self ) -> &Material { self.material.as_ref() }
+}
+
+// Cone from `base` to `top` closed by flat caps, pointed when `top_radius` is
+// zero. Uvs like `Cylinder`.
+#[allow(dead_code)]
+pub struct Cone {
+	pub base : Vec3,
+	pub top : Vec3,
+	pub base_radius : f32,
+	pub top_radius : f32,
+	pub material : Box<Material>
+}
+
+#[allow(dead_code)]
+impl Cone {
+	pub fn pointed( base : &Vec3, apex : &Vec3, radius : f32, material : Box<Material> ) -> Cone {
+		Cone { base : base.clone(), top : apex.clone(), base_radius : radius, top_radius : 0.0, material }
+	}
+}
+
+impl Solid for Cone {
+	fn frame( &self ) -> Frame {
+		Frame::along( &self.base, &(&self.top - &self.base) )
+	}
+
+	fn crossings( &self, ray : &Ray ) -> Vec<Crossing> {
+		frustum( ray, (&self.top - &self.base).length(), self.base_radius, self.top_radius )
+	}
+
+	fn local_bounds( &self ) -> Aabb {
+		let r = self.base_radius.max( self.top_radius );
+		Aabb { min : Vec3::new( -r, -r, 0.0 ), max : Vec3::new( r, r, (&self.top - &self.base).length() ) }
+	}
+
+	fn material( &self ) -> &Material { self.material.as_ref() }
+}
+
+// Ring around `axis` through `center`, `major` from the center to the middle
+// of the tube and `minor` across the tube. u goes around the axis and v
+// around the tube, starting outside.
+#[allow(dead_code)]
+pub struct Torus {
+	pub center : Vec3,
+	pub axis : Vec3,
+	pub major : f32,
+	pub minor : f32,
+	pub material : Box<Material>
+}
+
+impl Solid for Torus {
+	fn frame( &self ) -> Frame {
+		Frame::along( &self.center, &self.axis )
+	}
+
+	// (|p|² + R² - r²)² = 4 R² (x² + y²) along the ray, solved from the point
+	// nearest to the center for precision
+	fn crossings( &self, ray : &Ray ) -> Vec<Crossing> {
+		let (big, small) = (self.major, self.minor);
+		let reach = quadratic( 1.0, dot_product( &ray.origin, &ray.direction ), ray.origin.squre_length() - (big + small) * (big + small) );
+		if reach.len() < 2 {
+			return Vec::new();
+		}
+		let shift = -dot_product( &ray.origin, &ray.direction );
+		let o = ray.get_point( shift );
+		let d = &ray.direction;
+
+		let f = |x : f32| x as f64;
+		let r2 = f( big * big );
+		let k = f( o.squre_length() ) + r2 - f( small * small );
+		let b = f( dot_product( &o, d ) );
+		let c = [
+			k * k - 4.0 * r2 * f( o.x * o.x + o.y * o.y ),
+			4.0 * b * k - 8.0 * r2 * f( o.x * d.x + o.y * d.y ),
+			4.0 * b * b + 2.0 * k - 4.0 * r2 * f( d.x * d.x + d.y * d.y ),
+			4.0 * b,
+			1.0
+		];
+
+		poly_roots( &c, f( reach[0] - shift ), f( reach[1] - shift ) ).iter().map(|s| {
+			let t = *s as f32 + shift;
+			let p = ray.get_point( t );
+			let rho = (p.x * p.x + p.y * p.y).sqrt().max( 1e-12 );
+			Crossing {
+				distance : t,
+				normal : Vec3::new( p.x * (1.0 - big / rho), p.y * (1.0 - big / rho), p.z ),
+				dpdu : 2.0 * PI * Vec3::new( -p.y, p.x, 0.0 ),
+				dpdv : 2.0 * PI * Vec3::new( -p.z * p.x / rho, -p.z * p.y / rho, rho - big ),
+				u : turn( p.x, p.y ),
+				v : turn( rho - big, p.z )
+			}
+		}).collect()
+	}
+
+	fn local_bounds( &self ) -> Aabb {
+		let (r, h) = (self.major + self.minor, self.minor);
+		Aabb { min : Vec3::new( -r, -r, -h ), max : Vec3::new( r, r, h ) }
+	}
+
+	fn material( &self ) -> &Material { self.material.as_ref() }
+}
+
+// Everything within `radius` of the segment from `a` to `b`. u goes around
+// and v along it, from the tip of the cap at `a`.
+#[allow(dead_code)]
+pub struct Capsule {
+	pub a : Vec3,
+	pub b : Vec3,
+	pub radius : f32,
+	pub material : Box<Material>
+}
+
+impl Solid for Capsule {
+	fn frame( &self ) -> Frame {
+		Frame::along( &self.a, &(&self.b - &self.a) )
+	}
+
+	// union of the side and the balls at the ends, which is still convex
+	fn crossings( &self, ray : &Ray ) -> Vec<Crossing> {
+		let (o, d) = (&ray.origin, &ray.direction);
+		let (h, r) = ((&self.b - &self.a).length(), self.radius);
+
+		let mut distances = quadratic( d.x * d.x + d.y * d.y, o.x * d.x + o.y * d.y, o.x * o.x + o.y * o.y - r * r );
+		distances.retain(|t| { let z = o.z + t * d.z; z >= 0.0 && z <= h });
+		for z in [ 0.0, h ].iter() {
+			let oc = o - &Vec3::new( 0.0, 0.0, *z );
+			distances.extend( quadratic( 1.0, dot_product( &oc, d ), oc.squre_length() - r * r ) );
+		}
+
+		convex( distances.iter().map(|t| {
+			let p = ray.get_point( *t );
+			let axis = Vec3::new( 0.0, 0.0, p.z.max( 0.0 ).min( h ) );
+			// v follows the height, on the caps the point also moves in towards the axis
+			let rho2 = (p.x * p.x + p.y * p.y).max( 1e-12 );
+			let inward = (p.z - axis.z) / rho2;
+			Crossing {
+				distance : *t,
+				normal : &p - &axis,
+				dpdu : 2.0 * PI * Vec3::new( -p.y, p.x, 0.0 ),
+				dpdv : (h + 2.0 * r) * Vec3::new( -inward * p.x, -inward * p.y, 1.0 ),
+				u : turn( p.x, p.y ),
+				v : ((p.z + r) / (h + 2.0 * r)).max( 0.0 ).min( 1.0 )
+			}
+		}).collect() )
+	}
+
+	fn local_bounds( &self ) -> Aabb {
+		let r = self.radius;
+		Aabb { min : Vec3::new( -r, -r, -r ), max : Vec3::new( r, r, (&self.b - &self.a).length() + r ) }
+	}
+
+	fn material( &self ) -> &Material { self.material.as_ref() }
+}
+
+impl Hitable for Cuboid {
+	fn hit( &self, ray : &Ray ) -> Option<Hit> { solid_hit( self, ray ) }
+	fn intervals( &self, ray : &Ray ) -> Option<Vec<Interval>> { Some( solid_intervals( self, ray ) ) }
+	fn bounds( &self ) -> Option<Aabb> { Some( solid_bounds( self ) ) }
+}
+
+impl Hitable for Cylinder {
+	fn hit( &self, ray : &Ray ) -> Option<Hit> { solid_hit( self, ray ) }
+	fn intervals( &self, ray : &Ray ) -> Option<Vec<Interval>> { Some( solid_intervals( self, ray ) ) }
+	fn bounds( &self ) -> Option<Aabb> { Some( solid_bounds( self ) ) }
+}
+
+impl Hitable for Cone {
+	fn hit( &self, ray : &Ray ) -> Option<Hit> { solid_hit( self, ray ) }
+	fn intervals( &self, ray : &Ray ) -> Option<Vec<Interval>> { Some( solid_intervals( self, ray ) ) }
+	fn bounds( &self ) -> Option<Aabb> { Some( solid_bounds( self ) ) }
+}
+
+impl Hitable for Torus {
+	fn hit( &self, ray : &Ray ) -> Option<Hit> { solid_hit( self, ray ) }
+	fn intervals( &self, ray : &Ray ) -> Option<Vec<Interval>> { Some( solid_intervals( self, ray ) ) }
+	fn bounds( &self ) -> Option<Aabb> { Some( solid_bounds( self ) ) }
+}
+
+impl Hitable for Capsule {
+	fn hit( &self, ray : &Ray ) -> Option<Hit> { solid_hit( self, ray ) }
+	fn intervals( &self, ray : &Ray ) -> Option<Vec<Interval>> { Some( solid_intervals( self, ray ) ) }
+	fn bounds( &self ) -> Option<Aabb> { Some( solid_bounds( self ) ) }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::texture::*;
+
+	fn grey() -> Box<Material> {
+		Box::new( Lambertian { albedo : uniform( 0.5 ) } )
+	}
+
+	fn near( a : &Vec3, b : &Vec3 ) -> bool {
+		(a - b).length() < 1e-3
+	}
+
+	fn inside( b : &Aabb, p : &Vec3 ) -> bool {
+		let e = 1e-3;
+		p.x >= b.min.x - e && p.y >= b.min.y - e && p.z >= b.min.z - e &&
+		p.x <= b.max.x + e && p.y <= b.max.y + e && p.z <= b.max.z + e
+	}
+
+	#[test]
+	fn test_shapes() {
+		let x = Vec3::new( 1.0, 0.0, 0.0 );
+		let from_left = Ray::new( &Vec3::new( -5.0, 0.0, 0.0 ), &x );
+		let turned = Mat4x4::axis_rotation( &Vec3::new( 0.0, 0.0, 1.0 ), PI / 6.0 );
+		let slant = 1.0 / (PI / 6.0).cos();
+		let shapes : Vec<(Box<Hitable>, f32, Vec3, f32)> = vec![
+			(Box::new( Cuboid::aligned( &Vec3::new( -1.0, -1.0, -1.0 ), &Vec3::new( 2.0, 1.0, 1.0 ), grey() ) ), 4.0, -&x, 7.0),
+			(Box::new( Cuboid::oriented( &Vec3::zero(), &Vec3::ones(), &turned, grey() ) ), 5.0 - slant, Vec3::new( -0.8660, -0.5, 0.0 ), 5.0 + slant),
+			(Box::new( Cylinder { base : Vec3::new( 0.0, -1.0, 0.0 ), top : Vec3::new( 0.0, 1.0, 0.0 ), radius : 0.5, material : grey() } ), 4.5, -&x, 5.5),
+			(Box::new( Cylinder { base : Vec3::new( -1.0, 0.0, 0.0 ), top : Vec3::new( 1.0, 0.0, 0.0 ), radius : 0.5, material : grey() } ), 4.0, -&x, 6.0),
+			(Box::new( Cone::pointed( &Vec3::new( 0.0, -1.0, 0.0 ), &Vec3::new( 0.0, 1.0, 0.0 ), 1.0, grey() ) ), 4.5, Vec3::new( -0.8944, 0.4472, 0.0 ), 5.5),
+			(Box::new( Torus { center : Vec3::zero(), axis : Vec3::new( 0.0, 1.0, 0.0 ), major : 2.0, minor : 0.5, material : grey() } ), 2.5, -&x, 3.5),
+			(Box::new( Capsule { a : Vec3::new( 0.0, 0.0, -1.0 ), b : Vec3::new( 0.0, 0.0, 1.0 ), radius : 0.5, material : grey() } ), 4.5, -&x, 5.5),
+			(Box::new( Capsule { a : Vec3::new( -1.0, 0.0, 0.0 ), b : Vec3::new( 1.0, 0.0, 0.0 ), radius : 0.5, material : grey() } ), 3.5, -&x, 6.5)
+		];
+
+		for (shape, enter, normal, exit) in shapes.iter() {
+			let hit = shape.hit( &from_left ).unwrap();
+			assert!( (hit.distance - enter).abs() < 1e-3 );
+			assert!( near( &hit.normal, normal ) );
+			assert!( dot_product( &hit.tangent, &hit.normal ).abs() < 1e-3 && dot_product( &hit.bitangent, &hit.normal ).abs() < 1e-3 );
+			assert!( hit.u >= 0.0 && hit.u <= 1.0 && hit.v >= 0.0 && hit.v <= 1.0 );
+
+			// out of the far side from inside, with the normal still outwards
+			let inner = Ray::new( &from_left.get_point( 0.5 * (enter + exit) ), &x );
+			let out = shape.hit( &inner ).unwrap();
+			assert!( (out.distance - 0.5 * (exit - enter)).abs() < 1e-3 );
+			assert!( dot_product( &out.normal, &x ) > 0.0 );
+			let spans = shape.intervals( &inner ).unwrap();
+			assert!( spans.iter().any(|s| (s.exit.distance - out.distance).abs() < 1e-3) );
+
+			// whatever is hit lies in the bounds
+			let bounds = shape.bounds().unwrap();
+			for _ in 0..200 {
+				let from = random_unit_vector() * 6.0;
+				let ray = Ray::new( &from, &(random_unit_vector() * 1.5 - &from) );
+				if let Some(h) = shape.hit( &ray ) {
+					assert!( inside( &bounds, &h.pos ) );
+				}
+			}
+		}
+
+		// the hole of the torus, and through both sides of the ring
+		let torus = &shapes[5].0;
+		assert!( torus.hit( &Ray::new( &Vec3::new( 0.0, 5.0, 0.0 ), &Vec3::new( 0.0, -1.0, 0.0 ) ) ).is_none() );
+		assert_eq!( torus.intervals( &from_left ).unwrap().len(), 2 );
+	}
+
+	#[test]
+	fn test_flat_lights() {
+		let light = Box::new( BlackBody { radiation : uniform( 1.0 ), spectrum : None } );
+		let quad = Quad { corner : Vec3::new( -1.0, 2.0, -0.5 ), edge_u : Vec3::new( 2.0, 0.0, 0.0 ), edge_v : Vec3::new( 0.0, 0.0, 1.0 ), material : light };
+		assert!( quad.is_light() );
+		assert!( (quad.surface_pdf( &Vec3::zero(), 0.0 ) - 0.5).abs() < 1e-6 );
+
+		// faces down onto the origin, and is hit where it was sampled
+		let down = Vec3::new( 0.0, -1.0, 0.0 );
+		for _ in 0..100 {
+			let (s, _) = quad.sample_surface( 0.0 ).unwrap();
+			assert!( near( &s.normal, &down ) );
+			let ray = Ray::new( &Vec3::zero(), &s.pos );
+			let h = quad.hit( &ray ).unwrap();
+			assert!( near( &h.pos, &s.pos ) && (h.u - s.u).abs() < 1e-3 && (h.v - s.v).abs() < 1e-3 );
+		}
+		assert!( quad.hit( &Ray::new( &Vec3::zero(), &Vec3::new( 1.01, 2.0, 0.0 ) ) ).is_none() );
+
+		let disk = Disk { center : Vec3::new( 0.0, 2.0, 0.0 ), normal : down.clone(), radius : 0.5, material : grey() };
+		let bounds = disk.bounds().unwrap();
+		for _ in 0..100 {
+			let (s, pdf) = disk.sample_surface( 0.0 ).unwrap();
+			assert!( (pdf - 1.0 / (PI * 0.25)).abs() < 1e-4 );
+			assert!( inside( &bounds, &s.pos ) && (s.pos.y - 2.0).abs() < 1e-5 && s.v <= 1.0 );
+			assert!( near( &disk.hit( &Ray::new( &Vec3::zero(), &s.pos ) ).unwrap().pos, &s.pos ) );
+		}
+	}
+
+	// Moving a ray slightly moves its crossing by dpdu du + dpdv dv
+	fn check_derivatives<S : Solid>( solid : &S ) {
+		let frame = solid.frame();
+		let mut checked = 0;
+		for _ in 0..400 {
+			let from = random_unit_vector() * 6.0;
+			let ray = Ray::new( &from, &(random_unit_vector() * 0.5 - &from) );
+			let moved = Ray::new( &(&from + random_unit_vector() * 1e-3), &ray.direction );
+			let (all_a, all_b) = (solid.crossings( &frame.local( &ray ) ), solid.crossings( &frame.local( &moved ) ));
+			let (a, b) = match (all_a.first(), all_b.first()) {
+				(Some(a), Some(b)) => (a, b),
+				_ => continue
+			};
+			// the same face, away from the seams
+			let (du, dv) = (b.u - a.u, b.v - a.v);
+			if dot_product( &a.normal.normalized(), &b.normal.normalized() ) < 0.999 || du.abs() > 0.5 || dv.abs() > 0.5 {
+				continue;
+			}
+			let dp = &frame.local( &moved ).get_point( b.distance ) - &frame.local( &ray ).get_point( a.distance );
+			let predicted = du * &a.dpdu + dv * &a.dpdv;
+			assert!( (&dp - &predicted).length() < 0.05 * dp.length() + 1e-5, "{:?} {:?}", dp, predicted );
+			checked += 1;
+		}
+		assert!( checked > 50 );
+	}
+
+	#[test]
+	fn test_uv_derivatives() {
+		let turned = Mat4x4::axis_rotation( &Vec3::new( 0.0, 0.0, 1.0 ), PI / 6.0 );
+		check_derivatives( &Cuboid::oriented( &Vec3::zero(), &Vec3::new( 1.0, 0.5, 2.0 ), &turned, grey() ) );
+		check_derivatives( &Cylinder { base : Vec3::new( 0.0, -1.0, 0.0 ), top : Vec3::new( 0.0, 1.0, 0.0 ), radius : 0.7, material : grey() } );
+		check_derivatives( &Cone { base : Vec3::new( 0.0, -1.0, 0.0 ), top : Vec3::new( 0.0, 1.0, 0.0 ), base_radius : 1.0, top_radius : 0.3, material : grey() } );
+		check_derivatives( &Torus { center : Vec3::zero(), axis : Vec3::new( 0.0, 1.0, 0.0 ), major : 1.5, minor : 0.5, material : grey() } );
+		check_derivatives( &Capsule { a : Vec3::new( 0.0, 0.0, -1.0 ), b : Vec3::new( 0.0, 0.0, 1.0 ), radius : 0.8, material : grey() } );
+
+		// a face of a box filters an image like a quad of the same size
+		let cuboid = Cuboid::aligned( &Vec3::new( -1.0, -1.0, 4.0 ), &Vec3::new( 1.0, 1.0, 6.0 ), grey() );
+		let quad = Quad { corner : Vec3::new( -1.0, -1.0, 4.0 ), edge_u : Vec3::new( 2.0, 0.0, 0.0 ), edge_v : Vec3::new( 0.0, 2.0, 0.0 ), material : grey() };
+		let ray = Ray { spread : 0.01, ..Ray::new( &Vec3::zero(), &Vec3::new( 0.3, 0.2, 4.0 ) ) };
+		let (a, b) = (cuboid.hit( &ray ).unwrap().tex_coord(), quad.hit( &ray ).unwrap().tex_coord());
+		assert!( (a.u - b.u).abs() < 1e-5 && (a.v - b.v).abs() < 1e-5 );
+		assert!( (a.width * a.uv_scale - b.width * b.uv_scale).abs() < 1e-6, "{} {}", a.width * a.uv_scale, b.width * b.uv_scale );
+	}
+}
@@ -0,0 +1,394 @@
+use crate::vec_math::*;
+use crate::hitable::*;
+use crate::color::*;
+use crate::random::*;
+use crate::integrator::*;
+
+// Spectral path tracing with hero wavelengths (Wilkie et al. 2014). Every
+// path carries four wavelengths spread evenly over the visible range, RGB
+// reflectances and emissions are turned into spectra with Smits' method and
+// the result is converted back through the CIE XYZ matching functions.
+
+pub const LAMBDA_MIN : f32 = 380.0;
+pub const LAMBDA_MAX : f32 = 720.0;
+const WAVELENGTHS : usize = 4;
+
+// Smits (1999), ten bins from 380 to 720 nm
+const SMITS_WHITE   : [f32; 10] = [1.0000, 1.0000, 0.9999, 0.9993, 0.9992, 0.9998, 1.0000, 1.0000, 1.0000, 1.0000];
+const SMITS_CYAN    : [f32; 10] = [0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0000, 0.0000, 0.0000];
+const SMITS_MAGENTA : [f32; 10] = [1.0000, 1.0000, 0.9685, 0.2229, 0.0000, 0.0458, 0.8369, 1.0000, 1.0000, 0.9959];
+const SMITS_YELLOW  : [f32; 10] = [0.0001, 0.0000, 0.1088, 0.6651, 1.0000, 1.0000, 0.9996, 0.9586, 0.9685, 0.9840];
+const SMITS_RED     : [f32; 10] = [0.1012, 0.0515, 0.0000, 0.0000, 0.0000, 0.0000, 0.8325, 1.0149, 1.0149, 1.0149];
+const SMITS_GREEN   : [f32; 10] = [0.0000, 0.0000, 0.0273, 0.7937, 1.0000, 0.9418, 0.1719, 0.0000, 0.0000, 0.0025];
+const SMITS_BLUE    : [f32; 10] = [1.0000, 1.0000, 0.8916, 0.3323, 0.0000, 0.0000, 0.0003, 0.0369, 0.0483, 0.0496];
+
+// Value of the spectrum of `c` at `lambda` nanometers
+pub fn rgb_to_spectrum( c : &Color, lambda : f32 ) -> f32 {
+	let bin = ((lambda - LAMBDA_MIN) / (LAMBDA_MAX - LAMBDA_MIN) * 10.0).max(0.0) as usize;
+	let bin = bin.min(9);
+	let (r, g, b) = (c.r, c.g, c.b);
+
+	if r <= g && r <= b {
+		let s = r * SMITS_WHITE[bin];
+		if g <= b {
+			s + (g - r) * SMITS_CYAN[bin] + (b - g) * SMITS_BLUE[bin]
+		} else {
+			s + (b - r) * SMITS_CYAN[bin] + (g - b) * SMITS_GREEN[bin]
+		}
+	} else if g <= r && g <= b {
+		let s = g * SMITS_WHITE[bin];
+		if r <= b {
+			s + (r - g) * SMITS_MAGENTA[bin] + (b - r) * SMITS_BLUE[bin]
+		} else {
+			s + (b - g) * SMITS_MAGENTA[bin] + (r - b) * SMITS_RED[bin]
+		}
+	} else {
+		let s = b * SMITS_WHITE[bin];
+		if r <= g {
+			s + (r - b) * SMITS_YELLOW[bin] + (g - r) * SMITS_GREEN[bin]
+		} else {
+			s + (g - b) * SMITS_YELLOW[bin] + (r - g) * SMITS_RED[bin]
+		}
+	}
+}
+
+fn lobe( x : f32, mu : f32, sigma_low : f32, sigma_high : f32 ) -> f32 {
+	let t = (x - mu) / if x < mu { sigma_low } else { sigma_high };
+	(-0.5 * t * t).exp()
+}
+
+// CIE 1931 matching functions, multi-lobe fit of Wyman et al. (2013)
+pub fn cie_xyz( lambda : f32 ) -> Vec3 {
+	let x = 1.056 * lobe( lambda, 599.8, 37.9, 31.0 )
+	      + 0.362 * lobe( lambda, 442.0, 16.0, 26.7 )
+	      - 0.065 * lobe( lambda, 501.1, 20.4, 26.2 );
+	let y = 0.821 * lobe( lambda, 568.8, 46.9, 40.5 )
+	      + 0.286 * lobe( lambda, 530.9, 16.3, 31.1 );
+	let z = 1.217 * lobe( lambda, 437.0, 11.8, 36.0 )
+	      + 0.681 * lobe( lambda, 459.0, 26.0, 13.8 );
+	Vec3::new( x, y, z )
+}
+
+// Linear sRGB
+pub fn xyz_to_rgb( xyz : &Vec3 ) -> Color {
+	Color::new(
+		 3.2406 * xyz.x - 1.5372 * xyz.y - 0.4986 * xyz.z,
+		-0.9689 * xyz.x + 1.8758 * xyz.y + 0.0415 * xyz.z,
+		 0.0557 * xyz.x - 0.2040 * xyz.y + 1.0570 * xyz.z )
+}
+
+// Mean of `spectrum` weighted by the matching functions over the visible range
+fn spectrum_to_xyz<F : Fn( f32 ) -> f32>( spectrum : F, steps : usize ) -> Vec3 {
+	let mut xyz = Vec3::zero();
+	for i in 0..steps {
+		let lambda = LAMBDA_MIN + (i as f32 + 0.5) / steps as f32 * (LAMBDA_MAX - LAMBDA_MIN);
+		xyz = xyz + cie_xyz( lambda ) * spectrum( lambda );
+	}
+	xyz / steps as f32
+}
+
+// Colour of the constant spectrum, divided out so that white stays white
+pub fn equal_energy_white() -> Color {
+	xyz_to_rgb( &spectrum_to_xyz( |_| 1.0, 1000 ) )
+}
+
+const PLANCK : f64 = 6.62607015e-34;
+const LIGHT_SPEED : f64 = 2.99792458e8;
+const BOLTZMANN : f64 = 1.380649e-23;
+const STEFAN_BOLTZMANN : f64 = 5.670374419e-8;
+// lumens per watt at 555 nm
+const MAX_EFFICACY : f32 = 683.0;
+
+// Spectrum of a black body at `temperature` Kelvin, scaled to a luminance of
+// one. `colour` is its RGB colour under the same white balance as `Spectral`,
+// so RGB and spectral renders of an emitter agree.
+#[derive( Copy, Clone, Debug )]
+pub struct Planck {
+	pub temperature : f32,
+	colour : Color,
+	scale : f32
+}
+
+impl Planck {
+	// Colder bodies hardly glow and can't be scaled to a luminance of one, they
+	// get the spectrum of `MIN_GLOW`
+	pub fn new( temperature : f32 ) -> Planck {
+		let temperature = temperature.max( MIN_GLOW );
+		let unscaled = Planck { temperature, colour : Color::new( 1.0, 1.0, 1.0 ), scale : 1.0 };
+		let white = equal_energy_white();
+		let c = xyz_to_rgb( &spectrum_to_xyz( |lambda| unscaled.radiance( lambda ), 200 ) );
+		let c = Color::new( (c.r / white.r).max(0.0), (c.g / white.g).max(0.0), (c.b / white.b).max(0.0) );
+		let scale = 1.0 / c.luminance();
+		Planck { temperature, colour : c * scale, scale }
+	}
+
+	// Planck's law, spectral radiance in W / (sr m^2 nm) at `lambda` nanometers
+	pub fn radiance( &self, lambda : f32 ) -> f32 {
+		let l = lambda as f64 * 1e-9;
+		let t = self.temperature as f64;
+		let b = 2.0 * PLANCK * LIGHT_SPEED * LIGHT_SPEED / l.powi(5) / ((PLANCK * LIGHT_SPEED / (l * BOLTZMANN * t)).exp() - 1.0);
+		(b * 1e-9) as f32
+	}
+
+	// Spectrum with a luminance of one
+	pub fn value( &self, lambda : f32 ) -> f32 {
+		self.radiance( lambda ) * self.scale
+	}
+
+	pub fn colour( &self ) -> Color {
+		self.colour
+	}
+
+	// Luminance of the unscaled spectrum in candela per square metre
+	pub fn luminance( &self ) -> f32 {
+		let steps = 400;
+		let step = (LAMBDA_MAX - LAMBDA_MIN) / steps as f32;
+		let mut luminous = 0.0;
+		for i in 0..steps {
+			let lambda = LAMBDA_MIN + (i as f32 + 0.5) * step;
+			luminous += cie_xyz( lambda ).y * self.radiance( lambda ) * step;
+		}
+		MAX_EFFICACY * luminous
+	}
+
+	// Lumens per watt of radiated power
+	pub fn efficacy( &self ) -> f32 {
+		// Stefan-Boltzmann gives the radiance over all wavelengths
+		let total = STEFAN_BOLTZMANN * (self.temperature as f64).powi(4) / std::f64::consts::PI;
+		self.luminance() / total as f32
+	}
+}
+
+// Black body colours with their physical luminance, tabulated for looking up
+// many temperatures quickly. Nothing below `MIN_GLOW` Kelvin is visible.
+pub struct PlanckTable {
+	step : f32,
+	colours : Vec<Color>
+}
+
+const MIN_GLOW : f32 = 500.0;
+
+impl PlanckTable {
+	pub fn new( max_temperature : f32, step : f32 ) -> PlanckTable {
+		let count = (max_temperature / step).ceil().max( 1.0 ) as usize + 1;
+		let colours = (0..count).map(|i| {
+			let t = i as f32 * step;
+			if t < MIN_GLOW {
+				return Color::new( 0.0, 0.0, 0.0 );
+			}
+			let planck = Planck::new( t );
+			planck.colour() * planck.luminance()
+		}).collect();
+		PlanckTable { step, colours }
+	}
+
+	// Radiance of a black body at `temperature`, interpolated and clamped to
+	// the table
+	pub fn value( &self, temperature : f32 ) -> Color {
+		let x = (temperature / self.step).max( 0.0 );
+		let i = (x as usize).min( self.colours.len() - 1 );
+		let j = (i + 1).min( self.colours.len() - 1 );
+		let f = (x - i as f32).min( 1.0 );
+		self.colours[i] * (1.0 - f) + self.colours[j] * f
+	}
+}
+
+pub struct Spectral {
+	// colour of the constant spectrum, divided out so that white stays white
+	white : Color
+}
+
+impl Spectral {
+
+	pub fn new() -> Spectral {
+		Spectral { white : equal_energy_white() }
+	}
+
+	pub fn colour( &self, ray : &Ray, objects : &Vec<Box<Hitable>>, settings : &RenderSettings ) -> Color
+	{
+		let range = LAMBDA_MAX - LAMBDA_MIN;
+		let hero = random() * range;
+		let mut lambdas = [0.0; WAVELENGTHS];
+		for i in 0..WAVELENGTHS {
+			lambdas[i] = LAMBDA_MIN + (hero + i as f32 * range / WAVELENGTHS as f32) % range;
+		}
+
+		let mut throughput = [1.0; WAVELENGTHS];
+		let mut radiance = [0.0; WAVELENGTHS];
+		let mut ray = Ray { wavelength : lambdas[0], ..ray.clone() };
+
+		let mut media = MediumStack::new();
+
+		for _ in 0..settings.max_depth + 1 {
+			let (object, hit) = match closest_hit( &ray, objects ) {
+				Some(h) => h,
+				None => {
+					let sky = background( &ray.direction );
+					for i in 0..WAVELENGTHS {
+						radiance[i] += throughput[i] * rgb_to_spectrum( &sky, lambdas[i] );
+					}
+					break;
+				}
+			};
+
+			let tint = media.transmittance( hit.distance );
+			for i in 0..WAVELENGTHS {
+				throughput[i] *= rgb_to_spectrum( &tint, lambdas[i] );
+			}
+
+			// dielectrics nested in each other, with the indices of the hero wavelength
+			let interior = hit.material.interior( &hit );
+			let entering = dot_product( &ray.direction, &hit.normal ) < 0.0;
+			let outside = match interior {
+				Some(ref i) => match media.crossing( object, i, entering ) {
+					Some(outside) => Some(outside),
+					None => {
+						media = media.crossed( object, i, entering );
+						ray = ray.spawn( &hit.pos, &ray.direction );
+						continue;
+					}
+				},
+				None => None
+			};
+
+			let wo = -&ray.direction;
+			for i in 0..WAVELENGTHS {
+				radiance[i] += throughput[i] * hit.material.emit_spectrum( &hit, &wo, lambdas[i] );
+			}
+
+			let sample = match outside {
+				Some(outside) => hit.material.sample_nested( &hit, outside ),
+				None => hit.material.sample( &hit )
+			};
+			let sample = match sample {
+				Some(s) => s,
+				None => break
+			};
+			if let Some(ref i) = interior {
+				if (dot_product( &sample.ray.direction, &hit.normal ) < 0.0) == entering {
+					media = media.crossed( object, i, entering );
+				}
+			}
+
+			// the scattered ray was refracted for the hero wavelength only, the
+			// others are dropped and the hero stands in for all of them
+			if hit.material.is_dispersive() && throughput[1..].iter().any(|t| *t != 0.0) {
+				throughput[0] *= WAVELENGTHS as f32;
+				for t in throughput[1..].iter_mut() {
+					*t = 0.0;
+				}
+			}
+
+			let mut alive = false;
+			for i in 0..WAVELENGTHS {
+				throughput[i] *= rgb_to_spectrum( &sample.weight, lambdas[i] );
+				alive |= throughput[i] != 0.0;
+			}
+			if !alive {
+				break;
+			}
+			ray = sample.ray;
+		}
+
+		let mut xyz = Vec3::zero();
+		for i in 0..WAVELENGTHS {
+			xyz = xyz + cie_xyz( lambdas[i] ) * (radiance[i] / WAVELENGTHS as f32);
+		}
+		let c = xyz_to_rgb( &xyz );
+		Color::new( c.r / self.white.r, c.g / self.white.g, c.b / self.white.b )
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::material::*;
+	use crate::texture::*;
+
+	// RGB colour of a spectrum, as `Spectral` renders it
+	fn to_rgb<F : Fn( f32 ) -> f32>( spectrum : F ) -> Color {
+		let white = Spectral::new().white;
+		let c = xyz_to_rgb( &spectrum_to_xyz( spectrum, 1000 ) );
+		Color::new( c.r / white.r, c.g / white.g, c.b / white.b )
+	}
+
+	fn round_trip( c : &Color ) -> Color {
+		to_rgb( |lambda| rgb_to_spectrum( c, lambda ) )
+	}
+
+	#[test]
+	fn test_rgb_survives_round_trip_through_spectrum() {
+		let white = round_trip( &Color::new( 1.0, 1.0, 1.0 ) );
+		assert!( (white.r - 1.0).abs() < 0.01 && (white.g - 1.0).abs() < 0.01 && (white.b - 1.0).abs() < 0.01 );
+
+		let colours = [ Color::new( 0.8, 0.2, 0.1 ), Color::new( 0.1, 0.7, 0.2 ), Color::new( 0.2, 0.3, 0.9 ) ];
+		for c in colours.iter() {
+			let r = round_trip( c );
+			assert!( (r.r - c.r).abs() < 0.15 && (r.g - c.g).abs() < 0.15 && (r.b - c.b).abs() < 0.15, "{:?} -> {:?}", c, r );
+		}
+	}
+
+	#[test]
+	fn test_sellmeier_glass_disperses() {
+		let glass = Glass::bk7( Color::new( 1.0, 1.0, 1.0 ) );
+		let at = TexCoord::uv( 0.0, 0.0 );
+		assert!( (glass.ior( &at, 587.6 ) - 1.5168).abs() < 0.001 );
+		assert!( glass.ior( &at, 400.0 ) > glass.ior( &at, 700.0 ) );
+		assert_eq!( glass.ior( &at, 0.0 ), 1.5168 );
+	}
+
+	#[test]
+	fn test_planck_colour_and_efficacy() {
+		// Wien's displacement law puts the peak of 5000 K at 580 nm
+		let sun = Planck::new( 5000.0 );
+		assert!( sun.radiance( 580.0 ) > sun.radiance( 530.0 ) && sun.radiance( 580.0 ) > sun.radiance( 630.0 ) );
+
+		// white is the equal energy spectrum, close to a black body at 5500 K
+		let candle = Planck::new( 1900.0 ).colour();
+		let daylight = Planck::new( 5500.0 ).colour();
+		let sky = Planck::new( 12000.0 ).colour();
+		assert!( candle.r > candle.g && candle.g > candle.b );
+		assert!( (daylight.r - daylight.b).abs() < 0.1 && (daylight.g - daylight.b).abs() < 0.1, "{:?}", daylight );
+		assert!( sky.b > sky.r );
+		assert!( (daylight.luminance() - 1.0).abs() < 1e-4 );
+
+		// incandescent filaments radiate about 15 lm/W, black bodies peak near 95 lm/W around 6600 K
+		let bulb = Planck::new( 2700.0 ).efficacy();
+		assert!( bulb > 10.0 && bulb < 20.0, "{}", bulb );
+		let best = Planck::new( 6600.0 ).efficacy();
+		assert!( best > 85.0 && best < 105.0, "{}", best );
+	}
+
+	#[test]
+	fn test_cold_planck_stays_finite() {
+		for kelvin in [0.0, 300.0, -10.0].iter() {
+			let cold = Planck::new( *kelvin );
+			let c = cold.colour();
+			assert!( c.r.is_finite() && c.g.is_finite() && c.b.is_finite(), "{}: {:?}", kelvin, c );
+			assert!( (c.luminance() - 1.0).abs() < 1e-3 );
+			assert!( cold.value( 650.0 ).is_finite() && cold.value( 650.0 ) > 0.0 );
+		}
+	}
+
+	#[test]
+	fn test_spectral_emission_matches_rgb() {
+		let light = BlackBody::temperature( 3000.0, 2.0 );
+		let hit = Hit {
+			distance : 1.0,
+			pos : Vec3::zero(),
+			normal : Vec3::new( 0.0, 0.0, 1.0 ),
+			tangent : Vec3::new( 1.0, 0.0, 0.0 ),
+			bitangent : Vec3::new( 0.0, 1.0, 0.0 ),
+			u : 0.0,
+			v : 0.0,
+			uv_scale : 1.0,
+			ray : Ray::new( &Vec3::new( 0.0, 0.0, 1.0 ), &Vec3::new( 0.0, 0.0, -1.0 ) ),
+			material : &light
+		};
+		let wo = Vec3::new( 0.0, 0.0, 1.0 );
+		let rgb = light.emit( &hit, &wo );
+		let spectral = to_rgb( |lambda| light.emit_spectrum( &hit, &wo, lambda ) );
+		assert!( (rgb.luminance() - 2.0).abs() < 1e-3 );
+		assert!( (rgb.r - spectral.r).abs() < 0.02 && (rgb.g - spectral.g).abs() < 0.02 && (rgb.b - spectral.b).abs() < 0.02, "{:?} {:?}", rgb, spectral );
+	}
+}
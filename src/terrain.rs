@@ -0,0 +1,309 @@
+use crate::vec_math::*;
+use crate::hitable::*;
+use crate::material::*;
+use crate::image::*;
+use crate::noise::*;
+
+use std::path::Path;
+
+// Lowest and highest height in every block of cells of one level
+struct MinMaxLevel {
+	width : usize,
+	depth : usize,
+	ranges : Vec<(f32, f32)>
+}
+
+// Terrain over a grid of heights from 0 to 1, spanning `size.x` along x and
+// `size.z` along z from `origin`, and raised by up to `size.y`. Every cell is
+// two triangles with normals smoothed across the grid. Rays find the cells
+// through a min/max mip-map of the heights. The uvs span the whole terrain.
+#[allow(dead_code)]
+pub struct Heightfield {
+	pub origin : Vec3,
+	pub size : Vec3,
+	pub material : Box<Material>,
+	// vertices along x and z
+	width : usize,
+	depth : usize,
+	heights : Vec<f32>,
+	normals : Vec<Vec3>,
+	// the first has a block for every cell, every next one blocks of 2 x 2 of
+	// the one before, up to a single block
+	levels : Vec<MinMaxLevel>
+}
+
+#[allow(dead_code)]
+impl Heightfield {
+	// `heights` row by row, `width` along x in a row and `depth` rows along z
+	pub fn new( width : usize, depth : usize, heights : Vec<f32>, origin : Vec3, size : Vec3, material : Box<Material> ) -> Result<Heightfield, String> {
+		if width < 2 || depth < 2 {
+			return Err( "a heightfield needs at least 2 x 2 heights".to_string() );
+		}
+		if heights.len() != width * depth {
+			return Err( format!( "{} heights for a grid of {} x {}", heights.len(), width, depth ) );
+		}
+		let mut field = Heightfield { origin, size, material, width, depth, heights, normals : Vec::new(), levels : Vec::new() };
+		field.smooth_normals();
+		field.build_levels();
+		Ok( field )
+	}
+
+	// Samples `height` at `width` x `depth` points over u and v in [0, 1]
+	pub fn from_fn<F : Fn( f32, f32 ) -> f32>( width : usize, depth : usize, height : F, origin : Vec3, size : Vec3, material : Box<Material> ) -> Result<Heightfield, String> {
+		let mut heights = Vec::with_capacity( width * depth );
+		for j in 0..depth {
+			for i in 0..width {
+				heights.push( height( i as f32 / (width.max( 2 ) - 1) as f32, j as f32 / (depth.max( 2 ) - 1) as f32 ) );
+			}
+		}
+		Heightfield::new( width, depth, heights, origin, size, material )
+	}
+
+	// Luminance of every pixel, rows of the image run along z
+	pub fn from_image( image : &Image, origin : Vec3, size : Vec3, material : Box<Material> ) -> Result<Heightfield, String> {
+		let heights = image.pixels.iter().map(|c| c.luminance().max( 0.0 ).min( 1.0 )).collect();
+		Heightfield::new( image.width, image.height, heights, origin, size, material )
+	}
+
+	// Height maps are data, so 8 bit files are read as linear
+	pub fn load<P : AsRef<Path>>( path : P, origin : Vec3, size : Vec3, material : Box<Material> ) -> Result<Heightfield, String> {
+		Heightfield::from_image( &Image::load( path, ColourSpace::Linear )?, origin, size, material )
+	}
+
+	// Rolling hills of fractal noise, `features` hills or so across
+	pub fn noise( res : usize, seed : u32, features : f32, origin : Vec3, size : Vec3, material : Box<Material> ) -> Result<Heightfield, String> {
+		let perlin = Perlin::new( seed );
+		let fractal = Fractal::new();
+		let height = |u : f32, v : f32| (0.5 + 0.7 * perlin.fbm( &Vec3::new( u * features, 0.5, v * features ), &fractal )).max( 0.0 ).min( 1.0 );
+		Heightfield::from_fn( res, res, height, origin, size, material )
+	}
+
+	pub fn height( &self, i : usize, j : usize ) -> f32 {
+		self.heights[j * self.width + i]
+	}
+
+	fn step( &self ) -> (f32, f32) {
+		(self.size.x / (self.width - 1) as f32, self.size.z / (self.depth - 1) as f32)
+	}
+
+	fn vertex( &self, i : usize, j : usize ) -> Vec3 {
+		let (dx, dz) = self.step();
+		Vec3::new( self.origin.x + dx * i as f32, self.origin.y + self.size.y * self.height( i, j ), self.origin.z + dz * j as f32 )
+	}
+
+	// Slopes from central differences, one sided along the edges
+	fn smooth_normals( &mut self ) {
+		let (dx, dz) = self.step();
+		let mut normals = Vec::with_capacity( self.heights.len() );
+		for j in 0..self.depth {
+			for i in 0..self.width {
+				let (i0, i1) = (i.saturating_sub( 1 ), (i + 1).min( self.width - 1 ));
+				let (j0, j1) = (j.saturating_sub( 1 ), (j + 1).min( self.depth - 1 ));
+				let sx = self.size.y * (self.height( i1, j ) - self.height( i0, j )) / (dx * (i1 - i0) as f32);
+				let sz = self.size.y * (self.height( i, j1 ) - self.height( i, j0 )) / (dz * (j1 - j0) as f32);
+				normals.push( Vec3::new( -sx, 1.0, -sz ).normalized() );
+			}
+		}
+		self.normals = normals;
+	}
+
+	fn build_levels( &mut self ) {
+		let (w, d) = (self.width - 1, self.depth - 1);
+		let mut ranges = Vec::with_capacity( w * d );
+		for j in 0..d {
+			for i in 0..w {
+				let corners = [ self.height( i, j ), self.height( i + 1, j ), self.height( i, j + 1 ), self.height( i + 1, j + 1 ) ];
+				ranges.push( (corners.iter().cloned().fold( std::f32::INFINITY, f32::min ), corners.iter().cloned().fold( std::f32::NEG_INFINITY, f32::max )) );
+			}
+		}
+		let mut levels = vec![ MinMaxLevel { width : w, depth : d, ranges } ];
+
+		while levels.last().map_or( false, |l| l.width > 1 || l.depth > 1 ) {
+			let below = levels.last().unwrap();
+			let (w, d) = ((below.width + 1) / 2, (below.depth + 1) / 2);
+			let mut ranges = Vec::with_capacity( w * d );
+			for j in 0..d {
+				for i in 0..w {
+					let mut range = (std::f32::INFINITY, std::f32::NEG_INFINITY);
+					for (ci, cj) in [ (2 * i, 2 * j), (2 * i + 1, 2 * j), (2 * i, 2 * j + 1), (2 * i + 1, 2 * j + 1) ].iter() {
+						if *ci < below.width && *cj < below.depth {
+							let (lo, hi) = below.ranges[cj * below.width + ci];
+							range = (f32::min( range.0, lo ), f32::max( range.1, hi ));
+						}
+					}
+					ranges.push( range );
+				}
+			}
+			levels.push( MinMaxLevel { width : w, depth : d, ranges } );
+		}
+		self.levels = levels;
+	}
+
+	// Box around the cells of block (i, j) of `level`
+	fn block_bounds( &self, level : usize, i : usize, j : usize ) -> Aabb {
+		let (dx, dz) = self.step();
+		let span = 1 << level;
+		let (lo, hi) = self.levels[level].ranges[j * self.levels[level].width + i];
+		let (i1, j1) = (((i + 1) * span).min( self.width - 1 ), ((j + 1) * span).min( self.depth - 1 ));
+		Aabb {
+			min : Vec3::new( self.origin.x + dx * (i * span) as f32, self.origin.y + self.size.y * lo, self.origin.z + dz * (j * span) as f32 ),
+			max : Vec3::new( self.origin.x + dx * i1 as f32, self.origin.y + self.size.y * hi, self.origin.z + dz * j1 as f32 )
+		}
+	}
+
+	// Corners of the two triangles of a cell, wound to face up
+	fn triangle( &self, i : usize, j : usize, second : bool ) -> [(usize, usize); 3] {
+		if second {
+			[ (i, j), (i, j + 1), (i + 1, j + 1) ]
+		} else {
+			[ (i, j), (i + 1, j + 1), (i + 1, j) ]
+		}
+	}
+
+	// Möller-Trumbore, returns the distance and the barycentrics of the second and third corner
+	fn intersect( &self, ray : &Ray, corners : &[(usize, usize); 3], t_max : f32 ) -> Option<(f32, f32, f32)> {
+		let p0 = self.vertex( corners[0].0, corners[0].1 );
+		let e1 = self.vertex( corners[1].0, corners[1].1 ) - &p0;
+		let e2 = self.vertex( corners[2].0, corners[2].1 ) - &p0;
+
+		let pvec = cross_product( &ray.direction, &e2 );
+		let det = dot_product( &e1, &pvec );
+		if det.abs() < 1e-12 {
+			return None;
+		}
+		let inv = 1.0 / det;
+
+		let tvec = &ray.origin - &p0;
+		let b1 = dot_product( &tvec, &pvec ) * inv;
+		if b1 < 0.0 || b1 > 1.0 {
+			return None;
+		}
+		let qvec = cross_product( &tvec, &e1 );
+		let b2 = dot_product( &ray.direction, &qvec ) * inv;
+		if b2 < 0.0 || b1 + b2 > 1.0 {
+			return None;
+		}
+
+		let distance = dot_product( &e2, &qvec ) * inv;
+		if distance < 0.0001 || distance >= t_max {
+			return None;
+		}
+		Some( (distance, b1, b2) )
+	}
+
+	// Nearest triangle along the ray, going down the blocks it passes through
+	// nearest first
+	fn closest( &self, ray : &Ray ) -> Option<([(usize, usize); 3], f32, f32, f32)> {
+		let mut best = None;
+		let mut t_max = std::f32::INFINITY;
+		let mut stack = vec![ (self.levels.len() - 1, 0, 0) ];
+
+		while let Some((level, i, j)) = stack.pop() {
+			if self.block_bounds( level, i, j ).intersect( ray, t_max ).is_none() {
+				continue;
+			}
+			if level == 0 {
+				for second in [ false, true ].iter() {
+					let corners = self.triangle( i, j, *second );
+					if let Some((d, b1, b2)) = self.intersect( ray, &corners, t_max ) {
+						t_max = d;
+						best = Some( (corners, d, b1, b2) );
+					}
+				}
+				continue;
+			}
+
+			let below = &self.levels[level - 1];
+			let mut children = Vec::with_capacity( 4 );
+			for (ci, cj) in [ (2 * i, 2 * j), (2 * i + 1, 2 * j), (2 * i, 2 * j + 1), (2 * i + 1, 2 * j + 1) ].iter() {
+				if *ci < below.width && *cj < below.depth {
+					if let Some((enter, _)) = self.block_bounds( level - 1, *ci, *cj ).intersect( ray, t_max ) {
+						children.push( (enter, *ci, *cj) );
+					}
+				}
+			}
+			children.sort_by(|a, b| b.0.partial_cmp( &a.0 ).unwrap_or( std::cmp::Ordering::Equal ));
+			stack.extend( children.iter().map(|c| (level - 1, c.1, c.2)) );
+		}
+		best
+	}
+}
+
+impl Hitable for Heightfield {
+	fn hit( &self, ray : &Ray ) -> Option<Hit> {
+		let (corners, distance, b1, b2) = self.closest( ray )?;
+		let b0 = 1.0 - b1 - b2;
+		let n = |k : usize| &self.normals[corners[k].1 * self.width + corners[k].0];
+		let normal = (b0 * n( 0 ) + b1 * n( 1 ) + b2 * n( 2 )).normalized();
+		let (tangent, bitangent) = tangent_frame( &normal, &Vec3::new( 1.0, 0.0, 0.0 ), &Vec3::new( 0.0, 0.0, 1.0 ) );
+
+		let pos = ray.get_point( distance );
+		Some( Hit {
+			distance,
+			u : (pos.x - self.origin.x) / self.size.x,
+			v : (pos.z - self.origin.z) / self.size.z,
+			uv_scale : 1.0 / (self.size.x * self.size.z).sqrt(),
+			pos,
+			normal,
+			tangent,
+			bitangent,
+			ray : ray.clone(),
+			material : self.material.as_ref()
+		} )
+	}
+
+	fn bounds( &self ) -> Option<Aabb> {
+		Some( self.block_bounds( self.levels.len() - 1, 0, 0 ) )
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::texture::*;
+	use crate::random::*;
+
+	fn grey() -> Box<Material> {
+		Box::new( Lambertian { albedo : uniform( 0.5 ) } )
+	}
+
+	#[test]
+	fn test_slope() {
+		// rises by 1 over 2 along x, the grid is uneven on purpose
+		let origin = Vec3::new( -1.0, 0.0, -1.0 );
+		let slope = Heightfield::from_fn( 7, 5, |u, _| u, origin, Vec3::new( 2.0, 1.0, 2.0 ), grey() ).unwrap();
+		let down = Vec3::new( 0.0, -1.0, 0.0 );
+		for _ in 0..100 {
+			let (x, z) = (random() * 2.0 - 1.0, random() * 2.0 - 1.0);
+			let hit = slope.hit( &Ray::new( &Vec3::new( x, 5.0, z ), &down ) ).unwrap();
+			assert!( (hit.pos.y - 0.5 * (x + 1.0)).abs() < 1e-4 );
+			assert!( (hit.normal - Vec3::new( -0.5, 1.0, 0.0 ).normalized()).length() < 1e-4 );
+			assert!( (hit.u - 0.5 * (x + 1.0)).abs() < 1e-4 && (hit.v - 0.5 * (z + 1.0)).abs() < 1e-4 );
+		}
+		assert!( slope.hit( &Ray::new( &Vec3::new( 1.1, 5.0, 0.0 ), &down ) ).is_none() );
+		assert!( slope.bounds().unwrap().max.y == 1.0 );
+		assert!( Heightfield::new( 3, 3, vec![ 0.0; 8 ], Vec3::zero(), Vec3::ones(), grey() ).is_err() );
+	}
+
+	#[test]
+	fn test_mip_map_finds_the_closest_cell() {
+		let hills = Heightfield::noise( 37, 3, 4.0, Vec3::new( -2.0, -1.0, 1.0 ), Vec3::new( 4.0, 1.0, 4.0 ), grey() ).unwrap();
+		for _ in 0..300 {
+			let from = Vec3::new( random() * 6.0 - 3.0, 0.5, random() * 2.0 - 1.0 );
+			let target = Vec3::new( random() * 4.0 - 2.0, -1.0, 1.0 + random() * 4.0 );
+			let ray = Ray::new( &from, &(&target - &from) );
+
+			let mut brute : Option<f32> = None;
+			for j in 0..hills.depth - 1 {
+				for i in 0..hills.width - 1 {
+					for second in [ false, true ].iter() {
+						if let Some((d, _, _)) = hills.intersect( &ray, &hills.triangle( i, j, *second ), std::f32::INFINITY ) {
+							brute = Some( brute.map_or( d, |b| b.min( d ) ) );
+						}
+					}
+				}
+			}
+			assert_eq!( hills.hit( &ray ).map(|h| h.distance), brute );
+		}
+	}
+}
@@ -1,8 +1,50 @@
 
 use crate::color::*;
+use crate::vec_math::*;
+
+use std::rc::Rc;
+
+// Where a texture is looked up: the surface parametrisation for mapped
+// textures and the point in space for solid ones
+pub struct TexCoord {
+	pub u : f32,
+	pub v : f32,
+	pub pos : Vec3,
+	pub normal : Vec3,
+	// size of the area the lookup stands for on the surface, 0 for a point
+	pub width : f32,
+	// u, v units per unit of length on the surface
+	pub uv_scale : f32
+}
+
+impl TexCoord {
+	// Lookup without a surface behind it, the point is (u, v, 0) on a plane facing +z
+	#[allow(dead_code)]
+	pub fn uv( u : f32, v : f32 ) -> TexCoord {
+		TexCoord { u, v, pos : Vec3::new( u, v, 0.0 ), normal : Vec3::new( 0.0, 0.0, 1.0 ), width : 0.0, uv_scale : 1.0 }
+	}
+}
 
 pub trait Texture { 
-	fn value( &self, u : f32, v : f32 ) -> Color;
+	fn value( &self, at : &TexCoord ) -> Color;
+
+	// Scalar parameters are read from the luminance
+	fn scalar( &self, at : &TexCoord ) -> f32 {
+		self.value( at ).luminance()
+	}
+
+	// Only true when the texture is black everywhere
+	fn is_black( &self ) -> bool { false }
+}
+
+// Shared texture of one colour, for parameters which don't vary
+pub fn constant( color : Color ) -> Rc<Texture> {
+	Rc::new( ConstantTexture { color } )
+}
+
+// Shared texture of one value, for scalar parameters
+pub fn uniform( value : f32 ) -> Rc<Texture> {
+	Rc::new( ConstantTexture { color : Color::new( value, value, value ) } )
 }
 
 pub struct ConstantTexture {
@@ -10,12 +52,21 @@ pub struct ConstantTexture {
 }
 
 impl Texture for ConstantTexture {
-	fn value( &self, u : f32, v : f32 ) -> Color{
+	fn value( &self, _at : &TexCoord ) -> Color{
 		self.color.clone()
 	}
+
+	// exact for grey levels, the luminance weights don't quite sum to one in f32
+	fn scalar( &self, _at : &TexCoord ) -> f32 {
+		let c = &self.color;
+		if c.r == c.g && c.g == c.b { c.r } else { c.luminance() }
+	}
+
+	fn is_black( &self ) -> bool { self.color.is_black() }
 }
 
 
+// Solid checker of cubes 0.5 / scale wide, so it works on any surface
 pub struct ChessTexture {
 	pub color_a : Color,
 	pub color_b : Color,
@@ -25,15 +76,228 @@ pub struct ChessTexture {
 use std::f32;
 
 impl Texture for ChessTexture {
-	fn value( &self, u : f32, v : f32 ) -> Color{
-		let u = self.scale * u;
-		let u = u - u.round();
-
-		let v = self.scale * v;
-		let v = v - v.round();
+	fn value( &self, at : &TexCoord ) -> Color{
+		let cell = |x : f32| (2.0 * self.scale * x).floor() as i64;
+		let parity = (cell( at.pos.x ) + cell( at.pos.y ) + cell( at.pos.z )).rem_euclid( 2 );
 
-		let f = u * v > 0.0;
+		let f = parity == 0;
 		let color = if f { self.color_a.clone()}else{self.color_b.clone()};
 		return color;
 	}
 }
+
+use crate::image::*;
+use std::path::Path;
+
+// What happens to u, v outside of [0, 1]
+#[allow(dead_code)]
+#[derive( Copy, Clone, PartialEq, Debug )]
+pub enum Wrap {
+	Repeat,
+	Clamp,
+	Mirror
+}
+
+#[allow(dead_code)]
+#[derive( Copy, Clone, PartialEq, Debug )]
+pub enum Filter {
+	Nearest,
+	// bilinear on the MIP level closest to the footprint
+	Bilinear,
+	// bilinear on the two closest levels, blended
+	Trilinear
+}
+
+// Image mapped over [0, 1] in u and v, v goes from the bottom row to the top
+#[allow(dead_code)]
+pub struct ImageTexture {
+	// MIP pyramid, every level half the size of the one before down to 1x1
+	pub levels : Vec<Image>,
+	pub wrap : Wrap,
+	pub filter : Filter
+}
+
+#[allow(dead_code)]
+impl ImageTexture {
+	// `Image::load` and `Image::decode` never give an empty image
+	pub fn new( image : Image, wrap : Wrap, filter : Filter ) -> ImageTexture {
+		assert!( image.width > 0 && image.height > 0, "empty image texture" );
+		let mut levels = vec![ image ];
+		while levels[levels.len() - 1].width > 1 || levels[levels.len() - 1].height > 1 {
+			let next = levels[levels.len() - 1].downsample();
+			levels.push( next );
+		}
+		ImageTexture { levels, wrap, filter }
+	}
+
+	pub fn load<P : AsRef<Path>>( path : P, space : ColourSpace, wrap : Wrap, filter : Filter ) -> Result<ImageTexture, String> {
+		Ok( ImageTexture::new( Image::load( path, space )?, wrap, filter ) )
+	}
+
+	fn wrap_index( &self, i : i64, size : usize ) -> usize {
+		let n = size as i64;
+		match self.wrap {
+			Wrap::Repeat => i.rem_euclid( n ) as usize,
+			Wrap::Clamp => i.max( 0 ).min( n - 1 ) as usize,
+			Wrap::Mirror => {
+				let m = i.rem_euclid( 2 * n );
+				(if m < n { m } else { 2 * n - 1 - m }) as usize
+			}
+		}
+	}
+
+	fn texel( &self, level : usize, x : i64, y : i64 ) -> Color {
+		let image = &self.levels[level];
+		image.get( self.wrap_index( x, image.width ), self.wrap_index( y, image.height ) )
+	}
+
+	// continuous texel coordinates with the centres at half integers
+	fn coordinates( &self, level : usize, u : f32, v : f32 ) -> (f32, f32) {
+		let image = &self.levels[level];
+		(u * image.width as f32, (1.0 - v) * image.height as f32)
+	}
+
+	fn nearest( &self, level : usize, u : f32, v : f32 ) -> Color {
+		let (x, y) = self.coordinates( level, u, v );
+		self.texel( level, x.floor() as i64, y.floor() as i64 )
+	}
+
+	fn bilinear( &self, level : usize, u : f32, v : f32 ) -> Color {
+		let (x, y) = self.coordinates( level, u, v );
+		let (x, y) = (x - 0.5, y - 0.5);
+		let (x0, y0) = (x.floor(), y.floor());
+		let (fx, fy) = (x - x0, y - y0);
+		let (x0, y0) = (x0 as i64, y0 as i64);
+
+		let top = self.texel( level, x0, y0 ) * (1.0 - fx) + self.texel( level, x0 + 1, y0 ) * fx;
+		let bottom = self.texel( level, x0, y0 + 1 ) * (1.0 - fx) + self.texel( level, x0 + 1, y0 + 1 ) * fx;
+		top * (1.0 - fy) + bottom * fy
+	}
+
+	// Filtered colour for a footprint `width` wide in u, v units. A width of
+	// zero reads the full resolution image.
+	pub fn lookup( &self, u : f32, v : f32, width : f32 ) -> Color {
+		let base = &self.levels[0];
+		let top = (self.levels.len() - 1) as f32;
+		let level = (width * base.width.max( base.height ) as f32).log2().max( 0.0 ).min( top );
+
+		match self.filter {
+			Filter::Nearest => self.nearest( 0, u, v ),
+			Filter::Bilinear => self.bilinear( level.round() as usize, u, v ),
+			Filter::Trilinear => {
+				let l0 = level.floor();
+				let t = level - l0;
+				let fine = self.bilinear( l0 as usize, u, v );
+				if t == 0.0 {
+					return fine;
+				}
+				fine * (1.0 - t) + self.bilinear( l0 as usize + 1, u, v ) * t
+			}
+		}
+	}
+}
+
+impl Texture for ImageTexture {
+	fn value( &self, at : &TexCoord ) -> Color {
+		self.lookup( at.u, at.v, at.width * at.uv_scale )
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::hitable::*;
+	use crate::shapes::*;
+	use crate::material::*;
+
+	// 4x2 with a red left half and a blue right half
+	fn halves( wrap : Wrap, filter : Filter ) -> ImageTexture {
+		let mut image = Image::new( 4, 2 );
+		for y in 0..2 {
+			for x in 0..4 {
+				image.set( x, y, if x < 2 { Color::new( 1.0, 0.0, 0.0 ) } else { Color::new( 0.0, 0.0, 1.0 ) } );
+			}
+		}
+		ImageTexture::new( image, wrap, filter )
+	}
+
+	#[test]
+	fn test_wrap_modes() {
+		let repeat = halves( Wrap::Repeat, Filter::Nearest );
+		assert_eq!( repeat.value( &TexCoord::uv( 1.1, 0.5 ) ).r, 1.0 );
+		assert_eq!( repeat.value( &TexCoord::uv( -0.1, 0.5 ) ).b, 1.0 );
+
+		let clamp = halves( Wrap::Clamp, Filter::Nearest );
+		assert_eq!( clamp.value( &TexCoord::uv( 1.1, 0.5 ) ).b, 1.0 );
+		assert_eq!( clamp.value( &TexCoord::uv( -3.0, 0.5 ) ).r, 1.0 );
+
+		let mirror = halves( Wrap::Mirror, Filter::Nearest );
+		assert_eq!( mirror.value( &TexCoord::uv( 1.1, 0.5 ) ).b, 1.0 );
+		assert_eq!( mirror.value( &TexCoord::uv( -0.1, 0.5 ) ).r, 1.0 );
+	}
+
+	#[test]
+	fn test_bilinear_and_mip_levels() {
+		let texture = halves( Wrap::Clamp, Filter::Trilinear );
+		assert_eq!( texture.levels.len(), 3 );
+
+		// half way between the centres of texels 1 and 2
+		let c = texture.lookup( 0.5, 0.5, 0.0 );
+		assert!( (c.r - 0.5).abs() < 1e-6 && (c.b - 0.5).abs() < 1e-6, "{:?}", c );
+		let c = texture.lookup( 0.375, 0.5, 0.0 );
+		assert!( (c.r - 1.0).abs() < 1e-6, "{:?}", c );
+
+		// a footprint as wide as the image reads the 1x1 average
+		let c = texture.lookup( 0.1, 0.5, 1.0 );
+		assert!( (c.r - 0.5).abs() < 1e-6 && (c.b - 0.5).abs() < 1e-6, "{:?}", c );
+
+		// and one in between blends the 2x1 and 1x1 levels
+		let c = texture.lookup( 0.1, 0.5, 0.7 );
+		assert!( c.r > 0.5 && c.r < 1.0, "{:?}", c );
+	}
+
+	#[test]
+	fn test_chess_is_solid() {
+		let chess = ChessTexture { color_a : Color::new( 1.0, 1.0, 1.0 ), color_b : Color::new( 0.0, 0.0, 0.0 ), scale : 1.0 };
+		let at = |x : f32, y : f32, z : f32| chess.value( &TexCoord { pos : Vec3::new( x, y, z ), normal : Vec3::new( 0.0, 1.0, 0.0 ), ..TexCoord::uv( 0.0, 0.0 ) } ).r;
+
+		// same u, v but half a cell apart along every axis
+		assert_eq!( at( 0.25, 0.25, 0.25 ), 1.0 );
+		assert_eq!( at( 0.75, 0.25, 0.25 ), 0.0 );
+		assert_eq!( at( 0.25, -0.25, 0.25 ), 0.0 );
+		assert_eq!( at( 0.25, 0.25, 0.75 ), 0.0 );
+		assert_eq!( at( -0.25, -0.25, 0.25 ), 1.0 );
+	}
+
+	#[test]
+	fn test_distant_lookups_are_filtered() {
+		// a checkerboard of single texels
+		let mut image = Image::new( 64, 64 );
+		for y in 0..64 {
+			for x in 0..64 {
+				let c = ((x + y) % 2) as f32;
+				image.set( x, y, Color::new( c, c, c ) );
+			}
+		}
+		let texture = ImageTexture::new( image, Wrap::Repeat, Filter::Trilinear );
+
+		// a 2x2 wall seen through the middle of a texel, from near and far
+		let look = |z : f32| {
+			let wall = Quad {
+				corner : Vec3::new( -1.0, -1.0, z ),
+				edge_u : Vec3::new( 2.0, 0.0, 0.0 ),
+				edge_v : Vec3::new( 0.0, 2.0, 0.0 ),
+				material : Box::new( Lambertian { albedo : uniform( 0.5 ) } )
+			};
+			let at = Vec3::new( -1.0 + 2.0 * 10.5 / 64.0, -1.0 + 2.0 * 20.5 / 64.0, z );
+			let ray = Ray { spread : 1.0 / 800.0, ..Ray::new( &Vec3::zero(), &at ) };
+			let hit = wall.hit( &ray ).unwrap();
+			texture.value( &hit.tex_coord() ).r
+		};
+
+		let near = look( 1.0 );
+		assert!( near == 0.0 || near == 1.0, "{}", near );
+		let far = look( 1000.0 );
+		assert!( (far - 0.5).abs() < 0.01, "{}", far );
+	}
+}
@@ -167,6 +167,40 @@ pub fn refract( v : &Vec3, n : &Vec3, theta : f32) -> Option<Vec3> {
 	return None;
 }
 
+// Orthonormal basis with `w` along a given direction, used for local shading frames
+#[derive(Debug, Clone)]
+pub struct Onb {
+	pub u : Vec3,
+	pub v : Vec3,
+	pub w : Vec3
+}
+
+impl Onb {
+
+	// Duff et al. 2017, "Building an orthonormal basis, revisited"
+	#[allow(dead_code)]
+	pub fn from_w (w : &Vec3) -> Onb {
+		let sign = if w.z >= 0.0 { 1.0 } else { -1.0 };
+		let a = -1.0 / (sign + w.z);
+		let b = w.x * w.y * a;
+		Onb {
+			u : Vec3::new( 1.0 + sign * w.x * w.x * a, sign * b, -sign * w.x ),
+			v : Vec3::new( b, sign + w.y * w.y * a, -w.y ),
+			w : w.clone()
+		}
+	}
+
+	#[allow(dead_code)]
+	pub fn to_local (&self, a : &Vec3) -> Vec3 {
+		Vec3::new( dot_product(a, &self.u), dot_product(a, &self.v), dot_product(a, &self.w) )
+	}
+
+	#[allow(dead_code)]
+	pub fn to_world (&self, a : &Vec3) -> Vec3 {
+		a.x * &self.u + a.y * &self.v + a.z * &self.w
+	}
+}
+
 
 impl Mul<f32> for Vec3 {
 	type Output = Vec3;
@@ -414,7 +448,7 @@ impl<'a> Mul for &'a Mat3x3 {
 }
 //--------------------------------------------------------------
 //-----------------------------------------------------------------------------
-#[derive( Copy, Clone )]
+#[derive( Copy, Clone, Debug )]
 pub struct Mat4x4 {
 	pub m : [f32; 16]
 }
@@ -466,6 +500,21 @@ impl Mat4x4{
 		   0.0, 0.0, 0.0, 1.0,]}
 	}
 
+	// Rotation by `a` around the unit vector `axis` by the right hand rule,
+	// the same way round as `x_rotation` and `z_rotation`
+	#[allow(dead_code)]
+	pub fn axis_rotation (axis : &Vec3, a : f32) -> Mat4x4{
+		let c = a.cos();
+		let s = a.sin();
+		let t = 1.0 - c;
+		let (x, y, z) = (axis.x, axis.y, axis.z);
+		Mat4x4 {m :
+		  [c + x*x*t,   x*y*t - z*s, x*z*t + y*s, 0.0,
+		   y*x*t + z*s, c + y*y*t,   y*z*t - x*s, 0.0,
+		   z*x*t - y*s, z*y*t + x*s, c + z*z*t,   0.0,
+		   0.0,         0.0,         0.0,         1.0,]}
+	}
+
 	#[allow(dead_code)]
 	pub fn scale (x : f32, y : f32, z : f32) -> Mat4x4{
 		Mat4x4 {m :
@@ -582,6 +631,23 @@ impl Mat4x4{
 		}
 	}
 
+	// Transposed linear part, which carries normals with the inverse matrix
+	pub fn apply_transposed_rotation( &self, inp: &Vec3) -> Vec3{
+		Vec3 {
+			x : inp.x * self.m[0] + inp.y * self.m[4] + inp.z * self.m[8],
+			y : inp.x * self.m[1] + inp.y * self.m[5] + inp.z * self.m[9],
+			z : inp.x * self.m[2] + inp.y * self.m[6] + inp.z * self.m[10],
+		}
+	}
+
+	// Determinant of the linear part, how much volumes grow
+	pub fn linear_det( &self ) -> f32 {
+		let m = &self.m;
+		m[0] * (m[5] * m[10] - m[6] * m[9]) -
+		m[1] * (m[4] * m[10] - m[6] * m[8]) +
+		m[2] * (m[4] * m[9]  - m[5] * m[8])
+	}
+
 	pub fn inv (&self) -> Mat4x4 {
 		let mut inv = [0.0; 16];
 		let m = &self.m;
@@ -824,14 +890,26 @@ impl<'a> Mul for &'a Mat4x4 {
 #[derive(Debug, Clone)]
 pub struct Ray {
 	pub origin    : Vec3,
-	pub direction : Vec3
+	pub direction : Vec3,
+	// in nanometers, 0 when the ray carries RGB
+	pub wavelength : f32,
+	// moment within the shutter interval the ray sees the scene at
+	pub time : f32,
+	// angle the footprint of the ray widens by, per unit of distance, 0 for an
+	// infinitely thin ray
+	pub spread : f32
 }
 
 impl Ray{
 
 	#[allow(dead_code)]
 	pub fn new (origin: &Vec3, direction: &Vec3) -> Ray {
-		Ray { origin : origin.clone(), direction : direction.normalized() }
+		Ray { origin : origin.clone(), direction : direction.normalized(), wavelength : 0.0, time : 0.0, spread : 0.0 }
+	}
+
+	// New ray which keeps the wavelength, time and spread of this one
+	pub fn spawn (&self, origin: &Vec3, direction: &Vec3) -> Ray {
+		Ray { origin : origin.clone(), direction : direction.normalized(), wavelength : self.wavelength, time : self.time, spread : self.spread }
 	}
 
 	#[allow(dead_code)]
@@ -848,6 +926,88 @@ impl Ray{
 
 }
 
+//--------------------------------------------------------------
+// Axis aligned bounding box
+#[derive(Debug, Clone)]
+pub struct Aabb {
+	pub min : Vec3,
+	pub max : Vec3
+}
+
+impl Aabb {
+
+	pub fn empty () -> Aabb {
+		let inf = std::f32::INFINITY;
+		Aabb { min : Vec3::new( inf, inf, inf ), max : Vec3::new( -inf, -inf, -inf ) }
+	}
+
+	pub fn grow (&mut self, p : &Vec3) {
+		self.min = Vec3::new( self.min.x.min(p.x), self.min.y.min(p.y), self.min.z.min(p.z) );
+		self.max = Vec3::new( self.max.x.max(p.x), self.max.y.max(p.y), self.max.z.max(p.z) );
+	}
+
+	pub fn union (&self, other : &Aabb) -> Aabb {
+		let mut b = self.clone();
+		b.grow( &other.min );
+		b.grow( &other.max );
+		b
+	}
+
+	pub fn centroid (&self) -> Vec3 {
+		(&self.min + &self.max) * 0.5
+	}
+
+	#[allow(dead_code)]
+	pub fn corners (&self) -> Vec<Vec3> {
+		(0..8).map(|i| Vec3::new(
+			if i & 1 == 0 { self.min.x } else { self.max.x },
+			if i & 2 == 0 { self.min.y } else { self.max.y },
+			if i & 4 == 0 { self.min.z } else { self.max.z } )).collect()
+	}
+
+	// Box around this one moved by the affine transform `m`
+	#[allow(dead_code)]
+	pub fn transformed (&self, m : &Mat4x4) -> Aabb {
+		let mut b = Aabb::empty();
+		for c in self.corners().iter() {
+			b.grow( &m.apply( c ) );
+		}
+		b
+	}
+
+	// Index of the longest side
+	pub fn largest_axis (&self) -> usize {
+		let e = &self.max - &self.min;
+		if e.x >= e.y && e.x >= e.z { 0 } else if e.y >= e.z { 1 } else { 2 }
+	}
+
+	// Distances at which the ray enters and leaves the box, clipped to [0, t_max]
+	pub fn intersect (&self, ray : &Ray, t_max : f32) -> Option<(f32, f32)> {
+		let o = [ ray.origin.x, ray.origin.y, ray.origin.z ];
+		let d = [ ray.direction.x, ray.direction.y, ray.direction.z ];
+		let lo = [ self.min.x, self.min.y, self.min.z ];
+		let hi = [ self.max.x, self.max.y, self.max.z ];
+
+		let mut t0 = 0.0f32;
+		let mut t1 = t_max;
+		for i in 0..3 {
+			let inv = 1.0 / d[i];
+			let mut near = (lo[i] - o[i]) * inv;
+			let mut far = (hi[i] - o[i]) * inv;
+			if near > far {
+				std::mem::swap( &mut near, &mut far );
+			}
+			// NaN from 0 * inf leaves the bounds untouched
+			if near > t0 { t0 = near; }
+			if far < t1 { t1 = far; }
+			if t0 > t1 {
+				return None;
+			}
+		}
+		Some( (t0, t1) )
+	}
+}
+
 //--------------------------------------------------------------
 
 #[cfg(test)]